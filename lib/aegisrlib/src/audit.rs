@@ -0,0 +1,552 @@
+use crate::constant::{STORE_AUDIT_DIR, STORE_AUDIT_KEY, STORE_AUDIT_LOG};
+use crate::crypto::AegCrypto;
+use crate::file_system::AegFileSystem;
+use crate::permissions::AegPermissions;
+use base64::{Engine as _, engine::general_purpose};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use time::OffsetDateTime;
+use zeroize::Zeroizing;
+
+/// The audit log is rotated once it reaches this size (0 = never).
+pub const DEFAULT_AUDIT_MAX_SIZE: u64 = 16 * 1024 * 1024;
+/// Rotated audit logs kept by default (0 = keep all).
+pub const DEFAULT_AUDIT_RETENTION: usize = 0;
+const AUDIT_ROTATED_PREFIX: &str = "audit-";
+/// `prev` of the very first entry.
+const AUDIT_GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+/// Associated data of the sealed chain key in `audit.key`.
+const AUDIT_KEY_AAD: &[u8] = b"aegisr audit key v1";
+
+/// Key of the entry hashes.
+type ChainKey = Zeroizing<[u8; 32]>;
+
+/// Audit log settings (`audit_*` in the daemon config).
+#[derive(Debug, Clone)]
+pub struct AuditConfig {
+    /// Directory of the log (`~/.aegisr/audit` when `None`).
+    pub dir: Option<PathBuf>,
+    pub max_size: u64,
+    pub retention: usize,
+    /// Collections whose reads are recorded too.
+    pub read_collections: Vec<String>,
+}
+
+/// One line of the audit log. `hash` is the keyed blake3 hash of the line's JSON with an
+/// empty `hash`; `prev` is the hash of the line before it, so changing, removing or
+/// reordering a line breaks the chain from there on, and without the key the chain cannot
+/// be recomputed to hide it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditEntry {
+    pub seq: u64,
+    /// UTC, RFC 3339 with milliseconds.
+    pub time: String,
+    /// Who asked: the client's address, or `daemon` for what the daemon does by itself.
+    pub client: String,
+    pub op: String,
+    pub collection: Option<String>,
+    pub detail: Option<String>,
+    pub ok: bool,
+    pub prev: String,
+    pub hash: String,
+}
+
+impl AuditEntry {
+    fn compute_hash(&self, key: &ChainKey) -> String {
+        let unhashed = AuditEntry {
+            hash: String::new(),
+            ..self.clone()
+        };
+        let json = serde_json::to_vec(&unhashed).expect("audit entry serializes");
+        blake3::keyed_hash(key, &json).to_hex().to_string()
+    }
+}
+
+/// Outcome of `AegAudit::verify`.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct AuditVerifyReport {
+    pub files: usize,
+    pub entries: u64,
+    pub first_seq: u64,
+    pub last_seq: u64,
+    /// Hash of the last entry. Kept somewhere else, it also reveals a log cut short.
+    pub head: String,
+    /// Whether the chain starts at the first entry ever written; false once rotated logs
+    /// have been deleted by the retention policy.
+    pub complete: bool,
+}
+
+struct AuditState {
+    config: AuditConfig,
+    dir: PathBuf,
+    file: File,
+    size: u64,
+    next_seq: u64,
+    last_hash: String,
+    key: ChainKey,
+}
+
+static AUDIT: OnceLock<Mutex<Option<AuditState>>> = OnceLock::new();
+
+/// AUDIT LOG (append-only, hash-chained)
+///
+/// Administrative operations (collection create/delete/rename, init, clear, restore, key
+/// rotation, import, snapshot) and reads of the collections listed in
+/// `audit_read_collections` are appended to `audit.log`, one JSON entry per line, never
+/// values. Every entry carries the hash of the one before it, including across rotation:
+/// a rotated log is renamed to `audit-<utc timestamp>-<counter>.log` and the new log starts
+/// with a `rotate` entry chained to its last entry. `verify` walks the whole chain.
+///
+/// The hashes are keyed with a random key kept in `audit.key` in the data directory,
+/// sealed with the authorization key. It is re-wrapped rather than replaced when that key
+/// changes (rotation, `init --reset`), so older entries keep verifying.
+pub struct AegAudit;
+
+impl AegAudit {
+    fn state() -> &'static Mutex<Option<AuditState>> {
+        AUDIT.get_or_init(|| Mutex::new(None))
+    }
+
+    /// `~/.aegisr/audit`; `AegFileSystem::reset_files` leaves it in place.
+    pub fn default_dir() -> PathBuf {
+        AegFileSystem::get_config_path().join(STORE_AUDIT_DIR)
+    }
+
+    pub fn is_enabled() -> bool {
+        Self::state()
+            .lock()
+            .expect("Failed to lock audit state")
+            .is_some()
+    }
+
+    /// Open (or create) the log and start recording. A torn last line left by a crash is
+    /// cut off; the chain continues from the last complete entry.
+    pub fn open(config: AuditConfig, auth_key: &str) -> Result<(), String> {
+        let dir = config.dir.clone().unwrap_or_else(Self::default_dir);
+        AegPermissions::create_private_dir(&dir)
+            .map_err(|e| format!("create {}: {}", dir.display(), e))?;
+        let path = dir.join(STORE_AUDIT_LOG);
+        let mut last = Self::last_entry(&path, true)?;
+        if last.is_none()
+            && let Some(rotated) = Self::rotated_logs(&dir).last()
+        {
+            last = Self::last_entry(rotated, false)?;
+        }
+        let key = match Self::chain_key(auth_key)? {
+            Some(key) => key,
+            None if last.is_none() => Self::create_chain_key(auth_key)?,
+            None => {
+                return Err(format!(
+                    "{} is missing; the log cannot be continued",
+                    STORE_AUDIT_KEY
+                ));
+            }
+        };
+        let file = AegPermissions::private_file_options()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| format!("open {}: {}", path.display(), e))?;
+        let size = file.metadata().map(|m| m.len()).unwrap_or(0);
+        *Self::state().lock().expect("Failed to lock audit state") = Some(AuditState {
+            config,
+            dir,
+            file,
+            size,
+            next_seq: last.as_ref().map(|e| e.seq + 1).unwrap_or(1),
+            last_hash: last.map(|e| e.hash).unwrap_or_else(|| AUDIT_GENESIS.into()),
+            key,
+        });
+        Ok(())
+    }
+
+    /// Whether reads of `collection` are recorded.
+    pub fn audits_reads(collection: &str) -> bool {
+        Self::state()
+            .lock()
+            .expect("Failed to lock audit state")
+            .as_ref()
+            .is_some_and(|s| s.config.read_collections.iter().any(|c| c == collection))
+    }
+
+    /// Append an entry (and fsync it). Does nothing when the log is not open.
+    pub fn record(
+        client: &str,
+        op: &str,
+        collection: Option<&str>,
+        detail: Option<String>,
+        ok: bool,
+    ) -> Result<(), String> {
+        let mut guard = Self::state().lock().expect("Failed to lock audit state");
+        let Some(state) = guard.as_mut() else {
+            return Ok(());
+        };
+        Self::append(state, client, op, collection, detail, ok)?;
+        if state.config.max_size > 0 && state.size >= state.config.max_size {
+            Self::rotate_locked(state, "daemon")?;
+        }
+        Ok(())
+    }
+
+    /// Start a new log now. Returns the name the current one was renamed to.
+    pub fn rotate(client: &str) -> Result<String, String> {
+        let mut guard = Self::state().lock().expect("Failed to lock audit state");
+        let state = guard.as_mut().ok_or("the audit log is not enabled")?;
+        Self::rotate_locked(state, client)
+    }
+
+    /// Check every entry in `dir` (the open log's directory when `None`): each hash matches
+    /// its entry, each `prev` the entry before, and sequence numbers have no gaps.
+    pub fn verify(dir: Option<&Path>, auth_key: &str) -> Result<AuditVerifyReport, String> {
+        // Holding the lock keeps the daemon from appending or rotating meanwhile.
+        let guard = Self::state().lock().expect("Failed to lock audit state");
+        let dir = match (dir, guard.as_ref()) {
+            (Some(dir), _) => dir.to_path_buf(),
+            (None, Some(state)) => state.dir.clone(),
+            (None, None) => Self::default_dir(),
+        };
+        let mut files = Self::rotated_logs(&dir);
+        let current = dir.join(STORE_AUDIT_LOG);
+        if current.exists() {
+            files.push(current);
+        }
+        if files.is_empty() {
+            return Err(format!("no audit log in {}", dir.display()));
+        }
+        let key = Self::chain_key(auth_key)?
+            .ok_or_else(|| format!("{} is missing; the log cannot be verified", STORE_AUDIT_KEY))?;
+
+        let mut report = AuditVerifyReport {
+            files: files.len(),
+            ..Default::default()
+        };
+        let mut previous: Option<AuditEntry> = None;
+        for path in &files {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            let content =
+                fs::read_to_string(path).map_err(|e| format!("read {}: {}", path.display(), e))?;
+            for (index, line) in content.lines().enumerate() {
+                let at = format!("{} line {}", name, index + 1);
+                let entry: AuditEntry = serde_json::from_str(line)
+                    .map_err(|e| format!("{}: not an audit entry: {}", at, e))?;
+                if entry.compute_hash(&key) != entry.hash {
+                    return Err(format!("{}: entry {} was modified", at, entry.seq));
+                }
+                match &previous {
+                    Some(before) if entry.prev != before.hash => {
+                        return Err(format!(
+                            "{}: entry {} does not follow entry {} (entries removed or reordered)",
+                            at, entry.seq, before.seq
+                        ));
+                    }
+                    Some(before) if entry.seq != before.seq + 1 => {
+                        return Err(format!(
+                            "{}: entry {} follows entry {}",
+                            at, entry.seq, before.seq
+                        ));
+                    }
+                    Some(_) => {}
+                    None => {
+                        report.first_seq = entry.seq;
+                        report.complete = entry.seq == 1 && entry.prev == AUDIT_GENESIS;
+                    }
+                }
+                report.entries += 1;
+                previous = Some(entry);
+            }
+        }
+        if let Some(last) = previous {
+            report.last_seq = last.seq;
+            report.head = last.hash;
+        }
+        Ok(report)
+    }
+
+    fn append(
+        state: &mut AuditState,
+        client: &str,
+        op: &str,
+        collection: Option<&str>,
+        detail: Option<String>,
+        ok: bool,
+    ) -> Result<(), String> {
+        let mut entry = AuditEntry {
+            seq: state.next_seq,
+            time: Self::timestamp(),
+            client: client.to_string(),
+            op: op.to_string(),
+            collection: collection.map(str::to_string),
+            detail,
+            ok,
+            prev: state.last_hash.clone(),
+            hash: String::new(),
+        };
+        entry.hash = entry.compute_hash(&state.key);
+        let mut line = serde_json::to_string(&entry).map_err(|e| e.to_string())?;
+        line.push('\n');
+        state
+            .file
+            .write_all(line.as_bytes())
+            .and_then(|_| state.file.sync_data())
+            .map_err(|e| format!("write audit log: {}", e))?;
+        state.size += line.len() as u64;
+        state.next_seq += 1;
+        state.last_hash = entry.hash;
+        Ok(())
+    }
+
+    fn rotate_locked(state: &mut AuditState, client: &str) -> Result<String, String> {
+        let path = state.dir.join(STORE_AUDIT_LOG);
+        let now = OffsetDateTime::now_utc();
+        let stamp = format!(
+            "{:04}{:02}{:02}-{:02}{:02}{:02}-{:03}",
+            now.year(),
+            u8::from(now.month()),
+            now.day(),
+            now.hour(),
+            now.minute(),
+            now.second(),
+            now.millisecond()
+        );
+        // The counter keeps names unique within a millisecond and, being zero-padded,
+        // keeps sorting by name the same as sorting by age.
+        let mut n = 0;
+        let mut name = format!("{}{}-{:03}.log", AUDIT_ROTATED_PREFIX, stamp, n);
+        while state.dir.join(&name).exists() {
+            n += 1;
+            name = format!("{}{}-{:03}.log", AUDIT_ROTATED_PREFIX, stamp, n);
+        }
+        fs::rename(&path, state.dir.join(&name))
+            .map_err(|e| format!("rename {}: {}", path.display(), e))?;
+        state.file = AegPermissions::private_file_options()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| format!("open {}: {}", path.display(), e))?;
+        state.size = 0;
+        AegFileSystem::sync_dir(&state.dir)?;
+        Self::append(
+            state,
+            client,
+            "rotate",
+            None,
+            Some(format!("previous entries in {}", name)),
+            true,
+        )?;
+
+        if state.config.retention > 0 {
+            let rotated = Self::rotated_logs(&state.dir);
+            let excess = rotated.len().saturating_sub(state.config.retention);
+            for old in &rotated[..excess] {
+                if let Err(e) = fs::remove_file(old) {
+                    eprintln!("Failed to remove old audit log {}: {}", old.display(), e);
+                }
+            }
+        }
+        Ok(name)
+    }
+
+    /// Re-wrap the chain key in `audit.key` with `new_key`. Opened with the new key first and
+    /// the old one otherwise, so it can be repeated. Returns whether the file was rewritten.
+    pub(crate) fn reencrypt_key(old_key: &str, new_key: &str) -> Result<bool, String> {
+        if Self::chain_key(new_key).is_ok() {
+            return Ok(false);
+        }
+        let Some(key) = Self::chain_key(old_key)? else {
+            return Ok(false);
+        };
+        Self::write_chain_key(&key, new_key)?;
+        Ok(true)
+    }
+
+    fn key_path() -> PathBuf {
+        AegFileSystem::get_config_path().join(STORE_AUDIT_KEY)
+    }
+
+    /// The chain key from `audit.key`, `None` when there is none yet.
+    fn chain_key(auth_key: &str) -> Result<Option<ChainKey>, String> {
+        let Ok(content) = fs::read_to_string(Self::key_path()) else {
+            return Ok(None);
+        };
+        let sealed = general_purpose::STANDARD
+            .decode(content.trim())
+            .map_err(|_| format!("{} is not valid base64", STORE_AUDIT_KEY))?;
+        let sealing_key = AegCrypto::sealing_key(auth_key)?;
+        let key =
+            AegCrypto::open_with_nonce(&sealing_key, AUDIT_KEY_AAD, &sealed).map_err(|_| {
+                format!(
+                    "{} does not decrypt with this authorization key",
+                    STORE_AUDIT_KEY
+                )
+            })?;
+        let key: [u8; 32] = key
+            .as_slice()
+            .try_into()
+            .map_err(|_| format!("{} does not hold a 32-byte key", STORE_AUDIT_KEY))?;
+        Ok(Some(Zeroizing::new(key)))
+    }
+
+    fn create_chain_key(auth_key: &str) -> Result<ChainKey, String> {
+        let key = Zeroizing::new(AegCrypto::generate_random_bytes(None));
+        Self::write_chain_key(&key, auth_key)?;
+        Ok(key)
+    }
+
+    fn write_chain_key(key: &ChainKey, auth_key: &str) -> Result<(), String> {
+        let sealing_key = AegCrypto::sealing_key(auth_key)?;
+        let sealed = AegCrypto::seal_with_nonce(&sealing_key, AUDIT_KEY_AAD, key.as_slice())?;
+        AegFileSystem::write_atomic(
+            &Self::key_path(),
+            general_purpose::STANDARD.encode(sealed).as_bytes(),
+        )
+    }
+
+    /// Rotated logs in `dir`, oldest first.
+    fn rotated_logs(dir: &Path) -> Vec<PathBuf> {
+        let Ok(entries) = fs::read_dir(dir) else {
+            return Vec::new();
+        };
+        let mut logs: Vec<PathBuf> = entries
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.file_name()
+                    .and_then(|n| n.to_str())
+                    .is_some_and(|n| n.starts_with(AUDIT_ROTATED_PREFIX) && n.ends_with(".log"))
+            })
+            .collect();
+        logs.sort();
+        logs
+    }
+
+    /// Last complete entry of a log. With `repair`, a torn last line is cut off.
+    fn last_entry(path: &Path, repair: bool) -> Result<Option<AuditEntry>, String> {
+        let Ok(content) = fs::read_to_string(path) else {
+            return Ok(None);
+        };
+        let complete = content.rfind('\n').map(|i| i + 1).unwrap_or(0);
+        if repair && complete < content.len() {
+            eprintln!(
+                "Audit: discarding {} byte(s) of incomplete entry at end of {}",
+                content.len() - complete,
+                path.display()
+            );
+            let file = AegPermissions::private_file_options()
+                .write(true)
+                .open(path)
+                .map_err(|e| format!("open {}: {}", path.display(), e))?;
+            file.set_len(complete as u64)
+                .map_err(|e| format!("truncate {}: {}", path.display(), e))?;
+        }
+        match content[..complete].lines().last() {
+            Some(line) => serde_json::from_str(line)
+                .map(Some)
+                .map_err(|e| format!("{}: last entry unreadable: {}", path.display(), e)),
+            None => Ok(None),
+        }
+    }
+
+    fn timestamp() -> String {
+        let now = OffsetDateTime::now_utc();
+        format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            now.year(),
+            u8::from(now.month()),
+            now.day(),
+            now.hour(),
+            now.minute(),
+            now.second(),
+            now.millisecond()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, DataHome};
+
+    fn open_log(auth_key: &str) {
+        let config = AuditConfig {
+            dir: None,
+            max_size: 0,
+            retention: 0,
+            read_collections: Vec::new(),
+        };
+        AegAudit::open(config, auth_key).unwrap();
+    }
+
+    fn close_log() {
+        *AegAudit::state().lock().unwrap() = None;
+    }
+
+    /// Two entries in a rotated log, then the `rotate` entry and one more in `audit.log`.
+    fn write_rotated_log(auth_key: &str) -> PathBuf {
+        open_log(auth_key);
+        AegAudit::record("test", "create", Some("users"), None, true).unwrap();
+        AegAudit::record(
+            "test",
+            "rename",
+            Some("users"),
+            Some("to people".into()),
+            true,
+        )
+        .unwrap();
+        let rotated = AegAudit::rotate("test").unwrap();
+        AegAudit::record("test", "delete", Some("people"), None, true).unwrap();
+        close_log();
+        AegAudit::default_dir().join(rotated)
+    }
+
+    #[test]
+    fn verify_follows_the_chain_across_rotated_logs() {
+        let _home = DataHome::new();
+        let auth_key = test_support::auth_key();
+        write_rotated_log(&auth_key);
+
+        let report = AegAudit::verify(None, &auth_key).unwrap();
+        assert_eq!(report.files, 2);
+        assert_eq!(report.entries, 4);
+        assert_eq!((report.first_seq, report.last_seq), (1, 4));
+        assert!(report.complete);
+
+        // reopening continues the chain from the current log
+        open_log(&auth_key);
+        AegAudit::record("test", "clear", None, None, true).unwrap();
+        close_log();
+        let report = AegAudit::verify(None, &auth_key).unwrap();
+        assert_eq!(report.last_seq, 5);
+
+        assert!(AegAudit::verify(None, &test_support::auth_key()).is_err());
+    }
+
+    #[test]
+    fn verify_detects_an_edited_or_removed_entry() {
+        let _home = DataHome::new();
+        let auth_key = test_support::auth_key();
+        let rotated = write_rotated_log(&auth_key);
+        let original = fs::read_to_string(&rotated).unwrap();
+
+        fs::write(&rotated, original.replace("\"users\"", "\"admins\"")).unwrap();
+        let error = AegAudit::verify(None, &auth_key).unwrap_err();
+        assert!(error.contains("entry 1 was modified"), "{}", error);
+
+        let first = original.lines().next().unwrap();
+        fs::write(&rotated, format!("{}\n", first)).unwrap();
+        let error = AegAudit::verify(None, &auth_key).unwrap_err();
+        assert!(error.contains("does not follow entry 1"), "{}", error);
+
+        // without the first entry the chain checks out but no longer reaches back to it
+        let second = original.lines().nth(1).unwrap();
+        fs::write(&rotated, format!("{}\n", second)).unwrap();
+        let report = AegAudit::verify(None, &auth_key).unwrap();
+        assert_eq!(report.first_seq, 2);
+        assert!(!report.complete);
+
+        fs::write(&rotated, &original).unwrap();
+        AegAudit::verify(None, &auth_key).unwrap();
+    }
+}
@@ -0,0 +1,372 @@
+use crate::constant::{STORE_BACKUP_DIR, STORE_COLLECTION, STORE_RESTORE_PENDING};
+use crate::core::AegCore;
+use crate::file_system::AegFileSystem;
+use crate::memory_engine::AegMemoryEngine;
+use crate::permissions::AegPermissions;
+use crate::snapshot_format::AegSnapshotFormat;
+use crate::wal::AegWal;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+use time::OffsetDateTime;
+
+/// Snapshots kept by default; older ones are deleted after each new snapshot.
+pub const DEFAULT_SNAPSHOT_RETENTION: usize = 7;
+const SNAPSHOT_PREFIX: &str = "snapshot-";
+const SNAPSHOT_MANIFEST: &str = "manifest.json";
+
+static BACKUP_DIR: OnceLock<Mutex<Option<PathBuf>>> = OnceLock::new();
+static RETENTION: AtomicUsize = AtomicUsize::new(DEFAULT_SNAPSHOT_RETENTION);
+
+/// Description of a point-in-time snapshot, stored as `manifest.json` in its directory.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SnapshotInfo {
+    pub name: String,
+    /// Unix timestamp (seconds, UTC).
+    pub created_at: i64,
+    pub collections: Vec<String>,
+    pub keys: usize,
+}
+
+/// POINT-IN-TIME SNAPSHOTS
+///
+/// A snapshot is a directory `snapshot-<utc timestamp>` in the backup directory holding an
+/// encrypted copy of every collection plus `collection.lock`, taken under the engine lock.
+/// Files stay encrypted with the authorization key, so restoring needs the same key; a key
+/// rotation re-wraps them along with the live files.
+pub struct AegBackup;
+
+impl AegBackup {
+    fn backup_dir_slot() -> &'static Mutex<Option<PathBuf>> {
+        BACKUP_DIR.get_or_init(|| Mutex::new(None))
+    }
+
+    /// Where snapshots are written (`~/.aegisr/backups` unless configured).
+    pub fn backup_dir() -> PathBuf {
+        Self::backup_dir_slot()
+            .lock()
+            .expect("Failed to lock backup config")
+            .clone()
+            .unwrap_or_else(|| AegFileSystem::get_config_path().join(STORE_BACKUP_DIR))
+    }
+
+    /// Configure the backup directory and how many snapshots to keep (0 = keep all).
+    pub fn configure(dir: Option<PathBuf>, retention: usize) {
+        *Self::backup_dir_slot()
+            .lock()
+            .expect("Failed to lock backup config") = dir;
+        RETENTION.store(retention, Ordering::SeqCst);
+    }
+
+    /// Write a consistent snapshot of all collections and apply the retention policy.
+    pub fn create_snapshot() -> Result<SnapshotInfo, String> {
+        let backup_dir = Self::backup_dir();
+        AegPermissions::create_private_dir(&backup_dir)
+            .map_err(|e| format!("create {}: {}", backup_dir.display(), e))?;
+        Self::remove_partial_snapshots(&backup_dir);
+
+        // No file writer may run while we copy; writers of keys are blocked by the engine lock.
+        let _persist = AegMemoryEngine::persist_lock();
+        let lock_path = AegFileSystem::get_config_path().join(STORE_COLLECTION);
+        let (lock_bytes, names, engines) = AegMemoryEngine::with_collections(|collections| {
+            let names = AegCore::try_load()?.collections;
+            let lock_bytes = fs::read(&lock_path);
+            let engines = names
+                .iter()
+                .map(|name| collections.get_mut(name).map(|engine| engine.clone()))
+                .collect::<Result<Vec<AegMemoryEngine>, String>>()?;
+            Ok::<_, String>((lock_bytes, names, engines))
+        })??;
+        let lock_bytes = lock_bytes.map_err(|e| format!("read {}: {}", lock_path.display(), e))?;
+
+        let now = OffsetDateTime::now_utc();
+        let name = format!(
+            "{}{:04}{:02}{:02}-{:02}{:02}{:02}-{:03}",
+            SNAPSHOT_PREFIX,
+            now.year(),
+            u8::from(now.month()),
+            now.day(),
+            now.hour(),
+            now.minute(),
+            now.second(),
+            now.millisecond()
+        );
+        let info = SnapshotInfo {
+            name: name.clone(),
+            created_at: now.unix_timestamp(),
+            collections: names,
+            keys: engines.iter().map(|e| e.store.len()).sum(),
+        };
+
+        // Build it under a hidden name and rename once complete.
+        let partial = backup_dir.join(format!(".{}.partial", name));
+        AegPermissions::create_private_dir(&partial)
+            .map_err(|e| format!("create {}: {}", partial.display(), e))?;
+        for engine in &engines {
+            let file_name = format!("collection_{}.aekv", engine.collection_name);
+            AegMemoryEngine::save_to_path(engine, &partial.join(file_name))?;
+        }
+        AegFileSystem::write_atomic(&partial.join(STORE_COLLECTION), &lock_bytes)?;
+        let manifest =
+            serde_json::to_vec_pretty(&info).map_err(|e| format!("serialize error: {}", e))?;
+        AegFileSystem::write_atomic(&partial.join(SNAPSHOT_MANIFEST), &manifest)?;
+
+        let target = backup_dir.join(&name);
+        fs::rename(&partial, &target).map_err(|e| format!("rename snapshot: {}", e))?;
+        AegFileSystem::sync_dir(&backup_dir)?;
+
+        Self::apply_retention();
+        Ok(info)
+    }
+
+    /// `backup_dir` from a daemon config file, for tools that work on the data directory
+    /// without the daemon.
+    pub fn dir_from_file(path: &Path) -> Result<Option<PathBuf>, String> {
+        #[derive(Deserialize)]
+        struct Section {
+            backup_dir: Option<PathBuf>,
+        }
+        let content =
+            fs::read_to_string(path).map_err(|e| format!("read {}: {}", path.display(), e))?;
+        let section: Section = serde_json::from_str(&content)
+            .map_err(|e| format!("{}: invalid backup_dir: {}", path.display(), e))?;
+        Ok(section.backup_dir)
+    }
+
+    /// Directories of all complete snapshots, including those whose manifest is unreadable.
+    pub(crate) fn snapshot_dirs() -> Vec<PathBuf> {
+        let Ok(entries) = fs::read_dir(Self::backup_dir()) else {
+            return Vec::new();
+        };
+        let mut dirs: Vec<PathBuf> = entries
+            .flatten()
+            .filter(|e| e.file_name().to_string_lossy().starts_with(SNAPSHOT_PREFIX))
+            .map(|e| e.path())
+            .collect();
+        dirs.sort();
+        dirs
+    }
+
+    /// Existing snapshots, oldest first.
+    pub fn list_snapshots() -> Vec<SnapshotInfo> {
+        let Ok(entries) = fs::read_dir(Self::backup_dir()) else {
+            return Vec::new();
+        };
+        let mut snapshots: Vec<SnapshotInfo> = entries
+            .flatten()
+            .filter(|e| e.file_name().to_string_lossy().starts_with(SNAPSHOT_PREFIX))
+            .filter_map(|e| {
+                let manifest = fs::read(e.path().join(SNAPSHOT_MANIFEST)).ok()?;
+                serde_json::from_slice::<SnapshotInfo>(&manifest).ok()
+            })
+            .collect();
+        snapshots.sort_by(|a, b| a.name.cmp(&b.name));
+        snapshots
+    }
+
+    /// Replace every collection with the contents of snapshot `name`.
+    ///
+    /// The snapshot is fully verified first. A marker file makes the switch crash-safe:
+    /// if the daemon dies half-way, `recover_pending_restore` finishes it on startup.
+    pub fn restore_snapshot(name: &str) -> Result<SnapshotInfo, String> {
+        let info = Self::list_snapshots()
+            .into_iter()
+            .find(|s| s.name == name)
+            .ok_or_else(|| format!("Snapshot '{}' not found", name))?;
+        let snapshot_dir = Self::backup_dir().join(&info.name);
+        Self::load_snapshot(&snapshot_dir, &info)?;
+
+        let marker = AegFileSystem::get_config_path().join(STORE_RESTORE_PENDING);
+        let _persist = AegMemoryEngine::persist_lock();
+        AegFileSystem::write_atomic(&marker, snapshot_dir.to_string_lossy().as_bytes())?;
+        AegMemoryEngine::with_collections(|collections| {
+            let engines = Self::apply_snapshot(&snapshot_dir)?;
+            collections.replace_all(engines);
+            Ok::<(), String>(())
+        })??;
+        fs::remove_file(&marker).map_err(|e| format!("remove restore marker: {}", e))?;
+        AegFileSystem::sync_dir(&AegFileSystem::get_config_path())?;
+        Ok(info)
+    }
+
+    /// Finish a restore that was interrupted by a crash. Call before anything loads data.
+    pub fn recover_pending_restore() -> Result<Option<PathBuf>, String> {
+        let marker = AegFileSystem::get_config_path().join(STORE_RESTORE_PENDING);
+        let Ok(snapshot_dir) = fs::read_to_string(&marker) else {
+            return Ok(None);
+        };
+        let snapshot_dir = PathBuf::from(snapshot_dir);
+        Self::apply_snapshot(&snapshot_dir)?;
+        fs::remove_file(&marker).map_err(|e| format!("remove restore marker: {}", e))?;
+        Ok(Some(snapshot_dir))
+    }
+
+    fn manifest(snapshot_dir: &Path) -> Result<SnapshotInfo, String> {
+        let path = snapshot_dir.join(SNAPSHOT_MANIFEST);
+        let bytes = fs::read(&path).map_err(|e| format!("read {}: {}", path.display(), e))?;
+        serde_json::from_slice(&bytes).map_err(|e| format!("invalid manifest: {}", e))
+    }
+
+    /// Decrypt and check every file of a snapshot.
+    fn load_snapshot(
+        snapshot_dir: &Path,
+        info: &SnapshotInfo,
+    ) -> Result<Vec<AegMemoryEngine>, String> {
+        let auth_key = AegFileSystem::read_authorization_key();
+        let lock_path = snapshot_dir.join(STORE_COLLECTION);
+        if !AegFileSystem::is_intact(&lock_path) {
+            return Err(format!(
+                "{} is missing or cannot be decrypted with the current key",
+                lock_path.display()
+            ));
+        }
+        info.collections
+            .iter()
+            .map(|name| {
+                let path = snapshot_dir.join(format!("collection_{}.aekv", name));
+                AegSnapshotFormat::read(&path, &auth_key, name)
+                    .map_err(|e| format!("collection '{}': {}", name, e))
+            })
+            .collect()
+    }
+
+    /// Make the live files match the snapshot: write every collection (with `wal_seq` 0,
+    /// so later WAL records apply), the lock file, drop other collection files and empty
+    /// the WAL. Idempotent, so an interrupted restore can simply run again.
+    fn apply_snapshot(snapshot_dir: &Path) -> Result<Vec<AegMemoryEngine>, String> {
+        let info = Self::manifest(snapshot_dir)?;
+        let mut engines = Self::load_snapshot(snapshot_dir, &info)?;
+        for engine in &mut engines {
+            engine.wal_seq = 0;
+            AegMemoryEngine::save_to_disk(engine)?;
+            engine.mark_saved();
+        }
+
+        let config_dir = AegFileSystem::get_config_path();
+        let lock_path = snapshot_dir.join(STORE_COLLECTION);
+        let lock_bytes =
+            fs::read(&lock_path).map_err(|e| format!("read {}: {}", lock_path.display(), e))?;
+        AegFileSystem::write_atomic(&config_dir.join(STORE_COLLECTION), &lock_bytes)?;
+        AegFileSystem::forget_collection_lock();
+
+        if let Ok(entries) = fs::read_dir(&config_dir) {
+            for entry in entries.flatten() {
+                let file_name = entry.file_name().to_string_lossy().to_string();
+                let stale = file_name
+                    .strip_prefix("collection_")
+                    .and_then(|n| n.strip_suffix(".aekv"))
+                    .is_some_and(|n| !info.collections.iter().any(|c| c == n));
+                if stale {
+                    let _ = AegSnapshotFormat::shred(&entry.path());
+                }
+            }
+        }
+        AegWal::reset()?;
+        AegFileSystem::sync_dir(&config_dir)?;
+        Ok(engines)
+    }
+
+    fn apply_retention() {
+        let retention = RETENTION.load(Ordering::SeqCst);
+        if retention == 0 {
+            return;
+        }
+        let snapshots = Self::list_snapshots();
+        let excess = snapshots.len().saturating_sub(retention);
+        for snapshot in &snapshots[..excess] {
+            let path = Self::backup_dir().join(&snapshot.name);
+            if let Err(e) = fs::remove_dir_all(&path) {
+                eprintln!("Failed to remove old snapshot {}: {}", snapshot.name, e);
+            }
+        }
+    }
+
+    /// Remove directories of snapshots that were interrupted before completion.
+    fn remove_partial_snapshots(backup_dir: &Path) {
+        let Ok(entries) = fs::read_dir(backup_dir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.starts_with('.') && name.ends_with(".partial") {
+                let _ = fs::remove_dir_all(entry.path());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::DataHome;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    fn value(key: &str) -> Option<String> {
+        AegMemoryEngine::read_collection("default", |engine| engine.get(key)).unwrap()
+    }
+
+    #[test]
+    fn restore_replaces_the_data_and_drops_newer_collections() {
+        let _home = DataHome::new();
+        AegMemoryEngine::with_collection("default", |engine| engine.insert("k", "before")).unwrap();
+        let snapshot = AegBackup::create_snapshot().unwrap();
+        assert_eq!(snapshot.keys, 1);
+        assert_eq!(AegBackup::list_snapshots().len(), 1);
+
+        AegMemoryEngine::with_collection("default", |engine| engine.insert("k", "after")).unwrap();
+        AegCore::create_collection("newer");
+        AegMemoryEngine::with_collection("newer", |engine| engine.insert("k", "v")).unwrap();
+
+        AegBackup::restore_snapshot(&snapshot.name).unwrap();
+        assert_eq!(value("k").as_deref(), Some("before"));
+        assert_eq!(AegCore::collection_exists("newer"), Ok(false));
+        assert!(!AegMemoryEngine::engine_file_path("newer").exists());
+        assert!(AegBackup::restore_snapshot("snapshot-missing").is_err());
+    }
+
+    #[test]
+    fn retention_keeps_only_the_newest_snapshots() {
+        let _home = DataHome::new();
+        AegBackup::configure(None, 2);
+        let mut names = Vec::new();
+        for _ in 0..3 {
+            names.push(AegBackup::create_snapshot().unwrap().name);
+            // snapshot names have millisecond resolution
+            sleep(Duration::from_millis(2));
+        }
+        AegBackup::configure(None, DEFAULT_SNAPSHOT_RETENTION);
+
+        let kept: Vec<String> = AegBackup::list_snapshots()
+            .into_iter()
+            .map(|s| s.name)
+            .collect();
+        assert_eq!(kept, names[1..]);
+    }
+
+    #[test]
+    fn an_interrupted_restore_is_finished_from_its_marker() {
+        let _home = DataHome::new();
+        AegMemoryEngine::with_collection("default", |engine| engine.insert("k", "before")).unwrap();
+        let snapshot = AegBackup::create_snapshot().unwrap();
+        AegMemoryEngine::with_collection("default", |engine| engine.insert("k", "after")).unwrap();
+        AegMemoryEngine::save_all();
+
+        // a crash right after the marker was written: nothing was switched yet
+        let snapshot_dir = AegBackup::backup_dir().join(&snapshot.name);
+        let marker = AegFileSystem::get_config_path().join(STORE_RESTORE_PENDING);
+        fs::write(&marker, snapshot_dir.to_string_lossy().as_bytes()).unwrap();
+        AegMemoryEngine::with_collections(|collections| collections.replace_all(Vec::new()))
+            .unwrap();
+
+        assert_eq!(
+            AegBackup::recover_pending_restore().unwrap(),
+            Some(snapshot_dir)
+        );
+        assert!(!marker.exists());
+        assert_eq!(value("k").as_deref(), Some("before"));
+        assert_eq!(AegBackup::recover_pending_restore().unwrap(), None);
+    }
+}
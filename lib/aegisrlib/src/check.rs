@@ -0,0 +1,727 @@
+use crate::constant::{STORE_COLLECTION, STORE_QUARANTINE_DIR, STORE_TEMP_MARKER};
+use crate::file_system::{AegFileSystem, CollectionLock};
+use crate::memory_engine::AegMemoryEngine;
+use crate::permissions::AegPermissions;
+use crate::snapshot_format::AegSnapshotFormat;
+use crate::transfer::AegTransfer;
+use crate::wal::{AegWal, WalOp, WalRecord};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use time::OffsetDateTime;
+
+/// Something wrong with the data directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CheckIssue {
+    /// `collection.lock` is missing (while collection files exist), does not decrypt or
+    /// does not parse.
+    CorruptLock { detail: String },
+    /// A collection file that does not decrypt or parse.
+    CorruptCollection { name: String, detail: String },
+    /// A collection file that `collection.lock` does not list.
+    OrphanFile { name: String },
+    /// A collection listed in `collection.lock` that has no file.
+    MissingFile { name: String },
+    /// The WAL has an unreadable record before its end.
+    CorruptWal { detail: String },
+    /// Logged writes of a collection that do not decrypt with its data key.
+    UnreadableWalOps { name: String, detail: String },
+    /// A temp file left by an interrupted write.
+    LeftoverTemp { file: String },
+    /// A key file, or the data directory or a file in it, accessible by other users.
+    InsecurePermissions { path: String, detail: String },
+}
+
+impl CheckIssue {
+    /// Issues the daemon refuses to start with; the others are only warnings.
+    pub fn is_fatal(&self) -> bool {
+        matches!(
+            self,
+            Self::CorruptLock { .. }
+                | Self::CorruptCollection { .. }
+                | Self::CorruptWal { .. }
+                | Self::UnreadableWalOps { .. }
+        )
+    }
+}
+
+impl fmt::Display for CheckIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::CorruptLock { detail } => write!(f, "{}: {}", STORE_COLLECTION, detail),
+            Self::CorruptCollection { name, detail } => {
+                write!(f, "collection '{}' is damaged: {}", name, detail)
+            }
+            Self::OrphanFile { name } => write!(
+                f,
+                "collection file for '{}' is not listed in {}",
+                name, STORE_COLLECTION
+            ),
+            Self::MissingFile { name } => write!(
+                f,
+                "collection '{}' is listed in {} but has no file",
+                name, STORE_COLLECTION
+            ),
+            Self::CorruptWal { detail } => write!(f, "write-ahead log: {}", detail),
+            Self::UnreadableWalOps { name, detail } => write!(
+                f,
+                "logged writes of collection '{}' cannot be read: {}",
+                name, detail
+            ),
+            Self::LeftoverTemp { file } => write!(f, "leftover temp file {}", file),
+            Self::InsecurePermissions { path, detail } => write!(f, "{}: {}", path, detail),
+        }
+    }
+}
+
+/// How `AegCheck::resolve` deals with what it finds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    /// Move damaged and orphaned files aside and drop lock entries that have no data.
+    Quarantine,
+    /// Keep as much data as possible: salvage damaged collections, register orphaned
+    /// files and write missing ones from the WAL, when their logged writes can be read.
+    Repair,
+}
+
+/// Result of `AegCheck::scan`.
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    /// Collection files that passed the check, with their key count (deep scans only).
+    pub healthy: Vec<(String, Option<usize>)>,
+    pub issues: Vec<CheckIssue>,
+}
+
+/// DATA DIRECTORY INTEGRITY CHECK
+///
+/// Offline verification of `~/.aegisr`: the lock file, every collection file and the
+/// WAL. Used by `aegisr-check` and, in its quick form, by the daemon before it loads
+/// anything. Damaged files are never deleted: quarantine and repair keep a copy under
+/// `quarantine/<timestamp>/`.
+pub struct AegCheck;
+
+impl AegCheck {
+    /// Check the data directory. A quick scan (`deep = false`) verifies snapshot checksums
+    /// and decrypts only their metadata frame, and skips the WAL. A deep scan also opens the
+    /// logged writes of every listed collection with its data key.
+    pub fn scan(auth_key: &str, deep: bool) -> CheckReport {
+        let dir = AegFileSystem::get_config_path();
+        let mut report = CheckReport::default();
+        let (files, temps) = Self::list_files(&dir);
+
+        let lock_path = dir.join(STORE_COLLECTION);
+        let listed = if lock_path.exists() {
+            match AegFileSystem::decrypt_collection_lock(auth_key) {
+                Ok(lock) => Some(lock.collections),
+                Err(detail) => {
+                    report.issues.push(CheckIssue::CorruptLock { detail });
+                    None
+                }
+            }
+        } else {
+            if !files.is_empty() {
+                report.issues.push(CheckIssue::CorruptLock {
+                    detail: "missing".into(),
+                });
+            }
+            None
+        };
+
+        let wal_path = AegWal::wal_path();
+        let wal_readable = deep
+            && match AegWal::read_records(&wal_path, auth_key) {
+                Ok((records, _)) => {
+                    records.into_iter().for_each(WalRecord::wipe);
+                    true
+                }
+                Err(detail) => {
+                    report.issues.push(CheckIssue::CorruptWal { detail });
+                    false
+                }
+            };
+
+        for name in &files {
+            let path = AegMemoryEngine::engine_file_path(name);
+            let engine = match Self::verify_file(&path, name, auth_key, deep) {
+                Ok(engine) => engine,
+                Err(detail) => {
+                    report.issues.push(CheckIssue::CorruptCollection {
+                        name: name.clone(),
+                        detail,
+                    });
+                    continue;
+                }
+            };
+            report
+                .healthy
+                .push((name.clone(), engine.as_ref().map(|e| e.store.len())));
+            if let Some(engine) = engine
+                && wal_readable
+                && listed.as_ref().is_some_and(|l| l.contains(name))
+                && let Err(detail) = Self::open_wal_ops(&engine, auth_key)
+            {
+                report.issues.push(CheckIssue::UnreadableWalOps {
+                    name: name.clone(),
+                    detail,
+                });
+            }
+        }
+        if let Some(listed) = &listed {
+            for name in files.iter().filter(|f| !listed.contains(f)) {
+                report
+                    .issues
+                    .push(CheckIssue::OrphanFile { name: name.clone() });
+            }
+            for name in listed.iter().filter(|l| !files.contains(*l)) {
+                report
+                    .issues
+                    .push(CheckIssue::MissingFile { name: name.clone() });
+            }
+        }
+
+        for file in temps {
+            report.issues.push(CheckIssue::LeftoverTemp { file });
+        }
+        Self::scan_permissions(&mut report);
+        report
+    }
+
+    /// Key files with a wrong owner or mode, then anything else in the data directory that
+    /// group or others can access.
+    fn scan_permissions(report: &mut CheckReport) {
+        let key_files = AegPermissions::key_file_problems();
+        for (path, _) in AegPermissions::loose_data_files() {
+            if key_files.iter().all(|(key_file, _)| key_file != &path) {
+                report.issues.push(CheckIssue::InsecurePermissions {
+                    path: path.display().to_string(),
+                    detail: "accessible by other users (should be 0700/0600)".into(),
+                });
+            }
+        }
+        for (path, detail) in key_files {
+            report.issues.push(CheckIssue::InsecurePermissions {
+                path: path.display().to_string(),
+                detail,
+            });
+        }
+    }
+
+    /// Quick scan run by the daemon before it loads anything. Returns the warnings, or
+    /// an error describing the issues it must not start with.
+    pub fn startup_check() -> Result<Vec<CheckIssue>, String> {
+        let Ok(auth_key) = AegFileSystem::resolve_authorization_key(None) else {
+            return Ok(Vec::new()); // first start: nothing to check yet
+        };
+        let report = Self::scan(&auth_key, false);
+        let (fatal, warnings): (Vec<CheckIssue>, Vec<CheckIssue>) =
+            report.issues.into_iter().partition(CheckIssue::is_fatal);
+        if !fatal.is_empty() {
+            let issues: Vec<String> = fatal.iter().map(ToString::to_string).collect();
+            return Err(format!(
+                "{} (run aegisr-check to inspect, aegisr-check --repair to fix)",
+                issues.join("; ")
+            ));
+        }
+        // Leftover temp files are cleaned up by startup itself. Missing files are reported;
+        // the daemon then creates them (`AegMemoryEngine::create_missing_files`).
+        Ok(warnings
+            .into_iter()
+            .filter(|i| !matches!(i, CheckIssue::LeftoverTemp { .. }))
+            .collect())
+    }
+
+    /// Scan deeply and deal with every issue found. Returns a description of each action.
+    /// The daemon must not be running.
+    pub fn resolve(auth_key: &str, resolution: Resolution) -> Result<Vec<String>, String> {
+        let mut actions = Vec::new();
+        if !Self::list_files(&AegFileSystem::get_config_path())
+            .1
+            .is_empty()
+        {
+            AegFileSystem::recover_temp_files();
+            actions.push("cleaned up leftover temp files".to_string());
+        }
+        let report = Self::scan(auth_key, true);
+        if report.healthy.is_empty() && report.issues.iter().any(CheckIssue::is_fatal) {
+            return Err(
+                "nothing decrypts with this key; refusing to touch anything \
+                 (is it the right authorization key?)"
+                    .into(),
+            );
+        }
+
+        let mut quarantine = Quarantine::new();
+        if report
+            .issues
+            .iter()
+            .any(|i| matches!(i, CheckIssue::CorruptLock { .. }))
+        {
+            actions.push(Self::rebuild_lock(&report, auth_key, &mut quarantine)?);
+        }
+        if report
+            .issues
+            .iter()
+            .any(|i| matches!(i, CheckIssue::CorruptWal { .. }))
+        {
+            let wal_path = AegWal::wal_path();
+            let copy = quarantine.copy(&wal_path)?;
+            let removed = AegWal::truncate_at_corruption(&wal_path, auth_key)?;
+            actions.push(format!(
+                "truncated the write-ahead log at its first unreadable record ({} bytes dropped, copy in {})",
+                removed,
+                copy.display()
+            ));
+        }
+
+        // the lock is readable now; look again for per-collection issues
+        for issue in Self::scan(auth_key, true).issues {
+            let action = match (issue, resolution) {
+                (CheckIssue::CorruptCollection { name, .. }, Resolution::Quarantine) => {
+                    let moved = quarantine.take(&AegMemoryEngine::engine_file_path(&name))?;
+                    Self::update_lock(auth_key, |lock| lock.collections.retain(|c| c != &name))?;
+                    format!("moved damaged collection '{}' to {}", name, moved.display())
+                }
+                (CheckIssue::CorruptCollection { name, .. }, Resolution::Repair) => {
+                    Self::salvage(&name, auth_key, &mut quarantine)?
+                }
+                (CheckIssue::OrphanFile { name }, Resolution::Quarantine) => {
+                    let path = AegMemoryEngine::engine_file_path(&name);
+                    if !path.exists() {
+                        continue;
+                    }
+                    let moved = quarantine.take(&path)?;
+                    format!("moved orphaned file of '{}' to {}", name, moved.display())
+                }
+                (CheckIssue::OrphanFile { name }, Resolution::Repair) => {
+                    Self::update_lock(auth_key, |lock| {
+                        if !lock.collections.contains(&name) {
+                            lock.collections.push(name.clone());
+                        }
+                    })?;
+                    format!(
+                        "added orphaned collection '{}' to {}",
+                        name, STORE_COLLECTION
+                    )
+                }
+                (CheckIssue::MissingFile { name }, Resolution::Quarantine) => {
+                    Self::update_lock(auth_key, |lock| lock.collections.retain(|c| c != &name))?;
+                    format!("removed '{}' from {}", name, STORE_COLLECTION)
+                }
+                (CheckIssue::MissingFile { name }, Resolution::Repair) => {
+                    // The data key went with the file, so only writes logged before
+                    // collections had one (`v2:` records) can be read back.
+                    let mut engine = AegMemoryEngine::new(&name);
+                    let ops = AegWal::collection_ops(
+                        &AegWal::wal_path(),
+                        auth_key,
+                        &name,
+                        0,
+                        &engine.data_key,
+                    )
+                    .map_err(|e| {
+                        format!(
+                            "the file of '{}' is missing and its logged writes were sealed with the data key it held ({}); restore it from a snapshot, or drop it with --quarantine",
+                            name, e
+                        )
+                    })?;
+                    engine.apply_wal(ops);
+                    AegTransfer::offline_save(&mut engine, auth_key)?;
+                    format!(
+                        "wrote the missing file of '{}' ({} key(s) from the WAL)",
+                        name,
+                        engine.store.len()
+                    )
+                }
+                (CheckIssue::UnreadableWalOps { name, detail }, _) => {
+                    // The records stay in the copy in case the key turns up again.
+                    let wal_path = AegWal::wal_path();
+                    let copy = quarantine.copy(&wal_path)?;
+                    let path = AegMemoryEngine::engine_file_path(&name);
+                    let mut engine = Self::verify_file(&path, &name, auth_key, true)?
+                        .unwrap_or_else(|| AegMemoryEngine::new(&name));
+                    engine.wal_seq = engine
+                        .wal_seq
+                        .max(AegWal::last_seq_in(&wal_path, auth_key)?);
+                    AegTransfer::offline_save(&mut engine, auth_key)?;
+                    format!(
+                        "collection '{}' now skips the logged writes it cannot read ({}); kept the {} key(s) of its file (WAL copy in {})",
+                        name,
+                        detail,
+                        engine.store.len(),
+                        copy.display()
+                    )
+                }
+                (CheckIssue::InsecurePermissions { path, .. }, _) => {
+                    AegPermissions::tighten(Path::new(&path))?;
+                    format!("restricted {} to its owner", path)
+                }
+                (other, _) => return Err(format!("could not resolve: {}", other)),
+            };
+            actions.push(action);
+        }
+        Ok(actions)
+    }
+
+    /// Names of `collection_*.aekv` files and of leftover temp files in `dir`.
+    pub(crate) fn list_files(dir: &Path) -> (BTreeSet<String>, Vec<String>) {
+        let mut files = BTreeSet::new();
+        let mut temps = Vec::new();
+        let Ok(entries) = fs::read_dir(dir) else {
+            return (files, temps);
+        };
+        for entry in entries.flatten() {
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            if file_name.contains(STORE_TEMP_MARKER) {
+                temps.push(file_name);
+            } else if let Some(name) = file_name
+                .strip_prefix("collection_")
+                .and_then(|n| n.strip_suffix(".aekv"))
+            {
+                files.insert(name.to_string());
+            }
+        }
+        temps.sort();
+        (files, temps)
+    }
+
+    /// Check one collection file. A deep check reads it whole and returns the engine.
+    fn verify_file(
+        path: &Path,
+        name: &str,
+        auth_key: &str,
+        deep: bool,
+    ) -> Result<Option<AegMemoryEngine>, String> {
+        if fs::metadata(path).map(|m| m.len()).unwrap_or(0) == 0 {
+            return Ok(Some(AegMemoryEngine::new(name)));
+        }
+        if !AegSnapshotFormat::is_binary(path) {
+            return AegMemoryEngine::load_legacy(path, auth_key, name).map(Some);
+        }
+        if deep {
+            AegSnapshotFormat::read(path, auth_key, name).map(Some)
+        } else {
+            AegSnapshotFormat::verify_checksum(path)?;
+            AegSnapshotFormat::verify_meta(path, auth_key, name).map(|_| None)
+        }
+    }
+
+    /// Whether every write logged for `engine` after its snapshot opens with its data key,
+    /// as the daemon needs when it loads the collection.
+    fn open_wal_ops(engine: &AegMemoryEngine, auth_key: &str) -> Result<(), String> {
+        let records = AegWal::collection_ops(
+            &AegWal::wal_path(),
+            auth_key,
+            &engine.collection_name,
+            engine.wal_seq,
+            &engine.data_key,
+        )?;
+        for (_, ops) in records {
+            ops.into_iter().for_each(WalOp::wipe);
+        }
+        Ok(())
+    }
+
+    /// Write a new lock listing every healthy collection file.
+    fn rebuild_lock(
+        report: &CheckReport,
+        auth_key: &str,
+        quarantine: &mut Quarantine,
+    ) -> Result<String, String> {
+        let lock_path = AegFileSystem::get_config_path().join(STORE_COLLECTION);
+        if lock_path.exists() {
+            quarantine.take(&lock_path)?;
+        }
+        let mut collections: Vec<String> = report
+            .healthy
+            .iter()
+            .map(|(name, _)| name.clone())
+            .collect();
+        if collections.is_empty() {
+            collections.push("default".into());
+        }
+        let active = if collections.iter().any(|c| c == "default") {
+            "default".to_string()
+        } else {
+            collections[0].clone()
+        };
+        let lock = CollectionLock {
+            active,
+            collections,
+        };
+        Self::write_lock(&lock, auth_key)?;
+        Ok(format!(
+            "rebuilt {} from the collection files: {}",
+            STORE_COLLECTION,
+            lock.collections.join(", ")
+        ))
+    }
+
+    fn update_lock(auth_key: &str, change: impl FnOnce(&mut CollectionLock)) -> Result<(), String> {
+        let mut lock = AegFileSystem::decrypt_collection_lock(auth_key)?;
+        change(&mut lock);
+        if lock.collections.is_empty() {
+            lock.collections.push("default".into());
+        }
+        if !lock.collections.contains(&lock.active) {
+            lock.active = lock.collections[0].clone();
+        }
+        Self::write_lock(&lock, auth_key)
+    }
+
+    fn write_lock(lock: &CollectionLock, auth_key: &str) -> Result<(), String> {
+        let json = serde_json::to_string_pretty(lock)
+            .map_err(|e| format!("serialize {}: {}", STORE_COLLECTION, e))?;
+        AegFileSystem::write_collection_lock_json(&json, auth_key);
+        Ok(())
+    }
+
+    /// Keep a copy of a damaged collection file, recover what it still holds, roll it
+    /// forward with the WAL and write it back.
+    fn salvage(name: &str, auth_key: &str, quarantine: &mut Quarantine) -> Result<String, String> {
+        let path = AegMemoryEngine::engine_file_path(name);
+        let copy = quarantine.copy(&path)?;
+        let (mut engine, detail) = match AegSnapshotFormat::salvage(&path, auth_key, name) {
+            Ok(salvaged) => {
+                let expected = salvaged
+                    .expected
+                    .map_or("an unknown number of".to_string(), |n| n.to_string());
+                let detail = format!(
+                    "{} of {} key(s) salvaged, {} damaged frame(s)",
+                    salvaged.engine.store.len(),
+                    expected,
+                    salvaged.lost_frames
+                );
+                (salvaged.engine, detail)
+            }
+            Err(e) => (
+                AegMemoryEngine::new(name),
+                format!("nothing salvaged ({})", e),
+            ),
+        };
+        let note = Self::replay_wal(&mut engine, auth_key);
+        AegTransfer::offline_save(&mut engine, auth_key)?;
+        Ok(format!(
+            "rebuilt collection '{}': {}, {} key(s) after WAL replay{} (original in {})",
+            name,
+            detail,
+            engine.store.len(),
+            note,
+            copy.display()
+        ))
+    }
+
+    /// Roll a rebuilt engine forward with the WAL. Operations sealed with a data key that
+    /// was lost along with the file cannot be read; the rebuild goes on without them, skips
+    /// them on later loads too, and the returned note says so.
+    fn replay_wal(engine: &mut AegMemoryEngine, auth_key: &str) -> String {
+        let path = AegWal::wal_path();
+        match AegWal::collection_ops(
+            &path,
+            auth_key,
+            &engine.collection_name,
+            engine.wal_seq,
+            &engine.data_key,
+        ) {
+            Ok(ops) => {
+                engine.apply_wal(ops);
+                String::new()
+            }
+            Err(e) => {
+                if let Ok(last) = AegWal::last_seq_in(&path, auth_key) {
+                    engine.wal_seq = engine.wal_seq.max(last);
+                }
+                format!("; WAL not replayed: {}", e)
+            }
+        }
+    }
+}
+
+/// `quarantine/<timestamp>/` in the data directory, created on first use.
+struct Quarantine {
+    dir: PathBuf,
+    created: bool,
+}
+
+impl Quarantine {
+    fn new() -> Self {
+        let now = OffsetDateTime::now_utc();
+        let stamp = format!(
+            "{:04}{:02}{:02}-{:02}{:02}{:02}",
+            now.year(),
+            u8::from(now.month()),
+            now.day(),
+            now.hour(),
+            now.minute(),
+            now.second()
+        );
+        Self {
+            dir: AegFileSystem::get_config_path()
+                .join(STORE_QUARANTINE_DIR)
+                .join(stamp),
+            created: false,
+        }
+    }
+
+    fn target(&mut self, path: &Path) -> Result<PathBuf, String> {
+        if !self.created {
+            AegPermissions::create_private_dir(&self.dir)
+                .map_err(|e| format!("create {}: {}", self.dir.display(), e))?;
+            self.created = true;
+        }
+        let file_name = path
+            .file_name()
+            .ok_or_else(|| format!("invalid file path {}", path.display()))?;
+        Ok(self.dir.join(file_name))
+    }
+
+    /// Move `path` into quarantine.
+    fn take(&mut self, path: &Path) -> Result<PathBuf, String> {
+        let target = self.target(path)?;
+        fs::rename(path, &target).map_err(|e| format!("move {}: {}", path.display(), e))?;
+        Ok(target)
+    }
+
+    /// Copy `path` into quarantine, leaving the original in place.
+    fn copy(&mut self, path: &Path) -> Result<PathBuf, String> {
+        let target = self.target(path)?;
+        fs::copy(path, &target).map_err(|e| format!("copy {}: {}", path.display(), e))?;
+        Ok(target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::AegCore;
+    use crate::test_support::DataHome;
+
+    #[test]
+    fn logged_writes_that_do_not_open_are_reported_and_skipped_on_repair() {
+        let _home = DataHome::new();
+        AegMemoryEngine::with_collection("default", |engine| engine.insert("k", "v")).unwrap();
+        AegMemoryEngine::save_to_disk(&AegMemoryEngine::new("default")).unwrap();
+        let auth_key = AegFileSystem::read_authorization_key();
+
+        let issues = AegCheck::scan(&auth_key, true).issues;
+        assert!(
+            matches!(
+                issues.as_slice(),
+                [CheckIssue::UnreadableWalOps { name, .. }] if name == "default"
+            ),
+            "{:?}",
+            issues
+        );
+        assert!(issues[0].is_fatal());
+
+        let actions = AegCheck::resolve(&auth_key, Resolution::Repair).unwrap();
+        assert_eq!(actions.len(), 1, "{:?}", actions);
+        assert!(AegCheck::scan(&auth_key, true).issues.is_empty());
+        let engine = AegTransfer::offline_load("default", &auth_key).unwrap();
+        assert!(engine.store.is_empty());
+    }
+
+    #[test]
+    fn a_missing_file_is_not_rebuilt_from_writes_sealed_with_its_lost_key() {
+        let _home = DataHome::new();
+        AegMemoryEngine::with_collection("default", |engine| engine.insert("k", "v")).unwrap();
+        fs::remove_file(AegMemoryEngine::engine_file_path("default")).unwrap();
+        let auth_key = AegFileSystem::read_authorization_key();
+        let wal = fs::read(AegWal::wal_path()).unwrap();
+
+        let issues = AegCheck::scan(&auth_key, true).issues;
+        assert_eq!(
+            issues,
+            vec![CheckIssue::MissingFile {
+                name: "default".into()
+            }]
+        );
+        let error = AegCheck::resolve(&auth_key, Resolution::Repair).unwrap_err();
+        assert!(error.contains("restore it from a snapshot"), "{}", error);
+        assert_eq!(fs::read(AegWal::wal_path()).unwrap(), wal);
+        assert!(!AegMemoryEngine::engine_file_path("default").exists());
+
+        // with nothing logged for it, the file is simply written again
+        AegWal::reset().unwrap();
+        AegCheck::resolve(&auth_key, Resolution::Repair).unwrap();
+        assert!(AegCheck::scan(&auth_key, true).issues.is_empty());
+    }
+
+    /// A listed collection whose last frame is damaged and a file the lock does not list,
+    /// with nothing left in the WAL.
+    fn damaged_and_orphaned_files() -> PathBuf {
+        AegCore::create_collection("damaged");
+        AegMemoryEngine::with_collection("damaged", |engine| {
+            for i in 0..3000 {
+                engine.insert(format!("key-{:04}", i), format!("value {}", "x".repeat(40)));
+            }
+        })
+        .unwrap();
+        AegMemoryEngine::save_all();
+        AegWal::reset().unwrap();
+        AegMemoryEngine::save_to_disk(&AegMemoryEngine::new("orphan")).unwrap();
+
+        let path = AegMemoryEngine::engine_file_path("damaged");
+        let mut bytes = fs::read(&path).unwrap();
+        // inside the last records frame, before the end marker
+        let offset = bytes.len() - 100;
+        bytes[offset] ^= 0x01;
+        fs::write(&path, bytes).unwrap();
+        path
+    }
+
+    fn issue_names(issues: &[CheckIssue]) -> Vec<String> {
+        issues
+            .iter()
+            .map(|issue| match issue {
+                CheckIssue::CorruptCollection { name, .. } => format!("corrupt {}", name),
+                CheckIssue::OrphanFile { name } => format!("orphan {}", name),
+                other => other.to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn quarantine_moves_damaged_and_orphaned_files_aside() {
+        let _home = DataHome::new();
+        let damaged = damaged_and_orphaned_files();
+        let auth_key = AegFileSystem::read_authorization_key();
+        let issues = AegCheck::scan(&auth_key, false).issues;
+        assert_eq!(issue_names(&issues), ["corrupt damaged", "orphan orphan"]);
+
+        let actions = AegCheck::resolve(&auth_key, Resolution::Quarantine).unwrap();
+        assert_eq!(actions.len(), 2, "{:?}", actions);
+        assert!(!damaged.exists());
+        assert!(!AegMemoryEngine::engine_file_path("orphan").exists());
+        let quarantined = fs::read_dir(AegFileSystem::get_config_path().join(STORE_QUARANTINE_DIR))
+            .unwrap()
+            .flatten()
+            .flat_map(|dir| fs::read_dir(dir.path()).unwrap().flatten())
+            .count();
+        assert_eq!(quarantined, 2);
+        assert_eq!(
+            AegTransfer::offline_collections(&auth_key).unwrap(),
+            ["default"]
+        );
+        assert!(AegCheck::scan(&auth_key, true).issues.is_empty());
+    }
+
+    #[test]
+    fn repair_keeps_the_intact_frames_and_registers_orphans() {
+        let _home = DataHome::new();
+        damaged_and_orphaned_files();
+        let auth_key = AegFileSystem::read_authorization_key();
+
+        AegCheck::resolve(&auth_key, Resolution::Repair).unwrap();
+        assert!(AegCheck::scan(&auth_key, true).issues.is_empty());
+        let kept = AegTransfer::offline_load("damaged", &auth_key)
+            .unwrap()
+            .store
+            .len();
+        assert!(kept > 0 && kept < 3000, "{}", kept);
+        assert_eq!(
+            AegTransfer::offline_collections(&auth_key).unwrap(),
+            ["default", "damaged", "orphan"]
+        );
+    }
+}
@@ -1,5 +1,7 @@
+use crate::transfer::{ImportMode, TransferFormat};
 use clap::{Args, Subcommand};
 use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
 
 // INIT
 #[derive(Args, Debug)]
@@ -56,6 +58,59 @@ pub struct PutArgs {
     pub key: String,
     #[arg(help = "Value to associate with the key")]
     pub value: String,
+    #[arg(long, conflicts_with = "xx", help = "Only set the key if it does not exist")]
+    pub nx: bool,
+    #[arg(long, help = "Only set the key if it already exists")]
+    pub xx: bool,
+    #[arg(long, help = "Return the previous value of the key")]
+    pub get: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct SetNxArgs {
+    #[arg(short, long, help = "Enable verbose output")]
+    pub verbose: bool,
+    #[arg(help = "Key to store in the active collection if it does not exist")]
+    pub key: String,
+    #[arg(help = "Value to associate with the key")]
+    pub value: String,
+}
+
+#[derive(Args, Debug)]
+pub struct GetSetArgs {
+    #[arg(short, long, help = "Enable verbose output")]
+    pub verbose: bool,
+    #[arg(help = "Key to overwrite in the active collection")]
+    pub key: String,
+    #[arg(help = "New value for the key")]
+    pub value: String,
+}
+
+#[derive(Args, Debug)]
+pub struct CasArgs {
+    #[arg(short, long, help = "Enable verbose output")]
+    pub verbose: bool,
+    #[arg(help = "Key to swap in the active collection")]
+    pub key: String,
+    #[arg(help = "New value for the key")]
+    pub value: String,
+    #[arg(
+        long,
+        required_unless_present = "version",
+        conflicts_with = "version",
+        help = "Value the key must currently hold"
+    )]
+    pub expected: Option<String>,
+    #[arg(long, help = "Version the key must currently have (0 = key must not exist)")]
+    pub version: Option<u64>,
+}
+
+#[derive(Args, Debug)]
+pub struct KeyVersionArgs {
+    #[arg(short, long, help = "Enable verbose output")]
+    pub verbose: bool,
+    #[arg(help = "Key to inspect in the active collection")]
+    pub key: String,
 }
 
 #[derive(Args, Debug)]
@@ -80,6 +135,197 @@ pub struct ClearArgs {
     pub verbose: bool,
 }
 
+#[derive(Args, Debug)]
+pub struct MGetArgs {
+    #[arg(short, long, help = "Enable verbose output")]
+    pub verbose: bool,
+    #[arg(required = true, help = "Keys to retrieve from the active collection")]
+    pub keys: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct MSetArgs {
+    #[arg(short, long, help = "Enable verbose output")]
+    pub verbose: bool,
+    #[arg(
+        required = true,
+        num_args = 2..,
+        value_names = ["KEY", "VALUE"],
+        help = "Key/value pairs to store in the active collection"
+    )]
+    pub pairs: Vec<String>,
+}
+
+impl MSetArgs {
+    /// Group the flat `KEY VALUE KEY VALUE ...` list into pairs.
+    pub fn to_pairs(&self) -> Result<Vec<(String, String)>, String> {
+        if !self.pairs.len().is_multiple_of(2) {
+            return Err("Expected an even number of arguments: KEY VALUE [KEY VALUE ...]".into());
+        }
+        Ok(self
+            .pairs
+            .chunks(2)
+            .map(|pair| (pair[0].clone(), pair[1].clone()))
+            .collect())
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct ExistsArgs {
+    #[arg(short, long, help = "Enable verbose output")]
+    pub verbose: bool,
+    #[arg(required = true, help = "Keys to look up in the active collection")]
+    pub keys: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct RenameKeyArgs {
+    #[arg(short, long, help = "Enable verbose output")]
+    pub verbose: bool,
+    #[arg(long, help = "Only rename if the new key does not exist")]
+    pub nx: bool,
+    #[arg(help = "Key to rename in the active collection")]
+    pub key: String,
+    #[arg(help = "New name for the key")]
+    pub new_key: String,
+}
+
+#[derive(Args, Debug)]
+pub struct CopyArgs {
+    #[arg(short, long, help = "Enable verbose output")]
+    pub verbose: bool,
+    #[arg(short, long, help = "Overwrite the destination key if it exists")]
+    pub replace: bool,
+    #[arg(help = "Key to copy from")]
+    pub source: String,
+    #[arg(help = "Key to copy to")]
+    pub destination: String,
+    #[arg(long, help = "Collection to copy into (defaults to the same collection)")]
+    pub to_collection: Option<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct MoveArgs {
+    #[arg(short, long, help = "Enable verbose output")]
+    pub verbose: bool,
+    #[arg(help = "Key to move out of the active collection")]
+    pub key: String,
+    #[arg(help = "Collection to move the key into")]
+    pub collection: String,
+}
+
+#[derive(Args, Debug)]
+pub struct TypeArgs {
+    #[arg(short, long, help = "Enable verbose output")]
+    pub verbose: bool,
+    #[arg(help = "Key to inspect in the active collection")]
+    pub key: String,
+}
+
+#[derive(Args, Debug)]
+pub struct EvalArgs {
+    #[arg(short, long, help = "Enable verbose output")]
+    pub verbose: bool,
+    #[arg(help = "Rhai script to run against the active collection")]
+    pub script: String,
+    #[arg(short, long, num_args = 1.., help = "Keys passed to the script as KEYS")]
+    pub keys: Vec<String>,
+    #[arg(short, long, num_args = 1.., help = "Arguments passed to the script as ARGV")]
+    pub args: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct EvalShaArgs {
+    #[arg(short, long, help = "Enable verbose output")]
+    pub verbose: bool,
+    #[arg(help = "Sha of a script previously loaded with script-load")]
+    pub sha: String,
+    #[arg(short, long, num_args = 1.., help = "Keys passed to the script as KEYS")]
+    pub keys: Vec<String>,
+    #[arg(short, long, num_args = 1.., help = "Arguments passed to the script as ARGV")]
+    pub args: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct ScriptLoadArgs {
+    #[arg(short, long, help = "Enable verbose output")]
+    pub verbose: bool,
+    #[arg(help = "Rhai script to store in the script cache")]
+    pub script: String,
+}
+
+#[derive(Args, Debug)]
+pub struct RestoreArgs {
+    #[arg(short, long, help = "Enable verbose output")]
+    pub verbose: bool,
+    #[arg(help = "Name of the snapshot to restore (see `snapshots`)")]
+    pub name: String,
+}
+
+#[derive(Args, Debug)]
+pub struct ClientKeyArgs {
+    #[arg(long, help = "List the ids of the keys in the keyfile instead of adding one")]
+    pub list: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct ExportArgs {
+    #[arg(short, long, help = "Enable verbose output")]
+    pub verbose: bool,
+    #[arg(help = "Collection to export")]
+    pub collection: String,
+    #[arg(short, long, value_enum, default_value_t, help = "Output format")]
+    pub format: TransferFormat,
+    #[arg(short, long, help = "File to write to (defaults to stdout)")]
+    pub output: Option<PathBuf>,
+    #[arg(long, help = "Read the data files without the daemon")]
+    pub offline: bool,
+    #[arg(
+        long,
+        env = "AEGISR_AUTH_KEY",
+        hide_env_values = true,
+        help = "Authorization key for --offline (defaults to the key in the data directory)"
+    )]
+    pub auth_key: Option<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct ImportArgs {
+    #[arg(short, long, help = "Enable verbose output")]
+    pub verbose: bool,
+    #[arg(help = "Collection to import into (created if missing)")]
+    pub collection: String,
+    #[arg(help = "File to read from (defaults to stdin)")]
+    pub input: Option<PathBuf>,
+    #[arg(short, long, value_enum, default_value_t, help = "Input format")]
+    pub format: TransferFormat,
+    #[arg(
+        short,
+        long,
+        value_enum,
+        default_value_t,
+        help = "Whether existing keys keep their value (merge) or are replaced (overwrite)"
+    )]
+    pub mode: ImportMode,
+    #[arg(long, help = "Only report what would change")]
+    pub dry_run: bool,
+    #[arg(long, help = "Write the data files without the daemon")]
+    pub offline: bool,
+    #[arg(
+        long,
+        env = "AEGISR_AUTH_KEY",
+        hide_env_values = true,
+        help = "Authorization key for --offline (defaults to the key in the data directory)"
+    )]
+    pub auth_key: Option<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct ScriptExistsArgs {
+    #[arg(required = true, help = "Script shas to look up in the script cache")]
+    pub shas: Vec<String>,
+}
+
 // ===========================
 // SUBCOMMAND ENUM
 // ===========================
@@ -108,6 +354,63 @@ pub enum Commands {
     Del(DelArgs),
     #[command(about = "Clear all key/value pairs from the active collection")]
     Clear(ClearArgs),
+    #[command(name = "mget", about = "Retrieve the values of several keys at once")]
+    MGet(MGetArgs),
+    #[command(name = "mset", about = "Store several key/value pairs atomically")]
+    MSet(MSetArgs),
+    #[command(
+        name = "msetnx",
+        about = "Store several key/value pairs atomically, only if none of the keys exist"
+    )]
+    MSetNx(MSetArgs),
+    #[command(about = "Count how many of the given keys exist")]
+    Exists(ExistsArgs),
+    #[command(about = "Rename a key in the active collection")]
+    RenameKey(RenameKeyArgs),
+    #[command(about = "Copy the value of a key to another key, optionally in another collection")]
+    Copy(CopyArgs),
+    #[command(about = "Move a key to another collection")]
+    Move(MoveArgs),
+    #[command(about = "Show the type of the value stored at a key")]
+    Type(TypeArgs),
+    #[command(name = "setnx", about = "Store a key/value pair only if the key does not exist")]
+    SetNx(SetNxArgs),
+    #[command(name = "getset", about = "Store a new value and return the previous one")]
+    GetSet(GetSetArgs),
+    #[command(about = "Compare-and-swap a key against an expected value or version")]
+    Cas(CasArgs),
+    #[command(about = "Show the current version of a key")]
+    KeyVersion(KeyVersionArgs),
+    #[command(about = "Run a script atomically against the active collection")]
+    Eval(EvalArgs),
+    #[command(name = "evalsha", about = "Run a cached script by its sha")]
+    EvalSha(EvalShaArgs),
+    #[command(about = "Store a script in the script cache and print its sha")]
+    ScriptLoad(ScriptLoadArgs),
+    #[command(about = "Check whether scripts are in the script cache")]
+    ScriptExists(ScriptExistsArgs),
+    #[command(about = "Remove all scripts from the script cache")]
+    ScriptFlush,
+    #[command(about = "Compact the write-ahead log in the background")]
+    RewriteWal,
+    #[command(about = "Write a point-in-time snapshot of all collections")]
+    Snapshot,
+    #[command(about = "List existing snapshots")]
+    Snapshots,
+    #[command(about = "Replace all collections with a snapshot")]
+    Restore(RestoreArgs),
+    #[command(about = "Re-encrypt all data under a new authorization key")]
+    RotateKey,
+    #[command(about = "Export a collection as JSON, JSON Lines or CSV")]
+    Export(ExportArgs),
+    #[command(about = "Import key/value pairs from JSON, JSON Lines or CSV")]
+    Import(ImportArgs),
+    #[command(about = "Check that the audit log has not been tampered with")]
+    AuditVerify,
+    #[command(about = "Start a new audit log, keeping the current one under a timestamped name")]
+    AuditRotate,
+    #[command(about = "Add a new key to the client keyfile used by --keyfile (creating it if missing)")]
+    ClientKey(ClientKeyArgs),
 }
 
 // ===========================
@@ -118,13 +421,115 @@ pub enum Commands {
 pub enum AegisrCommand {
     Init { verbose: bool, reset: bool },
     List,
-    Use { verbose: bool, name: String },
+    /// Switch the session's collection. `persist` also makes it the default for new sessions.
+    Use {
+        verbose: bool,
+        name: String,
+        #[serde(default)]
+        persist: bool,
+    },
     New { verbose: bool, name: String },
     Delete { verbose: bool, name: String },
     Rename { verbose: bool, name: String, new_name: String },
     Status,
-    Put { verbose: bool, key: String, value: String },
+    Put {
+        verbose: bool,
+        key: String,
+        value: String,
+        #[serde(default)]
+        nx: bool,
+        #[serde(default)]
+        xx: bool,
+        #[serde(default)]
+        get: bool,
+    },
     Get { verbose: bool, key: String },
     Del { verbose: bool, key: String },
     Clear { verbose: bool },
+    MGet { verbose: bool, keys: Vec<String> },
+    MSet { verbose: bool, pairs: Vec<(String, String)> },
+    MSetNx { verbose: bool, pairs: Vec<(String, String)> },
+    Exists { verbose: bool, keys: Vec<String> },
+    RenameKey { verbose: bool, key: String, new_key: String, nx: bool },
+    Copy {
+        verbose: bool,
+        source: String,
+        destination: String,
+        replace: bool,
+        #[serde(default)]
+        to_collection: Option<String>,
+    },
+    Move { verbose: bool, key: String, collection: String },
+    /// Run a key command against `collection` instead of the active collection.
+    WithCollection { collection: String, command: Box<AegisrCommand> },
+    Type { verbose: bool, key: String },
+    SetNx { verbose: bool, key: String, value: String },
+    GetSet { verbose: bool, key: String, value: String },
+    Cas {
+        verbose: bool,
+        key: String,
+        value: String,
+        expected: Option<String>,
+        version: Option<u64>,
+    },
+    KeyVersion { verbose: bool, key: String },
+    Eval { verbose: bool, script: String, keys: Vec<String>, args: Vec<String> },
+    EvalSha { verbose: bool, sha: String, keys: Vec<String>, args: Vec<String> },
+    ScriptLoad { verbose: bool, script: String },
+    ScriptExists { shas: Vec<String> },
+    ScriptFlush,
+    RewriteWal,
+    Snapshot,
+    ListSnapshots,
+    Restore { verbose: bool, name: String },
+    RotateKey,
+    /// One page of `collection`: up to `count` keys after `after`, in key order.
+    Export { collection: String, after: Option<String>, count: usize },
+    /// One batch of an import; the client sends as many as the file needs.
+    Import {
+        verbose: bool,
+        collection: String,
+        pairs: Vec<(String, String)>,
+        mode: ImportMode,
+        dry_run: bool,
+    },
+    /// Check the hash chain of the audit log.
+    AuditVerify,
+    /// Start a new audit log; the current one is kept under a timestamped name.
+    AuditRotate,
+    // Transactions (persistent connections only)
+    Multi,
+    Exec,
+    Discard,
+    Watch { keys: Vec<String> },
+    Unwatch,
+}
+
+impl AegisrCommand {
+    /// Commands that only read or write keys of a single collection.
+    /// These are the ones that may be queued inside MULTI.
+    pub fn is_data_command(&self) -> bool {
+        matches!(
+            self,
+            AegisrCommand::Put { .. }
+                | AegisrCommand::Get { .. }
+                | AegisrCommand::Del { .. }
+                | AegisrCommand::Clear { .. }
+                | AegisrCommand::MGet { .. }
+                | AegisrCommand::MSet { .. }
+                | AegisrCommand::MSetNx { .. }
+                | AegisrCommand::Exists { .. }
+                | AegisrCommand::RenameKey { .. }
+                | AegisrCommand::Copy { .. }
+                | AegisrCommand::Move { .. }
+                | AegisrCommand::WithCollection { .. }
+                | AegisrCommand::Type { .. }
+                | AegisrCommand::SetNx { .. }
+                | AegisrCommand::GetSet { .. }
+                | AegisrCommand::Cas { .. }
+                | AegisrCommand::KeyVersion { .. }
+                | AegisrCommand::Eval { .. }
+                | AegisrCommand::EvalSha { .. }
+        )
+    }
 }
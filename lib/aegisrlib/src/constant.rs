@@ -5,4 +5,17 @@ pub const ENGINE_VERSION: &str = "1.0.2-beta"; /// TODO: Use Cargo app version
 pub const STORE_DIR: &str = ".aegisr";
 pub const STORE_COLLECTION: &str = "collection.lock";
 pub const STORE_CONFIG_AEG: &str = "config.aeg";
-pub const STORE_AUTHORIZATION_KEY: &str = "AUTHORIZATION_KEY";
\ No newline at end of file
+pub const STORE_AUTHORIZATION_KEY: &str = "AUTHORIZATION_KEY";
+pub const STORE_WAL: &str = "appendonly.aewal";
+/// Infix of temp files written by `AegFileSystem::write_atomic` (`<file>.tmp-<pid>-<n>`).
+pub const STORE_TEMP_MARKER: &str = ".tmp-";
+pub const STORE_BACKUP_DIR: &str = "backups";
+pub const STORE_RESTORE_PENDING: &str = "restore.pending";
+pub const STORE_QUARANTINE_DIR: &str = "quarantine";
+/// The next authorization key while a key rotation is in progress.
+pub const STORE_AUTHORIZATION_KEY_NEXT: &str = "AUTHORIZATION_KEY.next";
+/// Directory of the audit log; `init --reset` leaves it in place.
+pub const STORE_AUDIT_DIR: &str = "audit";
+pub const STORE_AUDIT_LOG: &str = "audit.log";
+/// Key of the audit hash chain, sealed with the authorization key; kept by `init --reset`.
+pub const STORE_AUDIT_KEY: &str = "audit.key";
\ No newline at end of file
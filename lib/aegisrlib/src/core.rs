@@ -1,10 +1,8 @@
-use crate::constant::STORE_COLLECTION;
 use crate::file_system::{AegFileSystem, CollectionLock};
-use crate::memory_engine::AegMemoryEngine;
+use crate::memory_engine::{AegCollections, AegMemoryEngine, CasExpectation, SetCondition};
 use rand_core::TryRngCore;
 use serde::{Deserialize, Serialize};
 use std::convert::TryInto;
-use std::fs;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct AegCore {
@@ -21,6 +19,16 @@ impl AegCore {
         }
     }
 
+    /// `load` that reports a damaged `collection.lock` instead of panicking. Cheap: the
+    /// lock is kept in memory once read.
+    pub fn try_load() -> Result<Self, String> {
+        let lock = AegFileSystem::collection_lock()?;
+        Ok(Self {
+            active_collection: lock.active,
+            collections: lock.collections,
+        })
+    }
+
     pub fn save(&self) {
         let lock = CollectionLock {
             active: self.active_collection.clone(),
@@ -28,13 +36,14 @@ impl AegCore {
         };
         let json = serde_json::to_string_pretty(&lock).expect("Serialize failed");
         let auth_key = AegFileSystem::read_authorization_key();
-
-        let path = AegFileSystem::get_config_path().join(STORE_COLLECTION);
-        fs::write(&path, json.clone()).expect("Write failed");
-
         AegFileSystem::write_collection_lock_json(&json, &auth_key);
     }
 
+    /// Whether `name` is listed in `collection.lock`.
+    pub fn collection_exists(name: &str) -> Result<bool, String> {
+        Ok(Self::try_load()?.collections.iter().any(|c| c == name))
+    }
+
     pub fn get_active_collection(&self) -> &str {
         &self.active_collection
     }
@@ -53,6 +62,10 @@ impl AegCore {
         if core.collections.contains(&name.to_string()) {
             return format!("✗ Collection '{}' already exists", name);
         }
+        // Every listed collection has a file, so aegisr-check can tell a lost one apart.
+        if let Err(e) = AegMemoryEngine::ensure_file(name) {
+            return format!("✗ Failed to create collection '{}': {}", name, e);
+        }
 
         core.collections.push(name.to_string());
         core.save();
@@ -73,7 +86,13 @@ impl AegCore {
                 core.active_collection = core.collections[0].clone();
             }
             core.save();
-            format!("✓ Collection '{}' deleted", name)
+            match AegMemoryEngine::drop_collection(name) {
+                Ok(()) => format!("✓ Collection '{}' deleted", name),
+                Err(e) => format!(
+                    "✓ Collection '{}' deleted, but its file could not be shredded: {}",
+                    name, e
+                ),
+            }
         } else {
             format!("✗ Collection '{}' does not exist", name)
         }
@@ -89,8 +108,10 @@ impl AegCore {
             if core.active_collection == name {
                 core.active_collection = new_name.to_string();
             }
-            core.save();
-            format!("✓ Collection '{}' renamed to '{}'", name, new_name)
+            match AegMemoryEngine::rename_collection(name, new_name, || core.save()) {
+                Ok(()) => format!("✓ Collection '{}' renamed to '{}'", name, new_name),
+                Err(e) => format!("✗ Collection '{}' could not be renamed: {}", name, e),
+            }
         } else {
             format!("✗ Collection '{}' does not exist", name)
         }
@@ -99,26 +120,168 @@ impl AegCore {
     /// Insert into memory (non-blocking). Does not perform immediate disk save.
     /// Background saver (if started) will persist this later.
     pub fn put_value(key: &str, value: &str) -> String {
-        let mut engine = AegMemoryEngine::load();
-        engine.insert(key, value);
-        // no engine.save() here - background saver will persist
-        format!(
-            "✓ Key '{}' saved in collection '{}' (in-memory)",
-            key, engine.collection_name
-        )
+        Self::put_value_with(key, value, SetCondition::Always).0
+    }
+
+    /// Conditional insert (SETNX / SET NX / SET XX), checked and applied under one lock.
+    /// Returns the message and the value the key held before the call.
+    pub fn put_value_with(
+        key: &str,
+        value: &str,
+        condition: SetCondition,
+    ) -> (String, Option<String>) {
+        AegMemoryEngine::with_active(|engine| Self::put_in(engine, key, value, condition))
+            .unwrap_or_else(|e| (format!("✗ {}", e), None))
+    }
+
+    /// Set a new value and return the old one (GETSET).
+    pub fn getset_value(key: &str, value: &str) -> Result<Option<String>, String> {
+        AegMemoryEngine::with_active(|engine| engine.set_with(key, value, SetCondition::Always).1)
+    }
+
+    /// Compare-and-swap against an expected value or version.
+    pub fn cas_value(key: &str, value: &str, expected: &CasExpectation) -> String {
+        AegMemoryEngine::with_active(|engine| Self::cas_in(engine, key, value, expected))
+            .unwrap_or_else(|e| format!("✗ {}", e))
+    }
+
+    /// Current version of a key (0 when absent), for use with version-based CAS.
+    pub fn key_version(key: &str) -> Result<u64, String> {
+        AegMemoryEngine::read_active(|engine| engine.key_version(key))
     }
 
     /// Read from memory (plaintext in RAM).
-    pub fn get_value(key: &str) -> Option<String> {
-        let engine = AegMemoryEngine::load();
-        engine.get(key)
+    pub fn get_value(key: &str) -> Result<Option<String>, String> {
+        AegMemoryEngine::read_active(|engine| engine.get(key))
     }
 
     /// Delete in-memory (non-blocking). Background saver will persist deletion later.
     pub fn delete_value(key: &str) -> String {
-        let mut engine = AegMemoryEngine::load();
-        if engine.get(key).is_some() {
-            engine.delete(key);
+        AegMemoryEngine::with_active(|engine| Self::delete_in(engine, key))
+            .unwrap_or_else(|e| format!("✗ {}", e))
+    }
+
+    /// Clear in-memory values (non-blocking). Background saver will persist later.
+    pub fn clear_values() -> String {
+        AegMemoryEngine::with_active(Self::clear_in).unwrap_or_else(|e| format!("✗ {}", e))
+    }
+
+    /// Read several keys in one lock acquisition. Missing keys yield `None`.
+    pub fn mget_values(keys: &[String]) -> Result<Vec<Option<String>>, String> {
+        AegMemoryEngine::read_active(|engine| engine.mget(keys))
+    }
+
+    /// Write several pairs atomically (in-memory).
+    pub fn mset_values(pairs: &[(String, String)]) -> String {
+        AegMemoryEngine::with_active(|engine| Self::mset_in(engine, pairs))
+            .unwrap_or_else(|e| format!("✗ {}", e))
+    }
+
+    /// Write several pairs atomically, but only if none of the keys exist yet.
+    pub fn msetnx_values(pairs: &[(String, String)]) -> String {
+        AegMemoryEngine::with_active(|engine| Self::msetnx_in(engine, pairs))
+            .unwrap_or_else(|e| format!("✗ {}", e))
+    }
+
+    /// Count how many of the given keys exist in the active collection.
+    pub fn exists_keys(keys: &[String]) -> Result<usize, String> {
+        AegMemoryEngine::read_active(|engine| engine.exists(keys))
+    }
+
+    /// Rename a key within the active collection. `nx` refuses to overwrite `new_key`.
+    pub fn rename_key(key: &str, new_key: &str, nx: bool) -> String {
+        AegMemoryEngine::with_active(|engine| Self::rename_key_in(engine, key, new_key, nx))
+            .unwrap_or_else(|e| format!("✗ {}", e))
+    }
+
+    /// Copy a key within the active collection. `replace` allows overwriting `destination`.
+    pub fn copy_key(source: &str, destination: &str, replace: bool) -> String {
+        AegMemoryEngine::with_active(|engine| {
+            Self::copy_key_in(engine, source, destination, replace)
+        })
+        .unwrap_or_else(|e| format!("✗ {}", e))
+    }
+
+    /// Type of the value stored at `key` ("string", or "none" when absent).
+    pub fn key_type(key: &str) -> Result<String, String> {
+        AegMemoryEngine::read_active(|engine| engine.key_type(key).to_string())
+    }
+
+    /// Move `key` from collection `from` to collection `to` atomically.
+    pub fn move_key(from: &str, to: &str, key: &str) -> String {
+        AegMemoryEngine::with_collections(|collections| {
+            Self::move_key_in(collections, from, to, key)
+        })
+        .unwrap_or_else(|e| format!("✗ {}", e))
+    }
+
+    /// Copy `source` in collection `from` to `destination` in collection `to` atomically.
+    pub fn copy_key_between(
+        from: &str,
+        to: &str,
+        source: &str,
+        destination: &str,
+        replace: bool,
+    ) -> String {
+        AegMemoryEngine::with_collections(|collections| {
+            Self::copy_key_between_in(collections, from, to, source, destination, replace)
+        })
+        .unwrap_or_else(|e| format!("✗ {}", e))
+    }
+
+    // ---------------------------------------------------------------------
+    // `*_in` variants operate on an engine the caller already holds through
+    // `AegMemoryEngine::with_collection`, so several of them can be applied
+    // under one lock (transactions, scripts). They never lock by themselves.
+    // ---------------------------------------------------------------------
+
+    pub fn put_in(
+        engine: &mut AegMemoryEngine,
+        key: &str,
+        value: &str,
+        condition: SetCondition,
+    ) -> (String, Option<String>) {
+        let (written, previous) = engine.set_with(key, value, condition);
+        // no engine.save() here - background saver will persist
+        let message = if written {
+            format!(
+                "✓ Key '{}' saved in collection '{}' (in-memory)",
+                key, engine.collection_name
+            )
+        } else if previous.is_some() {
+            format!(
+                "✗ Key '{}' already exists in collection '{}'",
+                key, engine.collection_name
+            )
+        } else {
+            format!(
+                "✗ Key '{}' not found in collection '{}'",
+                key, engine.collection_name
+            )
+        };
+        (message, previous)
+    }
+
+    pub fn cas_in(
+        engine: &mut AegMemoryEngine,
+        key: &str,
+        value: &str,
+        expected: &CasExpectation,
+    ) -> String {
+        match engine.compare_and_swap(key, expected, value) {
+            Ok(version) => format!(
+                "✓ Key '{}' swapped in collection '{}' (version {})",
+                key, engine.collection_name, version
+            ),
+            Err(current) => format!(
+                "✗ Compare-and-swap failed for key '{}' in collection '{}' (current version {})",
+                key, engine.collection_name, current
+            ),
+        }
+    }
+
+    pub fn delete_in(engine: &mut AegMemoryEngine, key: &str) -> String {
+        if engine.remove(key) {
             // no engine.save() here
             format!(
                 "✓ Key '{}' deleted from collection '{}' (in-memory)",
@@ -132,9 +295,7 @@ impl AegCore {
         }
     }
 
-    /// Clear in-memory values (non-blocking). Background saver will persist later.
-    pub fn clear_values() -> String {
-        let mut engine = AegMemoryEngine::load();
+    pub fn clear_in(engine: &mut AegMemoryEngine) -> String {
         engine.clear();
         format!(
             "✓ All keys cleared from collection '{}' (in-memory)",
@@ -142,6 +303,150 @@ impl AegCore {
         )
     }
 
+    pub fn mset_in(engine: &mut AegMemoryEngine, pairs: &[(String, String)]) -> String {
+        engine.mset(pairs);
+        format!(
+            "✓ {} key(s) saved in collection '{}' (in-memory)",
+            pairs.len(),
+            engine.collection_name
+        )
+    }
+
+    pub fn msetnx_in(engine: &mut AegMemoryEngine, pairs: &[(String, String)]) -> String {
+        if engine.msetnx(pairs) {
+            format!(
+                "✓ {} key(s) saved in collection '{}' (in-memory)",
+                pairs.len(),
+                engine.collection_name
+            )
+        } else {
+            format!(
+                "✗ No keys saved: at least one key already exists in collection '{}'",
+                engine.collection_name
+            )
+        }
+    }
+
+    pub fn rename_key_in(
+        engine: &mut AegMemoryEngine,
+        key: &str,
+        new_key: &str,
+        nx: bool,
+    ) -> String {
+        match engine.rename(key, new_key, nx) {
+            Ok(true) => format!(
+                "✓ Key '{}' renamed to '{}' in collection '{}' (in-memory)",
+                key, new_key, engine.collection_name
+            ),
+            Ok(false) => format!(
+                "✗ Key '{}' already exists in collection '{}'",
+                new_key, engine.collection_name
+            ),
+            Err(e) => format!("✗ {} in collection '{}'", e, engine.collection_name),
+        }
+    }
+
+    /// MOVE: fails if the key is missing in `from` or already present in `to`.
+    pub fn move_key_in(
+        collections: &mut AegCollections,
+        from: &str,
+        to: &str,
+        key: &str,
+    ) -> String {
+        if from == to {
+            return "✗ Source and destination collections are the same".into();
+        }
+        match Self::collection_exists(to) {
+            Ok(true) => {}
+            Ok(false) => return format!("✗ Collection '{}' does not exist", to),
+            Err(e) => return format!("✗ {}", e),
+        }
+        let value = match collections.get_mut(from) {
+            Ok(source) => source.get(key),
+            Err(e) => return format!("✗ {}", e),
+        };
+        let Some(value) = value else {
+            return format!("✗ Key '{}' not found in collection '{}'", key, from);
+        };
+        let target = match collections.get_mut(to) {
+            Ok(target) => target,
+            Err(e) => return format!("✗ {}", e),
+        };
+        if target.get(key).is_some() {
+            return format!("✗ Key '{}' already exists in collection '{}'", key, to);
+        }
+        target.insert(key, value);
+        if let Ok(source) = collections.get_mut(from) {
+            source.remove(key);
+        }
+        format!(
+            "✓ Key '{}' moved from collection '{}' to '{}' (in-memory)",
+            key, from, to
+        )
+    }
+
+    pub fn copy_key_between_in(
+        collections: &mut AegCollections,
+        from: &str,
+        to: &str,
+        source: &str,
+        destination: &str,
+        replace: bool,
+    ) -> String {
+        if from == to {
+            return match collections.get_mut(from) {
+                Ok(engine) => Self::copy_key_in(engine, source, destination, replace),
+                Err(e) => format!("✗ {}", e),
+            };
+        }
+        match Self::collection_exists(to) {
+            Ok(true) => {}
+            Ok(false) => return format!("✗ Collection '{}' does not exist", to),
+            Err(e) => return format!("✗ {}", e),
+        }
+        let value = match collections.get_mut(from) {
+            Ok(engine) => engine.get(source),
+            Err(e) => return format!("✗ {}", e),
+        };
+        let Some(value) = value else {
+            return format!("✗ Key '{}' not found in collection '{}'", source, from);
+        };
+        let target = match collections.get_mut(to) {
+            Ok(target) => target,
+            Err(e) => return format!("✗ {}", e),
+        };
+        if !replace && target.get(destination).is_some() {
+            return format!(
+                "✗ Key '{}' already exists in collection '{}'",
+                destination, to
+            );
+        }
+        target.insert(destination, value);
+        format!(
+            "✓ Key '{}' copied to '{}' in collection '{}' (in-memory)",
+            source, destination, to
+        )
+    }
+
+    pub fn copy_key_in(
+        engine: &mut AegMemoryEngine,
+        source: &str,
+        destination: &str,
+        replace: bool,
+    ) -> String {
+        if engine.copy(source, destination, replace) {
+            format!(
+                "✓ Key '{}' copied to '{}' in collection '{}' (in-memory)",
+                source, destination, engine.collection_name
+            )
+        } else {
+            format!(
+                "✗ Key '{}' not copied: source missing or destination exists in collection '{}'",
+                source, engine.collection_name
+            )
+        }
+    }
+
     /// Force immediate flush (saves all collections to disk synchronously).
     pub fn flush_now() {
         AegMemoryEngine::save_all();
@@ -158,3 +463,93 @@ impl AegCore {
         AegMemoryEngine::stop_background_saver();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constant::STORE_COLLECTION;
+    use crate::test_support::DataHome;
+    use std::fs;
+
+    #[test]
+    fn the_collection_list_is_kept_in_memory_and_follows_changes() {
+        let _home = DataHome::new();
+        AegCore::create_collection("c2");
+        let lock_path = AegFileSystem::get_config_path().join(STORE_COLLECTION);
+
+        // served from memory, not from the file
+        fs::write(&lock_path, "v3:damaged").unwrap();
+        assert_eq!(AegCore::collection_exists("c2"), Ok(true));
+        AegCore::rename_collection("c2", "c3");
+        assert_eq!(AegCore::collection_exists("c2"), Ok(false));
+        assert_eq!(AegCore::collection_exists("c3"), Ok(true));
+        let auth_key = AegFileSystem::read_authorization_key();
+        assert!(AegFileSystem::decrypt_collection_lock(&auth_key).is_ok());
+
+        // a lock replaced behind its back is read again, and a damaged one is an error
+        fs::write(&lock_path, "v3:damaged").unwrap();
+        AegFileSystem::forget_collection_lock();
+        assert!(AegCore::collection_exists("c3").is_err());
+        let response = AegMemoryEngine::with_collections(|collections| {
+            AegCore::move_key_in(collections, "default", "c3", "k")
+        })
+        .unwrap();
+        assert!(response.starts_with('✗'), "{}", response);
+    }
+
+    fn value_in(collection: &str, key: &str) -> Option<String> {
+        AegMemoryEngine::read_collection(collection, |engine| engine.get(key)).unwrap()
+    }
+
+    #[test]
+    fn moving_a_key_never_overwrites_the_destination() {
+        let _home = DataHome::new();
+        AegCore::create_collection("c2");
+        AegMemoryEngine::with_collection("default", |engine| {
+            engine.insert("a", "1");
+            engine.insert("b", "1");
+        })
+        .unwrap();
+        AegMemoryEngine::with_collection("c2", |engine| engine.insert("b", "2")).unwrap();
+
+        let moved = |from: &str, to: &str, key: &str| {
+            AegMemoryEngine::with_collections(|c| AegCore::move_key_in(c, from, to, key)).unwrap()
+        };
+        assert!(moved("default", "c2", "b").starts_with('✗'));
+        assert_eq!(value_in("default", "b").as_deref(), Some("1"));
+        assert_eq!(value_in("c2", "b").as_deref(), Some("2"));
+
+        assert!(moved("default", "default", "a").starts_with('✗'));
+        assert!(moved("default", "missing", "a").contains("does not exist"));
+        assert_eq!(value_in("default", "a").as_deref(), Some("1"));
+
+        assert!(moved("default", "c2", "a").starts_with('✓'));
+        assert_eq!(value_in("default", "a"), None);
+        assert_eq!(value_in("c2", "a").as_deref(), Some("1"));
+    }
+
+    #[test]
+    fn copying_between_collections_replaces_only_when_asked() {
+        let _home = DataHome::new();
+        AegCore::create_collection("c2");
+        AegMemoryEngine::with_collection("default", |engine| engine.insert("a", "1")).unwrap();
+        AegMemoryEngine::with_collection("c2", |engine| engine.insert("b", "2")).unwrap();
+
+        let copied = |to: &str, destination: &str, replace: bool| {
+            AegMemoryEngine::with_collections(|c| {
+                AegCore::copy_key_between_in(c, "default", to, "a", destination, replace)
+            })
+            .unwrap()
+        };
+        assert!(copied("c2", "b", false).starts_with('✗'));
+        assert_eq!(value_in("c2", "b").as_deref(), Some("2"));
+        assert!(copied("c2", "b", true).starts_with('✓'));
+        assert_eq!(value_in("c2", "b").as_deref(), Some("1"));
+        assert_eq!(value_in("default", "a").as_deref(), Some("1"));
+
+        // within one collection it is a plain copy
+        assert!(copied("default", "a2", false).starts_with('✓'));
+        assert_eq!(value_in("default", "a2").as_deref(), Some("1"));
+        assert!(copied("missing", "a", true).contains("does not exist"));
+    }
+}
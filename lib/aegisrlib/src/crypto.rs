@@ -1,24 +1,219 @@
-use base64::{Engine as _, engine::general_purpose};
-use rand_core::{OsRng, TryRngCore};
-use zeroize::Zeroize;
-
-pub struct AegCrypto;
-
-impl AegCrypto {
-    pub fn generate_random_bytes(_verbose: Option<bool>) -> [u8; 32] {
-        let mut key = [0u8; 32];
-        OsRng.try_fill_bytes(&mut key).unwrap();
-        key
-    }
-
-    pub fn encode_base64(input: impl AsRef<[u8]>, _verbose: Option<bool>) -> String {
-        general_purpose::STANDARD.encode(input.as_ref())
-    }
-
-    pub fn create_authorization_key(_verbose: Option<bool>) -> String {
-        let mut bytes = Self::generate_random_bytes(None);
-        let hash = blake3::hash(&bytes);
-        bytes.zeroize();
-        Self::encode_base64(hash.as_bytes(), None)
-    }
-}
+use crate::secret::{SecretBytes, SecretString};
+use aes_gcm::aead::{Aead, Payload};
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
+use base64::{Engine as _, engine::general_purpose};
+use rand_core::{OsRng, TryRngCore};
+use std::fmt;
+use zeroize::{Zeroize, Zeroizing};
+
+/// AES-256-GCM nonce length in bytes.
+pub const NONCE_LEN: usize = 12;
+
+/// AES-256-GCM key derived from the base64 authorization key. Build it once per file or
+/// stream and pass it to `AegCrypto::seal`/`open`. The key schedule is zeroized on drop.
+pub struct SealingKey {
+    cipher: Aes256Gcm,
+    /// Nonce that format version 1 files derived from the key; only used to read them.
+    legacy_nonce: [u8; NONCE_LEN],
+}
+
+impl Drop for SealingKey {
+    fn drop(&mut self) {
+        self.legacy_nonce.zeroize();
+    }
+}
+
+/// Random key of one collection: its snapshot frames and its WAL operations are sealed
+/// with it. It is created with the collection and kept across saves, and only stored
+/// wrapped by the authorization key, so destroying that wrapped copy destroys the data.
+#[derive(Clone)]
+pub struct DataKey(Zeroizing<[u8; 32]>);
+
+impl DataKey {
+    pub fn generate() -> Self {
+        Self(Zeroizing::new(AegCrypto::generate_random_bytes(None)))
+    }
+
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Self(Zeroizing::new(bytes))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    pub fn sealing_key(&self) -> SealingKey {
+        AegCrypto::sealing_key_from_bytes(&self.0)
+    }
+}
+
+impl fmt::Debug for DataKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("DataKey(..)")
+    }
+}
+
+pub struct AegCrypto;
+
+impl AegCrypto {
+    pub fn generate_random_bytes(_verbose: Option<bool>) -> [u8; 32] {
+        let mut key = [0u8; 32];
+        OsRng.try_fill_bytes(&mut key).unwrap();
+        key
+    }
+
+    pub fn encode_base64(input: impl AsRef<[u8]>, _verbose: Option<bool>) -> String {
+        general_purpose::STANDARD.encode(input.as_ref())
+    }
+
+    pub fn create_authorization_key(_verbose: Option<bool>) -> SecretString {
+        let bytes = Zeroizing::new(Self::generate_random_bytes(None));
+        let hash = Zeroizing::new(*blake3::hash(bytes.as_slice()).as_bytes());
+        Zeroizing::new(Self::encode_base64(hash.as_slice(), None))
+    }
+
+    /// Key for sealing and opening store data, from the base64 authorization key.
+    pub fn sealing_key(auth_key: &str) -> Result<SealingKey, String> {
+        let key_bytes = Zeroizing::new(
+            general_purpose::STANDARD
+                .decode(auth_key.trim())
+                .map_err(|_| "authorization key is not valid base64".to_string())?,
+        );
+        if key_bytes.len() != 32 {
+            return Err("authorization key must be 32 bytes".into());
+        }
+        Ok(Self::sealing_key_from_bytes(
+            key_bytes.as_slice().try_into().expect("32 bytes"),
+        ))
+    }
+
+    /// Key for sealing and opening from raw key bytes (e.g. derived from a passphrase).
+    pub fn sealing_key_from_bytes(key_bytes: &[u8; 32]) -> SealingKey {
+        SealingKey {
+            cipher: Aes256Gcm::new_from_slice(key_bytes).expect("32-byte key"),
+            legacy_nonce: key_bytes[..NONCE_LEN].try_into().expect("nonce slice"),
+        }
+    }
+
+    /// A fresh random nonce. Every `seal` with the same key needs a nonce never used before.
+    pub fn random_nonce() -> Result<[u8; NONCE_LEN], String> {
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng
+            .try_fill_bytes(&mut nonce)
+            .map_err(|e| format!("nonce generation: {}", e))?;
+        Ok(nonce)
+    }
+
+    /// Encrypt `plaintext`. `aad` (associated data) is authenticated but not stored: `open`
+    /// only succeeds when given the same bytes, which binds the ciphertext to its context
+    /// (which file, which collection, which format version).
+    pub fn seal(
+        key: &SealingKey,
+        nonce: &[u8; NONCE_LEN],
+        aad: &[u8],
+        plaintext: &[u8],
+    ) -> Result<Vec<u8>, String> {
+        key.cipher
+            .encrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .map_err(|_| "encrypt failed".to_string())
+    }
+
+    /// Decrypt and authenticate what `seal` produced with the same nonce and `aad`. The
+    /// plaintext is zeroized when dropped.
+    pub fn open(
+        key: &SealingKey,
+        nonce: &[u8; NONCE_LEN],
+        aad: &[u8],
+        ciphertext: &[u8],
+    ) -> Result<SecretBytes, String> {
+        key.cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .map(Zeroizing::new)
+            .map_err(|_| "decrypt failed".to_string())
+    }
+
+    /// `seal` with a fresh random nonce, returned in front of the ciphertext.
+    pub fn seal_with_nonce(
+        key: &SealingKey,
+        aad: &[u8],
+        plaintext: &[u8],
+    ) -> Result<Vec<u8>, String> {
+        let nonce = Self::random_nonce()?;
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&Self::seal(key, &nonce, aad, plaintext)?);
+        Ok(sealed)
+    }
+
+    /// `open` for the output of `seal_with_nonce`.
+    pub fn open_with_nonce(
+        key: &SealingKey,
+        aad: &[u8],
+        sealed: &[u8],
+    ) -> Result<SecretBytes, String> {
+        if sealed.len() < NONCE_LEN {
+            return Err("too short".into());
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        Self::open(key, nonce.try_into().expect("nonce slice"), aad, ciphertext)
+    }
+
+    /// Decrypt data in format version 1: nonce derived from the key, no associated data.
+    /// Nothing is written this way any more.
+    pub fn open_legacy(key: &SealingKey, ciphertext: &[u8]) -> Result<SecretBytes, String> {
+        Self::open(key, &key.legacy_nonce, &[], ciphertext)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::auth_key;
+
+    #[test]
+    fn open_needs_the_associated_data_it_was_sealed_with() {
+        let key = AegCrypto::sealing_key(&auth_key()).unwrap();
+        let sealed = AegCrypto::seal_with_nonce(&key, b"aegisr test v1", b"secret").unwrap();
+
+        let opened = AegCrypto::open_with_nonce(&key, b"aegisr test v1", &sealed).unwrap();
+        assert_eq!(opened.as_slice(), b"secret");
+        for aad in [&b"aegisr test v2"[..], b"", b"aegisr test v1 "] {
+            assert!(AegCrypto::open_with_nonce(&key, aad, &sealed).is_err());
+        }
+    }
+
+    #[test]
+    fn open_rejects_another_key_or_a_modified_ciphertext() {
+        let key = AegCrypto::sealing_key(&auth_key()).unwrap();
+        let other = AegCrypto::sealing_key(&auth_key()).unwrap();
+        let sealed = AegCrypto::seal_with_nonce(&key, b"aad", b"secret").unwrap();
+        assert!(AegCrypto::open_with_nonce(&other, b"aad", &sealed).is_err());
+
+        for index in [0, NONCE_LEN, sealed.len() - 1] {
+            let mut modified = sealed.clone();
+            modified[index] ^= 0x80;
+            assert!(AegCrypto::open_with_nonce(&key, b"aad", &modified).is_err());
+        }
+        assert!(AegCrypto::open_with_nonce(&key, b"aad", &sealed[..NONCE_LEN - 1]).is_err());
+    }
+
+    #[test]
+    fn every_seal_uses_a_fresh_nonce() {
+        let key = DataKey::generate().sealing_key();
+        let first = AegCrypto::seal_with_nonce(&key, b"aad", b"same").unwrap();
+        let second = AegCrypto::seal_with_nonce(&key, b"aad", b"same").unwrap();
+        assert_ne!(first[..NONCE_LEN], second[..NONCE_LEN]);
+        assert_ne!(first, second);
+    }
+}
@@ -0,0 +1,356 @@
+use crate::commands::AegisrCommand;
+use crate::crypto::{AegCrypto, SealingKey};
+use crate::file_system::AegFileSystem;
+use crate::permissions::AegPermissions;
+use crate::secret::SecretString;
+use base64::{Engine as _, engine::general_purpose};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use zeroize::{Zeroize, Zeroizing};
+
+/// Start of every value encrypted client-side: `aegenc:v1:<key_id>:<base64>`.
+pub const FIELD_ENVELOPE_PREFIX: &str = "aegenc:v1:";
+/// Environment variable naming the client keyfile when `--keyfile` is not given.
+pub const CLIENT_KEYFILE_ENV: &str = "AEGISR_CLIENT_KEYFILE";
+const CLIENT_KEYFILE_VERSION: u32 = 1;
+
+/// The client keyfile: every key that may have encrypted a stored value, by id, and the
+/// one new values are encrypted with.
+#[derive(Serialize, Deserialize)]
+struct ClientKeyFile {
+    version: u32,
+    current: String,
+    keys: BTreeMap<String, String>,
+}
+
+impl Drop for ClientKeyFile {
+    fn drop(&mut self) {
+        for key in self.keys.values_mut() {
+            key.zeroize();
+        }
+    }
+}
+
+/// CLIENT-SIDE FIELD ENCRYPTION
+///
+/// Values are encrypted before they are sent to the daemon and decrypted after they come
+/// back, with keys from a client keyfile the daemon never sees. The stored value is an
+/// envelope, `aegenc:v1:<key_id>:<base64 of nonce and ciphertext>`, recording which key
+/// encrypted it, so the keyfile can get a new current key while values encrypted under
+/// older ones still decrypt. The envelope header is authenticated; the key name is not, so
+/// encrypted values can be copied, renamed and moved between collections.
+pub struct AegFieldCrypto {
+    current: String,
+    keys: BTreeMap<String, SealingKey>,
+}
+
+impl AegFieldCrypto {
+    /// Load a keyfile. Like the authorization key, it must only be accessible by its owner.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        if let Some(problem) = AegPermissions::key_file_problem(path) {
+            return Err(format!("{}: {}", path.display(), problem));
+        }
+        let file = Self::read_file(path)?;
+        let mut keys = BTreeMap::new();
+        for (id, key) in &file.keys {
+            let sealing_key = AegCrypto::sealing_key(key)
+                .map_err(|e| format!("{}: key {}: {}", path.display(), id, e))?;
+            keys.insert(id.clone(), sealing_key);
+        }
+        if !keys.contains_key(&file.current) {
+            return Err(format!(
+                "{}: current key {} is not in the file",
+                path.display(),
+                file.current
+            ));
+        }
+        Ok(Self {
+            current: file.current.clone(),
+            keys,
+        })
+    }
+
+    /// Add a new random key to the keyfile (creating it, 0600, if missing) and make it
+    /// the current one. Older keys stay so existing values still decrypt. Returns its id.
+    pub fn generate(path: &Path) -> Result<String, String> {
+        let mut file = if path.exists() {
+            Self::read_file(path)?
+        } else {
+            ClientKeyFile {
+                version: CLIENT_KEYFILE_VERSION,
+                current: String::new(),
+                keys: BTreeMap::new(),
+            }
+        };
+        let key = AegCrypto::create_authorization_key(None);
+        let id = Self::key_id(&key)?;
+        file.keys.insert(id.clone(), key.to_string());
+        file.current = id.clone();
+        let json = Zeroizing::new(
+            serde_json::to_string_pretty(&file).map_err(|e| format!("serialize keyfile: {}", e))?,
+        );
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            AegPermissions::create_private_dir(parent)
+                .map_err(|e| format!("create {}: {}", parent.display(), e))?;
+        }
+        AegFileSystem::write_atomic(path, json.as_bytes())?;
+        Ok(id)
+    }
+
+    /// Id of the key new values are encrypted with.
+    pub fn current_id(&self) -> &str {
+        &self.current
+    }
+
+    /// Ids of every key in the keyfile.
+    pub fn key_ids(&self) -> impl Iterator<Item = &str> {
+        self.keys.keys().map(String::as_str)
+    }
+
+    /// Whether `value` is an envelope produced by `encrypt`.
+    pub fn is_envelope(value: &str) -> bool {
+        value.starts_with(FIELD_ENVELOPE_PREFIX)
+    }
+
+    /// Encrypt `plaintext` under the current key into an envelope.
+    pub fn encrypt(&self, plaintext: &str) -> Result<String, String> {
+        let header = format!("{}{}", FIELD_ENVELOPE_PREFIX, self.current);
+        let sealed = AegCrypto::seal_with_nonce(
+            &self.keys[&self.current],
+            header.as_bytes(),
+            plaintext.as_bytes(),
+        )?;
+        Ok(format!(
+            "{}:{}",
+            header,
+            general_purpose::STANDARD.encode(sealed)
+        ))
+    }
+
+    /// Decrypt an envelope with the key it names.
+    pub fn decrypt(&self, envelope: &str) -> Result<SecretString, String> {
+        let (header, payload) = envelope
+            .rsplit_once(':')
+            .filter(|(header, _)| Self::is_envelope(header))
+            .ok_or("not an encrypted value")?;
+        let id = &header[FIELD_ENVELOPE_PREFIX.len()..];
+        let key = self
+            .keys
+            .get(id)
+            .ok_or_else(|| format!("value was encrypted with key {}, not in the keyfile", id))?;
+        let sealed = general_purpose::STANDARD
+            .decode(payload)
+            .map_err(|_| "encrypted value is not valid base64".to_string())?;
+        let plaintext = AegCrypto::open_with_nonce(key, header.as_bytes(), &sealed)
+            .map_err(|_| format!("encrypted value does not decrypt with key {}", id))?;
+        String::from_utf8(plaintext.to_vec())
+            .map(Zeroizing::new)
+            .map_err(|_| "decrypted value is not UTF-8".to_string())
+    }
+
+    /// Encrypt the values a command writes. Commands that compare against a stored value
+    /// cannot work on envelopes, which differ on every encryption, and are refused.
+    pub fn encrypt_command(&self, cmd: AegisrCommand) -> Result<AegisrCommand, String> {
+        Ok(match cmd {
+            AegisrCommand::Put {
+                verbose,
+                key,
+                value,
+                nx,
+                xx,
+                get,
+            } => AegisrCommand::Put {
+                verbose,
+                key,
+                value: self.encrypt(&value)?,
+                nx,
+                xx,
+                get,
+            },
+            AegisrCommand::SetNx {
+                verbose,
+                key,
+                value,
+            } => AegisrCommand::SetNx {
+                verbose,
+                key,
+                value: self.encrypt(&value)?,
+            },
+            AegisrCommand::GetSet {
+                verbose,
+                key,
+                value,
+            } => AegisrCommand::GetSet {
+                verbose,
+                key,
+                value: self.encrypt(&value)?,
+            },
+            AegisrCommand::MSet { verbose, pairs } => AegisrCommand::MSet {
+                verbose,
+                pairs: self.encrypt_pairs(pairs)?,
+            },
+            AegisrCommand::MSetNx { verbose, pairs } => AegisrCommand::MSetNx {
+                verbose,
+                pairs: self.encrypt_pairs(pairs)?,
+            },
+            AegisrCommand::Cas {
+                expected: Some(_),
+                version: None,
+                ..
+            } => {
+                return Err(
+                    "cas cannot compare encrypted values; use --version instead".to_string()
+                );
+            }
+            AegisrCommand::Cas {
+                verbose,
+                key,
+                value,
+                expected,
+                version,
+            } => AegisrCommand::Cas {
+                verbose,
+                key,
+                value: self.encrypt(&value)?,
+                expected,
+                version,
+            },
+            AegisrCommand::WithCollection {
+                collection,
+                command,
+            } => AegisrCommand::WithCollection {
+                collection,
+                command: Box::new(self.encrypt_command(*command)?),
+            },
+            other => other,
+        })
+    }
+
+    /// Decrypt every envelope in a daemon response: the `message` of `get`, the `data` of
+    /// `getset` and `put --get`, and the values of `mget`. Other strings are left alone.
+    pub fn decrypt_response(&self, response: &mut Value) -> Result<(), String> {
+        if let Some(message) = response.get_mut("message") {
+            self.decrypt_value(message)?;
+        }
+        match response.get_mut("data") {
+            Some(Value::Array(items)) => {
+                for item in items {
+                    self.decrypt_value(item)?;
+                }
+            }
+            Some(data) => self.decrypt_value(data)?,
+            None => {}
+        }
+        Ok(())
+    }
+
+    fn decrypt_value(&self, value: &mut Value) -> Result<(), String> {
+        if let Value::String(text) = value
+            && Self::is_envelope(text)
+        {
+            *text = self.decrypt(text)?.to_string();
+        }
+        Ok(())
+    }
+
+    fn encrypt_pairs(&self, pairs: Vec<(String, String)>) -> Result<Vec<(String, String)>, String> {
+        pairs
+            .into_iter()
+            .map(|(key, value)| Ok((key, self.encrypt(&value)?)))
+            .collect()
+    }
+
+    fn read_file(path: &Path) -> Result<ClientKeyFile, String> {
+        let text = Zeroizing::new(
+            fs::read_to_string(path).map_err(|e| format!("read {}: {}", path.display(), e))?,
+        );
+        let file: ClientKeyFile = serde_json::from_str(&text)
+            .map_err(|e| format!("{} is not a client keyfile: {}", path.display(), e))?;
+        if file.version != CLIENT_KEYFILE_VERSION {
+            return Err(format!(
+                "{}: unsupported keyfile version {}",
+                path.display(),
+                file.version
+            ));
+        }
+        Ok(file)
+    }
+
+    /// Id of a key: the first 8 bytes of a blake3 hash of it, in hex. It identifies the key
+    /// without revealing anything about it.
+    fn key_id(key: &str) -> Result<String, String> {
+        let bytes = Zeroizing::new(
+            general_purpose::STANDARD
+                .decode(key)
+                .map_err(|_| "key is not valid base64".to_string())?,
+        );
+        let hash = blake3::derive_key("aegisr client key id v1", &bytes);
+        Ok(hash[..8].iter().map(|b| format!("{:02x}", b)).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    #[test]
+    fn values_decrypt_with_the_key_they_name() {
+        let dir = TempDir::new();
+        let path = dir.join("client.key");
+        let first_id = AegFieldCrypto::generate(&path).unwrap();
+        let first = AegFieldCrypto::load(&path).unwrap();
+        let old = first.encrypt("old value").unwrap();
+        assert!(old.starts_with(&format!("{}{}:", FIELD_ENVELOPE_PREFIX, first_id)));
+
+        let second_id = AegFieldCrypto::generate(&path).unwrap();
+        assert_ne!(first_id, second_id);
+        let both = AegFieldCrypto::load(&path).unwrap();
+        assert_eq!(both.current_id(), second_id);
+        assert_eq!(both.key_ids().count(), 2);
+        let new = both.encrypt("new value").unwrap();
+        assert!(new.starts_with(&format!("{}{}:", FIELD_ENVELOPE_PREFIX, second_id)));
+
+        assert_eq!(both.decrypt(&old).unwrap().as_str(), "old value");
+        assert_eq!(both.decrypt(&new).unwrap().as_str(), "new value");
+        let missing = first.decrypt(&new).unwrap_err();
+        assert!(missing.contains(&second_id), "{}", missing);
+    }
+
+    #[test]
+    fn tampered_envelopes_do_not_decrypt() {
+        let dir = TempDir::new();
+        let path = dir.join("client.key");
+        let first_id = AegFieldCrypto::generate(&path).unwrap();
+        let second_id = AegFieldCrypto::generate(&path).unwrap();
+        let crypto = AegFieldCrypto::load(&path).unwrap();
+        let envelope = crypto.encrypt("value").unwrap();
+
+        // the key id is authenticated
+        let relabeled = envelope.replace(&second_id, &first_id);
+        assert!(crypto.decrypt(&relabeled).is_err());
+
+        let (header, payload) = envelope.rsplit_once(':').unwrap();
+        let mut sealed = general_purpose::STANDARD.decode(payload).unwrap();
+        let last = sealed.len() - 1;
+        sealed[last] ^= 0x01;
+        let modified = format!("{}:{}", header, general_purpose::STANDARD.encode(sealed));
+        assert!(crypto.decrypt(&modified).is_err());
+
+        assert!(crypto.decrypt("plain value").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_keyfile_others_can_read_is_refused() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = TempDir::new();
+        let path = dir.join("client.key");
+        AegFieldCrypto::generate(&path).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(AegFieldCrypto::load(&path).is_err());
+    }
+}
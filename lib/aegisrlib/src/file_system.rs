@@ -1,17 +1,43 @@
-use crate::constant::{STORE_AUTHORIZATION_KEY, STORE_COLLECTION, STORE_CONFIG_AEG, STORE_DIR};
+use crate::audit::AegAudit;
+use crate::constant::{
+    STORE_AUDIT_DIR, STORE_AUDIT_KEY, STORE_AUTHORIZATION_KEY, STORE_BACKUP_DIR, STORE_COLLECTION,
+    STORE_CONFIG_AEG, STORE_DIR, STORE_TEMP_MARKER,
+};
 use crate::crypto::AegCrypto;
-use aes_gcm::aead::Aead;
-use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
+use crate::key_provider::AegKeyProvider;
+use crate::memory_engine::AegMemoryEngine;
+use crate::permissions::AegPermissions;
+use crate::secret::{SecretBytes, SecretString};
+use crate::snapshot_format::AegSnapshotFormat;
 use base64::{Engine as _, engine::general_purpose};
 use dirs_next::home_dir;
-use rand_core::TryRngCore;
 use serde::{Deserialize, Serialize};
-use std::convert::TryInto;
 use std::fs;
-use std::path::PathBuf;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
 
 pub struct AegFileSystem;
 
+/// Marks text store files (`collection.lock`) in format version 3: `v3:` followed by the
+/// base64 of a random 12-byte nonce and the ciphertext, sealed with `SEALED_TEXT_AAD`.
+/// Older versions are only read: version 2 (`v2:`, same layout without associated data)
+/// and version 1 (bare base64, nonce derived from the key).
+const SEALED_TEXT_V3: &str = "v3:";
+const SEALED_TEXT_V2: &str = "v2:";
+/// Associated data of version 3 text files.
+const SEALED_TEXT_AAD: &[u8] = b"aegisr collection.lock v3";
+
+/// Distinguishes temp files of concurrent writers within one process.
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// `collection.lock` (and the path it was read from) as last read or written by this
+/// process, so key commands do not decrypt it each time. Writes through
+/// `write_collection_lock_json` update it; other writers call `forget_collection_lock`.
+static COLLECTION_LOCK: OnceLock<Mutex<Option<(PathBuf, CollectionLock)>>> = OnceLock::new();
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CollectionLock {
     pub active: String,
@@ -23,25 +49,171 @@ impl AegFileSystem {
         let mut config_path = home_dir().expect("Failed to get home directory");
         config_path.push(STORE_DIR);
         if !config_path.exists() {
-            fs::create_dir_all(&config_path).expect("Failed to create config directory");
+            AegPermissions::create_private_dir(&config_path)
+                .expect("Failed to create config directory");
         }
         config_path
     }
 
     pub fn reset_files() {
         let path = Self::get_config_path();
-        if path.exists() {
-            fs::remove_dir_all(&path).expect("Failed to delete .aegisr configuration directory");
+        // The audit log records the reset, so it has to outlive it. Backups are kept too:
+        // after a reset they are the only copy of the data left.
+        let kept = [STORE_AUDIT_DIR, STORE_AUDIT_KEY, STORE_BACKUP_DIR];
+        if let Ok(entries) = fs::read_dir(&path) {
+            for entry in entries.flatten() {
+                if kept.iter().any(|name| entry.file_name() == *name) {
+                    continue;
+                }
+                let removed = if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                    fs::remove_dir_all(entry.path())
+                } else {
+                    fs::remove_file(entry.path())
+                };
+                removed.expect("Failed to delete .aegisr configuration directory");
+            }
+        }
+        AegPermissions::create_private_dir(&path).expect("Failed to recreate config directory");
+        Self::forget_collection_lock();
+    }
+
+    /// Replace `path` with `data` so that a crash leaves either the old or the new file:
+    /// write a temp file next to it, fsync, rename over the target, fsync the directory.
+    pub fn write_atomic(path: &Path, data: &[u8]) -> Result<(), String> {
+        Self::write_atomic_with(path, |file| {
+            file.write_all(data)
+                .map_err(|e| format!("write error: {}", e))
+        })
+    }
+
+    /// `write_atomic` for content produced incrementally: `write` fills the temp file.
+    pub fn write_atomic_with(
+        path: &Path,
+        write: impl FnOnce(&mut fs::File) -> Result<(), String>,
+    ) -> Result<(), String> {
+        let file_name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .ok_or_else(|| format!("invalid file path {}", path.display()))?;
+        let temp_path = path.with_file_name(format!(
+            "{}{}{}-{}",
+            file_name,
+            STORE_TEMP_MARKER,
+            process::id(),
+            TEMP_COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+
+        let written = AegPermissions::create_private_file(&temp_path)
+            .map_err(|e| e.to_string())
+            .and_then(|mut file| {
+                write(&mut file)?;
+                file.sync_all().map_err(|e| e.to_string())
+            })
+            .and_then(|_| fs::rename(&temp_path, path).map_err(|e| e.to_string()));
+        if let Err(e) = written {
+            let _ = fs::remove_file(&temp_path);
+            return Err(format!("write {}: {}", path.display(), e));
+        }
+
+        match path.parent() {
+            Some(dir) => Self::sync_dir(dir),
+            None => Ok(()),
         }
-        fs::create_dir_all(&path).expect("Failed to recreate config directory");
+    }
+
+    /// fsync a directory, making renames and new entries in it durable.
+    pub fn sync_dir(dir: &Path) -> Result<(), String> {
+        fs::File::open(dir)
+            .and_then(|d| d.sync_all())
+            .map_err(|e| format!("sync {}: {}", dir.display(), e))
+    }
+
+    /// Clean up temp files left by writes interrupted by a crash. The rename is the commit
+    /// point, so a temp file is only promoted when its target is missing and it is intact
+    /// (it decrypts with the authorization key); otherwise it is removed.
+    pub fn recover_temp_files() {
+        let dir = Self::get_config_path();
+        let Ok(entries) = fs::read_dir(&dir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let temp_path = entry.path();
+            let Some(name) = temp_path.file_name().and_then(|n| n.to_str()) else {
+                continue;
+            };
+            let Some((target_name, _)) = name.split_once(STORE_TEMP_MARKER) else {
+                continue;
+            };
+            let target = dir.join(target_name);
+            if !target.exists() && Self::is_intact(&temp_path) {
+                match fs::rename(&temp_path, &target) {
+                    Ok(()) => println!("Recovered {} from interrupted write.", target_name),
+                    Err(e) => eprintln!("Failed to recover {}: {}", target_name, e),
+                }
+            } else {
+                println!("Removing leftover temp file {}.", name);
+                let _ = fs::remove_file(&temp_path);
+            }
+        }
+    }
+
+    /// Whether an encrypted store file is complete: binary snapshots must match their
+    /// checksum, text files must decrypt (AES-GCM authenticates the full content).
+    pub(crate) fn is_intact(path: &Path) -> bool {
+        if AegSnapshotFormat::is_binary(path) {
+            return AegSnapshotFormat::verify_checksum(path).is_ok();
+        }
+        let (Some(auth_key), Ok(content)) = (Self::current_key(), fs::read_to_string(path)) else {
+            return false;
+        };
+        Self::open_text(&content, &auth_key).is_ok()
+    }
+
+    /// Encrypt `plain` for a text store file, with a fresh random nonce (format v3).
+    fn seal_text(plain: &[u8], auth_key: &str) -> Result<String, String> {
+        let key = AegCrypto::sealing_key(auth_key)?;
+        let sealed = AegCrypto::seal_with_nonce(&key, SEALED_TEXT_AAD, plain)?;
+        Ok(format!(
+            "{}{}",
+            SEALED_TEXT_V3,
+            general_purpose::STANDARD.encode(sealed)
+        ))
+    }
+
+    /// Decrypt a text store file in any format version. The flag is `true` for older
+    /// versions, which should be rewritten.
+    fn open_text(content: &str, auth_key: &str) -> Result<(SecretBytes, bool), String> {
+        let key = AegCrypto::sealing_key(auth_key)?;
+        let content = content.trim();
+        let (payload, version) = if let Some(rest) = content.strip_prefix(SEALED_TEXT_V3) {
+            (rest, 3)
+        } else if let Some(rest) = content.strip_prefix(SEALED_TEXT_V2) {
+            (rest, 2)
+        } else {
+            (content, 1)
+        };
+        let payload = general_purpose::STANDARD
+            .decode(payload)
+            .map_err(|_| "not valid base64".to_string())?;
+        let decrypted = match version {
+            3 => AegCrypto::open_with_nonce(&key, SEALED_TEXT_AAD, &payload),
+            2 => AegCrypto::open_with_nonce(&key, &[], &payload),
+            _ => AegCrypto::open_legacy(&key, &payload),
+        };
+        decrypted
+            .map(|plain| (plain, version < 3))
+            .map_err(|_| "does not decrypt with this authorization key".to_string())
     }
 
     pub fn validate_files() {
         let path = Self::get_config_path();
+        Self::recover_temp_files();
         let collection_lock: PathBuf = path.join(STORE_COLLECTION);
         let config_file = path.join(STORE_CONFIG_AEG);
         let auth_file = path.join(STORE_AUTHORIZATION_KEY);
-        if !config_file.exists() || !auth_file.exists() || !collection_lock.exists() {
+        // A key kept outside the data directory has no key file here.
+        let auth_missing = AegKeyProvider::is_managed() && !auth_file.exists();
+        if !config_file.exists() || auth_missing || !collection_lock.exists() {
             println!("Missing file. Running initialize config.");
             Self::initialize_config(None, None);
         } else {
@@ -57,25 +229,37 @@ impl AegFileSystem {
         let _verbose_mode = verbose_mode.unwrap_or(false);
         let dir = Self::get_config_path();
 
+        let key_path = dir.join(STORE_AUTHORIZATION_KEY);
+        // The audit chain key outlives the reset and has to be re-wrapped with the new key.
+        let previous_key = overwrite_mode.then(Self::current_key).flatten();
         if overwrite_mode && dir.exists() {
-            fs::remove_dir_all(&dir).expect("Failed to remove existing config directory");
+            Self::reset_files();
         }
 
         if !dir.exists() {
-            fs::create_dir_all(&dir).expect("Failed to create config directory");
+            AegPermissions::create_private_dir(&dir).expect("Failed to create config directory");
         }
 
-        let key_path = dir.join(STORE_AUTHORIZATION_KEY);
-        let auth_key = if key_path.exists() {
-            fs::read_to_string(&key_path).expect("Failed to read AUTHORIZATION_KEY")
+        let auth_key = if key_path.exists() || !AegKeyProvider::is_managed() {
+            Self::read_authorization_key()
         } else {
+            AegKeyProvider::forget();
             let k = AegCrypto::create_authorization_key(Some(_verbose_mode));
-            fs::write(&key_path, &k).expect("Failed to write AUTHORIZATION_KEY");
+            let content = AegKeyProvider::encode(&k)
+                .unwrap_or_else(|e| panic!("Failed to seal AUTHORIZATION_KEY: {}", e));
+            Self::write_atomic(&key_path, content.as_bytes())
+                .expect("Failed to write AUTHORIZATION_KEY");
             k
         };
+        if let Some(previous_key) = previous_key
+            && let Err(e) = AegAudit::reencrypt_key(&previous_key, &auth_key)
+        {
+            eprintln!("Failed to re-wrap {}: {}", STORE_AUDIT_KEY, e);
+        }
 
         let collection_path = dir.join(STORE_COLLECTION);
         if !collection_path.exists() {
+            AegMemoryEngine::ensure_file("default").expect("Failed to create default collection");
             Self::write_collection_lock_default(&auth_key);
         }
 
@@ -83,90 +267,162 @@ impl AegFileSystem {
     }
 
     pub fn write_collection_lock_json(data: &str, auth_key: &str) {
-        let key_bytes = general_purpose::STANDARD
-            .decode(auth_key)
-            .expect("Invalid base64");
-        let key_arr: [u8; 32] = key_bytes
-            .as_slice()
-            .try_into()
-            .expect("Auth key must be 32 bytes");
-        let key: &aes_gcm::Key<Aes256Gcm> = aes_gcm::Key::<Aes256Gcm>::from_slice(&key_bytes);
-        let cipher = Aes256Gcm::new(key);
-        let nonce = Nonce::from_slice(&key_arr[..12]);
-
-        let encrypted = cipher
-            .encrypt(nonce, data.as_bytes())
-            .expect("Encrypt failed");
-        let encoded = general_purpose::STANDARD.encode(&encrypted);
+        let encoded = Self::seal_text(data.as_bytes(), auth_key)
+            .unwrap_or_else(|e| panic!("Failed to encrypt collection lock: {}", e));
 
         let path = Self::get_config_path().join(STORE_COLLECTION);
-        let mut file = fs::File::create(&path).expect("Failed to open file");
-        use std::io::Write;
-        file.write_all(encoded.as_bytes()).expect("Write failed");
-        file.sync_all().expect("Flush failed");
+        Self::write_atomic(&path, encoded.as_bytes()).expect("Failed to write collection lock");
+        let cached = Self::parse_collection_lock(data).ok().map(|(lock, _)| (path, lock));
+        *Self::collection_lock_slot()
+            .lock()
+            .expect("Failed to lock collection lock cache") = cached;
     }
 
-    pub fn read_collection_lock() -> String {
-        let path = Self::get_config_path().join(STORE_COLLECTION);
-        if !path.exists() {
-            return String::new();
-        }
+    fn collection_lock_slot() -> &'static Mutex<Option<(PathBuf, CollectionLock)>> {
+        COLLECTION_LOCK.get_or_init(|| Mutex::new(None))
+    }
 
-        let auth_key = Self::read_authorization_key();
-        let key_bytes = general_purpose::STANDARD
-            .decode(auth_key)
-            .expect("Invalid auth key");
-
-        let key_arr: [u8; 32] = key_bytes
-            .as_slice()
-            .try_into()
-            .expect("Auth key must be 32 bytes");
-        let key: &aes_gcm::Key<Aes256Gcm> = aes_gcm::Key::<Aes256Gcm>::from_slice(&key_bytes);
-        let cipher = Aes256Gcm::new(key);
-        let nonce = Nonce::from_slice(&key_arr[..12]);
+    /// Read `collection.lock` from disk again on next use: call after replacing it other
+    /// than through `write_collection_lock_json`.
+    pub(crate) fn forget_collection_lock() {
+        *Self::collection_lock_slot()
+            .lock()
+            .expect("Failed to lock collection lock cache") = None;
+    }
+
+    pub fn read_collection_lock() -> String {
+        Self::read_collection_lock_versioned()
+            .unwrap_or_else(|e| panic!("{}", e))
+            .0
+    }
 
+    /// Decrypted `collection.lock`, and whether it is in an older format version.
+    fn read_collection_lock_versioned() -> Result<(String, bool), String> {
+        let path = Self::get_config_path().join(STORE_COLLECTION);
         let encrypted = fs::read_to_string(&path).unwrap_or_default();
-        if encrypted.is_empty() {
-            return String::new();
+        if encrypted.trim().is_empty() {
+            return Ok((String::new(), false));
         }
 
-        let encrypted_bytes = general_purpose::STANDARD
-            .decode(encrypted)
-            .expect("Invalid base64 content");
+        let auth_key = Self::read_authorization_key();
+        let (decrypted, legacy) = Self::open_text(&encrypted, &auth_key)
+            .map_err(|e| format!("{}: {} (run aegisr-check)", path.display(), e))?;
+        let json = String::from_utf8(decrypted.to_vec())
+            .map_err(|_| format!("{}: not valid UTF-8 (run aegisr-check)", path.display()))?;
+        Ok((json, legacy))
+    }
 
-        let decrypted = cipher
-            .decrypt(nonce, encrypted_bytes.as_ref())
-            .expect("Decrypt failed");
+    /// Decrypt `collection.lock` with an explicit key (offline tools). Unlike
+    /// `read_collection_lock`, a wrong key or damaged file is an error, not a panic.
+    pub fn decrypt_collection_lock(auth_key: &str) -> Result<CollectionLock, String> {
+        let path = Self::get_config_path().join(STORE_COLLECTION);
+        let content =
+            fs::read_to_string(&path).map_err(|e| format!("read {}: {}", path.display(), e))?;
+        let (decrypted, _) = Self::open_text(&content, auth_key)
+            .map_err(|e| format!("{} {}", STORE_COLLECTION, e))?;
+        let json = String::from_utf8(decrypted.to_vec())
+            .map_err(|_| "collection.lock is not valid UTF-8".to_string())?;
+        Self::parse_collection_lock(&json).map(|(lock, _)| lock)
+    }
 
-        String::from_utf8(decrypted).expect("Invalid UTF-8")
+    /// Parse decrypted `collection.lock` content. The oldest format held just the name of
+    /// the active collection (quoted or not); that is accepted and reported as `true`.
+    fn parse_collection_lock(json: &str) -> Result<(CollectionLock, bool), String> {
+        let error = match serde_json::from_str::<CollectionLock>(json) {
+            Ok(lock) => return Ok((lock, false)),
+            Err(e) => format!("invalid collection.lock: {}", e),
+        };
+        let bare = json.trim();
+        let name = match serde_json::from_str::<String>(json) {
+            Ok(name) => name,
+            Err(_)
+                if !bare.is_empty()
+                    && bare
+                        .chars()
+                        .all(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '.')) =>
+            {
+                bare.to_string()
+            }
+            Err(_) => return Err(error),
+        };
+        let lock = CollectionLock {
+            active: name.clone(),
+            collections: vec![name],
+        };
+        Ok((lock, true))
     }
 
+    /// Decrypted and parsed `collection.lock`. Panics when it is damaged; see
+    /// `collection_lock`.
     pub fn read_collection_lock_obj() -> CollectionLock {
-        let json_str = Self::read_collection_lock();
+        Self::collection_lock().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Decrypted and parsed `collection.lock`, from memory once it was read or written.
+    /// Files in an older format (older encryption or the bare active name) are rewritten
+    /// in the current one.
+    pub fn collection_lock() -> Result<CollectionLock, String> {
+        let path = Self::get_config_path().join(STORE_COLLECTION);
+        if let Some((cached_path, lock)) = &*Self::collection_lock_slot()
+            .lock()
+            .expect("Failed to lock collection lock cache")
+            && *cached_path == path
+        {
+            return Ok(lock.clone());
+        }
+
+        let (json_str, legacy_encryption) = Self::read_collection_lock_versioned()?;
         if json_str.trim().is_empty() {
-            return CollectionLock {
+            return Ok(CollectionLock {
                 active: "default".to_string(),
                 collections: vec!["default".to_string()],
-            };
+            });
         }
 
-        match serde_json::from_str::<CollectionLock>(&json_str) {
-            Ok(lock) => lock,
-            Err(_) => {
-                let s = json_str.trim().trim_matches('"').to_string();
-                let lock = CollectionLock {
-                    active: s.clone(),
-                    collections: vec![s],
-                };
-
+        match Self::parse_collection_lock(&json_str) {
+            Ok((lock, false)) if !legacy_encryption => {
+                *Self::collection_lock_slot()
+                    .lock()
+                    .expect("Failed to lock collection lock cache") = Some((path, lock.clone()));
+                Ok(lock)
+            }
+            Ok((lock, _)) => {
                 let auth_key = Self::read_authorization_key();
                 let serialized = serde_json::to_string_pretty(&lock).expect("Serialize failed");
                 Self::write_collection_lock_json(&serialized, &auth_key);
-                lock
+                Ok(lock)
             }
+            Err(e) => Err(format!("{} (run aegisr-check)", e)),
         }
     }
 
+    /// Re-encrypt `collection.lock` under `new_key`, whether it is still sealed with
+    /// `old_key` or already with `new_key` (key rotation). Returns whether it was rewritten.
+    pub(crate) fn reencrypt_collection_lock(old_key: &str, new_key: &str) -> Result<bool, String> {
+        let path = Self::get_config_path().join(STORE_COLLECTION);
+        Self::reencrypt_collection_lock_at(&path, old_key, new_key)
+    }
+
+    /// `reencrypt_collection_lock` for the copy of `collection.lock` at `path`.
+    pub(crate) fn reencrypt_collection_lock_at(
+        path: &Path,
+        old_key: &str,
+        new_key: &str,
+    ) -> Result<bool, String> {
+        let Ok(content) = fs::read_to_string(path) else {
+            return Ok(false);
+        };
+        if let Ok((_, false)) = Self::open_text(&content, new_key) {
+            return Ok(false);
+        }
+        let (plain, _) = Self::open_text(&content, old_key)
+            .or_else(|_| Self::open_text(&content, new_key))
+            .map_err(|e| format!("{} {}", STORE_COLLECTION, e))?;
+        let sealed = Self::seal_text(&plain, new_key)?;
+        Self::write_atomic(path, sealed.as_bytes())?;
+        Ok(true)
+    }
+
     fn maybe_migrate_collection_lock() -> Result<(), String> {
         let _ = Self::read_collection_lock_obj();
         Ok(())
@@ -181,8 +437,32 @@ impl AegFileSystem {
         Self::write_collection_lock_json(&serialized, auth_key);
     }
 
-    pub fn read_authorization_key() -> String {
+    /// Key for offline tools: `given` if set, else the configured key provider's key
+    /// (unlocking a passphrase-protected key file first).
+    pub fn resolve_authorization_key(given: Option<&str>) -> Result<SecretString, String> {
+        if let Some(key) = given {
+            return Ok(SecretString::new(key.trim().to_string()));
+        }
         let path = Self::get_config_path().join(STORE_AUTHORIZATION_KEY);
-        fs::read_to_string(&path).expect("Failed to read authorization key")
+        if AegKeyProvider::is_managed() && !path.exists() {
+            return Err(format!(
+                "no authorization key: pass --auth-key or set AEGISR_AUTH_KEY ({} not readable)",
+                path.display()
+            ));
+        }
+        AegKeyProvider::unlock(None)?;
+        AegKeyProvider::current_key()
+    }
+
+    /// The authorization key from the configured provider. Panics when it cannot be read,
+    /// or when it is passphrase-protected and was not unlocked at startup.
+    pub fn read_authorization_key() -> SecretString {
+        AegKeyProvider::current_key()
+            .unwrap_or_else(|e| panic!("Failed to read authorization key: {}", e))
+    }
+
+    /// The authorization key, `None` if it is not available.
+    fn current_key() -> Option<SecretString> {
+        AegKeyProvider::current_key().ok()
     }
 }
@@ -0,0 +1,675 @@
+use crate::constant::STORE_AUTHORIZATION_KEY;
+use crate::crypto::AegCrypto;
+use crate::file_system::AegFileSystem;
+use crate::keystore::{AegKeyStore, KMS_SEALED};
+use crate::permissions::AegPermissions;
+use crate::secret::SecretString;
+use base64::{Engine as _, engine::general_purpose};
+use dirs_next::home_dir;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use zeroize::Zeroizing;
+
+/// Environment variable read by the `env` provider unless configured otherwise.
+pub const DEFAULT_KEY_ENV: &str = "AEGISR_AUTH_KEY";
+/// Where the local KMS stand-in keeps its key-encryption keys unless configured otherwise.
+const MOCK_KMS_DIR: &str = ".aegisr-kms-mock";
+const KMS_KEY_FILE_VERSION: u8 = 1;
+/// Associated data of keys sealed by the local KMS stand-in, followed by the key id.
+const MOCK_KMS_AAD: &[u8] = b"aegisr mock kms v1";
+
+/// Where the authorization key comes from: `key_provider` in the daemon config, e.g.
+/// `{"type": "env", "var": "AEGISR_AUTH_KEY"}`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum KeyProviderConfig {
+    /// `AUTHORIZATION_KEY` in the data directory, optionally passphrase-protected.
+    #[default]
+    DataDir,
+    /// A file outside the data directory holding the base64 key.
+    File { path: PathBuf },
+    /// An environment variable holding the base64 key.
+    Env {
+        #[serde(default = "default_key_env")]
+        var: String,
+    },
+    /// The standard output of a command (a secrets manager CLI, for instance).
+    Command {
+        program: String,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+    /// `AUTHORIZATION_KEY` in the data directory, sealed by a KMS or HSM under `key_id`.
+    Kms {
+        key_id: String,
+        plugin: KmsPluginConfig,
+    },
+}
+
+fn default_key_env() -> String {
+    DEFAULT_KEY_ENV.to_string()
+}
+
+/// The KMS behind the `kms` provider.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum KmsPluginConfig {
+    /// An executable speaking the plugin protocol (see `CommandKmsPlugin`).
+    Command {
+        program: String,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+    /// In-process stand-in keeping its keys in a local directory; for development and tests.
+    Mock { dir: Option<PathBuf> },
+}
+
+/// A source of the authorization key.
+pub trait KeyProvider: Send + Sync {
+    /// Where the key comes from, for logs and errors.
+    fn describe(&self) -> String;
+
+    /// The base64 authorization key.
+    fn fetch(&self) -> Result<Zeroizing<String>, String>;
+
+    /// Whether the key is stored in the data directory's key file, so aegisr can create
+    /// and rotate it. Keys managed elsewhere are only ever read.
+    fn is_managed(&self) -> bool {
+        false
+    }
+
+    /// Key file content for `auth_key` (new key files and key rotation).
+    fn encode(&self, _auth_key: &str) -> Result<Zeroizing<String>, String> {
+        Err(self.unmanaged())
+    }
+
+    /// Read key file content written by `encode`.
+    fn decode(&self, _content: &str) -> Result<Zeroizing<String>, String> {
+        Err(self.unmanaged())
+    }
+
+    fn unmanaged(&self) -> String {
+        format!(
+            "the authorization key is managed outside aegisr ({})",
+            self.describe()
+        )
+    }
+}
+
+/// Key-encryption service behind the `kms` provider, in the style of a KMS or PKCS#11
+/// token: keys never leave it, it only seals and opens data under a key id.
+pub trait KmsPlugin: Send + Sync {
+    fn describe(&self) -> String;
+    fn encrypt(&self, key_id: &str, plaintext: &[u8]) -> Result<Vec<u8>, String>;
+    fn decrypt(&self, key_id: &str, ciphertext: &[u8]) -> Result<Zeroizing<Vec<u8>>, String>;
+}
+
+/// KEY PROVIDERS
+///
+/// The provider is chosen once at startup (`configure`). Keys from unmanaged providers and
+/// from the KMS are fetched once and kept in zeroized memory; the data directory key file
+/// is read as before (and unlocked through `AegKeyStore` when passphrase-protected).
+pub struct AegKeyProvider;
+
+struct ActiveProvider {
+    provider: Arc<dyn KeyProvider>,
+    config: KeyProviderConfig,
+    cached: Option<Zeroizing<String>>,
+}
+
+static ACTIVE: Mutex<Option<ActiveProvider>> = Mutex::new(None);
+
+impl AegKeyProvider {
+    /// Use the provider described by `config` from now on.
+    pub fn configure(config: KeyProviderConfig) {
+        *ACTIVE.lock().expect("Failed to lock key provider") = Some(ActiveProvider {
+            provider: Self::build(&config),
+            config,
+            cached: None,
+        });
+    }
+
+    /// `key_provider` from a daemon config file (the default provider when absent). A
+    /// malformed provider is an error: falling back to the data directory would create a
+    /// new key there.
+    pub fn config_from_file(path: &Path) -> Result<KeyProviderConfig, String> {
+        #[derive(Deserialize)]
+        struct Section {
+            key_provider: Option<KeyProviderConfig>,
+        }
+        let content =
+            fs::read_to_string(path).map_err(|e| format!("read {}: {}", path.display(), e))?;
+        let value: serde_json::Value = serde_json::from_str(&content)
+            .map_err(|e| format!("{}: invalid JSON: {}", path.display(), e))?;
+        let section: Section = serde_json::from_value(value)
+            .map_err(|e| format!("{}: invalid key_provider: {}", path.display(), e))?;
+        Ok(section.key_provider.unwrap_or_default())
+    }
+
+    /// Configuration of the active provider.
+    pub fn config() -> KeyProviderConfig {
+        Self::with_active(|active| active.config.clone())
+    }
+
+    pub fn describe() -> String {
+        Self::with_active(|active| active.provider.describe())
+    }
+
+    /// Whether the key lives in the data directory key file (see `KeyProvider::is_managed`).
+    pub fn is_managed() -> bool {
+        Self::with_active(|active| active.provider.is_managed())
+    }
+
+    /// Make the key available: unlock a protected key file (passphrase from `fd`,
+    /// `AEGISR_PASSPHRASE` or a prompt), or fetch it from the provider once.
+    pub fn unlock(passphrase_fd: Option<u32>) -> Result<(), String> {
+        if Self::config() == KeyProviderConfig::DataDir {
+            AegKeyStore::unlock_with(passphrase_fd)?;
+        }
+        if Self::is_managed() && !Self::key_path().exists() {
+            return Ok(()); // first start: the key is created with the data directory
+        }
+        Self::current_key().map(|_| ())
+    }
+
+    /// The authorization key.
+    pub fn current_key() -> Result<SecretString, String> {
+        let (provider, config, cached) = Self::with_active(|active| {
+            (
+                active.provider.clone(),
+                active.config.clone(),
+                active.cached.clone(),
+            )
+        });
+        if let Some(key) = cached {
+            return Ok(key);
+        }
+        let key = provider
+            .fetch()
+            .map_err(|e| format!("{}: {}", provider.describe(), e))?;
+        AegCrypto::sealing_key(&key).map_err(|e| format!("{}: {}", provider.describe(), e))?;
+        // The key file in the data directory can change underneath (init --reset).
+        if config != KeyProviderConfig::DataDir {
+            Self::with_active(|active| active.cached = Some(key.clone()));
+        }
+        Ok(key)
+    }
+
+    /// Key file content for `auth_key` as the active provider stores it.
+    pub fn encode(auth_key: &str) -> Result<Zeroizing<String>, String> {
+        Self::with_active(|active| active.provider.clone()).encode(auth_key)
+    }
+
+    /// Read key file content written by `encode`.
+    pub fn decode(content: &str) -> Result<Zeroizing<String>, String> {
+        Self::with_active(|active| active.provider.clone()).decode(content)
+    }
+
+    /// The key was replaced (rotation); `auth_key` is the new one.
+    pub fn replace_key(auth_key: &str) {
+        AegKeyStore::replace_unlocked(auth_key);
+        Self::with_active(|active| {
+            if active.cached.is_some() {
+                active.cached = Some(Zeroizing::new(auth_key.to_string()));
+            }
+        });
+    }
+
+    /// Drop the cached key (the key file was removed).
+    pub fn forget() {
+        AegKeyStore::forget();
+        Self::with_active(|active| active.cached = None);
+    }
+
+    fn with_active<R>(f: impl FnOnce(&mut ActiveProvider) -> R) -> R {
+        let mut guard = ACTIVE.lock().expect("Failed to lock key provider");
+        let active = guard.get_or_insert_with(|| ActiveProvider {
+            provider: Arc::new(DataDirKey),
+            config: KeyProviderConfig::DataDir,
+            cached: None,
+        });
+        f(active)
+    }
+
+    fn key_path() -> PathBuf {
+        AegFileSystem::get_config_path().join(STORE_AUTHORIZATION_KEY)
+    }
+
+    fn build(config: &KeyProviderConfig) -> Arc<dyn KeyProvider> {
+        match config {
+            KeyProviderConfig::DataDir => Arc::new(DataDirKey),
+            KeyProviderConfig::File { path } => Arc::new(FileKey { path: path.clone() }),
+            KeyProviderConfig::Env { var } => Arc::new(EnvKey { var: var.clone() }),
+            KeyProviderConfig::Command { program, args } => Arc::new(CommandKey {
+                program: program.clone(),
+                args: args.clone(),
+            }),
+            KeyProviderConfig::Kms { key_id, plugin } => Arc::new(KmsKey {
+                key_id: key_id.clone(),
+                plugin: match plugin {
+                    KmsPluginConfig::Command { program, args } => Box::new(CommandKmsPlugin {
+                        program: program.clone(),
+                        args: args.clone(),
+                    }),
+                    KmsPluginConfig::Mock { dir } => Box::new(LocalMockKms {
+                        dir: dir.clone().unwrap_or_else(|| {
+                            home_dir()
+                                .expect("Failed to get home directory")
+                                .join(MOCK_KMS_DIR)
+                        }),
+                    }),
+                },
+            }),
+        }
+    }
+}
+
+/// `AUTHORIZATION_KEY` in the data directory (the default).
+pub struct DataDirKey;
+
+impl KeyProvider for DataDirKey {
+    fn describe(&self) -> String {
+        format!("{} in the data directory", STORE_AUTHORIZATION_KEY)
+    }
+
+    fn fetch(&self) -> Result<Zeroizing<String>, String> {
+        if let Some(key) = AegKeyStore::unlocked_key() {
+            return Ok(key);
+        }
+        let path = AegKeyProvider::key_path();
+        let content = Zeroizing::new(
+            fs::read_to_string(&path).map_err(|e| format!("read {}: {}", path.display(), e))?,
+        );
+        if AegKeyStore::is_kms_sealed() {
+            return Err(KMS_SEALED.into());
+        }
+        if AegKeyStore::is_wrapped(&content) {
+            return Err("passphrase-protected and not unlocked".into());
+        }
+        Ok(Zeroizing::new(content.trim().to_string()))
+    }
+
+    fn is_managed(&self) -> bool {
+        true
+    }
+
+    fn encode(&self, auth_key: &str) -> Result<Zeroizing<String>, String> {
+        AegKeyStore::key_file_content(auth_key)
+    }
+
+    fn decode(&self, content: &str) -> Result<Zeroizing<String>, String> {
+        AegKeyStore::open_key_file_content(content)
+    }
+}
+
+/// A key file outside the data directory (e.g. a mounted secret).
+pub struct FileKey {
+    pub path: PathBuf,
+}
+
+impl KeyProvider for FileKey {
+    fn describe(&self) -> String {
+        format!("file {}", self.path.display())
+    }
+
+    fn fetch(&self) -> Result<Zeroizing<String>, String> {
+        let content = Zeroizing::new(fs::read_to_string(&self.path).map_err(|e| e.to_string())?);
+        Ok(Zeroizing::new(content.trim().to_string()))
+    }
+}
+
+/// An environment variable.
+pub struct EnvKey {
+    pub var: String,
+}
+
+impl KeyProvider for EnvKey {
+    fn describe(&self) -> String {
+        format!("environment variable {}", self.var)
+    }
+
+    fn fetch(&self) -> Result<Zeroizing<String>, String> {
+        std::env::var(&self.var)
+            .map(|key| Zeroizing::new(key.trim().to_string()))
+            .map_err(|_| "not set".to_string())
+    }
+}
+
+/// The trimmed standard output of a command; a non-zero exit status is an error.
+pub struct CommandKey {
+    pub program: String,
+    pub args: Vec<String>,
+}
+
+impl KeyProvider for CommandKey {
+    fn describe(&self) -> String {
+        format!("command {}", self.program)
+    }
+
+    fn fetch(&self) -> Result<Zeroizing<String>, String> {
+        let output = run_command(&self.program, &self.args, &[], None)?;
+        Ok(Zeroizing::new(
+            String::from_utf8_lossy(&output).trim().to_string(),
+        ))
+    }
+}
+
+/// `AUTHORIZATION_KEY` in the data directory holds the key sealed by a KMS plugin.
+pub struct KmsKey {
+    pub key_id: String,
+    pub plugin: Box<dyn KmsPlugin>,
+}
+
+/// Key file content of the `kms` provider.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct KmsKeyFile {
+    version: u8,
+    pub(crate) kms_key_id: String,
+    /// base64 of what the plugin returned.
+    wrapped: String,
+}
+
+impl KeyProvider for KmsKey {
+    fn describe(&self) -> String {
+        format!("KMS key '{}' via {}", self.key_id, self.plugin.describe())
+    }
+
+    fn fetch(&self) -> Result<Zeroizing<String>, String> {
+        let path = AegKeyProvider::key_path();
+        let content =
+            fs::read_to_string(&path).map_err(|e| format!("read {}: {}", path.display(), e))?;
+        self.decode(&content)
+    }
+
+    fn is_managed(&self) -> bool {
+        true
+    }
+
+    fn encode(&self, auth_key: &str) -> Result<Zeroizing<String>, String> {
+        let wrapped = self.plugin.encrypt(&self.key_id, auth_key.as_bytes())?;
+        let file = KmsKeyFile {
+            version: KMS_KEY_FILE_VERSION,
+            kms_key_id: self.key_id.clone(),
+            wrapped: general_purpose::STANDARD.encode(wrapped),
+        };
+        serde_json::to_string_pretty(&file)
+            .map(Zeroizing::new)
+            .map_err(|e| format!("serialize error: {}", e))
+    }
+
+    fn decode(&self, content: &str) -> Result<Zeroizing<String>, String> {
+        let file: KmsKeyFile = serde_json::from_str(content)
+            .map_err(|e| format!("not a KMS-sealed key file: {}", e))?;
+        if file.version != KMS_KEY_FILE_VERSION {
+            return Err(format!("unsupported KMS key file version {}", file.version));
+        }
+        let wrapped = general_purpose::STANDARD
+            .decode(&file.wrapped)
+            .map_err(|_| "invalid wrapped key".to_string())?;
+        // The file names its key id, so keys sealed before a KMS key change still open.
+        let plain = self.plugin.decrypt(&file.kms_key_id, &wrapped)?;
+        String::from_utf8(plain.to_vec())
+            .map(Zeroizing::new)
+            .map_err(|_| "invalid key".to_string())
+    }
+}
+
+/// KMS plugin run as an executable: `<program> [args] encrypt <key_id>` and
+/// `<program> [args] decrypt <key_id>` read base64 on stdin and print the base64 result.
+pub struct CommandKmsPlugin {
+    pub program: String,
+    pub args: Vec<String>,
+}
+
+impl CommandKmsPlugin {
+    fn call(&self, op: &str, key_id: &str, input: &[u8]) -> Result<Zeroizing<Vec<u8>>, String> {
+        let stdin = Zeroizing::new(general_purpose::STANDARD.encode(input));
+        let output = run_command(
+            &self.program,
+            &self.args,
+            &[op, key_id],
+            Some(stdin.as_bytes()),
+        )?;
+        general_purpose::STANDARD
+            .decode(String::from_utf8_lossy(&output).trim())
+            .map(Zeroizing::new)
+            .map_err(|_| format!("{} {}: output is not base64", self.program, op))
+    }
+}
+
+impl KmsPlugin for CommandKmsPlugin {
+    fn describe(&self) -> String {
+        format!("plugin {}", self.program)
+    }
+
+    fn encrypt(&self, key_id: &str, plaintext: &[u8]) -> Result<Vec<u8>, String> {
+        self.call("encrypt", key_id, plaintext)
+            .map(|sealed| sealed.to_vec())
+    }
+
+    fn decrypt(&self, key_id: &str, ciphertext: &[u8]) -> Result<Zeroizing<Vec<u8>>, String> {
+        self.call("decrypt", key_id, ciphertext)
+    }
+}
+
+/// Local stand-in for a KMS: one random key-encryption key per key id, stored base64 in
+/// `<dir>/<key_id>.key` and created on first use. Not a security boundary.
+pub struct LocalMockKms {
+    pub dir: PathBuf,
+}
+
+impl LocalMockKms {
+    fn key(&self, key_id: &str, create: bool) -> Result<Zeroizing<[u8; 32]>, String> {
+        if key_id.is_empty()
+            || !key_id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        {
+            return Err(format!("invalid key id '{}'", key_id));
+        }
+        let path = self.dir.join(format!("{}.key", key_id));
+        if !path.exists() && create {
+            AegPermissions::create_private_dir(&self.dir)
+                .map_err(|e| format!("create {}: {}", self.dir.display(), e))?;
+            let key = Zeroizing::new(AegCrypto::generate_random_bytes(None));
+            AegFileSystem::write_atomic(
+                &path,
+                Zeroizing::new(AegCrypto::encode_base64(key.as_slice(), None)).as_bytes(),
+            )?;
+        }
+        let content = Zeroizing::new(
+            fs::read_to_string(&path).map_err(|_| format!("unknown key id '{}'", key_id))?,
+        );
+        let bytes = Zeroizing::new(
+            general_purpose::STANDARD
+                .decode(content.trim())
+                .map_err(|_| format!("{}: invalid key", path.display()))?,
+        );
+        let mut key = Zeroizing::new([0u8; 32]);
+        if bytes.len() != key.len() {
+            return Err(format!("{}: invalid key", path.display()));
+        }
+        key.copy_from_slice(&bytes);
+        Ok(key)
+    }
+
+    fn aad(key_id: &str) -> Vec<u8> {
+        let mut aad = MOCK_KMS_AAD.to_vec();
+        aad.extend_from_slice(key_id.as_bytes());
+        aad
+    }
+}
+
+impl KmsPlugin for LocalMockKms {
+    fn describe(&self) -> String {
+        format!("local mock KMS in {}", self.dir.display())
+    }
+
+    fn encrypt(&self, key_id: &str, plaintext: &[u8]) -> Result<Vec<u8>, String> {
+        let kek = self.key(key_id, true)?;
+        let key = AegCrypto::sealing_key_from_bytes(&kek);
+        AegCrypto::seal_with_nonce(&key, &Self::aad(key_id), plaintext)
+    }
+
+    fn decrypt(&self, key_id: &str, ciphertext: &[u8]) -> Result<Zeroizing<Vec<u8>>, String> {
+        let kek = self.key(key_id, false)?;
+        let key = AegCrypto::sealing_key_from_bytes(&kek);
+        AegCrypto::open_with_nonce(&key, &Self::aad(key_id), ciphertext)
+            .map_err(|_| format!("cannot decrypt with key id '{}'", key_id))
+    }
+}
+
+/// Run `program args extra`, feeding `stdin` if given, and return its standard output.
+fn run_command(
+    program: &str,
+    args: &[String],
+    extra: &[&str],
+    stdin: Option<&[u8]>,
+) -> Result<Vec<u8>, String> {
+    let mut child = Command::new(program)
+        .args(args)
+        .args(extra)
+        .stdin(if stdin.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("run {}: {}", program, e))?;
+    if let (Some(input), Some(mut pipe)) = (stdin, child.stdin.take()) {
+        pipe.write_all(input)
+            .map_err(|e| format!("write to {}: {}", program, e))?;
+    }
+    let output = child
+        .wait_with_output()
+        .map_err(|e| format!("run {}: {}", program, e))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(match stderr.lines().next() {
+            Some(line) => format!("{} exited with {}: {}", program, output.status, line),
+            None => format!("{} exited with {}", program, output.status),
+        });
+    }
+    Ok(output.stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{TempDir, auth_key};
+
+    fn strings(items: &[&str]) -> Vec<String> {
+        items.iter().map(|item| item.to_string()).collect()
+    }
+
+    #[test]
+    fn unmanaged_providers_read_the_trimmed_key() {
+        let dir = TempDir::new();
+        let path = dir.join("key");
+        fs::write(&path, " file-key \n").unwrap();
+        let file = FileKey { path };
+        assert_eq!(file.fetch().unwrap().as_str(), "file-key");
+
+        let var = format!("AEGISR_TEST_KEY_{}", uuid::Uuid::new_v4().simple());
+        let env = EnvKey { var: var.clone() };
+        assert_eq!(env.fetch().unwrap_err(), "not set");
+        // SAFETY: the variable name is unique to this test and nothing else reads it.
+        unsafe { std::env::set_var(&var, "env-key\n") };
+        assert_eq!(env.fetch().unwrap().as_str(), "env-key");
+
+        let command = CommandKey {
+            program: "sh".into(),
+            args: strings(&["-c", "printf ' command-key \\n'"]),
+        };
+        assert_eq!(command.fetch().unwrap().as_str(), "command-key");
+        let failing = CommandKey {
+            program: "sh".into(),
+            args: strings(&["-c", "echo denied >&2; exit 3"]),
+        };
+        let error = failing.fetch().unwrap_err();
+        assert!(error.ends_with(": denied"), "{}", error);
+
+        for provider in [&file as &dyn KeyProvider, &env, &command] {
+            assert!(!provider.is_managed());
+            assert!(
+                provider
+                    .encode("key")
+                    .unwrap_err()
+                    .contains(&provider.describe())
+            );
+        }
+        assert!(DataDirKey.is_managed());
+    }
+
+    #[test]
+    fn the_mock_kms_opens_only_what_it_sealed() {
+        let dir = TempDir::new();
+        let kms = |key_id: &str, dir: PathBuf| KmsKey {
+            key_id: key_id.into(),
+            plugin: Box::new(LocalMockKms { dir }),
+        };
+        let provider = kms("primary", dir.join("kms"));
+        assert!(provider.is_managed());
+        assert!(provider.describe().contains("'primary'"));
+
+        let auth_key = auth_key();
+        let content = provider.encode(&auth_key).unwrap();
+        assert!(!content.contains(auth_key.as_str()));
+        assert!(AegKeyStore::is_wrapped(&content));
+        assert_eq!(
+            provider.decode(&content).unwrap().as_str(),
+            auth_key.as_str()
+        );
+        // the key file names the key id it was sealed under
+        let renamed = kms("secondary", dir.join("kms"));
+        assert_eq!(
+            renamed.decode(&content).unwrap().as_str(),
+            auth_key.as_str()
+        );
+
+        let elsewhere = kms("primary", dir.join("other-kms"));
+        assert!(elsewhere.decode(&content).is_err());
+        assert!(kms("../escape", dir.join("kms")).encode(&auth_key).is_err());
+    }
+
+    #[test]
+    fn the_provider_is_read_from_the_config_file() {
+        let dir = TempDir::new();
+        let path = dir.join("config.json");
+        let read = |content: &str| {
+            fs::write(&path, content).unwrap();
+            AegKeyProvider::config_from_file(&path)
+        };
+
+        assert_eq!(read(r#"{"port": 1}"#), Ok(KeyProviderConfig::DataDir));
+        assert_eq!(
+            read(r#"{"key_provider": {"type": "env"}}"#),
+            Ok(KeyProviderConfig::Env {
+                var: DEFAULT_KEY_ENV.into()
+            })
+        );
+        assert_eq!(
+            read(r#"{"key_provider": {"type": "command", "program": "vault"}}"#),
+            Ok(KeyProviderConfig::Command {
+                program: "vault".into(),
+                args: Vec::new()
+            })
+        );
+        assert_eq!(
+            read(r#"{"key_provider": {"type": "kms", "key_id": "k", "plugin": {"type": "mock"}}}"#),
+            Ok(KeyProviderConfig::Kms {
+                key_id: "k".into(),
+                plugin: KmsPluginConfig::Mock { dir: None }
+            })
+        );
+        let error = read(r#"{"key_provider": {"type": "vault"}}"#).unwrap_err();
+        assert!(error.contains("invalid key_provider"), "{}", error);
+        assert!(read("not json").is_err());
+    }
+}
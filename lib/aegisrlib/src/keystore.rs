@@ -0,0 +1,450 @@
+use crate::constant::STORE_AUTHORIZATION_KEY;
+use crate::crypto::AegCrypto;
+use crate::file_system::AegFileSystem;
+use crate::key_provider::KmsKeyFile;
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::{Engine as _, engine::general_purpose};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::sync::Mutex;
+use zeroize::Zeroizing;
+
+/// Environment variable read for the passphrase of a protected key when no file
+/// descriptor is given.
+pub const PASSPHRASE_ENV: &str = "AEGISR_PASSPHRASE";
+/// Key derivation function of protected key files.
+const KDF_ARGON2ID: &str = "argon2id";
+/// Argon2id cost for newly protected keys: 64 MiB of memory, 3 passes, 1 lane.
+const ARGON2_M_COST: u32 = 64 * 1024;
+const ARGON2_T_COST: u32 = 3;
+const ARGON2_P_COST: u32 = 1;
+/// Highest Argon2id cost accepted from a key file (1 GiB, 16 passes, 16 lanes), so an
+/// edited file cannot make unlocking exhaust memory or run for hours.
+const ARGON2_MAX_M_COST: u32 = 1024 * 1024;
+const ARGON2_MAX_T_COST: u32 = 16;
+const ARGON2_MAX_P_COST: u32 = 16;
+const WRAPPED_KEY_VERSION: u8 = 1;
+/// Associated data of the sealed key.
+const WRAPPED_KEY_AAD: &[u8] = b"aegisr AUTHORIZATION_KEY v1";
+
+pub(crate) const KMS_SEALED: &str =
+    "AUTHORIZATION_KEY is sealed by a KMS; configure the kms key provider (--config)";
+
+/// The authorization key, once a protected key file has been unlocked.
+static UNLOCKED_KEY: Mutex<Option<UnlockedKey>> = Mutex::new(None);
+
+/// An unlocked key and what it was wrapped with. The passphrase itself is not kept; the
+/// derived key is, so a rotated key can be wrapped for the same passphrase.
+struct UnlockedKey {
+    auth_key: Zeroizing<String>,
+    wrapping: Wrapping,
+}
+
+/// Key derived from the passphrase, with the salt and cost it was derived with.
+struct Wrapping {
+    derived: Zeroizing<[u8; 32]>,
+    salt: Vec<u8>,
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+}
+
+/// `AUTHORIZATION_KEY` when protected by a passphrase: the key sealed with a key derived
+/// from the passphrase. The Argon2 parameters are stored so they can change later.
+#[derive(Serialize, Deserialize)]
+struct WrappedKey {
+    version: u8,
+    kdf: String,
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+    /// base64, random per wrap.
+    salt: String,
+    /// base64 of nonce || ciphertext.
+    key: String,
+}
+
+/// A JSON key file, told apart by its fields: a KMS-sealed key names its `kms_key_id`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonKeyFile {
+    Kms(KmsKeyFile),
+    Passphrase(WrappedKey),
+}
+
+/// PASSPHRASE-PROTECTED AUTHORIZATION KEY
+///
+/// `AUTHORIZATION_KEY` is either the base64 key itself or, once protected, a JSON
+/// `WrappedKey`. A protected key is unlocked once per process (daemon start, offline
+/// tool) and only kept in zeroized memory; the key file never holds it in plaintext.
+pub struct AegKeyStore;
+
+impl AegKeyStore {
+    fn key_path() -> std::path::PathBuf {
+        AegFileSystem::get_config_path().join(STORE_AUTHORIZATION_KEY)
+    }
+
+    /// Whether key file content is a passphrase-protected key.
+    pub fn is_wrapped(content: &str) -> bool {
+        content.trim_start().starts_with('{')
+    }
+
+    /// Whether the key file in the data directory is passphrase-protected.
+    pub fn is_protected() -> bool {
+        fs::read_to_string(Self::key_path())
+            .is_ok_and(|content| Self::is_wrapped(&content) && !Self::is_kms_content(&content))
+    }
+
+    /// Whether the key file in the data directory was sealed by the `kms` key provider.
+    pub fn is_kms_sealed() -> bool {
+        fs::read_to_string(Self::key_path()).is_ok_and(|content| Self::is_kms_content(&content))
+    }
+
+    fn is_kms_content(content: &str) -> bool {
+        Self::is_wrapped(content)
+            && matches!(
+                serde_json::from_str::<JsonKeyFile>(content),
+                Ok(JsonKeyFile::Kms(_))
+            )
+    }
+
+    /// The unlocked authorization key, if a protected key has been unlocked.
+    pub fn unlocked_key() -> Option<Zeroizing<String>> {
+        Self::slot()
+            .as_ref()
+            .map(|unlocked| unlocked.auth_key.clone())
+    }
+
+    /// Drop the unlocked key (the key file was replaced).
+    pub fn forget() {
+        *Self::slot() = None;
+    }
+
+    /// Unlock the protected key file with `passphrase`.
+    pub fn unlock(passphrase: &str) -> Result<(), String> {
+        let content = Self::read_key_file()?;
+        let (auth_key, wrapping) = Self::unwrap(&content, passphrase)?;
+        *Self::slot() = Some(UnlockedKey { auth_key, wrapping });
+        Ok(())
+    }
+
+    /// Unlock the key file if it is protected and not unlocked yet, reading the passphrase
+    /// from file descriptor `fd`, `AEGISR_PASSPHRASE` or the terminal, in that order.
+    pub fn unlock_with(fd: Option<u32>) -> Result<(), String> {
+        if !Self::is_protected() || Self::slot().is_some() {
+            return Ok(());
+        }
+        let passphrase = Self::read_passphrase(fd, "Passphrase for AUTHORIZATION_KEY: ")?;
+        Self::unlock(&passphrase)
+    }
+
+    /// Protect the plaintext key file with `passphrase`.
+    pub fn protect(passphrase: &str) -> Result<(), String> {
+        let content = Self::read_key_file()?;
+        if Self::is_wrapped(&content) {
+            return Err("AUTHORIZATION_KEY is already passphrase-protected".into());
+        }
+        let auth_key = Zeroizing::new(content.trim().to_string());
+        AegCrypto::sealing_key(&auth_key)?;
+        let wrapping = Self::new_wrapping(passphrase)?;
+        let wrapped = Self::wrap(&auth_key, &wrapping)?;
+        AegFileSystem::write_atomic(&Self::key_path(), wrapped.as_bytes())?;
+        *Self::slot() = Some(UnlockedKey { auth_key, wrapping });
+        Ok(())
+    }
+
+    /// Store the key in plaintext again.
+    pub fn unprotect(passphrase: &str) -> Result<(), String> {
+        let content = Self::read_key_file()?;
+        if !Self::is_wrapped(&content) {
+            return Err("AUTHORIZATION_KEY is not passphrase-protected".into());
+        }
+        let (auth_key, _) = Self::unwrap(&content, passphrase)?;
+        AegFileSystem::write_atomic(&Self::key_path(), auth_key.as_bytes())?;
+        Self::forget();
+        Ok(())
+    }
+
+    /// Re-wrap the protected key under a new passphrase (with a new salt).
+    pub fn change_passphrase(old: &str, new: &str) -> Result<(), String> {
+        let content = Self::read_key_file()?;
+        if !Self::is_wrapped(&content) {
+            return Err("AUTHORIZATION_KEY is not passphrase-protected".into());
+        }
+        let (auth_key, _) = Self::unwrap(&content, old)?;
+        let wrapping = Self::new_wrapping(new)?;
+        let wrapped = Self::wrap(&auth_key, &wrapping)?;
+        AegFileSystem::write_atomic(&Self::key_path(), wrapped.as_bytes())?;
+        *Self::slot() = Some(UnlockedKey { auth_key, wrapping });
+        Ok(())
+    }
+
+    /// Key file content for `auth_key`, protected the same way as the current key file:
+    /// in plaintext, or wrapped for the passphrase it was unlocked with.
+    pub fn key_file_content(auth_key: &str) -> Result<Zeroizing<String>, String> {
+        if !Self::is_protected() {
+            return Ok(Zeroizing::new(auth_key.to_string()));
+        }
+        let slot = Self::slot();
+        let unlocked = slot
+            .as_ref()
+            .ok_or("AUTHORIZATION_KEY is passphrase-protected and has not been unlocked")?;
+        Self::wrap(auth_key, &unlocked.wrapping).map(Zeroizing::new)
+    }
+
+    /// Read key file content written by `key_file_content`.
+    pub fn open_key_file_content(content: &str) -> Result<Zeroizing<String>, String> {
+        if !Self::is_wrapped(content) {
+            return Ok(Zeroizing::new(content.trim().to_string()));
+        }
+        let slot = Self::slot();
+        let unlocked = slot
+            .as_ref()
+            .ok_or("AUTHORIZATION_KEY is passphrase-protected and has not been unlocked")?;
+        let wrapped = Self::parse(content)?;
+        if general_purpose::STANDARD.decode(&wrapped.salt).ok()
+            != Some(unlocked.wrapping.salt.clone())
+        {
+            return Err("key was wrapped for a different passphrase".into());
+        }
+        let sealed = general_purpose::STANDARD
+            .decode(&wrapped.key)
+            .map_err(|_| "invalid sealed key".to_string())?;
+        Self::open_sealed(&unlocked.wrapping, &sealed)
+    }
+
+    /// Make `auth_key` the unlocked key (it replaced the old one in the key file).
+    pub fn replace_unlocked(auth_key: &str) {
+        if let Some(unlocked) = Self::slot().as_mut() {
+            unlocked.auth_key = Zeroizing::new(auth_key.to_string());
+        }
+    }
+
+    /// Read a passphrase from file descriptor `fd` (its first line), else from
+    /// `AEGISR_PASSPHRASE`, else by prompting on the terminal.
+    pub fn read_passphrase(fd: Option<u32>, prompt: &str) -> Result<Zeroizing<String>, String> {
+        let passphrase = if let Some(fd) = fd {
+            let content = Zeroizing::new(
+                fs::read_to_string(format!("/dev/fd/{}", fd))
+                    .map_err(|e| format!("read passphrase from fd {}: {}", fd, e))?,
+            );
+            Zeroizing::new(content.lines().next().unwrap_or_default().to_string())
+        } else if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+            Zeroizing::new(passphrase)
+        } else {
+            Zeroizing::new(rpassword::prompt_password(prompt).map_err(|e| {
+                format!(
+                    "read passphrase from the terminal: {} (set {} instead)",
+                    e, PASSPHRASE_ENV
+                )
+            })?)
+        };
+        if passphrase.is_empty() {
+            return Err("empty passphrase".into());
+        }
+        Ok(passphrase)
+    }
+
+    /// Like `read_passphrase`, but a passphrase typed on the terminal must be entered twice.
+    pub fn read_new_passphrase(fd: Option<u32>) -> Result<Zeroizing<String>, String> {
+        if fd.is_some() || std::env::var(PASSPHRASE_ENV).is_ok() {
+            return Self::read_passphrase(fd, "");
+        }
+        let first = Self::read_passphrase(None, "New passphrase: ")?;
+        let second = Self::read_passphrase(None, "Repeat new passphrase: ")?;
+        if first != second {
+            return Err("passphrases do not match".into());
+        }
+        Ok(first)
+    }
+
+    fn slot() -> std::sync::MutexGuard<'static, Option<UnlockedKey>> {
+        UNLOCKED_KEY.lock().expect("Failed to lock unlocked key")
+    }
+
+    fn read_key_file() -> Result<String, String> {
+        let path = Self::key_path();
+        fs::read_to_string(&path).map_err(|e| format!("read {}: {}", path.display(), e))
+    }
+
+    /// Derive a wrapping key from `passphrase` with a fresh salt and the current cost.
+    fn new_wrapping(passphrase: &str) -> Result<Wrapping, String> {
+        let salt = AegCrypto::generate_random_bytes(None)[..16].to_vec();
+        let derived = Self::derive(
+            passphrase,
+            &salt,
+            ARGON2_M_COST,
+            ARGON2_T_COST,
+            ARGON2_P_COST,
+        )?;
+        Ok(Wrapping {
+            derived,
+            salt,
+            m_cost: ARGON2_M_COST,
+            t_cost: ARGON2_T_COST,
+            p_cost: ARGON2_P_COST,
+        })
+    }
+
+    fn wrap(auth_key: &str, wrapping: &Wrapping) -> Result<String, String> {
+        let sealing_key = AegCrypto::sealing_key_from_bytes(&wrapping.derived);
+        let sealed =
+            AegCrypto::seal_with_nonce(&sealing_key, WRAPPED_KEY_AAD, auth_key.as_bytes())?;
+        let wrapped = WrappedKey {
+            version: WRAPPED_KEY_VERSION,
+            kdf: KDF_ARGON2ID.into(),
+            m_cost: wrapping.m_cost,
+            t_cost: wrapping.t_cost,
+            p_cost: wrapping.p_cost,
+            salt: general_purpose::STANDARD.encode(&wrapping.salt),
+            key: general_purpose::STANDARD.encode(sealed),
+        };
+        serde_json::to_string_pretty(&wrapped).map_err(|e| format!("serialize error: {}", e))
+    }
+
+    fn parse(content: &str) -> Result<WrappedKey, String> {
+        let wrapped = match serde_json::from_str::<JsonKeyFile>(content) {
+            Ok(JsonKeyFile::Passphrase(wrapped)) => wrapped,
+            Ok(JsonKeyFile::Kms(file)) => {
+                return Err(format!("{} (KMS key '{}')", KMS_SEALED, file.kms_key_id));
+            }
+            // Parsed again for an error that says what is wrong.
+            Err(_) => serde_json::from_str::<WrappedKey>(content)
+                .map_err(|e| format!("invalid protected key file: {}", e))?,
+        };
+        if wrapped.version != WRAPPED_KEY_VERSION || wrapped.kdf != KDF_ARGON2ID {
+            return Err(format!(
+                "unsupported protected key file (version {}, kdf {})",
+                wrapped.version, wrapped.kdf
+            ));
+        }
+        if wrapped.m_cost > ARGON2_MAX_M_COST
+            || wrapped.t_cost > ARGON2_MAX_T_COST
+            || wrapped.p_cost > ARGON2_MAX_P_COST
+        {
+            return Err(format!(
+                "protected key file asks for Argon2 costs above the limit (m_cost {} KiB, t_cost {}, p_cost {}; at most {}, {}, {})",
+                wrapped.m_cost,
+                wrapped.t_cost,
+                wrapped.p_cost,
+                ARGON2_MAX_M_COST,
+                ARGON2_MAX_T_COST,
+                ARGON2_MAX_P_COST
+            ));
+        }
+        Ok(wrapped)
+    }
+
+    fn unwrap(content: &str, passphrase: &str) -> Result<(Zeroizing<String>, Wrapping), String> {
+        let wrapped = Self::parse(content)?;
+        let salt = general_purpose::STANDARD
+            .decode(&wrapped.salt)
+            .map_err(|_| "invalid salt".to_string())?;
+        let sealed = general_purpose::STANDARD
+            .decode(&wrapped.key)
+            .map_err(|_| "invalid sealed key".to_string())?;
+        let wrapping = Wrapping {
+            derived: Self::derive(
+                passphrase,
+                &salt,
+                wrapped.m_cost,
+                wrapped.t_cost,
+                wrapped.p_cost,
+            )?,
+            salt,
+            m_cost: wrapped.m_cost,
+            t_cost: wrapped.t_cost,
+            p_cost: wrapped.p_cost,
+        };
+        let auth_key =
+            Self::open_sealed(&wrapping, &sealed).map_err(|_| "wrong passphrase".to_string())?;
+        Ok((auth_key, wrapping))
+    }
+
+    fn open_sealed(wrapping: &Wrapping, sealed: &[u8]) -> Result<Zeroizing<String>, String> {
+        let sealing_key = AegCrypto::sealing_key_from_bytes(&wrapping.derived);
+        let plain = AegCrypto::open_with_nonce(&sealing_key, WRAPPED_KEY_AAD, sealed)?;
+        String::from_utf8(plain.to_vec())
+            .map(Zeroizing::new)
+            .map_err(|_| "invalid key".to_string())
+    }
+
+    fn derive(
+        passphrase: &str,
+        salt: &[u8],
+        m_cost: u32,
+        t_cost: u32,
+        p_cost: u32,
+    ) -> Result<Zeroizing<[u8; 32]>, String> {
+        let params = Params::new(m_cost, t_cost, p_cost, Some(32))
+            .map_err(|e| format!("invalid Argon2 parameters: {}", e))?;
+        let mut derived = Zeroizing::new([0u8; 32]);
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), salt, derived.as_mut())
+            .map_err(|e| format!("key derivation failed: {}", e))?;
+        Ok(derived)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::DataHome;
+
+    #[test]
+    fn a_protected_key_opens_only_with_its_current_passphrase() {
+        let _home = DataHome::new();
+        let auth_key = AegFileSystem::read_authorization_key();
+        AegKeyStore::protect("first").unwrap();
+        assert!(AegKeyStore::is_protected());
+        assert!(
+            !AegKeyStore::read_key_file()
+                .unwrap()
+                .contains(auth_key.as_str())
+        );
+        assert!(AegKeyStore::protect("again").is_err());
+
+        AegKeyStore::forget();
+        assert_eq!(
+            AegKeyStore::unlock("wrong"),
+            Err("wrong passphrase".to_string())
+        );
+        assert!(AegKeyStore::unlocked_key().is_none());
+        AegKeyStore::unlock("first").unwrap();
+        assert_eq!(
+            AegKeyStore::unlocked_key().unwrap().as_str(),
+            auth_key.as_str()
+        );
+
+        assert!(AegKeyStore::change_passphrase("wrong", "second").is_err());
+        AegKeyStore::change_passphrase("first", "second").unwrap();
+        AegKeyStore::forget();
+        assert!(AegKeyStore::unlock("first").is_err());
+        AegKeyStore::unlock("second").unwrap();
+
+        AegKeyStore::unprotect("second").unwrap();
+        assert!(!AegKeyStore::is_protected());
+        assert!(AegKeyStore::unlocked_key().is_none());
+        assert_eq!(AegKeyStore::read_key_file().unwrap(), auth_key.as_str());
+    }
+
+    #[test]
+    fn a_key_file_asking_for_excessive_argon2_costs_is_refused() {
+        let _home = DataHome::new();
+        let wrapped = WrappedKey {
+            version: WRAPPED_KEY_VERSION,
+            kdf: KDF_ARGON2ID.into(),
+            m_cost: ARGON2_MAX_M_COST + 1,
+            t_cost: ARGON2_T_COST,
+            p_cost: ARGON2_P_COST,
+            salt: general_purpose::STANDARD.encode([0u8; 16]),
+            key: general_purpose::STANDARD.encode([0u8; 60]),
+        };
+        let content = serde_json::to_string(&wrapped).unwrap();
+        fs::write(AegKeyStore::key_path(), content).unwrap();
+
+        assert!(AegKeyStore::is_protected());
+        let error = AegKeyStore::unlock("anything").unwrap_err();
+        assert!(error.contains("above the limit"), "{}", error);
+    }
+}
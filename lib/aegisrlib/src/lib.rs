@@ -3,11 +3,39 @@ pub mod commands;
 pub mod memory_engine;
 pub mod file_system;
 pub mod crypto;
+pub mod secret;
+pub mod keystore;
+pub mod key_provider;
 pub mod core;
+pub mod scripting;
+pub mod wal;
+pub mod snapshot_format;
+pub mod backup;
+pub mod transfer;
+pub mod check;
+pub mod rotation;
+pub mod permissions;
+pub mod field_crypto;
+pub mod audit;
+#[cfg(test)]
+mod test_support;
 
 pub use constant::*;
 pub use commands::*;
 pub use memory_engine::*;
 pub use file_system::*;
 pub use crypto::*;
+pub use secret::*;
+pub use keystore::*;
+pub use key_provider::*;
 pub use core::*;
+pub use scripting::*;
+pub use wal::*;
+pub use snapshot_format::*;
+pub use backup::*;
+pub use transfer::*;
+pub use check::*;
+pub use rotation::*;
+pub use permissions::*;
+pub use field_crypto::*;
+pub use audit::*;
@@ -1,34 +1,191 @@
 use crate::core::AegCore;
+use crate::crypto::{AegCrypto, DataKey};
 use crate::file_system::AegFileSystem;
-use aes_gcm::aead::Aead;
-use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
+use crate::secret::AegSecureMemory;
+use crate::snapshot_format::AegSnapshotFormat;
+use crate::wal::{AegWal, WalOp};
 use base64::{Engine as _, engine::general_purpose};
-use rand_core::TryRngCore;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::convert::TryInto;
+use std::collections::hash_map::Entry;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Mutex, OnceLock};
+use std::sync::{Mutex, MutexGuard, OnceLock};
 use std::thread;
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 /// IN-MEMORY KEY-VALUE STORE ENGINE
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct AegMemoryEngine {
     pub store: HashMap<String, String>,
     pub collection_name: String,
+    /// Bumped on every mutation; key versions are taken from it.
+    #[serde(default)]
+    pub revision: u64,
+    /// Revision at which each key was last written.
+    #[serde(default)]
+    pub versions: HashMap<String, u64>,
+    /// Sequence number of the last WAL record contained in this engine.
+    #[serde(default)]
+    pub wal_seq: u64,
+    /// Mutations not yet written to the WAL (drained when the global lock is released).
+    #[serde(skip)]
+    pending_ops: Vec<WalOp>,
+    /// `revision` as of the last snapshot on disk; the collection is dirty when they differ.
+    #[serde(skip)]
+    saved_revision: u64,
+    #[serde(skip, default = "Instant::now")]
+    last_save: Instant,
+    /// Seals the snapshot file and this collection's WAL operations; kept across saves.
+    #[serde(skip, default = "DataKey::generate")]
+    pub(crate) data_key: DataKey,
+    /// Whether a snapshot file holds `data_key`. One is written before anything sealed
+    /// with the key is logged, or the logged writes could not be read after a restart.
+    #[serde(skip)]
+    on_disk: bool,
+}
+
+/// Snapshot rule: save a collection once at least `changes` writes are unsaved and
+/// `seconds` have passed since its last snapshot (`save N M`).
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SaveRule {
+    pub changes: u64,
+    pub seconds: u64,
+}
+
+/// Save anything dirty every second.
+pub const DEFAULT_SAVE_RULES: &[SaveRule] = &[SaveRule {
+    changes: 1,
+    seconds: 1,
+}];
+
+/// Precondition for a conditional write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetCondition {
+    Always,
+    /// Only write if the key does not exist (SETNX / SET NX).
+    IfAbsent,
+    /// Only write if the key already exists (SET XX).
+    IfPresent,
+}
+
+/// What a compare-and-swap checks the current state against.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CasExpectation {
+    /// Current value must equal this (`None` = key must be absent).
+    Value(Option<String>),
+    /// Current key version must equal this (0 = key must be absent).
+    Version(u64),
 }
 
 /// SAFE GLOBAL IN-MEMORY CACHE (OnceLock + Mutex)
 static MEMORY_CACHE: OnceLock<Mutex<HashMap<String, AegMemoryEngine>>> = OnceLock::new();
 
+/// Locked view over the whole in-memory cache, handed out by `with_collections`.
+/// Collections are loaded from disk on first access.
+pub struct AegCollections<'a> {
+    guard: MutexGuard<'a, HashMap<String, AegMemoryEngine>>,
+    touched: Vec<String>,
+}
+
+impl AegCollections<'_> {
+    /// Write the mutations of every touched collection to the WAL as a single record.
+    /// When that fails the collections are dropped from the cache, so their next access
+    /// reloads them from disk without the unlogged changes, and the error is returned.
+    fn log_pending(&mut self) -> Result<(), String> {
+        let mut entries = Vec::new();
+        let mut unwritten_key = Ok(());
+        for name in &self.touched {
+            if let Some(engine) = self.guard.get_mut(name)
+                && !engine.pending_ops.is_empty()
+            {
+                if unwritten_key.is_ok() {
+                    unwritten_key = engine.write_data_key();
+                }
+                entries.push((
+                    name.clone(),
+                    engine.data_key.clone(),
+                    std::mem::take(&mut engine.pending_ops),
+                ));
+            }
+        }
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let names: Vec<String> = entries.iter().map(|(n, _, _)| n.clone()).collect();
+        let appended = match unwritten_key {
+            Ok(()) => AegWal::append(entries),
+            Err(e) => {
+                for (_, _, ops) in entries {
+                    ops.into_iter().for_each(WalOp::wipe);
+                }
+                Err(e)
+            }
+        };
+        match appended {
+            Ok(0) => Ok(()),
+            Ok(seq) => {
+                for name in names {
+                    if let Some(engine) = self.guard.get_mut(&name) {
+                        engine.wal_seq = seq;
+                    }
+                }
+                Ok(())
+            }
+            Err(e) => {
+                for name in names {
+                    self.guard.remove(&name);
+                }
+                Err(format!("Write not applied: {}", e))
+            }
+        }
+    }
+
+    /// Copies of every cached collection.
+    pub(crate) fn snapshot(&self) -> Vec<AegMemoryEngine> {
+        self.guard.values().cloned().collect()
+    }
+
+    /// Drop every cached collection and cache `engines` instead (used by restore).
+    pub(crate) fn replace_all(&mut self, engines: Vec<AegMemoryEngine>) {
+        self.guard.clear();
+        for engine in engines {
+            self.guard.insert(engine.collection_name.clone(), engine);
+        }
+    }
+
+    /// The cached engine of `collection_name`, loaded on first access. Fails when its file
+    /// or its logged writes cannot be read; nothing is cached then.
+    pub fn get_mut(&mut self, collection_name: &str) -> Result<&mut AegMemoryEngine, String> {
+        if !self.touched.iter().any(|n| n == collection_name) {
+            self.touched.push(collection_name.to_string());
+        }
+        match self.guard.entry(collection_name.to_string()) {
+            Entry::Occupied(entry) => Ok(entry.into_mut()),
+            Entry::Vacant(entry) => {
+                Ok(entry.insert(AegMemoryEngine::load_with_wal(collection_name)?))
+            }
+        }
+    }
+}
+
+static SAVE_RULES: OnceLock<Mutex<Vec<SaveRule>>> = OnceLock::new();
+/// Held by everything that writes collection files or the WAL from a copy of the cache
+/// (saver, WAL rewrite, restore), so a stale copy can never overwrite newer files.
+static PERSIST_LOCK: Mutex<()> = Mutex::new(());
+
 /// Background saver control
 static SAVER_RUNNING: OnceLock<AtomicBool> = OnceLock::new();
 static SAVER_STARTED: OnceLock<AtomicBool> = OnceLock::new();
 
+impl Drop for AegMemoryEngine {
+    fn drop(&mut self) {
+        AegSecureMemory::wipe_all(self.store.drain().map(|(_, value)| value));
+    }
+}
+
 impl AegMemoryEngine {
     /// Returns a reference to the global Mutex<HashMap<...>>.
     fn global_memory_mutex() -> &'static Mutex<HashMap<String, AegMemoryEngine>> {
@@ -39,34 +196,58 @@ impl AegMemoryEngine {
         Self {
             store: HashMap::new(),
             collection_name: collection_name.to_string(),
+            revision: 0,
+            versions: HashMap::new(),
+            wal_seq: 0,
+            pending_ops: Vec::new(),
+            saved_revision: 0,
+            last_save: Instant::now(),
+            data_key: DataKey::generate(),
+            on_disk: false,
         }
     }
 
-    fn engine_file_path(collection_name: &str) -> PathBuf {
+    pub(crate) fn engine_file_path(collection_name: &str) -> PathBuf {
         let mut path = AegFileSystem::get_config_path();
         path.push(format!("collection_{}.aekv", collection_name));
         path
     }
 
-    /// Insert into current engine and update global in-memory cache (fast).
+    /// Insert into the engine (memory only). Callers reach the cached engine
+    /// through `with_collection`, so the change is visible to everyone at once.
     pub fn insert(&mut self, key: impl Into<String>, value: impl Into<String>) {
-        self.store.insert(key.into(), value.into());
-        // persist to global in-memory cache (only memory)
-        let mutex = Self::global_memory_mutex();
-        let mut guard = mutex.lock().expect("Failed to lock global memory mutex");
-        guard.insert(self.collection_name.clone(), self.clone());
-        // intentionally not calling self.save() here
+        let key = key.into();
+        self.revision += 1;
+        self.versions.insert(key.clone(), self.revision);
+        let value = value.into();
+        self.pending_ops.push(WalOp::Set {
+            key: key.clone(),
+            value: value.clone(),
+        });
+        if let Some(previous) = self.store.insert(key, value) {
+            AegSecureMemory::wipe(previous);
+        }
     }
 
     pub fn get(&self, key: &str) -> Option<String> {
         self.store.get(key).cloned()
     }
 
-    pub fn delete(&mut self, key: &str) {
-        self.store.remove(key);
-        let mutex = Self::global_memory_mutex();
-        let mut guard = mutex.lock().expect("Failed to lock global memory mutex");
-        guard.insert(self.collection_name.clone(), self.clone());
+    pub fn delete(&mut self, key: &str) -> Option<String> {
+        let removed = self.store.remove(key);
+        if removed.is_some() {
+            self.revision += 1;
+            self.versions.remove(key);
+            self.pending_ops.push(WalOp::Del {
+                key: key.to_string(),
+            });
+        }
+        removed
+    }
+
+    /// `delete` for callers that do not need the value; it is wiped in secure mode.
+    pub fn remove(&mut self, key: &str) -> bool {
+        self.delete(key).map(AegSecureMemory::wipe).is_some()
     }
 
     pub fn list(&self) -> Vec<(String, String)> {
@@ -77,124 +258,556 @@ impl AegMemoryEngine {
     }
 
     pub fn clear(&mut self) {
-        self.store.clear();
-        let mutex = Self::global_memory_mutex();
-        let mut guard = mutex.lock().expect("Failed to lock global memory mutex");
-        guard.insert(self.collection_name.clone(), self.clone());
+        if !self.store.is_empty() {
+            self.revision += 1;
+            self.pending_ops.push(WalOp::Clear);
+        }
+        AegSecureMemory::wipe_all(self.store.drain().map(|(_, value)| value));
+        self.versions.clear();
     }
 
-    /// Persist single engine to disk (synchronous) — same encryption as before.
-    pub fn save_to_disk(engine: &AegMemoryEngine) -> Result<(), String> {
-        let path = Self::engine_file_path(&engine.collection_name);
+    /// Revision at which `key` was last written; 0 when the key does not exist.
+    pub fn key_version(&self, key: &str) -> u64 {
+        self.versions.get(key).copied().unwrap_or(0)
+    }
 
-        let json =
-            serde_json::to_string_pretty(engine).map_err(|e| format!("serialize error: {}", e))?;
+    /// Write `key` if `condition` holds. Returns whether the write happened and the
+    /// value that was there before (returned even when nothing was written).
+    pub fn set_with(
+        &mut self,
+        key: &str,
+        value: &str,
+        condition: SetCondition,
+    ) -> (bool, Option<String>) {
+        let previous = self.get(key);
+        let allowed = match condition {
+            SetCondition::Always => true,
+            SetCondition::IfAbsent => previous.is_none(),
+            SetCondition::IfPresent => previous.is_some(),
+        };
+        if allowed {
+            self.insert(key, value);
+        }
+        (allowed, previous)
+    }
 
-        let auth_key = AegFileSystem::read_authorization_key();
-        let key_bytes = general_purpose::STANDARD
-            .decode(auth_key)
-            .map_err(|e| format!("base64 decode auth key: {}", e))?;
+    /// Write `key` only if its current state matches `expected`.
+    /// Returns the new version on success, or the current version on mismatch.
+    pub fn compare_and_swap(
+        &mut self,
+        key: &str,
+        expected: &CasExpectation,
+        value: &str,
+    ) -> Result<u64, u64> {
+        let current_version = self.key_version(key);
+        let matches = match expected {
+            CasExpectation::Value(v) => self.store.get(key) == v.as_ref(),
+            CasExpectation::Version(v) => current_version == *v,
+        };
+        if !matches {
+            return Err(current_version);
+        }
+        self.insert(key, value);
+        Ok(self.key_version(key))
+    }
+
+    /// Values for every key, in order; missing keys yield `None`.
+    pub fn mget(&self, keys: &[String]) -> Vec<Option<String>> {
+        keys.iter().map(|k| self.get(k)).collect()
+    }
+
+    pub fn mset(&mut self, pairs: &[(String, String)]) {
+        for (key, value) in pairs {
+            self.insert(key.clone(), value.clone());
+        }
+    }
+
+    /// Set all pairs only if none of the keys exist yet. Returns whether anything was written.
+    pub fn msetnx(&mut self, pairs: &[(String, String)]) -> bool {
+        if pairs.iter().any(|(key, _)| self.store.contains_key(key)) {
+            return false;
+        }
+        self.mset(pairs);
+        true
+    }
+
+    /// Number of given keys that exist (a key repeated twice counts twice).
+    pub fn exists(&self, keys: &[String]) -> usize {
+        keys.iter().filter(|k| self.store.contains_key(*k)).count()
+    }
+
+    /// Move `key` to `new_key`. With `nx`, refuses to overwrite an existing `new_key`
+    /// and returns `Ok(false)`. Errors when `key` does not exist.
+    pub fn rename(&mut self, key: &str, new_key: &str, nx: bool) -> Result<bool, String> {
+        if !self.store.contains_key(key) {
+            return Err(format!("Key '{}' does not exist", key));
+        }
+        if key == new_key {
+            return Ok(!nx);
+        }
+        if nx && self.store.contains_key(new_key) {
+            return Ok(false);
+        }
+        if let Some(value) = self.delete(key) {
+            self.insert(new_key, value);
+        }
+        Ok(true)
+    }
+
+    /// Copy `source` to `destination`. Without `replace`, an existing destination is left alone.
+    /// Returns whether the copy happened.
+    pub fn copy(&mut self, source: &str, destination: &str, replace: bool) -> bool {
+        let Some(value) = self.get(source) else {
+            return false;
+        };
+        if !replace && self.store.contains_key(destination) {
+            return false;
+        }
+        self.insert(destination, value);
+        true
+    }
+
+    /// Type name of the value stored at `key` ("none" when absent).
+    pub fn key_type(&self, key: &str) -> &'static str {
+        if self.store.contains_key(key) {
+            "string"
+        } else {
+            "none"
+        }
+    }
+
+    /// Persist single engine to disk (synchronous) in the binary snapshot format.
+    pub fn save_to_disk(engine: &AegMemoryEngine) -> Result<(), String> {
+        Self::save_to_path(engine, &Self::engine_file_path(&engine.collection_name))
+    }
 
-        let key: &aes_gcm::Key<Aes256Gcm> = aes_gcm::Key::<Aes256Gcm>::from_slice(&key_bytes);
-        let cipher = Aes256Gcm::new(key);
-        let nonce = Nonce::from_slice(&key_bytes[..12]);
+    /// Whether `collection_name` has a non-empty file (an empty one loads as a new engine).
+    fn has_file(collection_name: &str) -> bool {
+        fs::metadata(Self::engine_file_path(collection_name)).is_ok_and(|m| m.len() > 0)
+    }
 
-        let encrypted = cipher
-            .encrypt(nonce, json.as_bytes())
-            .map_err(|e| format!("encrypt error: {:?}", e))?;
+    /// Write an empty snapshot for `collection_name` unless it already has a file. It starts
+    /// at the current WAL position, so records of a deleted collection of the same name are
+    /// not replayed into it.
+    pub fn ensure_file(collection_name: &str) -> Result<(), String> {
+        if Self::has_file(collection_name) {
+            return Ok(());
+        }
+        let mut engine = AegMemoryEngine::new(collection_name);
+        engine.wal_seq = AegWal::last_seq();
+        Self::save_to_disk(&engine)
+    }
+
+    /// Write an empty snapshot for every collection in `collection.lock` that has no file,
+    /// so its data key is on disk before anything is logged with it. Call before the WAL is
+    /// opened: the snapshots then start at sequence 0 and whatever the log holds for these
+    /// collections is replayed into them. Returns the collections that got a file.
+    pub fn create_missing_files() -> Result<Vec<String>, String> {
+        let mut created = Vec::new();
+        for name in AegCore::try_load()?.collections {
+            if !Self::has_file(&name) {
+                Self::ensure_file(&name)?;
+                created.push(name);
+            }
+        }
+        Ok(created)
+    }
 
-        let encoded = general_purpose::STANDARD.encode(&encrypted);
+    /// Before the first record sealed with `data_key` is logged, write the key out in an
+    /// empty snapshot at sequence 0. An engine without a file was built from the log alone,
+    /// so replaying the whole log onto that snapshot gives back the same state.
+    fn write_data_key(&mut self) -> Result<(), String> {
+        if self.on_disk || !AegWal::is_enabled() {
+            return Ok(());
+        }
+        let mut empty = Self::new(&self.collection_name);
+        empty.data_key = self.data_key.clone();
+        Self::save_to_disk(&empty)?;
+        self.on_disk = true;
+        Ok(())
+    }
 
-        fs::write(&path, encoded).map_err(|e| format!("write error: {}", e))?;
+    /// Move a collection to a new name. Its frames and data key are bound to the name, so
+    /// it is written to the new file sealed under that name (starting at the current WAL
+    /// position) before `commit` records the rename in `collection.lock`; then the cached
+    /// engine moves and the old file is removed. A log rewrite drops the records logged
+    /// under the old name. If writing fails, nothing changes.
+    pub fn rename_collection(
+        old_name: &str,
+        new_name: &str,
+        commit: impl FnOnce(),
+    ) -> Result<(), String> {
+        let _persist = Self::persist_lock();
+        Self::with_collections(|collections| {
+            collections.get_mut(old_name)?;
+            let mut engine = collections
+                .guard
+                .remove(old_name)
+                .expect("collection was just loaded");
+            let previous_seq = engine.wal_seq;
+            engine.collection_name = new_name.to_string();
+            engine.wal_seq = AegWal::last_seq();
+            if let Err(e) = Self::save_to_disk(&engine) {
+                let _ = fs::remove_file(Self::engine_file_path(new_name));
+                engine.collection_name = old_name.to_string();
+                engine.wal_seq = previous_seq;
+                collections.guard.insert(old_name.to_string(), engine);
+                return Err(e);
+            }
+            commit();
+            engine.mark_saved();
+            collections.guard.insert(new_name.to_string(), engine);
+            let _ = fs::remove_file(Self::engine_file_path(old_name));
+            Ok(())
+        })??;
+        if AegWal::is_enabled() {
+            let _ = AegWal::start_rewrite();
+        }
+        Ok(())
+    }
 
+    /// Forget a deleted collection: drop it from the cache and shred its file, so its data
+    /// key is gone. Its WAL records go at the next log rewrite, which this starts.
+    pub fn drop_collection(collection_name: &str) -> Result<(), String> {
+        let _persist = Self::persist_lock();
+        Self::global_memory_mutex()
+            .lock()
+            .expect("Failed to lock global memory mutex")
+            .remove(collection_name);
+        let path = Self::engine_file_path(collection_name);
+        if path.exists() {
+            AegSnapshotFormat::shred(&path)?;
+        }
+        if AegWal::is_enabled() {
+            let _ = AegWal::start_rewrite();
+        }
         Ok(())
     }
 
-    /// Save ALL collections currently in memory to disk.
-    /// This function clones the cache under the mutex and performs expensive work outside the lock.
+    /// Write `engine` as a snapshot file at `path` (atomically).
+    pub fn save_to_path(engine: &AegMemoryEngine, path: &Path) -> Result<(), String> {
+        Self::save_to_path_with_key(engine, path, &AegFileSystem::read_authorization_key())
+    }
+
+    /// `save_to_path` with an explicit authorization key (key rotation).
+    pub(crate) fn save_to_path_with_key(
+        engine: &AegMemoryEngine,
+        path: &Path,
+        auth_key: &str,
+    ) -> Result<(), String> {
+        AegFileSystem::write_atomic_with(path, |file| {
+            AegSnapshotFormat::write(
+                engine,
+                file,
+                auth_key,
+                AegSnapshotFormat::compression_for(&engine.collection_name),
+            )
+        })
+    }
+
+    /// Consider the current state persisted (not dirty).
+    pub(crate) fn mark_saved(&mut self) {
+        self.saved_revision = self.revision;
+        self.last_save = Instant::now();
+        self.on_disk = true;
+    }
+
+    /// Serialize writers of persisted files; see `PERSIST_LOCK`.
+    pub fn persist_lock() -> MutexGuard<'static, ()> {
+        PERSIST_LOCK.lock().expect("Failed to lock persistence")
+    }
+
+    /// Writes made since the last snapshot.
+    pub fn unsaved_changes(&self) -> u64 {
+        self.revision.saturating_sub(self.saved_revision)
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.unsaved_changes() > 0
+    }
+
+    /// Replace the snapshot rules used by the background saver. An empty list means
+    /// collections are only saved by `save_all` (e.g. at shutdown).
+    pub fn set_save_rules(rules: Vec<SaveRule>) {
+        *Self::save_rules()
+            .lock()
+            .expect("Failed to lock save rules") = rules;
+    }
+
+    fn save_rules() -> &'static Mutex<Vec<SaveRule>> {
+        SAVE_RULES.get_or_init(|| Mutex::new(DEFAULT_SAVE_RULES.to_vec()))
+    }
+
+    fn rule_matches(&self, rules: &[SaveRule]) -> bool {
+        let elapsed = self.last_save.elapsed().as_secs();
+        let changes = self.unsaved_changes();
+        rules
+            .iter()
+            .any(|r| changes >= r.changes.max(1) && elapsed >= r.seconds)
+    }
+
+    /// Save every dirty collection to disk, regardless of the save rules.
     pub fn save_all() {
-        // 1) Clone the memory map under the lock (minimize lock time)
-        let snapshot: HashMap<String, AegMemoryEngine> = {
+        Self::save_where(|_| true);
+    }
+
+    /// Save the dirty collections whose save rules are met.
+    pub fn save_due() {
+        let rules = Self::save_rules()
+            .lock()
+            .expect("Failed to lock save rules")
+            .clone();
+        Self::save_where(|engine| engine.rule_matches(&rules));
+    }
+
+    /// Clone the selected dirty collections under the mutex and do the expensive work outside
+    /// the lock. Idle collections are never rewritten.
+    fn save_where(select: impl Fn(&AegMemoryEngine) -> bool) {
+        let _persist = Self::persist_lock();
+        // 1) Clone what needs saving under the lock (minimize lock time)
+        let snapshot: Vec<AegMemoryEngine> = {
             let mutex = Self::global_memory_mutex();
             let guard = mutex.lock().expect("Failed to lock global memory mutex");
-            guard.clone()
+            guard
+                .values()
+                .filter(|engine| engine.is_dirty() && select(engine))
+                .cloned()
+                .collect()
         };
 
         // 2) For each collection, perform serialization/encryption/write outside the lock
-        for (_name, engine) in snapshot.into_iter() {
-            // best-effort: log errors but continue
+        for engine in snapshot {
+            // best-effort: log errors but continue (the collection stays dirty)
             if let Err(e) = Self::save_to_disk(&engine) {
                 eprintln!(
                     "Failed to save collection '{}': {}",
                     engine.collection_name, e
                 );
+                continue;
+            }
+            // 3) Record what is on disk now; writes made meanwhile keep it dirty
+            let mutex = Self::global_memory_mutex();
+            let mut guard = mutex.lock().expect("Failed to lock global memory mutex");
+            if let Some(cached) = guard.get_mut(&engine.collection_name) {
+                cached.saved_revision = cached.saved_revision.max(engine.revision);
+                cached.last_save = Instant::now();
+                cached.on_disk = true;
             }
         }
     }
 
-    /// Load engine from memory cache; otherwise load from disk; otherwise fresh engine.
-    pub fn load() -> Self {
-        let core = AegCore::load();
-        let collection_name = core.active_collection.clone();
+    /// Read an engine straight from its `.aekv` file; fresh engine if the file is absent or empty.
+    /// Files in the legacy text format or an older snapshot version are rewritten in the
+    /// current one.
+    ///
+    /// The daemon checks every file on startup (`AegCheck::startup_check`), so a damaged
+    /// file only shows up here if it is damaged while the daemon runs.
+    fn load_from_disk(collection_name: &str) -> Result<Self, String> {
+        Self::load_and_upgrade(collection_name).map(|(engine, _)| engine)
+    }
 
-        // First try in-memory (global cache)
-        {
-            let mutex = Self::global_memory_mutex();
-            let guard = mutex.lock().expect("Failed to lock global memory mutex");
-            if let Some(engine) = guard.get(&collection_name).cloned() {
-                return engine;
-            }
+    /// `load_from_disk`, which also tells whether the file was in an older format and has
+    /// been rewritten in the current one. A failed rewrite is only reported: the engine
+    /// was read, and the file is upgraded again on its next load or save.
+    fn load_and_upgrade(collection_name: &str) -> Result<(Self, bool), String> {
+        let path = Self::engine_file_path(collection_name);
+        if !Self::has_file(collection_name) {
+            return Ok((Self::new(collection_name), false));
         }
 
-        // If not in memory, load from disk
-        let path = Self::engine_file_path(&collection_name);
+        let auth_key = AegFileSystem::read_authorization_key();
+        let loaded = if AegSnapshotFormat::is_binary(&path) {
+            AegSnapshotFormat::read(&path, &auth_key, collection_name)
+        } else {
+            Self::load_legacy(&path, &auth_key, collection_name)
+        };
+        let mut engine = loaded.map_err(|e| {
+            format!(
+                "Failed to load collection '{}': {} (run aegisr-check)",
+                collection_name, e
+            )
+        })?;
+        engine.on_disk = true;
+        if AegSnapshotFormat::is_current(&path) {
+            return Ok((engine, false));
+        }
 
-        if path.exists() {
-            let encrypted = fs::read_to_string(&path).unwrap_or_default();
-            if encrypted.trim().is_empty() {
-                let engine = Self::new(&collection_name);
-                // store in memory
-                let mutex = Self::global_memory_mutex();
-                let mut guard = mutex.lock().expect("Failed to lock global memory mutex");
-                guard.insert(collection_name.clone(), engine.clone());
-                return engine;
+        let upgraded = match Self::save_to_disk(&engine) {
+            Ok(()) => {
+                println!(
+                    "Migrated collection '{}' to the current snapshot format.",
+                    collection_name
+                );
+                true
             }
+            Err(e) => {
+                eprintln!("Failed to migrate collection '{}': {}", collection_name, e);
+                false
+            }
+        };
+        Ok((engine, upgraded))
+    }
 
-            let auth_key = AegFileSystem::read_authorization_key();
-            let key_bytes = general_purpose::STANDARD
-                .decode(auth_key)
-                .expect("Invalid base64");
-
-            let key: &aes_gcm::Key<Aes256Gcm> = aes_gcm::Key::<Aes256Gcm>::from_slice(&key_bytes);
-            let cipher = Aes256Gcm::new(key);
+    /// Pre-binary format: base64(AES-GCM(pretty JSON)) with a key-derived nonce.
+    pub(crate) fn load_legacy(
+        path: &Path,
+        auth_key: &str,
+        collection_name: &str,
+    ) -> Result<Self, String> {
+        let encrypted = fs::read_to_string(path).map_err(|e| format!("read error: {}", e))?;
+        if encrypted.trim().is_empty() {
+            return Ok(Self::new(collection_name));
+        }
 
-            let nonce = Nonce::from_slice(&key_bytes[..12]);
+        let key = AegCrypto::sealing_key(auth_key)?;
+        let decoded = general_purpose::STANDARD
+            .decode(encrypted.trim())
+            .map_err(|_| "invalid base64".to_string())?;
+        let decrypted = AegCrypto::open_legacy(&key, &decoded)?;
 
-            let decoded = general_purpose::STANDARD
-                .decode(encrypted)
-                .expect("Invalid base64");
+        serde_json::from_slice(&decrypted).map_err(|e| format!("invalid collection JSON: {}", e))
+    }
 
-            let decrypted = cipher
-                .decrypt(nonce, decoded.as_ref())
-                .expect("Decrypt failed");
+    /// Rewrite every collection file in the legacy text format or an older snapshot
+    /// version. Returns how many were rewritten; files that could not be read or written
+    /// are reported and left as they are.
+    pub fn migrate_legacy_files() -> usize {
+        let Ok(entries) = fs::read_dir(AegFileSystem::get_config_path()) else {
+            return 0;
+        };
+        let mut migrated = 0;
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let Some(name) = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .and_then(|n| n.strip_prefix("collection_"))
+                .and_then(|n| n.strip_suffix(".aekv"))
+            else {
+                continue;
+            };
+            if path.metadata().map(|m| m.len()).unwrap_or(0) > 0
+                && !AegSnapshotFormat::is_current(&path)
+            {
+                match Self::load_and_upgrade(name) {
+                    Ok((_, true)) => migrated += 1,
+                    Ok((_, false)) => {}
+                    Err(e) => eprintln!("{}", e),
+                }
+            }
+        }
+        migrated
+    }
 
-            let engine: AegMemoryEngine =
-                serde_json::from_slice(&decrypted).unwrap_or(Self::new(&collection_name));
+    /// Load an engine from its snapshot and roll it forward with newer WAL records. Fails,
+    /// like a damaged snapshot, when a logged write cannot be read: the collection never
+    /// comes up without writes that were acknowledged.
+    fn load_with_wal(collection_name: &str) -> Result<Self, String> {
+        let mut engine = Self::load_from_disk(collection_name)?;
+        engine.saved_revision = engine.revision;
+        let records =
+            AegWal::replay(collection_name, engine.wal_seq, &engine.data_key).map_err(|e| {
+                format!(
+                    "Failed to replay the WAL of collection '{}': {} (run aegisr-check)",
+                    collection_name, e
+                )
+            })?;
+        engine.apply_wal(records);
+        std::mem::take(&mut engine.pending_ops)
+            .into_iter()
+            .for_each(WalOp::wipe);
+        AegWal::observe_seq(engine.wal_seq);
+        Ok(engine)
+    }
 
-            // Store to in-memory cache
-            let mutex = Self::global_memory_mutex();
-            let mut guard = mutex.lock().expect("Failed to lock global memory mutex");
-            guard.insert(collection_name.clone(), engine.clone());
+    /// Apply WAL records (as returned by `AegWal::replay`) in order.
+    pub(crate) fn apply_wal(&mut self, records: Vec<(u64, Vec<WalOp>)>) {
+        for (seq, ops) in records {
+            for op in ops {
+                self.apply_op(op);
+            }
+            self.wal_seq = seq;
+        }
+    }
 
-            return engine;
+    fn apply_op(&mut self, op: WalOp) {
+        match op {
+            WalOp::Set { key, value } => self.insert(key, value),
+            WalOp::Del { key } => {
+                self.remove(&key);
+            }
+            WalOp::Clear => self.clear(),
+            WalOp::Restore {
+                revision,
+                store,
+                versions,
+            } => {
+                self.revision = revision;
+                let previous = std::mem::replace(&mut self.store, store);
+                AegSecureMemory::wipe_all(previous.into_values());
+                self.versions = versions;
+            }
         }
+    }
+
+    /// Run `f` against the cached engine of `collection_name` while holding the global lock,
+    /// loading it from disk first if needed. Everything `f` does is atomic with respect to
+    /// other callers and to the background saver.
+    pub fn with_collection<R>(
+        collection_name: &str,
+        f: impl FnOnce(&mut AegMemoryEngine) -> R,
+    ) -> Result<R, String> {
+        Self::with_collections(|collections| collections.get_mut(collection_name).map(f))?
+    }
+
+    /// Run `f` with the global lock held over every collection, for operations that
+    /// must touch several collections atomically (e.g. EXEC checking WATCHed keys).
+    ///
+    /// Everything `f` changed is appended to the WAL as one record before the lock is
+    /// released, so the write is durable (per the fsync policy) when this returns. If the
+    /// append fails, the changes are undone and its error is returned instead of `f`'s result.
+    pub fn with_collections<R>(f: impl FnOnce(&mut AegCollections) -> R) -> Result<R, String> {
+        let mutex = Self::global_memory_mutex();
+        let guard = mutex.lock().expect("Failed to lock global memory mutex");
+        let mut collections = AegCollections {
+            guard,
+            touched: Vec::new(),
+        };
+        let result = f(&mut collections);
+        collections.log_pending()?;
+        Ok(result)
+    }
+
+    /// `with_collection` on the active collection.
+    pub fn with_active<R>(f: impl FnOnce(&mut AegMemoryEngine) -> R) -> Result<R, String> {
+        let core = AegCore::try_load()?;
+        Self::with_collection(&core.active_collection, f)
+    }
 
-        // Fresh engine
-        let engine = Self::new(&collection_name);
+    /// Run `f` against the cached engine of `collection_name` for reading. Nothing can be
+    /// changed, so nothing is logged; this only fails when the collection cannot be loaded.
+    pub fn read_collection<R>(
+        collection_name: &str,
+        f: impl FnOnce(&AegMemoryEngine) -> R,
+    ) -> Result<R, String> {
         let mutex = Self::global_memory_mutex();
-        let mut guard = mutex.lock().expect("Failed to lock global memory mutex");
-        guard.insert(collection_name.clone(), engine.clone());
-        engine
+        let guard = mutex.lock().expect("Failed to lock global memory mutex");
+        let mut collections = AegCollections {
+            guard,
+            touched: Vec::new(),
+        };
+        collections.get_mut(collection_name).map(|engine| f(engine))
+    }
+
+    /// `read_collection` on the active collection.
+    pub fn read_active<R>(f: impl FnOnce(&AegMemoryEngine) -> R) -> Result<R, String> {
+        let core = AegCore::try_load()?;
+        Self::read_collection(&core.active_collection, f)
+    }
+
+    /// Snapshot of the active collection (memory cache first, then disk, then fresh engine).
+    pub fn load() -> Result<Self, String> {
+        Self::read_active(|engine| engine.clone())
     }
 
     /// Start a background thread to periodically save memory to disk.
@@ -219,8 +832,8 @@ impl AegMemoryEngine {
         thread::spawn(move || {
             let interval = Duration::from_secs(interval_seconds.max(1));
             while running_ref.load(Ordering::SeqCst) {
-                // save snapshot
-                Self::save_all();
+                // save collections whose save rules are met
+                Self::save_due();
                 // sleep for interval (cooperative)
                 sleep(interval);
             }
@@ -256,3 +869,299 @@ impl AegMemoryEngine {
 // At application shutdown:
 // AegCore::stop_background_saver();               // stops the background thread
 // AegCore::flush_now();                           // optional final save
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::DataHome;
+
+    #[test]
+    fn logged_writes_to_a_collection_without_a_file_survive_a_reload() {
+        let _home = DataHome::new();
+        // as in a data directory from before every collection got a file on creation
+        fs::remove_file(AegMemoryEngine::engine_file_path("default")).unwrap();
+        AegMemoryEngine::with_collection("default", |engine| engine.insert("k", "v")).unwrap();
+        assert!(AegMemoryEngine::has_file("default"));
+
+        // a crash before the first snapshot: the cache is gone, only the log has the write
+        AegMemoryEngine::with_collections(|collections| collections.replace_all(Vec::new()))
+            .unwrap();
+        let value = AegMemoryEngine::read_collection("default", |engine| engine.get("k")).unwrap();
+        assert_eq!(value.as_deref(), Some("v"));
+    }
+
+    #[test]
+    fn a_collection_whose_logged_writes_do_not_open_fails_to_load() {
+        let _home = DataHome::new();
+        AegMemoryEngine::with_collection("default", |engine| engine.insert("k", "v")).unwrap();
+        // a file with another data key than the one the write was sealed with
+        AegMemoryEngine::save_to_disk(&AegMemoryEngine::new("default")).unwrap();
+        AegMemoryEngine::with_collections(|collections| collections.replace_all(Vec::new()))
+            .unwrap();
+
+        let error =
+            AegMemoryEngine::read_collection("default", |engine| engine.get("k")).unwrap_err();
+        assert!(error.contains("aegisr-check"), "{}", error);
+        // nothing was cached: the next access fails too, and so does a write
+        assert!(AegMemoryEngine::read_collection("default", |_| ()).is_err());
+        assert!(
+            AegMemoryEngine::with_collection("default", |engine| engine.insert("k", "w")).is_err()
+        );
+    }
+
+    #[test]
+    fn missing_files_are_created_for_listed_collections_only() {
+        let _home = DataHome::new();
+        fs::remove_file(AegMemoryEngine::engine_file_path("default")).unwrap();
+        assert_eq!(
+            AegMemoryEngine::create_missing_files().unwrap(),
+            vec!["default".to_string()]
+        );
+        assert!(AegMemoryEngine::has_file("default"));
+        assert!(AegMemoryEngine::create_missing_files().unwrap().is_empty());
+        assert!(!AegMemoryEngine::has_file("unlisted"));
+    }
+
+    #[test]
+    fn migration_counts_only_the_files_it_rewrote() {
+        let _home = DataHome::new();
+        let auth_key = AegFileSystem::read_authorization_key();
+        // a collection file in the pre-binary text format, with its key-derived nonce
+        let mut engine = AegMemoryEngine::new("legacy");
+        engine.insert("k", "v");
+        let key = AegCrypto::sealing_key(&auth_key).unwrap();
+        let key_bytes = general_purpose::STANDARD.decode(auth_key.trim()).unwrap();
+        let nonce = key_bytes[..12].try_into().unwrap();
+        let json = serde_json::to_vec(&engine).unwrap();
+        let sealed = AegCrypto::seal(&key, nonce, &[], &json).unwrap();
+        let legacy = AegMemoryEngine::engine_file_path("legacy");
+        fs::write(&legacy, general_purpose::STANDARD.encode(sealed)).unwrap();
+        // and one that does not open
+        fs::write(AegMemoryEngine::engine_file_path("damaged"), "not base64").unwrap();
+
+        assert_eq!(AegMemoryEngine::migrate_legacy_files(), 1);
+        assert!(AegSnapshotFormat::is_current(&legacy));
+        let engine = AegSnapshotFormat::read(&legacy, &auth_key, "legacy").unwrap();
+        assert_eq!(engine.get("k").as_deref(), Some("v"));
+        assert_eq!(AegMemoryEngine::migrate_legacy_files(), 0);
+    }
+
+    fn strings(items: &[&str]) -> Vec<String> {
+        items.iter().map(|item| item.to_string()).collect()
+    }
+
+    fn pairs(items: &[(&str, &str)]) -> Vec<(String, String)> {
+        items
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn msetnx_writes_nothing_when_any_key_exists() {
+        let mut engine = AegMemoryEngine::new("keys");
+        engine.mset(&pairs(&[("a", "1"), ("b", "2")]));
+        assert_eq!(
+            engine.mget(&strings(&["a", "b", "c"])),
+            vec![Some("1".to_string()), Some("2".to_string()), None]
+        );
+
+        assert!(!engine.msetnx(&pairs(&[("c", "3"), ("a", "changed")])));
+        assert_eq!(engine.get("a").as_deref(), Some("1"));
+        assert_eq!(engine.get("c"), None);
+
+        assert!(engine.msetnx(&pairs(&[("c", "3"), ("d", "4")])));
+        assert_eq!(engine.get("d").as_deref(), Some("4"));
+    }
+
+    #[test]
+    fn exists_counts_a_repeated_key_each_time() {
+        let mut engine = AegMemoryEngine::new("keys");
+        engine.insert("a", "1");
+        assert_eq!(engine.exists(&strings(&["a", "a", "missing"])), 2);
+        assert_eq!(engine.key_type("a"), "string");
+        assert_eq!(engine.key_type("missing"), "none");
+    }
+
+    #[test]
+    fn rename_with_nx_leaves_an_existing_target_alone() {
+        let mut engine = AegMemoryEngine::new("keys");
+        engine.mset(&pairs(&[("a", "1"), ("b", "2")]));
+
+        assert_eq!(engine.rename("a", "b", true), Ok(false));
+        assert_eq!(engine.get("a").as_deref(), Some("1"));
+        assert_eq!(engine.get("b").as_deref(), Some("2"));
+
+        assert_eq!(engine.rename("a", "b", false), Ok(true));
+        assert_eq!(engine.get("a"), None);
+        assert_eq!(engine.get("b").as_deref(), Some("1"));
+
+        assert!(engine.rename("a", "c", false).is_err());
+    }
+
+    #[test]
+    fn copy_overwrites_the_destination_only_with_replace() {
+        let mut engine = AegMemoryEngine::new("keys");
+        engine.mset(&pairs(&[("a", "1"), ("b", "2")]));
+
+        assert!(!engine.copy("a", "b", false));
+        assert_eq!(engine.get("b").as_deref(), Some("2"));
+        assert!(engine.copy("a", "b", true));
+        assert_eq!(engine.get("b").as_deref(), Some("1"));
+        assert_eq!(engine.get("a").as_deref(), Some("1"));
+        assert!(!engine.copy("missing", "c", true));
+    }
+
+    #[test]
+    fn set_nx_and_xx_write_only_when_the_key_is_absent_or_present() {
+        let mut engine = AegMemoryEngine::new("conditional");
+        assert_eq!(
+            engine.set_with("k", "1", SetCondition::IfPresent),
+            (false, None)
+        );
+        assert_eq!(engine.get("k"), None);
+        assert_eq!(
+            engine.set_with("k", "1", SetCondition::IfAbsent),
+            (true, None)
+        );
+        assert_eq!(
+            engine.set_with("k", "2", SetCondition::IfAbsent),
+            (false, Some("1".to_string()))
+        );
+        assert_eq!(engine.get("k").as_deref(), Some("1"));
+        assert_eq!(
+            engine.set_with("k", "2", SetCondition::IfPresent),
+            (true, Some("1".to_string()))
+        );
+        assert_eq!(engine.get("k").as_deref(), Some("2"));
+    }
+
+    #[test]
+    fn compare_and_swap_reports_the_current_version_on_a_mismatch() {
+        let mut engine = AegMemoryEngine::new("conditional");
+        // version 0 and an absent value both mean the key must not exist
+        let first = engine
+            .compare_and_swap("k", &CasExpectation::Version(0), "1")
+            .unwrap();
+        assert_eq!(
+            engine.compare_and_swap("k", &CasExpectation::Value(None), "x"),
+            Err(first)
+        );
+
+        assert_eq!(
+            engine.compare_and_swap("k", &CasExpectation::Version(first + 1), "x"),
+            Err(first)
+        );
+        assert_eq!(
+            engine.compare_and_swap("k", &CasExpectation::Value(Some("2".to_string())), "x"),
+            Err(first)
+        );
+        assert_eq!(engine.get("k").as_deref(), Some("1"));
+
+        let second = engine
+            .compare_and_swap("k", &CasExpectation::Version(first), "2")
+            .unwrap();
+        assert!(second > first);
+        assert_eq!(
+            engine.compare_and_swap("k", &CasExpectation::Value(Some("2".to_string())), "3"),
+            Ok(second + 1)
+        );
+        assert_eq!(engine.get("k").as_deref(), Some("3"));
+    }
+
+    #[test]
+    fn every_write_moves_the_key_version_that_a_watch_compares() {
+        let mut engine = AegMemoryEngine::new("watched");
+        engine.insert("k", "v");
+        let watched = engine.key_version("k");
+        // rewriting the same value still counts as a change
+        engine.insert("k", "v");
+        assert!(engine.key_version("k") > watched);
+
+        let watched = engine.key_version("k");
+        assert!(!engine.set_with("k", "other", SetCondition::IfAbsent).0);
+        engine.insert("unrelated", "v");
+        assert_eq!(engine.key_version("k"), watched);
+
+        engine.delete("k");
+        assert_eq!(engine.key_version("k"), 0);
+    }
+
+    #[test]
+    fn writes_to_several_collections_under_one_lock_are_one_log_record() {
+        let _home = DataHome::new();
+        AegCore::create_collection("other");
+        let auth_key = AegFileSystem::read_authorization_key();
+        let logged = || {
+            AegWal::read_records(&AegWal::wal_path(), &auth_key)
+                .unwrap()
+                .0
+        };
+        let before = logged().len();
+
+        AegMemoryEngine::with_collections(|collections| -> Result<(), String> {
+            collections.get_mut("default")?.insert("a", "1");
+            collections.get_mut("other")?.insert("b", "2");
+            collections.get_mut("other")?.insert("c", "3");
+            Ok(())
+        })
+        .unwrap()
+        .unwrap();
+
+        let records = logged();
+        assert_eq!(records.len(), before + 1);
+        let names: Vec<&str> = records[before]
+            .entries
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect();
+        assert_eq!(names, ["default", "other"]);
+
+        // a read-only pass logs nothing
+        AegMemoryEngine::with_collections(|collections| {
+            collections.get_mut("default").map(|engine| engine.get("a"))
+        })
+        .unwrap()
+        .unwrap();
+        assert_eq!(logged().len(), before + 1);
+    }
+
+    #[test]
+    fn only_dirty_collections_whose_save_rule_is_met_are_saved() {
+        let _home = DataHome::new();
+        AegCore::create_collection("idle");
+        let idle = AegMemoryEngine::engine_file_path("idle");
+        let idle_before = fs::read(&idle).unwrap();
+        let unsaved = || {
+            AegMemoryEngine::read_collection("default", |engine| {
+                (engine.unsaved_changes(), engine.is_dirty())
+            })
+            .unwrap()
+        };
+        AegMemoryEngine::set_save_rules(vec![SaveRule {
+            changes: 2,
+            seconds: 0,
+        }]);
+
+        AegMemoryEngine::with_collection("default", |engine| engine.insert("a", "1")).unwrap();
+        AegMemoryEngine::save_due();
+        assert_eq!(unsaved(), (1, true));
+
+        AegMemoryEngine::with_collection("default", |engine| engine.insert("b", "2")).unwrap();
+        AegMemoryEngine::save_due();
+        assert_eq!(unsaved(), (0, false));
+        let auth_key = AegFileSystem::read_authorization_key();
+        let file = AegMemoryEngine::engine_file_path("default");
+        let saved = AegSnapshotFormat::read(&file, &auth_key, "default").unwrap();
+        assert_eq!(saved.get("b").as_deref(), Some("2"));
+
+        AegMemoryEngine::with_collection("default", |engine| engine.insert("c", "3")).unwrap();
+        // cached but never written to
+        AegMemoryEngine::read_collection("idle", |engine| assert!(!engine.is_dirty())).unwrap();
+        AegMemoryEngine::save_all();
+        assert_eq!(unsaved(), (0, false));
+        assert_eq!(fs::read(&idle).unwrap(), idle_before);
+
+        AegMemoryEngine::set_save_rules(DEFAULT_SAVE_RULES.to_vec());
+    }
+}
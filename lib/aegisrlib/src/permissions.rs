@@ -0,0 +1,233 @@
+use crate::constant::{STORE_AUTHORIZATION_KEY, STORE_AUTHORIZATION_KEY_NEXT};
+use crate::file_system::AegFileSystem;
+use crate::key_provider::{AegKeyProvider, KeyProviderConfig};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Mode of directories aegisr creates: owner only.
+pub const PRIVATE_DIR_MODE: u32 = 0o700;
+/// Mode of files aegisr creates: owner read/write only.
+pub const PRIVATE_FILE_MODE: u32 = 0o600;
+
+/// What the daemon does when a key file is readable by others or not owned by it
+/// (`key_file_permissions` in the daemon config).
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PermissionPolicy {
+    /// Refuse to start (default).
+    #[default]
+    Strict,
+    /// Start anyway and log a warning.
+    Warn,
+}
+
+/// FILE PERMISSIONS
+///
+/// The data directory is created 0700 and every file in it 0600, whatever the umask. Key
+/// files are checked the way ssh checks private keys: they must be owned by the user
+/// running aegisr and not be accessible to group or others. On non-Unix platforms none of
+/// this applies.
+pub struct AegPermissions;
+
+impl AegPermissions {
+    /// `fs::create_dir_all`, with mode 0700 for every directory it creates.
+    pub fn create_private_dir(path: &Path) -> io::Result<()> {
+        let mut builder = DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, PRIVATE_DIR_MODE);
+        builder.create(path)
+    }
+
+    /// Options creating files with mode 0600; add `write`, `append` or `truncate` as needed.
+    pub fn private_file_options() -> OpenOptions {
+        let mut options = OpenOptions::new();
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, PRIVATE_FILE_MODE);
+        options
+    }
+
+    /// `File::create` with mode 0600.
+    pub fn create_private_file(path: &Path) -> io::Result<File> {
+        Self::private_file_options()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+    }
+
+    /// Key files in use (`AUTHORIZATION_KEY`, a pending `AUTHORIZATION_KEY.next`, the file of
+    /// the `file` key provider) with what is wrong with each.
+    pub fn key_file_problems() -> Vec<(PathBuf, String)> {
+        let dir = AegFileSystem::get_config_path();
+        let mut paths = vec![
+            dir.join(STORE_AUTHORIZATION_KEY),
+            dir.join(STORE_AUTHORIZATION_KEY_NEXT),
+        ];
+        if let KeyProviderConfig::File { path } = AegKeyProvider::config() {
+            paths.push(path);
+        }
+        paths
+            .into_iter()
+            .filter_map(|path| {
+                let problem = Self::key_file_problem(&path)?;
+                Some((path, problem))
+            })
+            .collect()
+    }
+
+    /// The data directory and files in it that group or others can access. A directory
+    /// others cannot enter protects everything below it, so that is not looked at.
+    pub fn loose_data_files() -> Vec<(PathBuf, u32)> {
+        let mut loose = Vec::new();
+        Self::collect_loose(&AegFileSystem::get_config_path(), &mut loose);
+        loose
+    }
+
+    /// Restrict `path` to its owner: 0700 for directories, 0600 for files.
+    pub fn tighten(path: &Path) -> Result<(), String> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = if path.is_dir() {
+                PRIVATE_DIR_MODE
+            } else {
+                PRIVATE_FILE_MODE
+            };
+            fs::set_permissions(path, fs::Permissions::from_mode(mode))
+                .map_err(|e| format!("chmod {:o} {}: {}", mode, path.display(), e))
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = path;
+            Ok(())
+        }
+    }
+
+    /// What is wrong with the permissions or owner of a key file, if anything (nothing
+    /// when it does not exist).
+    #[cfg(unix)]
+    pub fn key_file_problem(path: &Path) -> Option<String> {
+        use std::os::unix::fs::MetadataExt;
+        let metadata = fs::metadata(path).ok()?;
+        // SAFETY: geteuid takes no arguments and cannot fail.
+        let uid = unsafe { libc::geteuid() };
+        if metadata.uid() != uid {
+            return Some(format!(
+                "owned by uid {}, not by the current user (uid {})",
+                metadata.uid(),
+                uid
+            ));
+        }
+        let mode = metadata.mode() & 0o777;
+        (mode & 0o077 != 0).then(|| {
+            format!(
+                "permissions {:04o} are too open; it must not be accessible by others (chmod 600)",
+                mode
+            )
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn key_file_problem(_path: &Path) -> Option<String> {
+        None
+    }
+
+    #[cfg(unix)]
+    fn collect_loose(path: &Path, loose: &mut Vec<(PathBuf, u32)>) {
+        use std::os::unix::fs::PermissionsExt;
+        let Ok(metadata) = fs::symlink_metadata(path) else {
+            return;
+        };
+        if metadata.file_type().is_symlink() {
+            return;
+        }
+        let mode = metadata.permissions().mode() & 0o777;
+        if mode & 0o077 == 0 {
+            return;
+        }
+        loose.push((path.to_path_buf(), mode));
+        if metadata.is_dir()
+            && let Ok(entries) = fs::read_dir(path)
+        {
+            for entry in entries.flatten() {
+                Self::collect_loose(&entry.path(), loose);
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn collect_loose(_path: &Path, _loose: &mut Vec<(PathBuf, u32)>) {}
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::check::{AegCheck, CheckIssue, Resolution};
+    use crate::memory_engine::AegMemoryEngine;
+    use crate::test_support::{DataHome, TempDir};
+    use std::os::unix::fs::PermissionsExt;
+
+    fn mode(path: &Path) -> u32 {
+        fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    fn chmod(path: &Path, mode: u32) {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    #[test]
+    fn created_files_and_directories_are_private() {
+        let dir = TempDir::new();
+        let nested = dir.join("a/b");
+        AegPermissions::create_private_dir(&nested).unwrap();
+        assert_eq!(mode(&nested), PRIVATE_DIR_MODE);
+        assert_eq!(mode(&dir.join("a")), PRIVATE_DIR_MODE);
+        let file = nested.join("file");
+        AegPermissions::create_private_file(&file).unwrap();
+        assert_eq!(mode(&file), PRIVATE_FILE_MODE);
+    }
+
+    #[test]
+    fn loose_permissions_are_reported_and_repaired() {
+        let _home = DataHome::new();
+        let auth_key = AegFileSystem::read_authorization_key();
+        let dir = AegFileSystem::get_config_path();
+        let key_file = dir.join(STORE_AUTHORIZATION_KEY);
+        let collection = AegMemoryEngine::engine_file_path("default");
+        assert!(AegCheck::scan(&auth_key, false).issues.is_empty());
+
+        chmod(&dir, 0o755);
+        chmod(&key_file, 0o644);
+        chmod(&collection, 0o640);
+        let loose: Vec<PathBuf> = AegPermissions::loose_data_files()
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect();
+        assert!(
+            loose.contains(&dir) && loose.contains(&collection),
+            "{:?}",
+            loose
+        );
+        let problems = AegPermissions::key_file_problems();
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].1.contains("0644"), "{}", problems[0].1);
+        let issues = AegCheck::scan(&auth_key, false).issues;
+        assert!(
+            issues
+                .iter()
+                .all(|issue| matches!(issue, CheckIssue::InsecurePermissions { .. })),
+            "{:?}",
+            issues
+        );
+        assert_eq!(issues.len(), 3, "{:?}", issues);
+
+        AegCheck::resolve(&auth_key, Resolution::Repair).unwrap();
+        assert_eq!(mode(&dir), PRIVATE_DIR_MODE);
+        assert_eq!(mode(&key_file), PRIVATE_FILE_MODE);
+        assert_eq!(mode(&collection), PRIVATE_FILE_MODE);
+        assert!(AegCheck::scan(&auth_key, false).issues.is_empty());
+    }
+}
@@ -0,0 +1,370 @@
+use crate::audit::AegAudit;
+use crate::backup::AegBackup;
+use crate::check::AegCheck;
+use crate::constant::{
+    STORE_AUDIT_KEY, STORE_AUTHORIZATION_KEY, STORE_AUTHORIZATION_KEY_NEXT, STORE_COLLECTION,
+};
+use crate::core::AegCore;
+use crate::crypto::AegCrypto;
+use crate::file_system::AegFileSystem;
+use crate::key_provider::AegKeyProvider;
+use crate::memory_engine::{AegCollections, AegMemoryEngine};
+use crate::snapshot_format::AegSnapshotFormat;
+use crate::wal::AegWal;
+use std::fs;
+use std::path::{Path, PathBuf};
+use zeroize::Zeroizing;
+
+/// What a key rotation re-encrypted.
+#[derive(Debug, Clone, Default)]
+pub struct RotationReport {
+    /// Collection files whose data key is now wrapped with the new key.
+    pub collections: usize,
+    /// Snapshots in the backup directory re-wrapped along with them.
+    pub snapshots: usize,
+    /// Snapshots taken with an earlier key, left as they are.
+    pub skipped_snapshots: Vec<String>,
+    /// WAL records re-encrypted (offline only; the daemon empties the log instead).
+    pub wal_records: usize,
+}
+
+/// AUTHORIZATION KEY ROTATION
+///
+/// The new key is written to `AUTHORIZATION_KEY.next` first (protected with the same
+/// passphrase when the key is protected). Then the data key in every collection file is
+/// re-wrapped, `collection.lock`, the audit chain key and the WAL are re-encrypted under
+/// it, and renaming it over `AUTHORIZATION_KEY` commits the rotation. Until then the old
+/// key stays in place, and a rotation interrupted by a crash is finished by
+/// `recover_pending`: re-encrypting a file is idempotent, since each file is opened with
+/// the new key first and with the old one otherwise.
+///
+/// The collection files and `collection.lock` of every snapshot in the backup directory
+/// are re-wrapped too, so snapshots can still be restored; those taken with an earlier
+/// key (before an `init --reset`) are left as they are and reported.
+///
+/// With the `kms` key provider the new key is sealed by the KMS instead. Keys from the
+/// other providers live outside the data directory and cannot be rotated here.
+pub struct AegKeyRotation;
+
+impl AegKeyRotation {
+    fn next_key_path() -> PathBuf {
+        AegFileSystem::get_config_path().join(STORE_AUTHORIZATION_KEY_NEXT)
+    }
+
+    /// Whether a rotation was started and has not completed yet.
+    pub fn is_pending() -> bool {
+        Self::next_key_path().exists()
+    }
+
+    /// Rotate the key of a data directory no daemon is using. An interrupted rotation is
+    /// finished (with the key it started with) instead of starting a new one.
+    pub fn rotate_offline() -> Result<RotationReport, String> {
+        Self::ensure_managed()?;
+        let old_key = Zeroizing::new(AegFileSystem::resolve_authorization_key(None)?);
+        let new_key = Self::begin()?;
+        let report = Self::reencrypt_files(&old_key, &new_key)?;
+        Self::commit(&new_key)?;
+        Ok(report)
+    }
+
+    /// Rotate the key of the running daemon. Every collection is loaded, unsaved ones are
+    /// written, and all data keys re-wrapped with the engine and persistence locks held, so
+    /// no write can be sealed with the old key in between; the WAL is emptied since the
+    /// files now hold everything. If a step fails before the commit, everything already
+    /// sealed with the new key is put back under the old one, which the daemon keeps using.
+    pub fn rotate_live() -> Result<RotationReport, String> {
+        Self::ensure_managed()?;
+        if Self::is_pending() {
+            return Err(
+                "an interrupted key rotation is pending; restart the daemon to finish it".into(),
+            );
+        }
+        let _persist = AegMemoryEngine::persist_lock();
+        let old_key = Zeroizing::new(AegFileSystem::read_authorization_key());
+        AegMemoryEngine::with_collections(|collections| {
+            let new_key = Self::begin()?;
+            let mut wal_rekeyed = false;
+            match Self::reencrypt_live(collections, &old_key, &new_key, &mut wal_rekeyed) {
+                Ok(report) => Ok(report),
+                // The key file still holds the old key.
+                Err(e) if Self::is_pending() => {
+                    match Self::roll_back(&old_key, &new_key, wal_rekeyed) {
+                        Ok(()) => Err(format!("{}; the old key stays in use", e)),
+                        Err(rollback) => Err(format!(
+                            "{}; rolling back failed too ({}), restart the daemon to finish the rotation",
+                            e, rollback
+                        )),
+                    }
+                }
+                Err(e) => Err(e),
+            }
+        })?
+    }
+
+    /// The steps of `rotate_live` under its locks, up to and including the commit.
+    fn reencrypt_live(
+        collections: &mut AegCollections,
+        old_key: &str,
+        new_key: &str,
+        wal_rekeyed: &mut bool,
+    ) -> Result<RotationReport, String> {
+        let config_dir = AegFileSystem::get_config_path();
+        // Files must hold every logged write before the log is emptied.
+        for name in AegCore::try_load()?.collections {
+            let engine = collections
+                .get_mut(&name)
+                .map_err(|e| Self::failed(&name, e))?;
+            if !engine.is_dirty() {
+                continue;
+            }
+            let path = AegMemoryEngine::engine_file_path(&name);
+            AegMemoryEngine::save_to_path_with_key(engine, &path, new_key)
+                .map_err(|e| Self::failed(&name, e))?;
+            engine.mark_saved();
+        }
+        let collections = Self::reencrypt_collections(&config_dir, old_key, new_key)
+            .map_err(|e| Self::failed("collection files", e))?;
+        let (snapshots, skipped_snapshots) = Self::reencrypt_snapshots(old_key, new_key)
+            .map_err(|e| Self::failed("snapshots", e))?;
+        AegFileSystem::reencrypt_collection_lock(old_key, new_key)
+            .map_err(|e| Self::failed("collection.lock", e))?;
+        AegAudit::reencrypt_key(old_key, new_key).map_err(|e| Self::failed(STORE_AUDIT_KEY, e))?;
+        AegWal::rekey(new_key).map_err(|e| Self::failed("the WAL", e))?;
+        *wal_rekeyed = true;
+        Self::commit(new_key).map_err(|e| Self::failed("the key file", e))?;
+        Ok(RotationReport {
+            collections,
+            snapshots,
+            skipped_snapshots,
+            wal_records: 0,
+        })
+    }
+
+    /// Undo a live rotation that failed before its commit: what is sealed with `new_key`
+    /// goes back under `old_key`, and `AUTHORIZATION_KEY.next` is removed. The WAL is only
+    /// switched back if it was switched; it is empty then, as every file was saved first.
+    fn roll_back(old_key: &str, new_key: &str, wal_rekeyed: bool) -> Result<(), String> {
+        Self::reencrypt_collections(&AegFileSystem::get_config_path(), new_key, old_key)?;
+        Self::reencrypt_snapshots(new_key, old_key)?;
+        AegFileSystem::reencrypt_collection_lock(new_key, old_key)?;
+        AegAudit::reencrypt_key(new_key, old_key)?;
+        if wal_rekeyed {
+            AegWal::rekey(old_key)?;
+        }
+        fs::remove_file(Self::next_key_path())
+            .map_err(|e| format!("remove {}: {}", STORE_AUTHORIZATION_KEY_NEXT, e))?;
+        AegFileSystem::sync_dir(&AegFileSystem::get_config_path())
+    }
+
+    /// Finish a rotation interrupted by a crash. Call before anything reads the data
+    /// directory. Returns `None` when there was nothing to finish.
+    pub fn recover_pending() -> Result<Option<RotationReport>, String> {
+        if !Self::is_pending() {
+            return Ok(None);
+        }
+        let old_key = Zeroizing::new(AegFileSystem::resolve_authorization_key(None)?);
+        let new_key = Self::begin()?;
+        let report = Self::reencrypt_files(&old_key, &new_key)?;
+        Self::commit(&new_key)?;
+        Ok(Some(report))
+    }
+
+    /// The key being rotated to: read back from `AUTHORIZATION_KEY.next`, or generated and
+    /// written there when no rotation is in progress.
+    fn begin() -> Result<Zeroizing<String>, String> {
+        let path = Self::next_key_path();
+        if let Ok(content) = fs::read_to_string(&path) {
+            return AegKeyProvider::decode(&content)
+                .map_err(|e| format!("{}: {}", STORE_AUTHORIZATION_KEY_NEXT, e));
+        }
+        let new_key = AegCrypto::create_authorization_key(None);
+        let content = AegKeyProvider::encode(&new_key)?;
+        AegFileSystem::write_atomic(&path, content.as_bytes())?;
+        Ok(new_key)
+    }
+
+    /// Bring every collection file, `collection.lock`, the snapshots and the WAL under
+    /// `new_key`.
+    fn reencrypt_files(old_key: &str, new_key: &str) -> Result<RotationReport, String> {
+        let collections =
+            Self::reencrypt_collections(&AegFileSystem::get_config_path(), old_key, new_key)?;
+        AegFileSystem::reencrypt_collection_lock(old_key, new_key)?;
+        AegAudit::reencrypt_key(old_key, new_key)?;
+        let (snapshots, skipped_snapshots) = Self::reencrypt_snapshots(old_key, new_key)?;
+        let wal_records = AegWal::reencrypt(old_key, new_key)?;
+        Ok(RotationReport {
+            collections,
+            snapshots,
+            skipped_snapshots,
+            wal_records,
+        })
+    }
+
+    /// Bring the collection files and `collection.lock` of every snapshot under `new_key`.
+    /// Returns how many were re-wrapped, and the names of those whose `collection.lock`
+    /// opens with neither key: taken with an earlier key, they could not be restored
+    /// before the rotation either, and are left as they are.
+    fn reencrypt_snapshots(old_key: &str, new_key: &str) -> Result<(usize, Vec<String>), String> {
+        let mut count = 0;
+        let mut skipped = Vec::new();
+        for dir in AegBackup::snapshot_dirs() {
+            let snapshot = dir
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string();
+            let lock_path = dir.join(STORE_COLLECTION);
+            if AegFileSystem::reencrypt_collection_lock_at(&lock_path, old_key, new_key).is_err() {
+                skipped.push(snapshot);
+                continue;
+            }
+            Self::reencrypt_collections(&dir, old_key, new_key)
+                .map_err(|e| format!("snapshot {}: {}", snapshot, e))?;
+            count += 1;
+        }
+        Ok((count, skipped))
+    }
+
+    /// Bring every collection file in `dir` under `new_key`: current files only get their
+    /// data key re-wrapped, older ones are rewritten. Returns how many files there are.
+    fn reencrypt_collections(dir: &Path, old_key: &str, new_key: &str) -> Result<usize, String> {
+        let mut count = 0;
+        let (files, _) = AegCheck::list_files(dir);
+        for name in &files {
+            let path = dir.join(format!("collection_{}.aekv", name));
+            if fs::metadata(&path).map(|m| m.len()).unwrap_or(0) == 0 {
+                continue;
+            }
+            count += 1;
+            if AegSnapshotFormat::is_current(&path) {
+                AegSnapshotFormat::rewrap(&path, old_key, new_key, name)
+                    .map_err(|e| format!("collection '{}': {} (run aegisr-check)", name, e))?;
+                continue;
+            }
+            let read = |key: &str| {
+                if AegSnapshotFormat::is_binary(&path) {
+                    AegSnapshotFormat::read(&path, key, name)
+                } else {
+                    AegMemoryEngine::load_legacy(&path, key, name)
+                }
+            };
+            let engine = read(old_key)
+                .or_else(|_| read(new_key))
+                .map_err(|e| format!("collection '{}': {} (run aegisr-check)", name, e))?;
+            AegMemoryEngine::save_to_path_with_key(&engine, &path, new_key)?;
+        }
+        Ok(count)
+    }
+
+    /// The commit point: the new key replaces the old one in the key file. Once renamed,
+    /// the new key is the one in use, even if syncing the directory fails.
+    fn commit(new_key: &str) -> Result<(), String> {
+        let dir = AegFileSystem::get_config_path();
+        fs::rename(Self::next_key_path(), dir.join(STORE_AUTHORIZATION_KEY))
+            .map_err(|e| format!("replace {}: {}", STORE_AUTHORIZATION_KEY, e))?;
+        AegKeyProvider::replace_key(new_key);
+        AegFileSystem::sync_dir(&dir)
+    }
+
+    /// Only a key kept in the data directory can be replaced; keys from other providers
+    /// are rotated where they are managed.
+    fn ensure_managed() -> Result<(), String> {
+        if AegKeyProvider::is_managed() {
+            return Ok(());
+        }
+        Err(format!(
+            "the authorization key comes from {} and cannot be rotated by aegisr",
+            AegKeyProvider::describe()
+        ))
+    }
+
+    fn failed(what: &str, e: String) -> String {
+        format!("re-encrypting {} failed: {}", what, e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::DataHome;
+
+    #[test]
+    fn rotate_offline_finishes_an_interrupted_rotation_with_its_key() {
+        let _home = DataHome::new();
+        AegMemoryEngine::with_collection("default", |engine| engine.insert("saved", "1")).unwrap();
+        AegMemoryEngine::read_collection("default", AegMemoryEngine::save_to_disk)
+            .unwrap()
+            .unwrap();
+        AegMemoryEngine::with_collection("default", |engine| engine.insert("logged", "2")).unwrap();
+        let old_key = AegFileSystem::read_authorization_key();
+
+        // interrupted after the collection files were re-wrapped
+        let new_key = AegKeyRotation::begin().unwrap();
+        AegKeyRotation::reencrypt_collections(
+            &AegFileSystem::get_config_path(),
+            &old_key,
+            &new_key,
+        )
+        .unwrap();
+        assert!(AegKeyRotation::is_pending());
+
+        let report = AegKeyRotation::rotate_offline().unwrap();
+        assert!(!AegKeyRotation::is_pending());
+        assert_eq!(report.collections, 1);
+        assert_eq!(report.wal_records, 2);
+        assert_eq!(*AegFileSystem::read_authorization_key(), *new_key);
+
+        assert!(AegFileSystem::decrypt_collection_lock(&new_key).is_ok());
+        assert!(AegFileSystem::decrypt_collection_lock(&old_key).is_err());
+        let path = AegMemoryEngine::engine_file_path("default");
+        assert!(AegSnapshotFormat::read(&path, &old_key, "default").is_err());
+        let mut engine = AegSnapshotFormat::read(&path, &new_key, "default").unwrap();
+        assert_eq!(engine.get("saved").as_deref(), Some("1"));
+        engine.apply_wal(
+            AegWal::collection_ops(
+                &AegWal::wal_path(),
+                &new_key,
+                "default",
+                engine.wal_seq,
+                &engine.data_key,
+            )
+            .unwrap(),
+        );
+        assert_eq!(engine.get("logged").as_deref(), Some("2"));
+    }
+
+    #[test]
+    fn rotation_rewraps_snapshots_so_they_can_still_be_restored() {
+        let _home = DataHome::new();
+        AegMemoryEngine::with_collection("default", |engine| engine.insert("k", "1")).unwrap();
+        let snapshot = AegBackup::create_snapshot().unwrap();
+        let old_key = AegFileSystem::read_authorization_key();
+        // one taken before an `init --reset`, under a key that is gone (snapshot names
+        // have millisecond resolution)
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        let earlier = AegBackup::create_snapshot().unwrap();
+        let earlier_dir = AegBackup::backup_dir().join(&earlier.name);
+        let earlier_key = AegCrypto::create_authorization_key(None);
+        AegKeyRotation::reencrypt_collections(&earlier_dir, &old_key, &earlier_key).unwrap();
+        AegFileSystem::reencrypt_collection_lock_at(
+            &earlier_dir.join(STORE_COLLECTION),
+            &old_key,
+            &earlier_key,
+        )
+        .unwrap();
+
+        let report = AegKeyRotation::rotate_offline().unwrap();
+        assert_eq!(report.snapshots, 1);
+        assert_eq!(report.skipped_snapshots, vec![earlier.name.clone()]);
+        let earlier_file = earlier_dir.join("collection_default.aekv");
+        assert!(AegSnapshotFormat::read(&earlier_file, &earlier_key, "default").is_ok());
+        let dir = AegBackup::backup_dir().join(&snapshot.name);
+        let path = dir.join("collection_default.aekv");
+        assert!(AegSnapshotFormat::read(&path, &old_key, "default").is_err());
+
+        AegMemoryEngine::with_collection("default", |engine| engine.insert("k", "2")).unwrap();
+        AegBackup::restore_snapshot(&snapshot.name).unwrap();
+        let value = AegMemoryEngine::read_collection("default", |engine| engine.get("k")).unwrap();
+        assert_eq!(value.as_deref(), Some("1"));
+    }
+}
@@ -0,0 +1,340 @@
+use crate::memory_engine::AegMemoryEngine;
+use rhai::{Array, Dynamic, Engine, EvalAltResult, Scope};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// Default wall-clock budget for a single script run.
+pub const DEFAULT_SCRIPT_TIME_LIMIT_MS: u64 = 5_000;
+
+/// SCRIPT CACHE (sha -> source), shared by every connection
+static SCRIPT_CACHE: OnceLock<Mutex<HashMap<String, String>>> = OnceLock::new();
+static SCRIPT_TIME_LIMIT_MS: AtomicU64 = AtomicU64::new(DEFAULT_SCRIPT_TIME_LIMIT_MS);
+static SCRIPT_LIMITS: OnceLock<Mutex<ScriptLimits>> = OnceLock::new();
+
+/// Caps on what a single script run may build or do, so a script cannot exhaust the
+/// daemon's memory or stack while it holds the engine lock. 0 means no limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScriptLimits {
+    /// Longest string, in bytes.
+    pub max_string_size: usize,
+    /// Most elements in an array.
+    pub max_array_size: usize,
+    /// Most properties in an object map.
+    pub max_map_size: usize,
+    /// Deepest nesting of function calls.
+    pub max_call_levels: usize,
+    /// Most operations in one run.
+    pub max_operations: u64,
+}
+
+impl Default for ScriptLimits {
+    fn default() -> Self {
+        Self {
+            max_string_size: 16 * 1024 * 1024,
+            max_array_size: 1_000_000,
+            max_map_size: 100_000,
+            max_call_levels: 64,
+            max_operations: 10_000_000,
+        }
+    }
+}
+
+/// Server-side scripting (Rhai).
+///
+/// Scripts see the collection they run against through `get`, `set`, `del`,
+/// `exists` and `keys`, plus the `KEYS` and `ARGV` arrays. Writes are buffered
+/// and only applied to the engine when the script finishes without error, so a
+/// failing or timed-out script leaves the collection untouched.
+pub struct AegScripting;
+
+/// Script view of a collection: the untouched engine plus the script's pending writes.
+struct ScriptState {
+    base: AegMemoryEngine,
+    /// Latest value written per key; `None` = deleted.
+    writes: BTreeMap<String, Option<String>>,
+}
+
+impl ScriptState {
+    fn get(&self, key: &str) -> Option<String> {
+        match self.writes.get(key) {
+            Some(v) => v.clone(),
+            None => self.base.get(key),
+        }
+    }
+
+    fn keys(&self) -> Vec<String> {
+        let mut keys: Vec<String> = self
+            .base
+            .store
+            .keys()
+            .filter(|k| !self.writes.contains_key(*k))
+            .cloned()
+            .collect();
+        keys.extend(
+            self.writes
+                .iter()
+                .filter(|(_, v)| v.is_some())
+                .map(|(k, _)| k.clone()),
+        );
+        keys.sort();
+        keys
+    }
+
+    /// Apply buffered writes through the engine so key versions are bumped as usual.
+    fn commit(mut self) -> AegMemoryEngine {
+        for (key, value) in std::mem::take(&mut self.writes) {
+            match value {
+                Some(v) => self.base.insert(key, v),
+                None => {
+                    self.base.remove(&key);
+                }
+            }
+        }
+        self.base
+    }
+}
+
+impl AegScripting {
+    fn script_cache() -> &'static Mutex<HashMap<String, String>> {
+        SCRIPT_CACHE.get_or_init(|| Mutex::new(HashMap::new()))
+    }
+
+    /// Set the wall-clock limit applied to every script run.
+    pub fn set_time_limit(limit: Duration) {
+        SCRIPT_TIME_LIMIT_MS.store(limit.as_millis() as u64, Ordering::SeqCst);
+    }
+
+    pub fn time_limit() -> Duration {
+        Duration::from_millis(SCRIPT_TIME_LIMIT_MS.load(Ordering::SeqCst))
+    }
+
+    fn limits_slot() -> &'static Mutex<ScriptLimits> {
+        SCRIPT_LIMITS.get_or_init(|| Mutex::new(ScriptLimits::default()))
+    }
+
+    /// Set the size, depth and operation limits applied to every script run.
+    pub fn set_limits(limits: ScriptLimits) {
+        *Self::limits_slot()
+            .lock()
+            .expect("Failed to lock script limits") = limits;
+    }
+
+    pub fn limits() -> ScriptLimits {
+        *Self::limits_slot()
+            .lock()
+            .expect("Failed to lock script limits")
+    }
+
+    /// Identifier of a script in the cache (blake3 of its source, hex).
+    pub fn script_sha(source: &str) -> String {
+        blake3::hash(source.as_bytes()).to_hex().to_string()
+    }
+
+    /// Check that `source` compiles and store it in the cache. Returns its sha.
+    pub fn script_load(source: &str) -> Result<String, String> {
+        Engine::new()
+            .compile(source)
+            .map_err(|e| format!("Script compile error: {}", e))?;
+        let sha = Self::script_sha(source);
+        let mut cache = Self::script_cache()
+            .lock()
+            .expect("Failed to lock script cache");
+        cache.insert(sha.clone(), source.to_string());
+        Ok(sha)
+    }
+
+    pub fn script_exists(sha: &str) -> bool {
+        let cache = Self::script_cache()
+            .lock()
+            .expect("Failed to lock script cache");
+        cache.contains_key(sha)
+    }
+
+    pub fn script_flush() {
+        let mut cache = Self::script_cache()
+            .lock()
+            .expect("Failed to lock script cache");
+        cache.clear();
+    }
+
+    /// Run `source` against `engine` (EVAL). Only `script_load` adds scripts to the
+    /// cache, so clients sending ever-new sources cannot grow it.
+    /// The caller must hold the engine through `AegMemoryEngine::with_collection`.
+    pub fn eval_in(
+        engine: &mut AegMemoryEngine,
+        source: &str,
+        keys: &[String],
+        args: &[String],
+    ) -> Result<serde_json::Value, String> {
+        Self::run(engine, source, keys, args)
+    }
+
+    /// Run a cached script by sha (EVALSHA).
+    pub fn eval_sha_in(
+        engine: &mut AegMemoryEngine,
+        sha: &str,
+        keys: &[String],
+        args: &[String],
+    ) -> Result<serde_json::Value, String> {
+        let source = {
+            let cache = Self::script_cache()
+                .lock()
+                .expect("Failed to lock script cache");
+            cache.get(sha).cloned()
+        };
+        match source {
+            Some(source) => Self::run(engine, &source, keys, args),
+            None => Err(format!(
+                "No script with sha '{}'. Use script-load first",
+                sha
+            )),
+        }
+    }
+
+    fn run(
+        engine: &mut AegMemoryEngine,
+        source: &str,
+        keys: &[String],
+        args: &[String],
+    ) -> Result<serde_json::Value, String> {
+        // Move the engine out for the duration of the run (cheap), restore it afterwards.
+        let placeholder = AegMemoryEngine::new(&engine.collection_name);
+        let state = Rc::new(RefCell::new(ScriptState {
+            base: std::mem::replace(engine, placeholder),
+            writes: BTreeMap::new(),
+        }));
+
+        let result = {
+            let rhai = Self::build_engine(&state);
+            let mut scope = Scope::new();
+            scope.push_constant("KEYS", Self::to_array(keys));
+            scope.push_constant("ARGV", Self::to_array(args));
+            rhai.eval_with_scope::<Dynamic>(&mut scope, source)
+                .map_err(|e| Self::describe_error(*e))
+        };
+
+        // The Rhai engine (and the closures sharing the state) is gone by now.
+        let Ok(state) = Rc::try_unwrap(state) else {
+            unreachable!("script state outlived the script engine");
+        };
+        let state = state.into_inner();
+        let result = result.and_then(|value| {
+            rhai::serde::from_dynamic::<serde_json::Value>(&value)
+                .map_err(|e| format!("Script returned an unsupported value: {}", e))
+        });
+        match result {
+            Ok(value) => {
+                *engine = state.commit();
+                Ok(value)
+            }
+            Err(e) => {
+                // discard buffered writes
+                *engine = state.base;
+                Err(e)
+            }
+        }
+    }
+
+    fn build_engine(state: &Rc<RefCell<ScriptState>>) -> Engine {
+        let mut rhai = Engine::new();
+        rhai.on_print(|_| {});
+        rhai.on_debug(|_, _, _| {});
+
+        let limits = Self::limits();
+        rhai.set_max_string_size(limits.max_string_size);
+        rhai.set_max_array_size(limits.max_array_size);
+        rhai.set_max_map_size(limits.max_map_size);
+        rhai.set_max_call_levels(limits.max_call_levels);
+        rhai.set_max_operations(limits.max_operations);
+
+        let limit = Self::time_limit();
+        let started = Instant::now();
+        rhai.on_progress(move |_| {
+            if started.elapsed() > limit {
+                Some(Dynamic::from(format!(
+                    "Script exceeded time limit of {} ms",
+                    limit.as_millis()
+                )))
+            } else {
+                None
+            }
+        });
+
+        let s = state.clone();
+        rhai.register_fn("get", move |key: &str| -> Dynamic {
+            s.borrow()
+                .get(key)
+                .map(Dynamic::from)
+                .unwrap_or(Dynamic::UNIT)
+        });
+        let s = state.clone();
+        rhai.register_fn("set", move |key: &str, value: &str| {
+            s.borrow_mut()
+                .writes
+                .insert(key.to_string(), Some(value.to_string()));
+        });
+        let s = state.clone();
+        rhai.register_fn("del", move |key: &str| -> bool {
+            let mut st = s.borrow_mut();
+            let existed = st.get(key).is_some();
+            st.writes.insert(key.to_string(), None);
+            existed
+        });
+        let s = state.clone();
+        rhai.register_fn("exists", move |key: &str| -> bool {
+            s.borrow().get(key).is_some()
+        });
+        let s = state.clone();
+        rhai.register_fn("keys", move || -> Array {
+            s.borrow().keys().into_iter().map(Dynamic::from).collect()
+        });
+
+        rhai
+    }
+
+    fn to_array(items: &[String]) -> Array {
+        items.iter().cloned().map(Dynamic::from).collect()
+    }
+
+    fn describe_error(err: EvalAltResult) -> String {
+        match err {
+            EvalAltResult::ErrorTerminated(reason, _) => reason.to_string(),
+            other => format!("Script error: {}", other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scripts_that_outgrow_the_limits_fail_without_writing() {
+        let mut engine = AegMemoryEngine::new("default");
+        let grow = r#"set("k", "v"); let s = "x"; loop { s += s; }"#;
+        let error = AegScripting::eval_in(&mut engine, grow, &[], &[]).unwrap_err();
+        assert!(error.contains("too large"), "{}", error);
+        assert_eq!(engine.get("k"), None);
+
+        let recurse = "fn down(n) { down(n + 1) } down(0)";
+        let error = AegScripting::eval_in(&mut engine, recurse, &[], &[]).unwrap_err();
+        assert!(error.contains("Stack overflow"), "{}", error);
+    }
+
+    #[test]
+    fn eval_does_not_cache_its_script() {
+        let mut engine = AegMemoryEngine::new("default");
+        let source = "40 + 2";
+        let value = AegScripting::eval_in(&mut engine, source, &[], &[]).unwrap();
+        assert_eq!(value, serde_json::json!(42));
+        assert!(!AegScripting::script_exists(&AegScripting::script_sha(source)));
+
+        let sha = AegScripting::script_load(source).unwrap();
+        let value = AegScripting::eval_sha_in(&mut engine, &sha, &[], &[]).unwrap();
+        assert_eq!(value, serde_json::json!(42));
+    }
+}
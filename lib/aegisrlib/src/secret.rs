@@ -0,0 +1,90 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use zeroize::{Zeroize, Zeroizing};
+
+/// Key material or other secret text; overwritten with zeros when dropped.
+pub type SecretString = Zeroizing<String>;
+/// Decrypted data or raw key bytes; overwritten with zeros when dropped.
+pub type SecretBytes = Zeroizing<Vec<u8>>;
+
+static SECURE_MEMORY: AtomicBool = AtomicBool::new(false);
+
+/// SECURE MEMORY
+///
+/// Key material and decrypted buffers are always zeroized when dropped. Collection values
+/// are plain `String`s for speed; with `secure_memory` enabled (daemon config) they are
+/// zeroized too when deleted, overwritten or cleared, or when their collection is dropped.
+/// Enabling it also locks the process memory (`mlockall`) so nothing is written to swap,
+/// and disables core dumps.
+pub struct AegSecureMemory;
+
+impl AegSecureMemory {
+    /// Turn secure memory on. Values are zeroized from now on even if locking the memory
+    /// fails; the error says why it did.
+    pub fn enable() -> Result<(), String> {
+        SECURE_MEMORY.store(true, Ordering::SeqCst);
+        Self::lock_memory()
+    }
+
+    pub fn is_enabled() -> bool {
+        SECURE_MEMORY.load(Ordering::Relaxed)
+    }
+
+    /// Drop a value removed from a collection, zeroizing it first in secure mode.
+    pub fn wipe(mut value: String) {
+        if Self::is_enabled() {
+            value.zeroize();
+        }
+    }
+
+    /// `wipe` every value.
+    pub fn wipe_all(values: impl IntoIterator<Item = String>) {
+        if !Self::is_enabled() {
+            return;
+        }
+        for mut value in values {
+            value.zeroize();
+        }
+    }
+
+    #[cfg(unix)]
+    fn lock_memory() -> Result<(), String> {
+        let no_core = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        // SAFETY: plain syscalls on a valid struct; no memory is handed over.
+        unsafe {
+            libc::setrlimit(libc::RLIMIT_CORE, &no_core);
+            #[cfg(target_os = "linux")]
+            libc::prctl(libc::PR_SET_DUMPABLE, 0);
+        }
+
+        // With MCL_FUTURE every later allocation must fit under RLIMIT_MEMLOCK, so only lock
+        // when the limit cannot be hit; otherwise the daemon would fail allocating later.
+        let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        // SAFETY: `limit` is a valid, writable rlimit.
+        let unlimited = unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut limit) } == 0
+            && limit.rlim_cur == libc::RLIM_INFINITY;
+        // SAFETY: no arguments besides the uid query.
+        let privileged = unsafe { libc::geteuid() } == 0;
+        if !unlimited && !privileged {
+            return Err(
+                "RLIMIT_MEMLOCK is limited; raise it (ulimit -l unlimited, LimitMEMLOCK=infinity) to lock memory"
+                    .into(),
+            );
+        }
+        // SAFETY: mlockall takes flags only.
+        if unsafe { libc::mlockall(libc::MCL_CURRENT | libc::MCL_FUTURE) } != 0 {
+            return Err(format!("mlockall: {}", std::io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn lock_memory() -> Result<(), String> {
+        Err("locking memory is not supported on this platform".into())
+    }
+}
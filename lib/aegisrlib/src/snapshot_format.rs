@@ -0,0 +1,911 @@
+use crate::crypto::{AegCrypto, DataKey, NONCE_LEN, SealingKey};
+use crate::file_system::AegFileSystem;
+use crate::memory_engine::AegMemoryEngine;
+use crate::secret::SecretBytes;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+use zeroize::Zeroizing;
+
+/// First bytes of every binary collection snapshot.
+pub const SNAPSHOT_MAGIC: &[u8; 4] = b"AEKV";
+/// Current snapshot format version. Version 3 seals frames with the collection's data key,
+/// stored in the header wrapped by the authorization key. Version 2 authenticates the
+/// header fields and the collection name with every frame. Versions 1 and 2 (frames
+/// sealed with the authorization key) are still read.
+pub const SNAPSHOT_VERSION: u8 = 3;
+/// AES-256-GCM, one sealed frame per chunk of records.
+pub const CIPHER_AES_256_GCM: u8 = 1;
+
+/// magic(4) version(1) cipher(1) compression(1) reserved(1) nonce(12) checksum(32),
+/// followed in version 3 by the wrapped data key.
+pub const SNAPSHOT_HEADER_LEN: usize = 52;
+/// Wrapped data key in version 3 headers: nonce(12) AES-GCM(key(32)) tag(16).
+pub const WRAPPED_DATA_KEY_LEN: usize = NONCE_LEN + 32 + 16;
+/// Associated data of the wrapped data key, followed by the collection name.
+const DATA_KEY_AAD: &[u8] = b"aegisr collection data key v3";
+/// Plaintext size at which a frame is sealed and a new one started.
+const FRAME_TARGET_LEN: usize = 64 * 1024;
+/// Upper bound accepted when reading a frame length (guards against corrupt headers).
+const FRAME_MAX_LEN: u32 = 1 << 30;
+/// zstd level used for snapshots (fast, still a good ratio on JSON-like text).
+const ZSTD_LEVEL: i32 = 3;
+
+/// Compression applied to each frame before it is sealed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+}
+
+impl Compression {
+    fn id(self) -> u8 {
+        match self {
+            Self::None => 0,
+            Self::Zstd => 1,
+        }
+    }
+
+    fn from_id(id: u8) -> Result<Self, String> {
+        match id {
+            0 => Ok(Self::None),
+            1 => Ok(Self::Zstd),
+            other => Err(format!("unsupported compression id {}", other)),
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(Self::None),
+            "zstd" => Ok(Self::Zstd),
+            other => Err(format!(
+                "Unknown compression '{}' (expected none or zstd)",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::None => "none",
+            Self::Zstd => "zstd",
+        })
+    }
+}
+
+/// Global default and per-collection overrides for snapshot compression.
+#[derive(Debug, Clone, Default)]
+struct CompressionConfig {
+    default: Compression,
+    collections: HashMap<String, Compression>,
+}
+
+static COMPRESSION: OnceLock<Mutex<CompressionConfig>> = OnceLock::new();
+
+/// Fixed-size header at the start of a snapshot file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotHeader {
+    pub version: u8,
+    pub cipher: u8,
+    pub compression: Compression,
+    /// Base nonce, random per file. Frame `i` is sealed with this nonce XOR `i`.
+    pub nonce: [u8; NONCE_LEN],
+    /// blake3 of everything after the header; verifiable without the key.
+    pub checksum: [u8; 32],
+    /// Data key of the frames, sealed with the authorization key (version 3).
+    pub wrapped_key: Option<[u8; WRAPPED_DATA_KEY_LEN]>,
+}
+
+impl SnapshotHeader {
+    /// Header size in bytes, which depends on the version.
+    pub fn size(&self) -> usize {
+        match self.wrapped_key {
+            Some(_) => SNAPSHOT_HEADER_LEN + WRAPPED_DATA_KEY_LEN,
+            None => SNAPSHOT_HEADER_LEN,
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut out = vec![0u8; SNAPSHOT_HEADER_LEN];
+        out[..4].copy_from_slice(SNAPSHOT_MAGIC);
+        out[4] = self.version;
+        out[5] = self.cipher;
+        out[6] = self.compression.id();
+        out[8..20].copy_from_slice(&self.nonce);
+        out[20..].copy_from_slice(&self.checksum);
+        if let Some(wrapped_key) = &self.wrapped_key {
+            out.extend_from_slice(wrapped_key);
+        }
+        out
+    }
+
+    fn from_bytes(bytes: &[u8; SNAPSHOT_HEADER_LEN]) -> Result<Self, String> {
+        if &bytes[..4] != SNAPSHOT_MAGIC {
+            return Err("not a binary snapshot (bad magic)".into());
+        }
+        let header = Self {
+            version: bytes[4],
+            cipher: bytes[5],
+            compression: Compression::from_id(bytes[6])?,
+            nonce: bytes[8..20].try_into().expect("nonce slice"),
+            checksum: bytes[20..].try_into().expect("checksum slice"),
+            wrapped_key: None,
+        };
+        if !(1..=SNAPSHOT_VERSION).contains(&header.version) {
+            return Err(format!("unsupported snapshot version {}", header.version));
+        }
+        if header.cipher != CIPHER_AES_256_GCM {
+            return Err(format!("unsupported cipher id {}", header.cipher));
+        }
+        Ok(header)
+    }
+
+    /// Read the header at the start of `input`, leaving it positioned at the first frame.
+    fn read_from(input: &mut impl Read) -> Result<Self, String> {
+        let mut bytes = [0u8; SNAPSHOT_HEADER_LEN];
+        input
+            .read_exact(&mut bytes)
+            .map_err(|_| "file too short for a snapshot header".to_string())?;
+        let mut header = Self::from_bytes(&bytes)?;
+        if header.version >= 3 {
+            let mut wrapped_key = [0u8; WRAPPED_DATA_KEY_LEN];
+            input
+                .read_exact(&mut wrapped_key)
+                .map_err(|_| "file too short for a snapshot header".to_string())?;
+            header.wrapped_key = Some(wrapped_key);
+        }
+        Ok(header)
+    }
+
+    fn data_key_aad(collection_name: &str) -> Vec<u8> {
+        let mut aad = DATA_KEY_AAD.to_vec();
+        aad.extend_from_slice(collection_name.as_bytes());
+        aad
+    }
+
+    /// Seal a data key for the header of `collection_name`.
+    fn wrap_key(
+        data_key: &DataKey,
+        auth_key: &str,
+        collection_name: &str,
+    ) -> Result<[u8; WRAPPED_DATA_KEY_LEN], String> {
+        let key = AegCrypto::sealing_key(auth_key)?;
+        let sealed = AegCrypto::seal_with_nonce(
+            &key,
+            &Self::data_key_aad(collection_name),
+            data_key.as_bytes(),
+        )?;
+        Ok(sealed.try_into().expect("wrapped data key length"))
+    }
+
+    /// The data key, unsealed with `auth_key`.
+    fn unwrap_key(&self, auth_key: &str, collection_name: &str) -> Result<DataKey, String> {
+        let wrapped_key = self.wrapped_key.as_ref().ok_or("no data key in header")?;
+        let key = AegCrypto::sealing_key(auth_key)?;
+        let plain =
+            AegCrypto::open_with_nonce(&key, &Self::data_key_aad(collection_name), wrapped_key)
+                .map_err(|_| "data key does not decrypt with this authorization key".to_string())?;
+        let bytes: [u8; 32] = plain
+            .as_slice()
+            .try_into()
+            .map_err(|_| "invalid data key".to_string())?;
+        Ok(DataKey::from_bytes(bytes))
+    }
+
+    /// Key the frames are sealed with: the data key (version 3, also returned) or the
+    /// authorization key.
+    fn frame_key(
+        &self,
+        auth_key: &str,
+        collection_name: &str,
+    ) -> Result<(SealingKey, Option<DataKey>), String> {
+        if self.wrapped_key.is_none() {
+            return Ok((AegCrypto::sealing_key(auth_key)?, None));
+        }
+        let data_key = self.unwrap_key(auth_key, collection_name)?;
+        Ok((data_key.sealing_key(), Some(data_key)))
+    }
+
+    /// Associated data of every frame: the header up to the nonce plus the collection
+    /// name, so a file only opens as the collection it was written for.
+    fn aad(&self, collection_name: &str) -> Vec<u8> {
+        if self.version < 2 {
+            return Vec::new();
+        }
+        let mut aad = self.to_bytes()[..8].to_vec();
+        aad.extend_from_slice(collection_name.as_bytes());
+        aad
+    }
+}
+
+/// What `AegSnapshotFormat::salvage` could recover from a damaged file.
+pub struct SalvagedSnapshot {
+    pub engine: AegMemoryEngine,
+    /// Record count stored in the metadata frame, if that frame was readable.
+    pub expected: Option<u64>,
+    /// Frames that failed to decrypt or decompress and were skipped.
+    pub lost_frames: usize,
+}
+
+/// BINARY COLLECTION SNAPSHOT FORMAT
+///
+/// `header | frame* | end`, where each frame is `len: u32 LE | AES-GCM ciphertext` and
+/// `end` is a zero length. Frame 0 holds the collection metadata, later frames hold
+/// records (`key`, `value`, `version`). Frames are written and read one at a time, so
+/// neither side needs the whole collection serialized in memory.
+pub struct AegSnapshotFormat;
+
+impl AegSnapshotFormat {
+    fn compression_config() -> &'static Mutex<CompressionConfig> {
+        COMPRESSION.get_or_init(|| Mutex::new(CompressionConfig::default()))
+    }
+
+    /// Compression for newly written snapshots: `default` for every collection except
+    /// those listed in `collections`. Existing files keep theirs until rewritten.
+    pub fn set_compression(default: Compression, collections: HashMap<String, Compression>) {
+        *Self::compression_config()
+            .lock()
+            .expect("Failed to lock compression config") = CompressionConfig {
+            default,
+            collections,
+        };
+    }
+
+    pub fn compression_for(collection_name: &str) -> Compression {
+        let config = Self::compression_config()
+            .lock()
+            .expect("Failed to lock compression config");
+        config
+            .collections
+            .get(collection_name)
+            .copied()
+            .unwrap_or(config.default)
+    }
+
+    /// Whether `path` starts with the binary snapshot magic (as opposed to the legacy
+    /// base64 text format).
+    pub fn is_binary(path: &Path) -> bool {
+        let mut magic = [0u8; 4];
+        File::open(path)
+            .and_then(|mut f| f.read_exact(&mut magic))
+            .is_ok()
+            && &magic == SNAPSHOT_MAGIC
+    }
+
+    /// Read and validate the header only.
+    pub fn read_header(path: &Path) -> Result<SnapshotHeader, String> {
+        let mut file = File::open(path).map_err(|e| format!("open {}: {}", path.display(), e))?;
+        SnapshotHeader::read_from(&mut file)
+    }
+
+    /// Whether `path` is a binary snapshot in the current format version.
+    pub fn is_current(path: &Path) -> bool {
+        Self::read_header(path).is_ok_and(|header| header.version == SNAPSHOT_VERSION)
+    }
+
+    /// Check the body against the header checksum (no key needed).
+    pub fn verify_checksum(path: &Path) -> Result<(), String> {
+        let header = Self::read_header(path)?;
+        let mut file = File::open(path).map_err(|e| format!("open {}: {}", path.display(), e))?;
+        file.seek(SeekFrom::Start(header.size() as u64))
+            .map_err(|e| e.to_string())?;
+        let mut hasher = blake3::Hasher::new();
+        io::copy(&mut file, &mut hasher).map_err(|e| e.to_string())?;
+        if hasher.finalize().as_bytes() != &header.checksum {
+            return Err("checksum mismatch".into());
+        }
+        Ok(())
+    }
+
+    /// Open only the metadata frame: cheap proof that the file decrypts with `auth_key` and
+    /// was written for `collection_name`. Returns the record count it announces.
+    pub fn verify_meta(path: &Path, auth_key: &str, collection_name: &str) -> Result<u64, String> {
+        let file = File::open(path).map_err(|e| format!("open {}: {}", path.display(), e))?;
+        let mut reader = BufReader::new(file);
+        let header = SnapshotHeader::read_from(&mut reader)?;
+        let mut frames = FrameReader {
+            input: reader,
+            key: header.frame_key(auth_key, collection_name)?.0,
+            aad: header.aad(collection_name),
+            nonce: header.nonce,
+            compression: header.compression,
+            index: 0,
+            hasher: blake3::Hasher::new(),
+        };
+        let meta = frames.next()?.ok_or("missing metadata frame")?;
+        let (name, _, _, count) = take_meta(&mut meta.as_slice())?;
+        if name != collection_name {
+            return Err(format!("file belongs to collection '{}'", name));
+        }
+        Ok(count)
+    }
+
+    /// Stream `engine` into `file` (positioned at the start of an empty file), sealed with
+    /// the engine's data key. Every file gets a fresh random base nonce.
+    pub fn write(
+        engine: &AegMemoryEngine,
+        file: &mut File,
+        auth_key: &str,
+        compression: Compression,
+    ) -> Result<(), String> {
+        let data_key = &engine.data_key;
+        let mut header = SnapshotHeader {
+            version: SNAPSHOT_VERSION,
+            cipher: CIPHER_AES_256_GCM,
+            compression,
+            nonce: AegCrypto::random_nonce()?,
+            checksum: [0u8; 32],
+            wrapped_key: Some(SnapshotHeader::wrap_key(
+                data_key,
+                auth_key,
+                &engine.collection_name,
+            )?),
+        };
+
+        let mut writer = FrameWriter {
+            out: BufWriter::new(&mut *file),
+            key: data_key.sealing_key(),
+            aad: header.aad(&engine.collection_name),
+            nonce: header.nonce,
+            compression,
+            index: 0,
+            hasher: blake3::Hasher::new(),
+        };
+        // placeholder; rewritten with the checksum once the body is complete
+        writer
+            .out
+            .write_all(&header.to_bytes())
+            .map_err(|e| format!("write error: {}", e))?;
+
+        let mut meta = Vec::new();
+        put_bytes(&mut meta, engine.collection_name.as_bytes());
+        meta.extend_from_slice(&engine.revision.to_le_bytes());
+        meta.extend_from_slice(&engine.wal_seq.to_le_bytes());
+        meta.extend_from_slice(&(engine.store.len() as u64).to_le_bytes());
+        writer.frame(&meta)?;
+
+        // Plaintext records; zeroized once written.
+        let mut chunk = Zeroizing::new(Vec::with_capacity(FRAME_TARGET_LEN));
+        for (key, value) in &engine.store {
+            put_bytes(&mut chunk, key.as_bytes());
+            put_bytes(&mut chunk, value.as_bytes());
+            chunk.extend_from_slice(&engine.key_version(key).to_le_bytes());
+            if chunk.len() >= FRAME_TARGET_LEN {
+                writer.frame(&chunk)?;
+                chunk.clear();
+            }
+        }
+        if !chunk.is_empty() {
+            writer.frame(&chunk)?;
+        }
+        writer.raw(&0u32.to_le_bytes())?;
+
+        header.checksum = *writer.hasher.finalize().as_bytes();
+        writer
+            .out
+            .flush()
+            .map_err(|e| format!("write error: {}", e))?;
+        drop(writer);
+        file.seek(SeekFrom::Start(0))
+            .and_then(|_| file.write_all(&header.to_bytes()))
+            .map_err(|e| format!("write error: {}", e))
+    }
+
+    /// Stream the snapshot of `collection_name` back into an engine. Fails on any
+    /// corruption (bad checksum, frame that does not authenticate, truncation, wrong record
+    /// count) and on a file written for another collection.
+    pub fn read(
+        path: &Path,
+        auth_key: &str,
+        collection_name: &str,
+    ) -> Result<AegMemoryEngine, String> {
+        let file = File::open(path).map_err(|e| format!("open {}: {}", path.display(), e))?;
+        let mut reader = BufReader::new(file);
+        let header = SnapshotHeader::read_from(&mut reader)?;
+        let (key, data_key) = header.frame_key(auth_key, collection_name)?;
+
+        let mut frames = FrameReader {
+            input: reader,
+            key,
+            aad: header.aad(collection_name),
+            nonce: header.nonce,
+            compression: header.compression,
+            index: 0,
+            hasher: blake3::Hasher::new(),
+        };
+
+        let meta = frames.next()?.ok_or("missing metadata frame")?;
+        let (name, revision, wal_seq, count) = take_meta(&mut meta.as_slice())?;
+        if name != collection_name {
+            return Err(format!("file belongs to collection '{}'", name));
+        }
+
+        let mut engine = AegMemoryEngine::new(&name);
+        // older files have none; the engine keeps the fresh one it was created with
+        if let Some(data_key) = data_key {
+            engine.data_key = data_key;
+        }
+        engine.revision = revision;
+        engine.wal_seq = wal_seq;
+        engine.store.reserve(count as usize);
+        while let Some(chunk) = frames.next()? {
+            let mut cursor = chunk.as_slice();
+            while !cursor.is_empty() {
+                let (key, value, version) = take_record(&mut cursor)?;
+                engine.versions.insert(key.clone(), version);
+                engine.store.insert(key, value);
+            }
+        }
+
+        let mut trailing = [0u8; 1];
+        if frames
+            .input
+            .read(&mut trailing)
+            .map_err(|e| e.to_string())?
+            != 0
+        {
+            return Err("trailing data after end marker".into());
+        }
+        if frames.hasher.finalize().as_bytes() != &header.checksum {
+            return Err("checksum mismatch".into());
+        }
+        if engine.store.len() as u64 != count {
+            return Err(format!(
+                "expected {} records, found {}",
+                count,
+                engine.store.len()
+            ));
+        }
+        Ok(engine)
+    }
+
+    /// Seal the data key of a version 3 file with `new_key` instead of `old_key`; the frames
+    /// are copied as they are (key rotation). Returns `false` when the key already opens
+    /// with `new_key`.
+    pub fn rewrap(
+        path: &Path,
+        old_key: &str,
+        new_key: &str,
+        collection_name: &str,
+    ) -> Result<bool, String> {
+        let file = File::open(path).map_err(|e| format!("open {}: {}", path.display(), e))?;
+        let mut reader = BufReader::new(file);
+        let mut header = SnapshotHeader::read_from(&mut reader)?;
+        if header.unwrap_key(new_key, collection_name).is_ok() {
+            return Ok(false);
+        }
+        let data_key = header.unwrap_key(old_key, collection_name)?;
+        header.wrapped_key = Some(SnapshotHeader::wrap_key(
+            &data_key,
+            new_key,
+            collection_name,
+        )?);
+        AegFileSystem::write_atomic_with(path, |out| {
+            out.write_all(&header.to_bytes())
+                .and_then(|_| io::copy(&mut reader, out).map(|_| ()))
+                .map_err(|e| format!("write error: {}", e))
+        })?;
+        Ok(true)
+    }
+
+    /// Delete a snapshot file so its contents cannot be recovered even from the disk:
+    /// the wrapped data key is overwritten (and synced) before the file is removed. The
+    /// collection's WAL records are sealed with the same key and become unreadable too.
+    /// Backup snapshots carry their own wrapped copy of the key, so the collection stays
+    /// recoverable from them until they are deleted.
+    /// Files older than version 3 have no data key of their own and are just removed.
+    pub fn shred(path: &Path) -> Result<(), String> {
+        if let Ok(header) = Self::read_header(path)
+            && header.wrapped_key.is_some()
+        {
+            let mut file = OpenOptions::new()
+                .write(true)
+                .open(path)
+                .map_err(|e| format!("open {}: {}", path.display(), e))?;
+            file.seek(SeekFrom::Start(SNAPSHOT_HEADER_LEN as u64))
+                .and_then(|_| file.write_all(&[0u8; WRAPPED_DATA_KEY_LEN]))
+                .and_then(|_| file.sync_all())
+                .map_err(|e| format!("shred {}: {}", path.display(), e))?;
+        }
+        fs::remove_file(path).map_err(|e| format!("remove {}: {}", path.display(), e))?;
+        match path.parent() {
+            Some(dir) => AegFileSystem::sync_dir(dir),
+            None => Ok(()),
+        }
+    }
+
+    /// Best-effort read of a damaged snapshot: records from every frame that still
+    /// authenticates are kept, frames that do not are skipped, and reading stops where the
+    /// framing itself is broken. Only fails when the header is unusable.
+    pub fn salvage(
+        path: &Path,
+        auth_key: &str,
+        collection_name: &str,
+    ) -> Result<SalvagedSnapshot, String> {
+        let file = File::open(path).map_err(|e| format!("open {}: {}", path.display(), e))?;
+        let mut reader = BufReader::new(file);
+        let header = SnapshotHeader::read_from(&mut reader)?;
+        let (key, data_key) = header.frame_key(auth_key, collection_name)?;
+        let mut frames = FrameReader {
+            input: reader,
+            key,
+            aad: header.aad(collection_name),
+            nonce: header.nonce,
+            compression: header.compression,
+            index: 0,
+            hasher: blake3::Hasher::new(),
+        };
+
+        let mut salvaged = SalvagedSnapshot {
+            engine: AegMemoryEngine::new(collection_name),
+            expected: None,
+            lost_frames: 0,
+        };
+        if let Some(data_key) = data_key {
+            salvaged.engine.data_key = data_key;
+        }
+        while let Ok(Some(sealed)) = frames.next_sealed() {
+            let Ok(plaintext) = frames.open(&sealed) else {
+                salvaged.lost_frames += 1;
+                continue;
+            };
+            let mut cursor = plaintext.as_slice();
+            if frames.index == 1 {
+                if let Ok((_, revision, wal_seq, count)) = take_meta(&mut cursor) {
+                    salvaged.engine.revision = revision;
+                    salvaged.engine.wal_seq = wal_seq;
+                    salvaged.expected = Some(count);
+                }
+                continue;
+            }
+            while let Ok((key, value, version)) = take_record(&mut cursor) {
+                salvaged.engine.versions.insert(key.clone(), version);
+                salvaged.engine.store.insert(key, value);
+            }
+        }
+        // keep key versions below the revision new writes will get
+        let newest = salvaged
+            .engine
+            .versions
+            .values()
+            .copied()
+            .max()
+            .unwrap_or(0);
+        salvaged.engine.revision = salvaged.engine.revision.max(newest);
+        Ok(salvaged)
+    }
+
+    fn frame_nonce(base: &[u8; NONCE_LEN], index: u64) -> [u8; NONCE_LEN] {
+        let mut nonce = *base;
+        for (n, i) in nonce[4..].iter_mut().zip(index.to_be_bytes()) {
+            *n ^= i;
+        }
+        nonce
+    }
+}
+
+struct FrameWriter<W: Write> {
+    out: W,
+    key: SealingKey,
+    aad: Vec<u8>,
+    nonce: [u8; NONCE_LEN],
+    compression: Compression,
+    index: u64,
+    hasher: blake3::Hasher,
+}
+
+impl<W: Write> FrameWriter<W> {
+    fn raw(&mut self, bytes: &[u8]) -> Result<(), String> {
+        self.hasher.update(bytes);
+        self.out
+            .write_all(bytes)
+            .map_err(|e| format!("write error: {}", e))
+    }
+
+    fn frame(&mut self, plaintext: &[u8]) -> Result<(), String> {
+        let compressed;
+        let payload = match self.compression {
+            Compression::None => plaintext,
+            Compression::Zstd => {
+                compressed = Zeroizing::new(
+                    zstd::bulk::compress(plaintext, ZSTD_LEVEL)
+                        .map_err(|e| format!("compress error: {}", e))?,
+                );
+                compressed.as_slice()
+            }
+        };
+        let nonce = AegSnapshotFormat::frame_nonce(&self.nonce, self.index);
+        let sealed = AegCrypto::seal(&self.key, &nonce, &self.aad, payload)?;
+        self.index += 1;
+        self.raw(&(sealed.len() as u32).to_le_bytes())?;
+        self.raw(&sealed)
+    }
+}
+
+struct FrameReader<R: Read> {
+    input: R,
+    key: SealingKey,
+    aad: Vec<u8>,
+    nonce: [u8; NONCE_LEN],
+    compression: Compression,
+    index: u64,
+    hasher: blake3::Hasher,
+}
+
+impl<R: Read> FrameReader<R> {
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), String> {
+        self.input
+            .read_exact(buf)
+            .map_err(|_| "truncated snapshot".to_string())?;
+        self.hasher.update(buf);
+        Ok(())
+    }
+
+    /// Next decrypted frame, or `None` at the end marker.
+    fn next(&mut self) -> Result<Option<SecretBytes>, String> {
+        match self.next_sealed()? {
+            Some(sealed) => self.open(&sealed).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Next frame as stored, or `None` at the end marker.
+    fn next_sealed(&mut self) -> Result<Option<Vec<u8>>, String> {
+        let mut len = [0u8; 4];
+        self.read_exact(&mut len)?;
+        let len = u32::from_le_bytes(len);
+        if len == 0 {
+            return Ok(None);
+        }
+        if len > FRAME_MAX_LEN {
+            return Err(format!("frame {} is implausibly large", self.index));
+        }
+        let mut sealed = vec![0u8; len as usize];
+        self.read_exact(&mut sealed)?;
+        Ok(Some(sealed))
+    }
+
+    /// Decrypt (and decompress) the frame at the current index and move past it,
+    /// whether or not it authenticates.
+    fn open(&mut self, sealed: &[u8]) -> Result<SecretBytes, String> {
+        let index = self.index;
+        self.index += 1;
+        let nonce = AegSnapshotFormat::frame_nonce(&self.nonce, index);
+        let plaintext = AegCrypto::open(&self.key, &nonce, &self.aad, sealed)
+            .map_err(|_| format!("frame {} failed to decrypt", index))?;
+        match self.compression {
+            Compression::None => Ok(plaintext),
+            Compression::Zstd => zstd::stream::decode_all(plaintext.as_slice())
+                .map(Zeroizing::new)
+                .map_err(|e| format!("frame {} failed to decompress: {}", index, e)),
+        }
+    }
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    out.extend_from_slice(bytes);
+}
+
+fn take<'a>(cursor: &mut &'a [u8], len: usize) -> Result<&'a [u8], String> {
+    if cursor.len() < len {
+        return Err("record runs past the end of its frame".into());
+    }
+    let (head, rest) = cursor.split_at(len);
+    *cursor = rest;
+    Ok(head)
+}
+
+fn take_u64(cursor: &mut &[u8]) -> Result<u64, String> {
+    Ok(u64::from_le_bytes(
+        take(cursor, 8)?.try_into().expect("8 bytes"),
+    ))
+}
+
+fn take_string(cursor: &mut &[u8]) -> Result<String, String> {
+    let len = u32::from_le_bytes(take(cursor, 4)?.try_into().expect("4 bytes")) as usize;
+    String::from_utf8(take(cursor, len)?.to_vec()).map_err(|_| "invalid UTF-8 in record".into())
+}
+
+/// Metadata frame: name, revision, wal_seq, record count.
+fn take_meta(cursor: &mut &[u8]) -> Result<(String, u64, u64, u64), String> {
+    Ok((
+        take_string(cursor)?,
+        take_u64(cursor)?,
+        take_u64(cursor)?,
+        take_u64(cursor)?,
+    ))
+}
+
+/// One record: key, value, version.
+fn take_record(cursor: &mut &[u8]) -> Result<(String, String, u64), String> {
+    Ok((
+        take_string(cursor)?,
+        take_string(cursor)?,
+        take_u64(cursor)?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{TempDir, auth_key};
+    use std::path::PathBuf;
+
+    /// An engine large enough to span several frames.
+    fn engine(name: &str) -> AegMemoryEngine {
+        let mut engine = AegMemoryEngine::new(name);
+        for i in 0..3000 {
+            engine.insert(
+                format!("key-{:04}", i),
+                format!("value {} {}", i, "x".repeat(40)),
+            );
+        }
+        engine.wal_seq = 17;
+        engine
+    }
+
+    fn write_file(
+        dir: &TempDir,
+        engine: &AegMemoryEngine,
+        auth_key: &str,
+        compression: Compression,
+    ) -> PathBuf {
+        let path = dir.join(&format!("collection_{}.aekv", engine.collection_name));
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        AegSnapshotFormat::write(engine, &mut file, auth_key, compression).unwrap();
+        path
+    }
+
+    fn flip_byte(path: &Path, offset: u64) {
+        let mut bytes = fs::read(path).unwrap();
+        bytes[offset as usize] ^= 0x01;
+        fs::write(path, bytes).unwrap();
+    }
+
+    #[test]
+    fn round_trip_keeps_records_versions_and_data_key() {
+        let dir = TempDir::new();
+        let auth_key = auth_key();
+        for compression in [Compression::None, Compression::Zstd] {
+            let original = engine("roundtrip");
+            let path = write_file(&dir, &original, &auth_key, compression);
+            AegSnapshotFormat::verify_checksum(&path).unwrap();
+            assert!(AegSnapshotFormat::is_current(&path));
+
+            let loaded = AegSnapshotFormat::read(&path, &auth_key, "roundtrip").unwrap();
+            assert_eq!(loaded.store, original.store);
+            assert_eq!(loaded.versions, original.versions);
+            assert_eq!(loaded.revision, original.revision);
+            assert_eq!(loaded.wal_seq, 17);
+            assert_eq!(loaded.data_key.as_bytes(), original.data_key.as_bytes());
+            assert_eq!(
+                AegSnapshotFormat::read_header(&path).unwrap().compression,
+                compression
+            );
+        }
+    }
+
+    #[test]
+    fn bit_flips_are_detected() {
+        let dir = TempDir::new();
+        let auth_key = auth_key();
+        let path = write_file(&dir, &engine("flips"), &auth_key, Compression::None);
+        let header_len = AegSnapshotFormat::read_header(&path).unwrap().size() as u64;
+        let len = fs::metadata(&path).unwrap().len();
+
+        // body: caught by the checksum and by the frame tag
+        for offset in [header_len + 3, len / 2, len - 1] {
+            let copy = path.with_extension(format!("flip{}", offset));
+            fs::copy(&path, &copy).unwrap();
+            flip_byte(&copy, offset);
+            assert!(AegSnapshotFormat::verify_checksum(&copy).is_err());
+            assert!(AegSnapshotFormat::read(&copy, &auth_key, "flips").is_err());
+        }
+
+        // header: the nonce and the wrapped data key are authenticated too
+        for offset in [8, SNAPSHOT_HEADER_LEN as u64 + 20] {
+            let copy = path.with_extension(format!("flip{}", offset));
+            fs::copy(&path, &copy).unwrap();
+            flip_byte(&copy, offset);
+            assert!(AegSnapshotFormat::read(&copy, &auth_key, "flips").is_err());
+        }
+    }
+
+    #[test]
+    fn truncation_is_detected_and_salvage_keeps_intact_frames() {
+        let dir = TempDir::new();
+        let auth_key = auth_key();
+        let original = engine("truncated");
+        let path = write_file(&dir, &original, &auth_key, Compression::None);
+        let len = fs::metadata(&path).unwrap().len();
+
+        for cut in [1, 100, len / 2] {
+            let copy = path.with_extension(format!("cut{}", cut));
+            fs::copy(&path, &copy).unwrap();
+            File::options()
+                .write(true)
+                .open(&copy)
+                .unwrap()
+                .set_len(len - cut)
+                .unwrap();
+            assert!(AegSnapshotFormat::read(&copy, &auth_key, "truncated").is_err());
+        }
+
+        let copy = path.with_extension("half");
+        fs::copy(&path, &copy).unwrap();
+        File::options()
+            .write(true)
+            .open(&copy)
+            .unwrap()
+            .set_len(len / 2)
+            .unwrap();
+        let salvaged = AegSnapshotFormat::salvage(&copy, &auth_key, "truncated").unwrap();
+        assert_eq!(salvaged.expected, Some(original.store.len() as u64));
+        assert!(!salvaged.engine.store.is_empty());
+        assert!(salvaged.engine.store.len() < original.store.len());
+        for (key, value) in &salvaged.engine.store {
+            assert_eq!(original.store.get(key), Some(value));
+        }
+    }
+
+    #[test]
+    fn a_file_does_not_open_under_another_name_or_key() {
+        let dir = TempDir::new();
+        let auth_key = auth_key();
+        let path = write_file(&dir, &engine("owner"), &auth_key, Compression::None);
+        assert!(AegSnapshotFormat::read(&path, &auth_key, "intruder").is_err());
+        assert!(AegSnapshotFormat::read(&path, &crate::test_support::auth_key(), "owner").is_err());
+    }
+
+    #[test]
+    fn zstd_shrinks_repetitive_records() {
+        let dir = TempDir::new();
+        let auth_key = auth_key();
+        let original = engine("repetitive");
+        let plain = write_file(&dir, &original, &auth_key, Compression::None);
+        let plain_len = fs::metadata(&plain).unwrap().len();
+        let packed = write_file(&dir, &original, &auth_key, Compression::Zstd);
+        assert!(fs::metadata(&packed).unwrap().len() * 2 < plain_len);
+        let restored = AegSnapshotFormat::read(&packed, &auth_key, "repetitive").unwrap();
+        assert_eq!(restored.store, original.store);
+    }
+
+    #[test]
+    fn a_collection_can_override_the_default_compression() {
+        let dir = TempDir::new();
+        let auth_key = auth_key();
+        AegSnapshotFormat::set_compression(
+            Compression::None,
+            HashMap::from([("packed".to_string(), "ZSTD".parse().unwrap())]),
+        );
+        for (name, expected) in [("packed", Compression::Zstd), ("plain", Compression::None)] {
+            assert_eq!(AegSnapshotFormat::compression_for(name), expected);
+            let path = dir.join(&format!("collection_{}.aekv", name));
+            AegMemoryEngine::save_to_path_with_key(&engine(name), &path, &auth_key).unwrap();
+            let header = AegSnapshotFormat::read_header(&path).unwrap();
+            assert_eq!(header.compression, expected);
+        }
+        AegSnapshotFormat::set_compression(Compression::default(), HashMap::new());
+        assert!("lz4".parse::<Compression>().is_err());
+    }
+}
@@ -0,0 +1,61 @@
+use crate::crypto::AegCrypto;
+use crate::file_system::AegFileSystem;
+use crate::memory_engine::AegMemoryEngine;
+use crate::wal::{AegWal, FsyncPolicy};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard};
+
+/// Held by every test that uses the data directory, which is found through `HOME`.
+static DATA_HOME: Mutex<()> = Mutex::new(());
+
+/// A new empty directory under the system temp directory, removed when dropped.
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    pub(crate) fn new() -> Self {
+        let dir = std::env::temp_dir().join(format!("aegisr-test-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).expect("create temp dir");
+        Self(dir)
+    }
+
+    pub(crate) fn join(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A data directory of its own, initialized like `aegisr init`, with an empty collection
+/// cache and the WAL open on it. Tests holding it run one at a time.
+pub(crate) struct DataHome {
+    _dir: TempDir,
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl DataHome {
+    pub(crate) fn new() -> Self {
+        let lock = DATA_HOME.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = TempDir::new();
+        // SAFETY: the environment is only read through std, which synchronizes it with
+        // `set_var`, and only tests holding the lock depend on `HOME`.
+        unsafe { std::env::set_var("HOME", &dir.0) };
+        AegMemoryEngine::with_collections(|collections| collections.replace_all(Vec::new()))
+            .expect("clear collection cache");
+        AegFileSystem::initialize_config(None, None);
+        AegWal::open(FsyncPolicy::Always).expect("open WAL");
+        Self {
+            _dir: dir,
+            _lock: lock,
+        }
+    }
+}
+
+/// A random authorization key.
+pub(crate) fn auth_key() -> String {
+    AegCrypto::encode_base64(AegCrypto::generate_random_bytes(None), None)
+}
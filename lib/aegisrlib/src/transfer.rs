@@ -0,0 +1,508 @@
+use crate::constant::STORE_COLLECTION;
+use crate::file_system::AegFileSystem;
+use crate::memory_engine::AegMemoryEngine;
+use crate::snapshot_format::AegSnapshotFormat;
+use crate::wal::AegWal;
+use clap::ValueEnum;
+use serde::de::{self, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use std::fmt;
+use std::io::{BufRead, Read, Write};
+
+/// Keys per EXPORT page.
+pub const EXPORT_BATCH_SIZE: usize = 1000;
+/// An IMPORT batch is sent once it holds this many pairs...
+pub const IMPORT_BATCH_SIZE: usize = 1000;
+/// ...or this many bytes of keys and values, well below the daemon's request limit.
+pub const IMPORT_BATCH_BYTES: usize = 1024 * 1024;
+
+/// File formats understood by export and import.
+#[derive(ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TransferFormat {
+    /// One JSON object mapping keys to values.
+    Json,
+    /// One `{"key": ..., "value": ...}` object per line.
+    #[default]
+    Jsonl,
+    /// `key,value` rows with a header line.
+    Csv,
+}
+
+/// How imported keys that already exist in the collection are treated.
+#[derive(ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportMode {
+    /// Keep the current value; only missing keys are added.
+    #[default]
+    Merge,
+    /// Replace the current value with the imported one.
+    Overwrite,
+}
+
+/// One page of an export, in key order. `next` is the cursor for the following page.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ExportPage {
+    pub entries: Vec<(String, String)>,
+    pub next: Option<String>,
+}
+
+/// What an import did (or, in a dry run, would do).
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ImportSummary {
+    /// Keys that did not exist.
+    pub added: usize,
+    /// Existing keys given a new value (overwrite mode).
+    pub updated: usize,
+    /// Existing keys that already held the imported value.
+    pub unchanged: usize,
+    /// Existing keys left alone because their value differs (merge mode).
+    pub skipped: usize,
+}
+
+impl ImportSummary {
+    pub fn add(&mut self, other: ImportSummary) {
+        self.added += other.added;
+        self.updated += other.updated;
+        self.unchanged += other.unchanged;
+        self.skipped += other.skipped;
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    key: String,
+    value: Value,
+}
+
+/// Stored values are strings; other JSON values are imported as their JSON text.
+fn value_to_string(value: Value) -> String {
+    match value {
+        Value::String(s) => s,
+        other => other.to_string(),
+    }
+}
+
+/// Writes exported entries incrementally in one of the transfer formats.
+pub struct ExportWriter<W: Write> {
+    inner: ExportSink<W>,
+    written: usize,
+}
+
+enum ExportSink<W: Write> {
+    Json(W),
+    Jsonl(W),
+    Csv(Box<csv::Writer<W>>),
+}
+
+impl<W: Write> ExportWriter<W> {
+    pub fn new(out: W, format: TransferFormat) -> Result<Self, String> {
+        let inner = match format {
+            TransferFormat::Json => {
+                let mut out = out;
+                out.write_all(b"{").map_err(|e| e.to_string())?;
+                ExportSink::Json(out)
+            }
+            TransferFormat::Jsonl => ExportSink::Jsonl(out),
+            TransferFormat::Csv => {
+                let mut writer = csv::Writer::from_writer(out);
+                writer
+                    .write_record(["key", "value"])
+                    .map_err(|e| e.to_string())?;
+                ExportSink::Csv(Box::new(writer))
+            }
+        };
+        Ok(Self { inner, written: 0 })
+    }
+
+    pub fn write_entry(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match &mut self.inner {
+            ExportSink::Json(out) => {
+                let separator = if self.written == 0 { "\n  " } else { ",\n  " };
+                let key = serde_json::to_string(key).map_err(|e| e.to_string())?;
+                let value = serde_json::to_string(value).map_err(|e| e.to_string())?;
+                write!(out, "{}{}: {}", separator, key, value).map_err(|e| e.to_string())?;
+            }
+            ExportSink::Jsonl(out) => {
+                let entry = Entry {
+                    key: key.to_string(),
+                    value: Value::String(value.to_string()),
+                };
+                serde_json::to_writer(&mut *out, &entry).map_err(|e| e.to_string())?;
+                out.write_all(b"\n").map_err(|e| e.to_string())?;
+            }
+            ExportSink::Csv(writer) => writer
+                .write_record([key, value])
+                .map_err(|e| e.to_string())?,
+        }
+        self.written += 1;
+        Ok(())
+    }
+
+    /// Close the document and flush. Returns how many entries were written.
+    pub fn finish(self) -> Result<usize, String> {
+        match self.inner {
+            ExportSink::Json(mut out) => {
+                let end: &[u8] = if self.written == 0 { b"}\n" } else { b"\n}\n" };
+                out.write_all(end)
+                    .and_then(|_| out.flush())
+                    .map_err(|e| e.to_string())?;
+            }
+            ExportSink::Jsonl(mut out) => out.flush().map_err(|e| e.to_string())?,
+            ExportSink::Csv(mut writer) => writer.flush().map_err(|e| e.to_string())?,
+        }
+        Ok(self.written)
+    }
+}
+
+/// Collects parsed entries and hands them on in bounded batches.
+struct Batcher<'a> {
+    batch: Vec<(String, String)>,
+    bytes: usize,
+    total: usize,
+    sink: &'a mut dyn FnMut(Vec<(String, String)>) -> Result<(), String>,
+}
+
+impl Batcher<'_> {
+    fn push(&mut self, key: String, value: String) -> Result<(), String> {
+        self.bytes += key.len() + value.len();
+        self.batch.push((key, value));
+        self.total += 1;
+        if self.batch.len() >= IMPORT_BATCH_SIZE || self.bytes >= IMPORT_BATCH_BYTES {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        self.bytes = 0;
+        (self.sink)(std::mem::take(&mut self.batch))
+    }
+}
+
+/// Streams the entries of a top-level JSON object into a `Batcher`.
+struct ObjectVisitor<'a, 'b>(&'a mut Batcher<'b>);
+
+impl<'de> Visitor<'de> for ObjectVisitor<'_, '_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a JSON object mapping keys to values")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        while let Some((key, value)) = map.next_entry::<String, Value>()? {
+            self.0
+                .push(key, value_to_string(value))
+                .map_err(de::Error::custom)?;
+        }
+        Ok(())
+    }
+}
+
+/// EXPORT / IMPORT
+///
+/// Moves collections to and from portable files. Through the daemon, exports are read
+/// page by page (`export_page`) and imports are applied in batches (`import_into`), so
+/// neither side holds a whole file in memory. The offline helpers work directly on the
+/// files in the data directory with an explicit authorization key.
+pub struct AegTransfer;
+
+impl AegTransfer {
+    /// Up to `count` entries with keys after `after`, in key order. Pages are taken
+    /// independently, so writes made during an export may or may not be included.
+    pub fn export_page(engine: &AegMemoryEngine, after: Option<&str>, count: usize) -> ExportPage {
+        let mut keys: Vec<&String> = engine
+            .store
+            .keys()
+            .filter(|k| after.is_none_or(|a| k.as_str() > a))
+            .collect();
+        let more = keys.len() > count;
+        if more {
+            keys.select_nth_unstable(count);
+            keys.truncate(count);
+        }
+        keys.sort_unstable();
+        let entries: Vec<(String, String)> = keys
+            .into_iter()
+            .map(|k| (k.clone(), engine.store[k].clone()))
+            .collect();
+        let next = if more {
+            entries.last().map(|(k, _)| k.clone())
+        } else {
+            None
+        };
+        ExportPage { entries, next }
+    }
+
+    /// Apply one batch of imported pairs. With `dry_run`, only count what would change.
+    pub fn import_into(
+        engine: &mut AegMemoryEngine,
+        pairs: &[(String, String)],
+        mode: ImportMode,
+        dry_run: bool,
+    ) -> ImportSummary {
+        let mut summary = ImportSummary::default();
+        for (key, value) in pairs {
+            let write = match engine.store.get(key) {
+                None => {
+                    summary.added += 1;
+                    true
+                }
+                Some(current) if current == value => {
+                    summary.unchanged += 1;
+                    false
+                }
+                Some(_) if mode == ImportMode::Overwrite => {
+                    summary.updated += 1;
+                    true
+                }
+                Some(_) => {
+                    summary.skipped += 1;
+                    false
+                }
+            };
+            if write && !dry_run {
+                engine.insert(key.clone(), value.clone());
+            }
+        }
+        summary
+    }
+
+    /// Parse `input` and pass its entries to `sink` in batches. Returns the entry count.
+    pub fn read_entries(
+        input: impl BufRead,
+        format: TransferFormat,
+        mut sink: impl FnMut(Vec<(String, String)>) -> Result<(), String>,
+    ) -> Result<usize, String> {
+        let mut batcher = Batcher {
+            batch: Vec::new(),
+            bytes: 0,
+            total: 0,
+            sink: &mut sink,
+        };
+        match format {
+            TransferFormat::Json => Self::read_json(input, &mut batcher)?,
+            TransferFormat::Jsonl => {
+                for (index, line) in input.lines().enumerate() {
+                    let line = line.map_err(|e| format!("read error: {}", e))?;
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    let entry: Entry = serde_json::from_str(&line)
+                        .map_err(|e| format!("line {}: {}", index + 1, e))?;
+                    batcher.push(entry.key, value_to_string(entry.value))?;
+                }
+            }
+            TransferFormat::Csv => {
+                let mut reader = csv::Reader::from_reader(input);
+                for (index, record) in reader.deserialize::<(String, String)>().enumerate() {
+                    // +2: the header is line 1
+                    let (key, value) = record.map_err(|e| format!("row {}: {}", index + 2, e))?;
+                    batcher.push(key, value)?;
+                }
+            }
+        }
+        batcher.flush()?;
+        Ok(batcher.total)
+    }
+
+    fn read_json(input: impl Read, batcher: &mut Batcher) -> Result<(), String> {
+        let mut deserializer = serde_json::Deserializer::from_reader(input);
+        deserializer
+            .deserialize_map(ObjectVisitor(batcher))
+            .and_then(|_| deserializer.end())
+            .map_err(|e| format!("invalid JSON: {}", e))
+    }
+
+    /// Check `auth_key` against `collection.lock` and return the collection names.
+    pub fn offline_collections(auth_key: &str) -> Result<Vec<String>, String> {
+        Ok(AegFileSystem::decrypt_collection_lock(auth_key)?.collections)
+    }
+
+    /// Load a collection straight from its snapshot file, rolled forward with the WAL.
+    pub fn offline_load(collection: &str, auth_key: &str) -> Result<AegMemoryEngine, String> {
+        if !Self::offline_collections(auth_key)?
+            .iter()
+            .any(|c| c == collection)
+        {
+            return Err(format!("Collection '{}' does not exist", collection));
+        }
+        let path = AegMemoryEngine::engine_file_path(collection);
+        let mut engine = if !path.exists() || path.metadata().map(|m| m.len()).unwrap_or(0) == 0 {
+            AegMemoryEngine::new(collection)
+        } else if AegSnapshotFormat::is_binary(&path) {
+            AegSnapshotFormat::read(&path, auth_key, collection)?
+        } else {
+            return Err(format!(
+                "collection '{}' is still in the legacy format; start the daemon once to migrate it",
+                collection
+            ));
+        };
+        engine.apply_wal(AegWal::collection_ops(
+            &AegWal::wal_path(),
+            auth_key,
+            collection,
+            engine.wal_seq,
+            &engine.data_key,
+        )?);
+        Ok(engine)
+    }
+
+    /// Write an engine loaded with `offline_load` back to its snapshot file, adding the
+    /// collection to `collection.lock` if needed. The daemon must not be running.
+    pub fn offline_save(engine: &mut AegMemoryEngine, auth_key: &str) -> Result<(), String> {
+        let mut lock = AegFileSystem::decrypt_collection_lock(auth_key)?;
+        if !lock.collections.contains(&engine.collection_name) {
+            // Like `ensure_file`: a new collection starts after every logged record, so
+            // those of an earlier collection with the same name are never replayed into it.
+            engine.wal_seq = engine
+                .wal_seq
+                .max(AegWal::last_seq_in(&AegWal::wal_path(), auth_key)?);
+        }
+        let path = AegMemoryEngine::engine_file_path(&engine.collection_name);
+        AegFileSystem::write_atomic_with(&path, |file| {
+            AegSnapshotFormat::write(
+                engine,
+                file,
+                auth_key,
+                AegSnapshotFormat::compression_for(&engine.collection_name),
+            )
+        })?;
+        if !lock.collections.contains(&engine.collection_name) {
+            lock.collections.push(engine.collection_name.clone());
+            let json = serde_json::to_string_pretty(&lock)
+                .map_err(|e| format!("serialize {}: {}", STORE_COLLECTION, e))?;
+            AegFileSystem::write_collection_lock_json(&json, auth_key.trim());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn engine(entries: &[(&str, &str)]) -> AegMemoryEngine {
+        let mut engine = AegMemoryEngine::new("transfer");
+        for (key, value) in entries {
+            engine.insert(*key, *value);
+        }
+        engine
+    }
+
+    fn sorted(engine: &AegMemoryEngine) -> Vec<(String, String)> {
+        let mut entries = engine.list();
+        entries.sort();
+        entries
+    }
+
+    fn export(engine: &AegMemoryEngine, format: TransferFormat) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut writer = ExportWriter::new(&mut out, format).unwrap();
+        let mut after = None;
+        loop {
+            let page = AegTransfer::export_page(engine, after.as_deref(), 2);
+            for (key, value) in &page.entries {
+                writer.write_entry(key, value).unwrap();
+            }
+            match page.next {
+                Some(next) => after = Some(next),
+                None => break,
+            }
+        }
+        assert_eq!(writer.finish().unwrap(), engine.store.len());
+        out
+    }
+
+    fn read(input: &[u8], format: TransferFormat) -> Result<Vec<(String, String)>, String> {
+        let mut entries = Vec::new();
+        AegTransfer::read_entries(input, format, |batch| {
+            entries.extend(batch);
+            Ok(())
+        })?;
+        Ok(entries)
+    }
+
+    #[test]
+    fn exports_read_back_in_every_format() {
+        let original = engine(&[
+            ("plain", "value"),
+            ("quoted", "a \"b\", c"),
+            ("multiline", "line 1\nline 2"),
+            ("empty", ""),
+            ("unicode", "clé ✓"),
+        ]);
+        for format in [
+            TransferFormat::Json,
+            TransferFormat::Jsonl,
+            TransferFormat::Csv,
+        ] {
+            let mut entries = read(&export(&original, format), format).unwrap();
+            entries.sort();
+            assert_eq!(entries, sorted(&original), "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn non_string_values_are_imported_as_json_text() {
+        let input = br#"{"key": "n", "value": 5}
+{"key": "o", "value": {"a": [1, null]}}
+"#;
+        assert_eq!(
+            read(input, TransferFormat::Jsonl).unwrap(),
+            [
+                ("n".to_string(), "5".to_string()),
+                ("o".to_string(), r#"{"a":[1,null]}"#.to_string())
+            ]
+        );
+        let broken = read(
+            b"{\"key\": \"k\", \"value\": \"v\"}\nnot json\n",
+            TransferFormat::Jsonl,
+        );
+        assert!(broken.unwrap_err().starts_with("line 2"));
+        assert!(read(b"[1, 2]", TransferFormat::Json).is_err());
+    }
+
+    #[test]
+    fn merge_keeps_existing_values_and_overwrite_replaces_them() {
+        let pairs = [
+            ("same".to_string(), "1".to_string()),
+            ("changed".to_string(), "new".to_string()),
+            ("added".to_string(), "3".to_string()),
+        ];
+        let current = [("same", "1"), ("changed", "old")];
+        let counts = |added, updated, unchanged, skipped| ImportSummary {
+            added,
+            updated,
+            unchanged,
+            skipped,
+        };
+
+        let mut merged = engine(&current);
+        let summary = AegTransfer::import_into(&mut merged, &pairs, ImportMode::Merge, false);
+        assert_eq!(summary, counts(1, 0, 1, 1));
+        assert_eq!(merged.get("changed").as_deref(), Some("old"));
+        assert_eq!(merged.get("added").as_deref(), Some("3"));
+
+        let mut overwritten = engine(&current);
+        let summary =
+            AegTransfer::import_into(&mut overwritten, &pairs, ImportMode::Overwrite, false);
+        assert_eq!(summary, counts(1, 1, 1, 0));
+        assert_eq!(overwritten.get("changed").as_deref(), Some("new"));
+
+        // a dry run reports the same counts without writing
+        let mut untouched = engine(&current);
+        let summary = AegTransfer::import_into(&mut untouched, &pairs, ImportMode::Overwrite, true);
+        assert_eq!(summary, counts(1, 1, 1, 0));
+        assert_eq!(sorted(&untouched), sorted(&engine(&current)));
+        assert_eq!(
+            untouched.unsaved_changes(),
+            engine(&current).unsaved_changes()
+        );
+    }
+}
@@ -0,0 +1,970 @@
+use crate::constant::STORE_WAL;
+use crate::core::AegCore;
+use crate::crypto::{AegCrypto, DataKey};
+use crate::file_system::AegFileSystem;
+use crate::memory_engine::AegMemoryEngine;
+use crate::permissions::AegPermissions;
+use crate::secret::AegSecureMemory;
+use base64::{Engine as _, engine::general_purpose};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::Duration;
+use zeroize::Zeroizing;
+
+/// When the write-ahead log is flushed to stable storage.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum FsyncPolicy {
+    /// fsync after every logged write, before it is acknowledged.
+    Always,
+    /// fsync once per second from a background thread (at most ~1s of writes lost).
+    #[default]
+    EverySec,
+    /// Never fsync explicitly; the OS decides when to flush.
+    No,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "always" => Ok(Self::Always),
+            "everysec" => Ok(Self::EverySec),
+            "no" => Ok(Self::No),
+            other => Err(format!(
+                "Unknown fsync policy '{}' (expected always, everysec or no)",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for FsyncPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Always => "always",
+            Self::EverySec => "everysec",
+            Self::No => "no",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A single mutation, as produced by the engine primitives.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum WalOp {
+    Set {
+        key: String,
+        value: String,
+    },
+    Del {
+        key: String,
+    },
+    Clear,
+    /// Whole collection state, written by a log rewrite. Replaces the engine contents.
+    Restore {
+        revision: u64,
+        store: HashMap<String, String>,
+        versions: HashMap<String, u64>,
+    },
+}
+
+impl WalOp {
+    /// Drop the op, zeroizing the values it carries when secure memory is on.
+    pub(crate) fn wipe(self) {
+        match self {
+            WalOp::Set { value, .. } => AegSecureMemory::wipe(value),
+            WalOp::Restore { store, .. } => AegSecureMemory::wipe_all(store.into_values()),
+            WalOp::Del { .. } | WalOp::Clear => {}
+        }
+    }
+}
+
+/// Default growth (in percent of the size after the last rewrite) that triggers a rewrite.
+pub const DEFAULT_WAL_REWRITE_PERCENTAGE: u64 = 100;
+/// Default minimum log size before automatic rewrites are considered.
+pub const DEFAULT_WAL_REWRITE_MIN_SIZE: u64 = 64 * 1024 * 1024;
+/// Prefix of records in format version 3.
+const WAL_RECORD_V3: &str = "v3:";
+/// Prefix of records in format version 2 (only read).
+const WAL_RECORD_V2: &str = "v2:";
+/// Associated data of version 3 records.
+const WAL_RECORD_AAD: &[u8] = b"aegisr appendonly.aewal v3";
+/// Associated data of version 2 records.
+const WAL_RECORD_AAD_V2: &[u8] = b"aegisr appendonly.aewal v2";
+/// Associated data of a collection's sealed operations, followed by the record's
+/// sequence number and the collection name.
+const WAL_OPS_AAD: &[u8] = b"aegisr wal ops v3";
+
+/// The operations of one collection in a record. Version 3 records seal them with the
+/// collection's data key, so they are gone with it once the collection is deleted;
+/// version 2 records hold them as they are.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum LoggedOps {
+    /// base64 of nonce and ciphertext.
+    Sealed(String),
+    Plain(Vec<WalOp>),
+}
+
+/// One atomic unit in the log: every mutation made under one engine lock,
+/// grouped by collection. A record is either replayed whole or not at all.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WalRecord {
+    pub seq: u64,
+    pub entries: Vec<(String, LoggedOps)>,
+}
+
+impl WalRecord {
+    /// Seal the operations of every collection with its data key.
+    fn seal(seq: u64, entries: Vec<(String, DataKey, Vec<WalOp>)>) -> Result<Self, String> {
+        let mut sealed = Vec::with_capacity(entries.len());
+        let mut result = Ok(());
+        for (name, data_key, ops) in entries {
+            if result.is_ok() {
+                match Self::seal_ops(seq, &name, &data_key, &ops) {
+                    Ok(logged) => sealed.push((name, logged)),
+                    Err(e) => result = Err(e),
+                }
+            }
+            ops.into_iter().for_each(WalOp::wipe);
+        }
+        result.map(|()| Self {
+            seq,
+            entries: sealed,
+        })
+    }
+
+    fn seal_ops(
+        seq: u64,
+        collection: &str,
+        data_key: &DataKey,
+        ops: &[WalOp],
+    ) -> Result<LoggedOps, String> {
+        let json =
+            Zeroizing::new(serde_json::to_vec(ops).map_err(|e| format!("serialize error: {}", e))?);
+        let sealed = AegCrypto::seal_with_nonce(
+            &data_key.sealing_key(),
+            &Self::ops_aad(seq, collection),
+            &json,
+        )?;
+        Ok(LoggedOps::Sealed(general_purpose::STANDARD.encode(sealed)))
+    }
+
+    /// The operations logged for `collection`, opened with its data key.
+    fn open_ops(
+        seq: u64,
+        collection: &str,
+        logged: LoggedOps,
+        data_key: &DataKey,
+    ) -> Result<Vec<WalOp>, String> {
+        let sealed = match logged {
+            LoggedOps::Plain(ops) => return Ok(ops),
+            LoggedOps::Sealed(sealed) => sealed,
+        };
+        let sealed = general_purpose::STANDARD
+            .decode(sealed)
+            .map_err(|e| format!("invalid base64: {}", e))?;
+        let json = AegCrypto::open_with_nonce(
+            &data_key.sealing_key(),
+            &Self::ops_aad(seq, collection),
+            &sealed,
+        )
+        .map_err(|_| {
+            format!(
+                "record {}: operations of '{}' do not decrypt with its data key",
+                seq, collection
+            )
+        })?;
+        serde_json::from_slice(&json).map_err(|e| format!("invalid record: {}", e))
+    }
+
+    fn ops_aad(seq: u64, collection: &str) -> Vec<u8> {
+        let mut aad = WAL_OPS_AAD.to_vec();
+        aad.extend_from_slice(&seq.to_le_bytes());
+        aad.extend_from_slice(collection.as_bytes());
+        aad
+    }
+
+    /// Drop the record, zeroizing the values it carries when secure memory is on.
+    pub(crate) fn wipe(self) {
+        for (_, logged) in self.entries {
+            Self::wipe_logged(logged);
+        }
+    }
+
+    fn wipe_logged(logged: LoggedOps) {
+        if let LoggedOps::Plain(ops) = logged {
+            ops.into_iter().for_each(WalOp::wipe);
+        }
+    }
+}
+
+struct WalState {
+    file: File,
+    policy: FsyncPolicy,
+    auth_key: Zeroizing<String>,
+    next_seq: u64,
+    unsynced: bool,
+    /// Current log size in bytes.
+    size: u64,
+    /// Log size right after it was opened or last rewritten.
+    base_size: u64,
+    /// Set when a failed append could not be cut off again; no record may follow it.
+    broken: Option<String>,
+}
+
+/// WRITE-AHEAD LOG (encrypted, append-only)
+///
+/// Each line is `v3:` followed by base64(nonce || AES-256-GCM(json record)), sealed with
+/// the authorization key, a fresh random nonce and the log's associated data. Inside the
+/// record, each collection's operations are sealed again with that collection's data key,
+/// bound to the record's sequence number and the collection name. Older lines are still
+/// read: version 2 (`v2:`, operations not sealed on their own) and version 1 (no prefix,
+/// no associated data).
+/// Records carry a global sequence number; collection snapshots remember the last
+/// sequence they include (`wal_seq`), so replay only applies newer records.
+pub struct AegWal;
+
+static WAL: OnceLock<Mutex<Option<WalState>>> = OnceLock::new();
+static REWRITE_RUNNING: AtomicBool = AtomicBool::new(false);
+static REWRITE_PERCENTAGE: AtomicU64 = AtomicU64::new(DEFAULT_WAL_REWRITE_PERCENTAGE);
+static REWRITE_MIN_SIZE: AtomicU64 = AtomicU64::new(DEFAULT_WAL_REWRITE_MIN_SIZE);
+
+impl AegWal {
+    fn state() -> &'static Mutex<Option<WalState>> {
+        WAL.get_or_init(|| Mutex::new(None))
+    }
+
+    pub fn wal_path() -> PathBuf {
+        AegFileSystem::get_config_path().join(STORE_WAL)
+    }
+
+    fn rewrite_path() -> PathBuf {
+        AegFileSystem::get_config_path().join(format!("{}.rewrite", STORE_WAL))
+    }
+
+    /// Rewrite automatically once the log has grown by `percentage` percent since the
+    /// last rewrite and is at least `min_size` bytes. A percentage of 0 disables it.
+    pub fn set_auto_rewrite(percentage: u64, min_size: u64) {
+        REWRITE_PERCENTAGE.store(percentage, Ordering::SeqCst);
+        REWRITE_MIN_SIZE.store(min_size, Ordering::SeqCst);
+    }
+
+    pub fn is_rewriting() -> bool {
+        REWRITE_RUNNING.load(Ordering::SeqCst)
+    }
+
+    /// Fsync policy of the open log (`None` when logging is disabled).
+    pub fn policy() -> Option<FsyncPolicy> {
+        Self::state()
+            .lock()
+            .expect("Failed to lock WAL state")
+            .as_ref()
+            .map(|s| s.policy)
+    }
+
+    pub fn is_enabled() -> bool {
+        Self::state()
+            .lock()
+            .expect("Failed to lock WAL state")
+            .is_some()
+    }
+
+    /// Open (or create) the log and start logging mutations. A torn last record left by
+    /// a crash is cut off. Safe to call again, e.g. after the data directory was reset.
+    pub fn open(policy: FsyncPolicy) -> Result<(), String> {
+        let path = Self::wal_path();
+        let auth_key = AegFileSystem::read_authorization_key();
+        let (records, valid_len) = Self::read_records(&path, &auth_key)?;
+
+        let file = AegPermissions::private_file_options()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| format!("open {}: {}", path.display(), e))?;
+        let current_len = file.metadata().map(|m| m.len()).unwrap_or(0);
+        if valid_len < current_len {
+            eprintln!(
+                "WAL: discarding {} byte(s) of incomplete record at end of {}",
+                current_len - valid_len,
+                path.display()
+            );
+            file.set_len(valid_len)
+                .map_err(|e| format!("truncate {}: {}", path.display(), e))?;
+        }
+        // a rewrite that was interrupted never replaced the log; drop its leftovers
+        let _ = fs::remove_file(Self::rewrite_path());
+
+        let mut guard = Self::state().lock().expect("Failed to lock WAL state");
+        let last_seq = records.last().map(|r| r.seq).unwrap_or(0);
+        // never go backwards within a process (the directory may have been reset)
+        let next_seq = guard
+            .as_ref()
+            .map(|s| s.next_seq)
+            .unwrap_or(1)
+            .max(last_seq + 1);
+        let first_open = guard.is_none();
+        *guard = Some(WalState {
+            file,
+            policy,
+            auth_key,
+            next_seq,
+            unsynced: false,
+            size: valid_len,
+            base_size: valid_len,
+            broken: None,
+        });
+        drop(guard);
+
+        if first_open && policy == FsyncPolicy::EverySec {
+            thread::spawn(|| {
+                loop {
+                    thread::sleep(Duration::from_secs(1));
+                    if let Err(e) = Self::sync() {
+                        eprintln!("WAL: fsync failed: {}", e);
+                    }
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// Append one record and return its sequence number (0 when the log is disabled).
+    /// With `FsyncPolicy::Always` the record is on stable storage when this returns.
+    /// When it fails the log is left as it was, or refuses every further append if a
+    /// partial record could not be removed.
+    pub fn append(entries: Vec<(String, DataKey, Vec<WalOp>)>) -> Result<u64, String> {
+        let mut guard = Self::state().lock().expect("Failed to lock WAL state");
+        let Some(state) = guard.as_mut() else {
+            return Ok(0);
+        };
+        if let Some(reason) = &state.broken {
+            return Err(format!(
+                "The write-ahead log is unusable ({}); restart the daemon",
+                reason
+            ));
+        }
+
+        let record = WalRecord::seal(state.next_seq, entries)?;
+        let seq = record.seq;
+        let line = Self::encrypt_record(&record, &state.auth_key);
+        record.wipe();
+        let line = line?;
+        let written = state
+            .file
+            .write_all(line.as_bytes())
+            .map_err(|e| format!("WAL write: {}", e))
+            .and_then(|()| match state.policy {
+                FsyncPolicy::Always => state
+                    .file
+                    .sync_data()
+                    .map_err(|e| format!("WAL fsync: {}", e)),
+                FsyncPolicy::EverySec | FsyncPolicy::No => Ok(()),
+            });
+        if let Err(e) = written {
+            // A partial record in the middle of the log would make it unreadable; cut the
+            // file back to the last complete record so the caller can drop the write.
+            if let Err(truncate) = state.file.set_len(state.size) {
+                state.broken = Some(format!("truncate after failed write: {}", truncate));
+            }
+            return Err(e);
+        }
+        state.next_seq += 1;
+        state.size += line.len() as u64;
+        if state.policy == FsyncPolicy::EverySec {
+            state.unsynced = true;
+        }
+
+        let percentage = REWRITE_PERCENTAGE.load(Ordering::SeqCst);
+        if percentage > 0
+            && state.size >= REWRITE_MIN_SIZE.load(Ordering::SeqCst)
+            && state.size >= state.base_size + state.base_size * percentage / 100
+        {
+            Self::spawn_rewrite();
+        }
+        Ok(seq)
+    }
+
+    /// Start a background rewrite of the log from the current in-memory state.
+    /// Writers keep running; only the final swap briefly holds the log lock.
+    pub fn start_rewrite() -> Result<(), String> {
+        if !Self::is_enabled() {
+            return Err("The write-ahead log is disabled".into());
+        }
+        if !Self::spawn_rewrite() {
+            return Err("A WAL rewrite is already in progress".into());
+        }
+        Ok(())
+    }
+
+    /// Returns false when a rewrite is already running.
+    fn spawn_rewrite() -> bool {
+        if REWRITE_RUNNING.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+        thread::spawn(|| {
+            match Self::rewrite() {
+                Ok(size) => eprintln!("WAL: rewrite complete ({} bytes)", size),
+                Err(e) => {
+                    eprintln!("WAL: rewrite failed: {}", e);
+                    let _ = fs::remove_file(Self::rewrite_path());
+                }
+            }
+            REWRITE_RUNNING.store(false, Ordering::SeqCst);
+        });
+        true
+    }
+
+    /// Replace the log with one record holding the state of every collection, followed by
+    /// whatever was appended while that record was being written. Returns the new size.
+    fn rewrite() -> Result<u64, String> {
+        let _persist = AegMemoryEngine::persist_lock();
+        let path = Self::wal_path();
+        let auth_key = AegFileSystem::read_authorization_key();
+
+        // Collections only present in the log (not touched since startup) must be loaded
+        // first, or their logged writes would be dropped. Anything written after this read
+        // goes through the cache, which never evicts. Deleted collections are left out.
+        let (records, _) = Self::read_records(&path, &auth_key)?;
+        let listed = AegCore::try_load()?.collections;
+        let names: HashSet<String> = records
+            .iter()
+            .flat_map(|r| r.entries.iter().map(|(name, _)| name.clone()))
+            .filter(|name| listed.contains(name))
+            .collect();
+        records.into_iter().for_each(WalRecord::wipe);
+
+        // Every append happens under the engine lock, so holding it pins the log position
+        // to exactly the state being cloned.
+        let (boundary, offset, engines) = AegMemoryEngine::with_collections(|collections| {
+            for name in &names {
+                collections.get_mut(name)?;
+            }
+            let guard = Self::state().lock().expect("Failed to lock WAL state");
+            let (seq, size) = guard
+                .as_ref()
+                .map(|s| (s.next_seq - 1, s.size))
+                .unwrap_or((0, 0));
+            Ok::<_, String>((seq, size, collections.snapshot()))
+        })??;
+        if boundary == 0 {
+            return Ok(offset);
+        }
+
+        let entries = engines
+            .into_iter()
+            .map(|mut engine| {
+                let op = WalOp::Restore {
+                    revision: engine.revision,
+                    store: std::mem::take(&mut engine.store),
+                    versions: std::mem::take(&mut engine.versions),
+                };
+                let data_key = engine.data_key.clone();
+                (
+                    std::mem::take(&mut engine.collection_name),
+                    data_key,
+                    vec![op],
+                )
+            })
+            .collect();
+        let record = WalRecord::seal(boundary, entries)?;
+        let temp_path = Self::rewrite_path();
+        let mut temp = AegPermissions::create_private_file(&temp_path)
+            .map_err(|e| format!("create {}: {}", temp_path.display(), e))?;
+        let line = Self::encrypt_record(&record, &auth_key);
+        record.wipe();
+        temp.write_all(line?.as_bytes())
+            .map_err(|e| format!("write {}: {}", temp_path.display(), e))?;
+
+        // Swap: copy the tail appended meanwhile, then rename over the live log.
+        let mut guard = Self::state().lock().expect("Failed to lock WAL state");
+        let Some(state) = guard.as_mut() else {
+            return Err("the write-ahead log was closed".into());
+        };
+        let mut tail = Vec::new();
+        let mut live = File::open(&path).map_err(|e| format!("open {}: {}", path.display(), e))?;
+        live.seek(SeekFrom::Start(offset))
+            .and_then(|_| live.read_to_end(&mut tail))
+            .map_err(|e| format!("read {}: {}", path.display(), e))?;
+        temp.write_all(&tail)
+            .and_then(|_| temp.sync_all())
+            .map_err(|e| format!("write {}: {}", temp_path.display(), e))?;
+        fs::rename(&temp_path, &path).map_err(|e| format!("rename: {}", e))?;
+        if let Some(dir) = path.parent()
+            && let Ok(dir) = File::open(dir)
+        {
+            let _ = dir.sync_all();
+        }
+
+        state.file = OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .map_err(|e| format!("reopen {}: {}", path.display(), e))?;
+        state.size = state.file.metadata().map(|m| m.len()).unwrap_or(0);
+        state.base_size = state.size;
+        state.unsynced = false;
+        Ok(state.size)
+    }
+
+    /// Flush pending log writes to stable storage.
+    pub fn sync() -> Result<(), String> {
+        let mut guard = Self::state().lock().expect("Failed to lock WAL state");
+        if let Some(state) = guard.as_mut()
+            && state.unsynced
+        {
+            state.file.sync_data().map_err(|e| e.to_string())?;
+            state.unsynced = false;
+        }
+        Ok(())
+    }
+
+    /// Empty the log (after a restore replaced every collection file). Sequence numbers
+    /// keep counting up. Works on the file directly when the log is not open.
+    pub fn reset() -> Result<(), String> {
+        let mut guard = Self::state().lock().expect("Failed to lock WAL state");
+        match guard.as_mut() {
+            Some(state) => {
+                state
+                    .file
+                    .set_len(0)
+                    .and_then(|_| state.file.sync_all())
+                    .map_err(|e| format!("WAL truncate: {}", e))?;
+                state.size = 0;
+                state.base_size = 0;
+                state.unsynced = false;
+            }
+            None => {
+                let path = Self::wal_path();
+                if path.exists() {
+                    OpenOptions::new()
+                        .write(true)
+                        .open(&path)
+                        .and_then(|f| {
+                            f.set_len(0)?;
+                            f.sync_all()
+                        })
+                        .map_err(|e| format!("WAL truncate: {}", e))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Empty the log and seal everything appended from now on with `auth_key` (live key
+    /// rotation, once every collection file holds all logged writes).
+    pub(crate) fn rekey(auth_key: &str) -> Result<(), String> {
+        Self::reset()?;
+        if let Some(state) = Self::state()
+            .lock()
+            .expect("Failed to lock WAL state")
+            .as_mut()
+        {
+            state.auth_key = Zeroizing::new(auth_key.to_string());
+        }
+        Ok(())
+    }
+
+    /// Re-encrypt every record of the log file under `new_key`, whether it is sealed with
+    /// `old_key` or already with `new_key` (offline key rotation; the log must not be
+    /// open). Operations sealed with a collection's data key are carried over as they are.
+    /// A torn last record is dropped. Returns the number of records.
+    pub(crate) fn reencrypt(old_key: &str, new_key: &str) -> Result<usize, String> {
+        let path = Self::wal_path();
+        let Ok(content) = fs::read_to_string(&path) else {
+            return Ok(0);
+        };
+        let mut out = String::with_capacity(content.len());
+        let mut count = 0;
+        for line in content.split_inclusive('\n') {
+            if !line.ends_with('\n') {
+                break;
+            }
+            let line = line.trim_end();
+            let record = Self::decrypt_record(line, new_key)
+                .or_else(|_| Self::decrypt_record(line, old_key))
+                .map_err(|e| format!("{} record {}: {}", STORE_WAL, count + 1, e))?;
+            let sealed = Self::encrypt_record(&record, new_key);
+            record.wipe();
+            out.push_str(&sealed?);
+            count += 1;
+        }
+        AegFileSystem::write_atomic(&path, out.as_bytes())?;
+        Ok(count)
+    }
+
+    /// Sequence number of the last record appended (0 when the log is disabled).
+    pub fn last_seq() -> u64 {
+        Self::state()
+            .lock()
+            .expect("Failed to lock WAL state")
+            .as_ref()
+            .map(|s| s.next_seq - 1)
+            .unwrap_or(0)
+    }
+
+    /// Sequence number of the last record in the log file at `path` (0 when there is
+    /// none), whether or not the log is open (used by offline tools).
+    pub(crate) fn last_seq_in(path: &PathBuf, auth_key: &str) -> Result<u64, String> {
+        let (records, _) = Self::read_records(path, auth_key)?;
+        let last = records.iter().map(|r| r.seq).max().unwrap_or(0);
+        records.into_iter().for_each(WalRecord::wipe);
+        Ok(last)
+    }
+
+    /// Make sure sequence numbers handed out from now on are above `seq`
+    /// (the `wal_seq` of a snapshot that was just loaded).
+    pub fn observe_seq(seq: u64) {
+        let mut guard = Self::state().lock().expect("Failed to lock WAL state");
+        if let Some(state) = guard.as_mut() {
+            state.next_seq = state.next_seq.max(seq + 1);
+        }
+    }
+
+    /// Records touching `collection` with a sequence number above `after_seq`,
+    /// oldest first, reduced to that collection's operations (opened with `data_key`).
+    /// Fails when the log or one of those operations cannot be read.
+    pub fn replay(
+        collection: &str,
+        after_seq: u64,
+        data_key: &DataKey,
+    ) -> Result<Vec<(u64, Vec<WalOp>)>, String> {
+        if !Self::is_enabled() {
+            return Ok(Vec::new());
+        }
+        let auth_key = AegFileSystem::read_authorization_key();
+        Self::collection_ops(
+            &Self::wal_path(),
+            &auth_key,
+            collection,
+            after_seq,
+            data_key,
+        )
+    }
+
+    /// `replay` against an explicit log file and key, whether or not the log is open
+    /// (used by offline tools).
+    pub(crate) fn collection_ops(
+        path: &PathBuf,
+        auth_key: &str,
+        collection: &str,
+        after_seq: u64,
+        data_key: &DataKey,
+    ) -> Result<Vec<(u64, Vec<WalOp>)>, String> {
+        let (records, _) = Self::read_records(path, auth_key)?;
+        let mut result = Vec::new();
+        let mut failure = None;
+        for record in records {
+            if record.seq <= after_seq || failure.is_some() {
+                record.wipe();
+                continue;
+            }
+            let mut ops = Vec::new();
+            for (name, logged) in record.entries {
+                if name != collection {
+                    WalRecord::wipe_logged(logged);
+                } else if failure.is_none() {
+                    match WalRecord::open_ops(record.seq, collection, logged, data_key) {
+                        Ok(opened) => ops.extend(opened),
+                        Err(e) => failure = Some(e),
+                    }
+                }
+            }
+            if !ops.is_empty() {
+                result.push((record.seq, ops));
+            }
+        }
+        match failure {
+            None => Ok(result),
+            Some(e) => {
+                for (_, ops) in result {
+                    ops.into_iter().for_each(WalOp::wipe);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Cut the log at its first unreadable record, dropping it and everything after it
+    /// (offline repair). Returns how many bytes were removed.
+    pub(crate) fn truncate_at_corruption(path: &PathBuf, auth_key: &str) -> Result<u64, String> {
+        let content =
+            fs::read_to_string(path).map_err(|e| format!("read {}: {}", path.display(), e))?;
+        let mut valid_len = 0usize;
+        for line in content.split_inclusive('\n') {
+            if !line.ends_with('\n') || Self::decrypt_record(line.trim_end(), auth_key).is_err() {
+                break;
+            }
+            valid_len += line.len();
+        }
+        let removed = (content.len() - valid_len) as u64;
+        if removed > 0 {
+            let file = OpenOptions::new()
+                .write(true)
+                .open(path)
+                .map_err(|e| format!("open {}: {}", path.display(), e))?;
+            file.set_len(valid_len as u64)
+                .and_then(|_| file.sync_all())
+                .map_err(|e| format!("truncate {}: {}", path.display(), e))?;
+        }
+        Ok(removed)
+    }
+
+    /// Decode every complete record. Also returns the byte length of the valid prefix,
+    /// so a torn tail can be cut off.
+    pub(crate) fn read_records(
+        path: &PathBuf,
+        auth_key: &str,
+    ) -> Result<(Vec<WalRecord>, u64), String> {
+        if !path.exists() {
+            return Ok((Vec::new(), 0));
+        }
+        let content =
+            fs::read_to_string(path).map_err(|e| format!("read {}: {}", path.display(), e))?;
+
+        let mut records = Vec::new();
+        let mut valid_len = 0u64;
+        let mut offset = 0u64;
+        for line in content.split_inclusive('\n') {
+            offset += line.len() as u64;
+            if !line.ends_with('\n') {
+                break; // torn write: the newline is the last byte of a record
+            }
+            match Self::decrypt_record(line.trim_end(), auth_key) {
+                Ok(record) => {
+                    records.push(record);
+                    valid_len = offset;
+                }
+                Err(e) if offset == content.len() as u64 => {
+                    eprintln!("WAL: ignoring unreadable last record: {}", e);
+                }
+                Err(e) => {
+                    return Err(format!(
+                        "corrupt record in {} before byte {}: {}",
+                        path.display(),
+                        offset,
+                        e
+                    ));
+                }
+            }
+        }
+        Ok((records, valid_len))
+    }
+
+    fn encrypt_record(record: &WalRecord, auth_key: &str) -> Result<String, String> {
+        let json = Zeroizing::new(
+            serde_json::to_vec(record).map_err(|e| format!("serialize error: {}", e))?,
+        );
+        let key = AegCrypto::sealing_key(auth_key)?;
+        let sealed = AegCrypto::seal_with_nonce(&key, WAL_RECORD_AAD, &json)?;
+        Ok(format!(
+            "{}{}\n",
+            WAL_RECORD_V3,
+            general_purpose::STANDARD.encode(sealed)
+        ))
+    }
+
+    fn decrypt_record(line: &str, auth_key: &str) -> Result<WalRecord, String> {
+        let (payload, aad) = if let Some(rest) = line.strip_prefix(WAL_RECORD_V3) {
+            (rest, WAL_RECORD_AAD)
+        } else if let Some(rest) = line.strip_prefix(WAL_RECORD_V2) {
+            (rest, WAL_RECORD_AAD_V2)
+        } else {
+            (line, &[][..])
+        };
+        let payload = general_purpose::STANDARD
+            .decode(payload)
+            .map_err(|e| format!("invalid base64: {}", e))?;
+        let key = AegCrypto::sealing_key(auth_key)?;
+        let decrypted = AegCrypto::open_with_nonce(&key, aad, &payload)?;
+        serde_json::from_slice(&decrypted).map_err(|e| format!("invalid record: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot_format::AegSnapshotFormat;
+    use crate::test_support::DataHome;
+
+    fn set(key: &str, value: &str) -> WalOp {
+        WalOp::Set {
+            key: key.into(),
+            value: value.into(),
+        }
+    }
+
+    #[test]
+    fn appended_records_replay_per_collection() {
+        let _home = DataHome::new();
+        let (a, b) = (DataKey::generate(), DataKey::generate());
+        let first = AegWal::append(vec![
+            ("a".into(), a.clone(), vec![set("k1", "v1")]),
+            ("b".into(), b.clone(), vec![set("k2", "v2")]),
+        ])
+        .unwrap();
+        let del = WalOp::Del { key: "k1".into() };
+        let second = AegWal::append(vec![(
+            "a".into(),
+            a.clone(),
+            vec![del.clone(), set("k3", "v3")],
+        )])
+        .unwrap();
+        assert_eq!(second, first + 1);
+        assert_eq!(AegWal::last_seq(), second);
+
+        assert_eq!(
+            AegWal::replay("a", 0, &a).unwrap(),
+            vec![
+                (first, vec![set("k1", "v1")]),
+                (second, vec![del.clone(), set("k3", "v3")])
+            ]
+        );
+        assert_eq!(
+            AegWal::replay("a", first, &a).unwrap(),
+            vec![(second, vec![del, set("k3", "v3")])]
+        );
+        assert_eq!(
+            AegWal::replay("b", 0, &b).unwrap(),
+            vec![(first, vec![set("k2", "v2")])]
+        );
+
+        // each collection's operations are sealed with its own data key
+        let auth_key = AegFileSystem::read_authorization_key();
+        assert!(AegWal::collection_ops(&AegWal::wal_path(), &auth_key, "a", 0, &b).is_err());
+    }
+
+    #[test]
+    fn a_torn_last_record_is_cut_off() {
+        let _home = DataHome::new();
+        let auth_key = AegFileSystem::read_authorization_key();
+        let path = AegWal::wal_path();
+        let key = DataKey::generate();
+        let start = AegWal::last_seq();
+        let line = |seq: u64| {
+            let ops = vec![set("k", &seq.to_string())];
+            let record = WalRecord::seal(seq, vec![("c".into(), key.clone(), ops)]).unwrap();
+            AegWal::encrypt_record(&record, &auth_key).unwrap()
+        };
+        let mut content: String = (start + 1..=start + 3).map(line).collect();
+        let complete = content.len() as u64;
+        let torn = line(start + 4);
+        content.push_str(&torn[..torn.len() / 2]);
+        fs::write(&path, &content).unwrap();
+
+        let (records, valid_len) = AegWal::read_records(&path, &auth_key).unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(valid_len, complete);
+        assert_eq!(
+            AegWal::collection_ops(&path, &auth_key, "c", start + 2, &key).unwrap(),
+            vec![(start + 3, vec![set("k", &(start + 3).to_string())])]
+        );
+
+        // opening the log cuts the partial record off and appends after the last whole one
+        AegWal::open(FsyncPolicy::Always).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), complete);
+        let seq = AegWal::append(vec![("c".into(), key.clone(), vec![set("k", "new")])]).unwrap();
+        assert_eq!(seq, start + 4);
+        assert_eq!(AegWal::read_records(&path, &auth_key).unwrap().0.len(), 4);
+    }
+
+    #[test]
+    fn a_damaged_record_before_the_end_is_an_error() {
+        let _home = DataHome::new();
+        let auth_key = AegFileSystem::read_authorization_key();
+        let path = AegWal::wal_path();
+        let key = DataKey::generate();
+        for value in ["1", "2", "3"] {
+            AegWal::append(vec![("c".into(), key.clone(), vec![set("k", value)])]).unwrap();
+        }
+        let mut lines: Vec<String> = fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect();
+        let damaged = lines[1].replace('A', "B").replace('a', "b");
+        assert_ne!(damaged, lines[1]);
+
+        lines[1] = damaged.clone();
+        fs::write(&path, lines.join("\n") + "\n").unwrap();
+        assert!(AegWal::read_records(&path, &auth_key).is_err());
+
+        // the same damage in the last record is taken for a torn write
+        lines.swap(1, 2);
+        fs::write(&path, lines.join("\n") + "\n").unwrap();
+        assert_eq!(AegWal::read_records(&path, &auth_key).unwrap().0.len(), 2);
+    }
+
+    #[test]
+    fn rewrite_replaces_the_log_with_the_current_state() {
+        let _home = DataHome::new();
+        for i in 0..20 {
+            AegMemoryEngine::with_collection("default", |engine| {
+                engine.insert(format!("k{}", i % 5), format!("v{}", i))
+            })
+            .unwrap();
+        }
+        AegMemoryEngine::with_collection("default", |engine| engine.remove("k0")).unwrap();
+        let mut expected =
+            AegMemoryEngine::read_collection("default", |e| e.store.clone()).unwrap();
+        let auth_key = AegFileSystem::read_authorization_key();
+        let path = AegWal::wal_path();
+        assert_eq!(AegWal::read_records(&path, &auth_key).unwrap().0.len(), 21);
+
+        AegWal::rewrite().unwrap();
+        let (records, _) = AegWal::read_records(&path, &auth_key).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].seq, AegWal::last_seq());
+
+        AegMemoryEngine::with_collection("default", |engine| engine.insert("after", "rewrite"))
+            .unwrap();
+        expected.insert("after".into(), "rewrite".into());
+
+        // what a restart sees: the snapshot on disk rolled forward with the log
+        let file = AegMemoryEngine::engine_file_path("default");
+        let mut engine = AegSnapshotFormat::read(&file, &auth_key, "default").unwrap();
+        engine.apply_wal(AegWal::replay("default", engine.wal_seq, &engine.data_key).unwrap());
+        assert_eq!(engine.store, expected);
+    }
+
+    #[test]
+    fn rewrite_keeps_collections_only_in_the_log_and_drops_deleted_ones() {
+        let _home = DataHome::new();
+        AegCore::create_collection("kept");
+        AegCore::create_collection("deleted");
+        for name in ["kept", "deleted"] {
+            AegMemoryEngine::with_collection(name, |engine| engine.insert("k", name)).unwrap();
+        }
+        // as after a restart: neither collection is cached, their writes are only logged
+        AegMemoryEngine::with_collections(|collections| collections.replace_all(Vec::new()))
+            .unwrap();
+        let mut core = AegCore::load();
+        core.collections.retain(|name| name != "deleted");
+        core.save();
+
+        AegWal::rewrite().unwrap();
+        let auth_key = AegFileSystem::read_authorization_key();
+        let (records, _) = AegWal::read_records(&AegWal::wal_path(), &auth_key).unwrap();
+        assert_eq!(records.len(), 1);
+        let names: Vec<&str> = records[0]
+            .entries
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect();
+        assert_eq!(names, ["kept"]);
+
+        let file = AegMemoryEngine::engine_file_path("kept");
+        let mut engine = AegSnapshotFormat::read(&file, &auth_key, "kept").unwrap();
+        engine.apply_wal(AegWal::replay("kept", engine.wal_seq, &engine.data_key).unwrap());
+        assert_eq!(engine.get("k").as_deref(), Some("kept"));
+    }
+}
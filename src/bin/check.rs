@@ -0,0 +1,190 @@
+use aegisrlib::{
+    AegAudit, AegCheck, AegFileSystem, AegKeyProvider, AegKeyRotation, CheckIssue, CheckReport,
+    ENGINE_DEVELOPER, ENGINE_VERSION, Resolution,
+};
+use clap::Parser;
+use colored::Colorize;
+use std::net::{SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Duration;
+
+const DAEMON_ADDRESS: &str = "127.0.0.1:1211";
+
+/// Verify the data directory and optionally fix what is wrong with it.
+///
+/// Exit status: 0 when everything is healthy, 1 when issues remain, 2 on errors.
+#[derive(Parser)]
+#[command(name = "aegisr-check", author = ENGINE_DEVELOPER[0], version = ENGINE_VERSION)]
+pub struct AegCheckCli {
+    #[arg(
+        long,
+        conflicts_with = "quarantine",
+        help = "Salvage damaged collections, register orphaned files and rebuild missing ones"
+    )]
+    repair: bool,
+    #[arg(
+        long,
+        help = "Move damaged and orphaned files to the quarantine directory instead"
+    )]
+    quarantine: bool,
+    #[arg(
+        long = "auth-key",
+        env = "AEGISR_AUTH_KEY",
+        hide_env_values = true,
+        help = "Authorization key (default: the key file in the data directory)"
+    )]
+    auth_key: Option<String>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Daemon config file whose key_provider supplies the key"
+    )]
+    config: Option<PathBuf>,
+    #[arg(long, help = "Verify the hash chain of the audit log instead")]
+    audit: bool,
+    #[arg(
+        long,
+        value_name = "DIR",
+        requires = "audit",
+        help = "Audit log directory (default: audit/ in the data directory)"
+    )]
+    audit_dir: Option<PathBuf>,
+}
+
+impl AegCheckCli {
+    pub fn start() {
+        let cli = AegCheckCli::parse();
+        if let Some(path) = &cli.config {
+            match AegKeyProvider::config_from_file(path) {
+                Ok(config) => AegKeyProvider::configure(config),
+                Err(e) => Self::fail(&e),
+            }
+        }
+        if AegKeyRotation::is_pending() {
+            Self::fail(
+                "a key rotation was interrupted; finish it with `aegisr-key rotate` or by starting the daemon",
+            );
+        }
+        let auth_key = match AegFileSystem::resolve_authorization_key(cli.auth_key.as_deref()) {
+            Ok(key) => key,
+            Err(e) => Self::fail(&e),
+        };
+        if cli.audit {
+            return Self::verify_audit(cli.audit_dir.as_deref(), &auth_key);
+        }
+        let resolution = match (cli.repair, cli.quarantine) {
+            (true, _) => Some(Resolution::Repair),
+            (_, true) => Some(Resolution::Quarantine),
+            _ => None,
+        };
+
+        let report = AegCheck::scan(&auth_key, true);
+        Self::print_report(&report);
+        let Some(resolution) = resolution else {
+            if !report.issues.is_empty() {
+                println!(
+                    "Run with --repair to keep as much data as possible, or --quarantine to set damaged files aside."
+                );
+                process::exit(1);
+            }
+            return;
+        };
+        if report.issues.is_empty() {
+            return;
+        }
+        if Self::daemon_running() {
+            Self::fail("the daemon is running; stop it before repairing the data directory");
+        }
+
+        match AegCheck::resolve(&auth_key, resolution) {
+            Ok(actions) => {
+                for action in actions {
+                    println!("{}", format!("✓ {}", action).green());
+                }
+            }
+            Err(e) => Self::fail(&e),
+        }
+        let after = AegCheck::scan(&auth_key, true);
+        if after.issues.is_empty() {
+            println!("{}", "✓ Data directory is healthy.".green());
+        } else {
+            for issue in &after.issues {
+                println!("{}", format!("✗ still unresolved: {}", issue).red());
+            }
+            process::exit(1);
+        }
+    }
+
+    fn print_report(report: &CheckReport) {
+        for (name, keys) in &report.healthy {
+            match keys {
+                Some(keys) => println!("{}", format!("✓ {} ({} keys)", name, keys).green()),
+                None => println!("{}", format!("✓ {}", name).green()),
+            }
+        }
+        for issue in &report.issues {
+            let line = format!("✗ {}", issue);
+            match issue {
+                CheckIssue::LeftoverTemp { .. } | CheckIssue::InsecurePermissions { .. } => {
+                    println!("{}", line.yellow())
+                }
+                _ => println!("{}", line.red()),
+            }
+        }
+        if report.issues.is_empty() {
+            println!("{}", "✓ No issues found.".green());
+        }
+    }
+
+    fn daemon_running() -> bool {
+        let address: SocketAddr = DAEMON_ADDRESS.parse().unwrap();
+        TcpStream::connect_timeout(&address, Duration::from_secs(1)).is_ok()
+    }
+
+    /// Entries are plaintext; the key only opens `audit.key`, which the hashes are keyed with.
+    fn verify_audit(dir: Option<&Path>, auth_key: &str) {
+        let dir = dir
+            .map(Path::to_path_buf)
+            .unwrap_or_else(AegAudit::default_dir);
+        if !dir.exists() {
+            Self::fail(&format!("no audit log in {}", dir.display()));
+        }
+        match AegAudit::verify(Some(&dir), auth_key) {
+            Ok(report) => {
+                println!(
+                    "{}",
+                    format!(
+                        "✓ Audit log intact: {} entr(ies) in {} file(s), seq {}-{}",
+                        report.entries, report.files, report.first_seq, report.last_seq
+                    )
+                    .green()
+                );
+                if !report.complete {
+                    println!(
+                        "{}",
+                        format!(
+                            "The chain starts at entry {}; older logs were removed.",
+                            report.first_seq
+                        )
+                        .yellow()
+                    );
+                }
+                println!("Head: {}", report.head);
+            }
+            Err(e) => {
+                println!("{}", format!("✗ {}", e).red());
+                process::exit(1);
+            }
+        }
+    }
+
+    fn fail(message: &str) -> ! {
+        eprintln!("{}", format!("Error: {}", message).red());
+        process::exit(2);
+    }
+}
+
+fn main() {
+    AegCheckCli::start();
+}
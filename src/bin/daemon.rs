@@ -1,15 +1,24 @@
-use aegisrlib::{AegCore, AegFileSystem, AegisrCommand};
+use aegisrlib::{
+    AegAudit, AegBackup, AegCheck, AegCollections, AegCore, AegFileSystem, AegKeyProvider,
+    AegKeyRotation, AegMemoryEngine, AegPermissions, AegScripting, AegSecureMemory,
+    AegSnapshotFormat, AegTransfer, AegWal, AegisrCommand, AuditConfig, CasExpectation,
+    Compression, DEFAULT_AUDIT_MAX_SIZE, DEFAULT_AUDIT_RETENTION, DEFAULT_SNAPSHOT_RETENTION,
+    DEFAULT_WAL_REWRITE_MIN_SIZE, DEFAULT_WAL_REWRITE_PERCENTAGE, FsyncPolicy, PermissionPolicy,
+    SaveRule, ScriptLimits, SetCondition,
+};
 use clap::Parser;
 use hostname::get as get_hostname;
-use serde::{Deserialize, Serialize};
-use serde_json::json;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::collections::HashMap;
 use std::fs;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::process;
 use std::thread;
 use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpListener;
+use tokio::net::{TcpListener, TcpStream};
 use tokio::signal;
 use tracing::{debug, error, info, warn};
 use tracing_appender::rolling::{RollingFileAppender, Rotation};
@@ -30,11 +39,58 @@ impl Default for LoggerConfig {
     }
 }
 
-/// JSON config structure
-#[derive(Debug, Deserialize)]
+/// JSON config structure. `key_provider` is read on its own, strictly, by
+/// `AegKeyProvider::config_from_file`.
+#[derive(Debug, Default, Deserialize)]
 struct DaemonConfig {
     host: Option<String>,
     port: Option<u16>,
+    /// Wall-clock limit for a single EVAL/EVALSHA run.
+    script_time_limit_ms: Option<u64>,
+    /// Longest string a script may build, in bytes (0 = no limit).
+    script_max_string_size: Option<usize>,
+    /// Most elements in an array a script builds (0 = no limit).
+    script_max_array_size: Option<usize>,
+    /// Most properties in an object map a script builds (0 = no limit).
+    script_max_map_size: Option<usize>,
+    /// Deepest nesting of function calls in a script (0 = no limit).
+    script_max_call_levels: Option<usize>,
+    /// Most operations in one script run (0 = no limit).
+    script_max_operations: Option<u64>,
+    /// Log every write to the append-only WAL (default true).
+    appendonly: Option<bool>,
+    /// When the WAL is fsynced: "always", "everysec" (default) or "no".
+    appendfsync: Option<FsyncPolicy>,
+    /// Rewrite the WAL once it grew by this many percent since the last rewrite (0 = never).
+    wal_rewrite_percentage: Option<u64>,
+    /// ...and is at least this many bytes.
+    wal_rewrite_min_size: Option<u64>,
+    /// Snapshot rules, e.g. `[{"changes": 1000, "seconds": 60}]` (default: any change, every second).
+    save: Option<Vec<SaveRule>>,
+    /// Snapshot compression for all collections: "none" (default) or "zstd".
+    compression: Option<Compression>,
+    /// Per-collection compression overrides, e.g. `{"logs": "zstd"}`.
+    collection_compression: Option<HashMap<String, Compression>>,
+    /// Where SNAPSHOT writes backups (default `~/.aegisr/backups`).
+    backup_dir: Option<String>,
+    /// Snapshots to keep; older ones are deleted (default 7, 0 = keep all).
+    snapshot_retention: Option<usize>,
+    /// Lock the process memory (mlockall), disable core dumps, and zeroize values when they
+    /// are deleted, overwritten or cleared (default false).
+    secure_memory: Option<bool>,
+    /// A key file accessible by others or owned by another user: "strict" (default) refuses
+    /// to start, "warn" only prints a warning.
+    key_file_permissions: Option<PermissionPolicy>,
+    /// Record administrative operations in a hash-chained audit log (default false).
+    audit_log: Option<bool>,
+    /// Where the audit log is written (default `~/.aegisr/audit`).
+    audit_dir: Option<String>,
+    /// Rotate the audit log once it reaches this many bytes (default 16 MiB, 0 = never).
+    audit_max_size: Option<u64>,
+    /// Rotated audit logs to keep; older ones are deleted (default 0 = keep all).
+    audit_retention: Option<usize>,
+    /// Collections whose reads are audited too, e.g. `["secrets"]`.
+    audit_read_collections: Option<Vec<String>>,
 }
 
 /// CLI arguments
@@ -47,14 +103,10 @@ struct CliArgs {
     port: Option<u16>,
     #[arg(short, long)]
     config: Option<String>,
-}
-
-/// Standard JSON response
-#[derive(Serialize)]
-struct JsonResponse<T: Serialize> {
-    status: String,
-    message: String,
-    data: Option<T>,
+    /// Read the passphrase of a protected AUTHORIZATION_KEY from this file descriptor
+    /// (otherwise AEGISR_PASSPHRASE, otherwise a prompt).
+    #[arg(long, value_name = "FD")]
+    passphrase_fd: Option<u32>,
 }
 
 /// Initialize tracing subscriber
@@ -65,9 +117,8 @@ fn init_tracing(cfg: &LoggerConfig) {
 
     if cfg.log_to_file {
         let mut log_dir = AegFileSystem::get_config_path();
-        std::fs::create_dir_all(&log_dir).ok();
         log_dir.push("logs");
-        std::fs::create_dir_all(&log_dir).ok();
+        AegPermissions::create_private_dir(&log_dir).ok();
 
         let file_appender = RollingFileAppender::new(Rotation::DAILY, &log_dir, "daemon.log");
         let (file_writer, _guard) = tracing_appender::non_blocking(file_appender);
@@ -91,10 +142,19 @@ pub struct AegDaemon {
     pub pid: u32,
     pub hostname: String,
     pub logger_cfg: LoggerConfig,
+    /// `None` disables the write-ahead log.
+    pub wal_policy: Option<FsyncPolicy>,
+    /// `None` disables the audit log.
+    pub audit: Option<AuditConfig>,
 }
 
 impl AegDaemon {
-    pub fn new(address: &str, logger_cfg: LoggerConfig) -> Self {
+    pub fn new(
+        address: &str,
+        logger_cfg: LoggerConfig,
+        wal_policy: Option<FsyncPolicy>,
+        audit: Option<AuditConfig>,
+    ) -> Self {
         let hostname = get_hostname()
             .map(|s| s.to_string_lossy().to_string())
             .unwrap_or("unknown".into());
@@ -104,13 +164,101 @@ impl AegDaemon {
             pid: process::id(),
             hostname,
             logger_cfg,
+            wal_policy,
+            audit,
         }
     }
 
     pub async fn start(&self) {
         AegCore::start_background_saver(1);
-        AegFileSystem::validate_files();
         init_tracing(&self.logger_cfg);
+        info!(provider = %AegKeyProvider::describe(), "Using key provider");
+        if AegSecureMemory::is_enabled() {
+            info!("Secure memory enabled");
+        }
+        AegFileSystem::recover_temp_files();
+        match AegKeyRotation::recover_pending() {
+            Ok(Some(report)) => {
+                warn!(
+                    collections = report.collections,
+                    snapshots = report.snapshots,
+                    wal_records = report.wal_records,
+                    "Finished interrupted key rotation"
+                );
+                for snapshot in report.skipped_snapshots {
+                    warn!(%snapshot, "Snapshot taken with an earlier key was not re-wrapped");
+                }
+            }
+            Ok(None) => {}
+            Err(e) => {
+                error!("Failed to finish interrupted key rotation: {}", e);
+                return;
+            }
+        }
+        match AegCheck::startup_check() {
+            Ok(warnings) => {
+                for issue in warnings {
+                    warn!("Data directory: {}", issue);
+                }
+            }
+            Err(e) => {
+                error!("Refusing to start, data directory is damaged: {}", e);
+                return;
+            }
+        }
+        AegFileSystem::validate_files();
+        match AegBackup::recover_pending_restore() {
+            Ok(Some(snapshot)) => warn!("Finished interrupted restore of {}", snapshot.display()),
+            Ok(None) => {}
+            Err(e) => {
+                error!("Failed to finish interrupted restore: {}", e);
+                return;
+            }
+        }
+        let migrated = AegMemoryEngine::migrate_legacy_files();
+        if migrated > 0 {
+            info!(
+                migrated,
+                "Collections migrated to the current snapshot format"
+            );
+        }
+        // Before the WAL opens, so the writes it holds for them are replayed.
+        match AegMemoryEngine::create_missing_files() {
+            Ok(created) => {
+                for collection in created {
+                    warn!(%collection, "Created missing collection file");
+                }
+            }
+            Err(e) => {
+                error!("Failed to create missing collection files: {}", e);
+                return;
+            }
+        }
+        if let Some(policy) = self.wal_policy {
+            match AegWal::open(policy) {
+                Ok(()) => info!(%policy, "Write-ahead log enabled"),
+                Err(e) => {
+                    error!("Failed to open write-ahead log: {}", e);
+                    return;
+                }
+            }
+        }
+        if let Some(config) = &self.audit {
+            match AegAudit::open(config.clone(), &AegFileSystem::read_authorization_key()) {
+                Ok(()) => info!("Audit log enabled"),
+                Err(e) => {
+                    error!("Failed to open audit log: {}", e);
+                    return;
+                }
+            }
+            record_audit(
+                "daemon",
+                "start",
+                None,
+                Some(format!("pid {}", self.pid)),
+                true,
+            );
+        }
         self.print_banner();
         self.spawn_background_worker();
 
@@ -129,36 +277,20 @@ impl AegDaemon {
 
         loop {
             tokio::select! {
-                Ok((mut socket, addr)) = listener.accept() => {
+                Ok((socket, addr)) = listener.accept() => {
                     info!(%addr, "Client connected");
 
-                    tokio::spawn(async move {
-                        let mut buffer = vec![0u8; 4096];
-
-                        match socket.read(&mut buffer).await {
-                            Ok(n) if n > 0 => {
-                                let data = &buffer[..n];
-                                let response_json = match serde_json::from_slice::<AegisrCommand>(data) {
-                                Ok(cmd) => handle_command(cmd).await.to_json(),
-                                    Err(_) => serde_json::to_string(&json!({
-                                        "status": "error",
-                                        "message": "Invalid command"
-                                    })).unwrap(),
-                                };
-                                if let Err(e) = socket.write_all(response_json.as_bytes()).await {
-                                    error!(%e, "Failed sending response");
-                                }
-                            }
-                            Ok(_) => debug!("Connection closed without data"),
-                            Err(e) => error!(%e, "Socket read error"),
-                        }
-                    });
+                    tokio::spawn(serve_connection(socket, addr));
                 }
 
                 _ = signal::ctrl_c() => {
                     info!("Ctrl+C detected — shutting down daemon");
                     AegCore::stop_background_saver();
                     AegCore::flush_now();
+                    if let Err(e) = AegWal::sync() {
+                        error!("Failed to sync write-ahead log: {}", e);
+                    }
+                    record_audit("daemon", "stop", None, None, true);
 
                     break;
                 }
@@ -190,33 +322,281 @@ impl AegDaemon {
 }
 
 enum CommandResult {
-    Text { message: String, success: bool },
-    List { items: Vec<String>, success: bool },
+    Text {
+        message: String,
+        success: bool,
+    },
+    List {
+        items: Vec<String>,
+        success: bool,
+    },
+    Values {
+        items: Vec<Option<String>>,
+        success: bool,
+    },
+    Previous {
+        message: String,
+        previous: Option<String>,
+        success: bool,
+    },
+    Batch {
+        results: Vec<CommandResult>,
+    },
+    Data {
+        data: Value,
+        success: bool,
+    },
 }
 
 impl CommandResult {
-    fn to_json(&self) -> String {
+    /// A failed command whose change was not applied.
+    fn failed(error: String) -> Self {
+        CommandResult::Text {
+            message: format!("✗ {}", error),
+            success: false,
+        }
+    }
+
+    fn succeeded(&self) -> bool {
+        match self {
+            CommandResult::Text { success, .. }
+            | CommandResult::List { success, .. }
+            | CommandResult::Values { success, .. }
+            | CommandResult::Previous { success, .. }
+            | CommandResult::Data { success, .. } => *success,
+            CommandResult::Batch { .. } => true,
+        }
+    }
+
+    fn to_value(&self) -> Value {
         match self {
-            CommandResult::Text { message, success } => serde_json::to_string(&json!({
+            CommandResult::Text { message, success } => json!({
                 "status": if *success { "ok" } else { "error" },
                 "message": message
-            }))
-                .unwrap(),
-            CommandResult::List { items, success } => serde_json::to_string(&json!({
+            }),
+            CommandResult::List { items, success } => json!({
+                "status": if *success { "ok" } else { "error" },
+                "data": items
+            }),
+            CommandResult::Values { items, success } => json!({
                 "status": if *success { "ok" } else { "error" },
                 "data": items
-            }))
-                .unwrap(),
+            }),
+            CommandResult::Previous {
+                message,
+                previous,
+                success,
+            } => json!({
+                "status": if *success { "ok" } else { "error" },
+                "message": message,
+                "data": previous
+            }),
+            CommandResult::Batch { results } => json!({
+                "status": "ok",
+                "data": results.iter().map(CommandResult::to_value).collect::<Vec<_>>()
+            }),
+            CommandResult::Data { data, success } => json!({
+                "status": if *success { "ok" } else { "error" },
+                "data": data
+            }),
         }
     }
+
+    fn to_json(&self) -> String {
+        serde_json::to_string(&self.to_value()).unwrap()
+    }
+}
+
+/// Per-connection state. Lives as long as the client keeps its socket open.
+struct Session {
+    /// Collection key commands run against. Starts at the persisted active collection;
+    /// `Use` changes it for this connection only (unless asked to persist).
+    collection: String,
+    /// Commands queued since MULTI; `None` outside a transaction.
+    queued: Option<Vec<AegisrCommand>>,
+    /// (collection, key, version) captured by WATCH.
+    watched: Vec<(String, String, u64)>,
+    /// Client address, as recorded in the audit log.
+    client: String,
+}
+
+/// Upper bound for a single not-yet-complete request buffered from a client.
+const MAX_PENDING_BYTES: usize = 16 * 1024 * 1024;
+
+impl Session {
+    fn new(addr: SocketAddr) -> Self {
+        Self {
+            collection: AegCore::load().active_collection,
+            queued: None,
+            watched: Vec::new(),
+            client: addr.to_string(),
+        }
+    }
+
+    fn audit(&self, op: &str, collection: Option<&str>, detail: Option<String>, ok: bool) {
+        record_audit(&self.client, op, collection, detail, ok);
+    }
+}
+
+/// Append to the audit log (when enabled). A failed write is logged but does not fail
+/// the operation.
+fn record_audit(
+    client: &str,
+    op: &str,
+    collection: Option<&str>,
+    detail: Option<String>,
+    ok: bool,
+) {
+    if let Err(e) = AegAudit::record(client, op, collection, detail, ok) {
+        error!("Failed to write audit log: {}", e);
+    }
+}
+
+/// Audit log entries for a key command run against `collection`: clearing it, and reading
+/// from it when its reads are audited. Scripts count as reads of the whole collection.
+fn data_audit_events(
+    cmd: &AegisrCommand,
+    collection: &str,
+) -> Vec<(&'static str, String, Option<String>)> {
+    let read = |detail: String| {
+        if AegAudit::audits_reads(collection) {
+            vec![("read", collection.to_string(), Some(detail))]
+        } else {
+            Vec::new()
+        }
+    };
+    match cmd {
+        AegisrCommand::WithCollection {
+            collection,
+            command,
+        } => data_audit_events(command, collection),
+        AegisrCommand::Clear { .. } => vec![("clear", collection.to_string(), None)],
+        AegisrCommand::Get { key, .. }
+        | AegisrCommand::GetSet { key, .. }
+        | AegisrCommand::Put { key, get: true, .. }
+        | AegisrCommand::Move { key, .. }
+        | AegisrCommand::Copy { source: key, .. } => read(format!("key {}", key)),
+        AegisrCommand::MGet { keys, .. } => read(format!("keys {}", keys.join(", "))),
+        AegisrCommand::Eval { .. } | AegisrCommand::EvalSha { .. } => read("script".into()),
+        _ => Vec::new(),
+    }
+}
+
+/// Serve one client until it disconnects. Requests are JSON lines; every response is one
+/// JSON line. A request without its newline (as sent by older clients) is parsed once the
+/// buffered bytes end with `}`, so only new bytes are scanned while a request arrives.
+/// A request that does not parse gets an error, and the rest of its line is skipped.
+async fn serve_connection(mut socket: TcpStream, addr: SocketAddr) {
+    let mut session = Session::new(addr);
+    let mut pending: Vec<u8> = Vec::new();
+    let mut buffer = vec![0u8; 4096];
+    // Set after an invalid request without its newline: drop bytes up to the next one.
+    let mut skipping = false;
+
+    loop {
+        let n = match socket.read(&mut buffer).await {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) => {
+                error!(%e, "Socket read error");
+                break;
+            }
+        };
+        let scanned = pending.len();
+        pending.extend_from_slice(&buffer[..n]);
+
+        let mut requests = Vec::new();
+        let mut line_start = 0;
+        let mut from = scanned;
+        while let Some(pos) = pending[from..].iter().position(|&b| b == b'\n') {
+            let end = from + pos;
+            if skipping {
+                skipping = false;
+            } else {
+                requests.extend(parse_requests(&pending[line_start..end]));
+            }
+            line_start = end + 1;
+            from = line_start;
+        }
+        pending.drain(..line_start);
+
+        if skipping {
+            pending.clear();
+        } else if pending.iter().rev().find(|b| !b.is_ascii_whitespace()) == Some(&b'}') {
+            let parsed = parse_requests(&pending);
+            if !matches!(parsed.last(), Some(Err(e)) if e.is_eof()) {
+                skipping = matches!(parsed.last(), Some(Err(_)));
+                requests.extend(parsed);
+                pending.clear();
+            }
+        }
+
+        let mut responses = String::new();
+        for request in requests {
+            let response = match request {
+                Ok(cmd) => handle_command(cmd, &mut session).await,
+                Err(e) => CommandResult::failed(format!("Invalid command: {}", e)),
+            };
+            responses.push_str(&response.to_json());
+            responses.push('\n');
+        }
+        if !responses.is_empty()
+            && let Err(e) = socket.write_all(responses.as_bytes()).await
+        {
+            error!(%e, "Failed sending response");
+            break;
+        }
+        if pending.len() > MAX_PENDING_BYTES {
+            warn!(%addr, "Request too large — closing connection");
+            break;
+        }
+    }
+
+    debug!(%addr, "Client disconnected");
+}
+
+/// The requests in `bytes`, up to and including the first one that does not parse (an
+/// end-of-input error when the last one is cut short).
+fn parse_requests(bytes: &[u8]) -> Vec<Result<AegisrCommand, serde_json::Error>> {
+    let mut requests = Vec::new();
+    for request in serde_json::Deserializer::from_slice(bytes).into_iter::<AegisrCommand>() {
+        let failed = request.is_err();
+        requests.push(request);
+        if failed {
+            break;
+        }
+    }
+    requests
 }
 
 /// TODO: Destructure this function into smaller parts
 /// TODO: Rename handle_command to tcp_responder
-async fn handle_command(cmd: AegisrCommand) -> CommandResult {
+async fn handle_command(cmd: AegisrCommand, session: &mut Session) -> CommandResult {
+    if let Some(queued) = session.queued.as_mut() {
+        if cmd.is_data_command() {
+            queued.push(cmd);
+            return CommandResult::Text {
+                message: "QUEUED".into(),
+                success: true,
+            };
+        }
+        if !matches!(cmd, AegisrCommand::Exec | AegisrCommand::Discard) {
+            return CommandResult::Text {
+                message: "Command not allowed inside MULTI".into(),
+                success: false,
+            };
+        }
+    }
+
     match cmd {
         AegisrCommand::New { verbose, name } => {
             let resp = AegCore::create_collection(&name);
+            session.audit(
+                "create-collection",
+                Some(&name),
+                None,
+                resp.starts_with('✓'),
+            );
             if verbose {
                 info!("Verbose: {}", resp);
             }
@@ -241,6 +621,18 @@ async fn handle_command(cmd: AegisrCommand) -> CommandResult {
         }
         AegisrCommand::Delete { verbose, name } => {
             let resp = AegCore::delete_collection(&name);
+            session.audit(
+                "delete-collection",
+                Some(&name),
+                None,
+                resp.starts_with('✓'),
+            );
+            if session.collection == name
+                && AegCore::collection_exists(&name) == Ok(false)
+                && let Ok(core) = AegCore::try_load()
+            {
+                session.collection = core.active_collection;
+            }
             if verbose {
                 info!("Verbose: {}", resp);
             }
@@ -255,6 +647,15 @@ async fn handle_command(cmd: AegisrCommand) -> CommandResult {
             new_name,
         } => {
             let resp = AegCore::rename_collection(&name, &new_name);
+            session.audit(
+                "rename-collection",
+                Some(&name),
+                Some(format!("to {}", new_name)),
+                resp.starts_with('✓'),
+            );
+            if session.collection == name && AegCore::collection_exists(&name) == Ok(false) {
+                session.collection = new_name.clone();
+            }
             if verbose {
                 info!("Verbose: {}", resp);
             }
@@ -263,12 +664,26 @@ async fn handle_command(cmd: AegisrCommand) -> CommandResult {
                 success: true,
             }
         }
-        AegisrCommand::Use { verbose, name } => {
-            let mut engine = AegCore::load();
-            match engine.set_active_collection(&name) {
+        AegisrCommand::Use {
+            verbose,
+            name,
+            persist,
+        } => {
+            let switched = if persist {
+                // Only changes the default picked up by new sessions.
+                AegCore::try_load().and_then(|mut core| core.set_active_collection(&name))
+            } else {
+                match AegCore::collection_exists(&name) {
+                    Ok(true) => Ok(()),
+                    Ok(false) => Err(format!("Collection '{}' does not exist", name)),
+                    Err(e) => Err(e),
+                }
+            };
+            match switched {
                 Ok(_) => {
+                    session.collection = name.clone();
                     if verbose {
-                        info!("Verbose: switched to '{}'", name);
+                        info!("Verbose: switched to '{}' (persist: {})", name, persist);
                     }
                     CommandResult::Text {
                         message: format!("Active Collection set to '{}'", name),
@@ -287,9 +702,16 @@ async fn handle_command(cmd: AegisrCommand) -> CommandResult {
         AegisrCommand::Init { verbose, reset } => {
             if reset {
                 warn!("Reset requested — clearing engine files");
-                AegFileSystem::reset_files();
             }
+            // With `reset` this empties the data directory first.
             let config_path = AegFileSystem::initialize_config(Some(reset), Some(verbose));
+            // the old log went away with the directory; start a fresh one
+            if reset
+                && let Some(policy) = AegWal::policy()
+                && let Err(e) = AegWal::open(policy)
+            {
+                error!("Failed to reopen write-ahead log: {}", e);
+            }
             let mut engine = AegCore::load();
             if engine.collections.is_empty() {
                 engine.collections.push("default".to_string());
@@ -298,6 +720,8 @@ async fn handle_command(cmd: AegisrCommand) -> CommandResult {
                 engine.active_collection = engine.collections[0].clone();
             }
             engine.save();
+            session.audit("init", None, reset.then(|| "reset".into()), true);
+            session.collection = engine.active_collection.clone();
             if verbose {
                 info!("Verbose: init completed at {}", config_path.display());
             }
@@ -309,28 +733,496 @@ async fn handle_command(cmd: AegisrCommand) -> CommandResult {
                 success: true,
             }
         }
-        AegisrCommand::Status => {
-            let engine = AegCore::load();
+        AegisrCommand::Status => CommandResult::Text {
+            message: session.collection.clone(),
+            success: true,
+        },
+        AegisrCommand::Multi => {
+            if session.queued.is_some() {
+                return CommandResult::Text {
+                    message: "MULTI calls can not be nested".into(),
+                    success: false,
+                };
+            }
+            session.queued = Some(Vec::new());
             CommandResult::Text {
-                message: engine.get_active_collection().to_string(),
+                message: "OK".into(),
                 success: true,
             }
         }
-        AegisrCommand::Put {
+        AegisrCommand::Discard => match session.queued.take() {
+            Some(_) => {
+                session.watched.clear();
+                CommandResult::Text {
+                    message: "OK".into(),
+                    success: true,
+                }
+            }
+            None => CommandResult::Text {
+                message: "DISCARD without MULTI".into(),
+                success: false,
+            },
+        },
+        AegisrCommand::Exec => {
+            let Some(queued) = session.queued.take() else {
+                return CommandResult::Text {
+                    message: "EXEC without MULTI".into(),
+                    success: false,
+                };
+            };
+            let watched = std::mem::take(&mut session.watched);
+            let collection = session.collection.clone();
+            let events: Vec<_> = queued
+                .iter()
+                .flat_map(|cmd| data_audit_events(cmd, &collection))
+                .collect();
+
+            // One lock for the watch check and every queued command: other clients and the
+            // background saver either see none of the transaction or all of it.
+            let result = AegMemoryEngine::with_collections(|collections| {
+                match AegCore::collection_exists(&collection) {
+                    Ok(true) => {}
+                    Ok(false) => return collection_gone(&collection),
+                    Err(e) => return CommandResult::failed(e),
+                }
+                let mut changed = false;
+                for (watched_collection, key, version) in &watched {
+                    match collections.get_mut(watched_collection) {
+                        Ok(engine) => changed |= engine.key_version(key) != *version,
+                        Err(e) => return CommandResult::failed(e),
+                    }
+                }
+                if changed {
+                    return CommandResult::Text {
+                        message: "Transaction aborted: a watched key was modified".into(),
+                        success: false,
+                    };
+                }
+                CommandResult::Batch {
+                    results: queued
+                        .into_iter()
+                        .map(|cmd| apply_scoped_command(collections, &collection, cmd))
+                        .collect(),
+                }
+            })
+            .unwrap_or_else(CommandResult::failed);
+            for (op, target, detail) in events {
+                session.audit(op, Some(&target), detail, result.succeeded());
+            }
+            result
+        }
+        AegisrCommand::Watch { keys } => {
+            let collection = session.collection.clone();
+            let watched = AegMemoryEngine::read_collection(&collection, |engine| {
+                for key in keys {
+                    let version = engine.key_version(&key);
+                    session.watched.push((collection.clone(), key, version));
+                }
+            });
+            match watched {
+                Ok(()) => CommandResult::Text {
+                    message: "OK".into(),
+                    success: true,
+                },
+                Err(e) => CommandResult::failed(e),
+            }
+        }
+        AegisrCommand::Unwatch => {
+            session.watched.clear();
+            CommandResult::Text {
+                message: "OK".into(),
+                success: true,
+            }
+        }
+        AegisrCommand::ScriptLoad { verbose, script } => match AegScripting::script_load(&script) {
+            Ok(sha) => {
+                if verbose {
+                    info!("Verbose: SCRIPT LOAD {}", sha);
+                }
+                CommandResult::Text {
+                    message: sha,
+                    success: true,
+                }
+            }
+            Err(e) => CommandResult::Text {
+                message: e,
+                success: false,
+            },
+        },
+        AegisrCommand::Snapshot => match AegBackup::create_snapshot() {
+            Ok(snapshot) => {
+                info!(name = %snapshot.name, keys = snapshot.keys, "Snapshot written");
+                session.audit("snapshot", None, Some(snapshot.name.clone()), true);
+                CommandResult::Text {
+                    message: format!(
+                        "✓ Snapshot '{}' written ({} collection(s), {} key(s))",
+                        snapshot.name,
+                        snapshot.collections.len(),
+                        snapshot.keys
+                    ),
+                    success: true,
+                }
+            }
+            Err(e) => {
+                error!("Snapshot failed: {}", e);
+                session.audit("snapshot", None, Some(e.clone()), false);
+                CommandResult::Text {
+                    message: format!("✗ Snapshot failed: {}", e),
+                    success: false,
+                }
+            }
+        },
+        AegisrCommand::ListSnapshots => CommandResult::Data {
+            data: json!(AegBackup::list_snapshots()),
+            success: true,
+        },
+        AegisrCommand::Restore { verbose, name } => match AegBackup::restore_snapshot(&name) {
+            Ok(snapshot) => {
+                warn!(name = %snapshot.name, "Collections restored from snapshot");
+                session.audit("restore", None, Some(snapshot.name.clone()), true);
+                let core = AegCore::load();
+                if !core.collections.contains(&session.collection) {
+                    session.collection = core.active_collection.clone();
+                }
+                if verbose {
+                    info!("Verbose: restored {:?}", snapshot.collections);
+                }
+                CommandResult::Text {
+                    message: format!(
+                        "✓ Restored snapshot '{}' ({} collection(s), {} key(s))",
+                        snapshot.name,
+                        snapshot.collections.len(),
+                        snapshot.keys
+                    ),
+                    success: true,
+                }
+            }
+            Err(e) => {
+                error!("Restore of '{}' failed: {}", name, e);
+                session.audit("restore", None, Some(format!("{}: {}", name, e)), false);
+                CommandResult::Text {
+                    message: format!("✗ Restore failed: {}", e),
+                    success: false,
+                }
+            }
+        },
+        AegisrCommand::RotateKey => match AegKeyRotation::rotate_live() {
+            Ok(report) => {
+                warn!(
+                    collections = report.collections,
+                    snapshots = report.snapshots,
+                    "Authorization key rotated"
+                );
+                session.audit("rotate-key", None, None, true);
+                let mut message = format!(
+                    "✓ Authorization key rotated ({} collection data key(s) and {} snapshot(s) re-wrapped)",
+                    report.collections, report.snapshots
+                );
+                for snapshot in &report.skipped_snapshots {
+                    warn!(%snapshot, "Snapshot taken with an earlier key was not re-wrapped");
+                }
+                if !report.skipped_snapshots.is_empty() {
+                    message.push_str(&format!(
+                        "; left {} snapshot(s) taken with an earlier key as they are: {}",
+                        report.skipped_snapshots.len(),
+                        report.skipped_snapshots.join(", ")
+                    ));
+                }
+                CommandResult::Text {
+                    message,
+                    success: true,
+                }
+            }
+            Err(e) => {
+                error!("Key rotation failed: {}", e);
+                session.audit("rotate-key", None, Some(e.clone()), false);
+                CommandResult::Text {
+                    message: format!("✗ Key rotation failed: {}", e),
+                    success: false,
+                }
+            }
+        },
+        AegisrCommand::Export {
+            collection,
+            after,
+            count,
+        } => {
+            match AegCore::collection_exists(&collection) {
+                Ok(true) => {}
+                Ok(false) => {
+                    return CommandResult::Text {
+                        message: format!("✗ Collection '{}' does not exist", collection),
+                        success: false,
+                    };
+                }
+                Err(e) => return CommandResult::failed(e),
+            }
+            if after.is_none() && AegAudit::audits_reads(&collection) {
+                session.audit("export", Some(&collection), None, true);
+            }
+            match AegMemoryEngine::read_collection(&collection, |engine| {
+                AegTransfer::export_page(engine, after.as_deref(), count.max(1))
+            }) {
+                Ok(page) => CommandResult::Data {
+                    data: json!(page),
+                    success: true,
+                },
+                Err(e) => CommandResult::failed(e),
+            }
+        }
+        AegisrCommand::Import {
+            verbose,
+            collection,
+            pairs,
+            mode,
+            dry_run,
+        } => {
+            let exists = match AegCore::collection_exists(&collection) {
+                Ok(exists) => exists,
+                Err(e) => return CommandResult::failed(e),
+            };
+            let summary = if !exists && dry_run {
+                let mut empty = AegMemoryEngine::new(&collection);
+                Ok(AegTransfer::import_into(&mut empty, &pairs, mode, true))
+            } else {
+                if !exists {
+                    info!("{}", AegCore::create_collection(&collection));
+                }
+                AegMemoryEngine::with_collection(&collection, |engine| {
+                    AegTransfer::import_into(engine, &pairs, mode, dry_run)
+                })
+            };
+            if !dry_run {
+                session.audit(
+                    "import",
+                    Some(&collection),
+                    Some(format!("{} pair(s)", pairs.len())),
+                    summary.is_ok(),
+                );
+            }
+            let summary = match summary {
+                Ok(summary) => summary,
+                Err(e) => return CommandResult::failed(e),
+            };
+            if verbose {
+                info!(
+                    "Verbose: IMPORT {} pair(s) into '{}' ({:?}, dry run: {}): {:?}",
+                    pairs.len(),
+                    collection,
+                    mode,
+                    dry_run,
+                    summary
+                );
+            }
+            CommandResult::Data {
+                data: json!(summary),
+                success: true,
+            }
+        }
+        AegisrCommand::ScriptExists { shas } => CommandResult::Data {
+            data: json!(
+                shas.iter()
+                    .map(|sha| AegScripting::script_exists(sha))
+                    .collect::<Vec<_>>()
+            ),
+            success: true,
+        },
+        AegisrCommand::ScriptFlush => {
+            AegScripting::script_flush();
+            CommandResult::Text {
+                message: "OK".into(),
+                success: true,
+            }
+        }
+        AegisrCommand::RewriteWal => match AegWal::start_rewrite() {
+            Ok(()) => {
+                info!("WAL rewrite started");
+                CommandResult::Text {
+                    message: "✓ WAL rewrite started in the background".into(),
+                    success: true,
+                }
+            }
+            Err(e) => CommandResult::Text {
+                message: format!("✗ {}", e),
+                success: false,
+            },
+        },
+        AegisrCommand::AuditVerify => {
+            match AegAudit::verify(None, &AegFileSystem::read_authorization_key()) {
+                Ok(report) => CommandResult::Data {
+                    data: json!(report),
+                    success: true,
+                },
+                Err(e) => {
+                    error!("Audit log verification failed: {}", e);
+                    CommandResult::Text {
+                        message: format!("✗ Audit log verification failed: {}", e),
+                        success: false,
+                    }
+                }
+            }
+        }
+        AegisrCommand::AuditRotate => match AegAudit::rotate(&session.client) {
+            Ok(name) => {
+                info!(%name, "Audit log rotated");
+                CommandResult::Text {
+                    message: format!("✓ Audit log rotated; previous entries are in {}", name),
+                    success: true,
+                }
+            }
+            Err(e) => CommandResult::Text {
+                message: format!("✗ {}", e),
+                success: false,
+            },
+        },
+        data => {
+            let collection = session.collection.clone();
+            let events = data_audit_events(&data, &collection);
+            // Checked under the engine lock: a collection deleted or renamed by another
+            // client must not be brought back by writing to it.
+            let result = AegMemoryEngine::with_collections(|collections| {
+                match AegCore::collection_exists(&collection) {
+                    Ok(true) => {}
+                    Ok(false) => return collection_gone(&collection),
+                    Err(e) => return CommandResult::failed(e),
+                }
+                apply_scoped_command(collections, &collection, data)
+            })
+            .unwrap_or_else(CommandResult::failed);
+            for (op, target, detail) in events {
+                session.audit(op, Some(&target), detail, result.succeeded());
+            }
+            result
+        }
+    }
+}
+
+/// The session's collection was deleted or renamed by another client.
+fn collection_gone(collection: &str) -> CommandResult {
+    CommandResult::Text {
+        message: format!(
+            "✗ Collection '{}' no longer exists; switch with `use <collection>`",
+            collection
+        ),
+        success: false,
+    }
+}
+
+/// Apply a key command with `collection` as its default collection. Handles the commands
+/// that pick or span collections (WithCollection, Move, Copy --to-collection) and hands
+/// everything else to `apply_data_command`.
+fn apply_scoped_command(
+    collections: &mut AegCollections,
+    collection: &str,
+    cmd: AegisrCommand,
+) -> CommandResult {
+    match cmd {
+        AegisrCommand::WithCollection {
+            collection: target,
+            command,
+        } => {
+            if !command.is_data_command() {
+                return CommandResult::Text {
+                    message: "Only key commands can target a collection".into(),
+                    success: false,
+                };
+            }
+            match AegCore::collection_exists(&target) {
+                Ok(true) => {}
+                Ok(false) => {
+                    return CommandResult::Text {
+                        message: format!("Collection '{}' does not exist", target),
+                        success: false,
+                    };
+                }
+                Err(e) => return CommandResult::failed(e),
+            }
+            apply_scoped_command(collections, &target, *command)
+        }
+        AegisrCommand::Move {
             verbose,
             key,
-            value,
+            collection: to,
         } => {
-            let resp = AegCore::put_value(&key, &value);
+            let resp = AegCore::move_key_in(collections, collection, &to, &key);
             if verbose {
-                info!("Verbose: PUT {} = {}", key, value);
+                info!("Verbose: MOVE {} {} -> {}", key, collection, to);
             }
             CommandResult::Text {
                 message: resp,
                 success: true,
             }
         }
-        AegisrCommand::Get { verbose, key } => match AegCore::get_value(&key) {
+        AegisrCommand::Copy {
+            verbose,
+            source,
+            destination,
+            replace,
+            to_collection: Some(to),
+        } => {
+            let resp = AegCore::copy_key_between_in(
+                collections,
+                collection,
+                &to,
+                &source,
+                &destination,
+                replace,
+            );
+            if verbose {
+                info!(
+                    "Verbose: COPY {}/{} -> {}/{} (replace: {})",
+                    collection, source, to, destination, replace
+                );
+            }
+            CommandResult::Text {
+                message: resp,
+                success: true,
+            }
+        }
+        other => match collections.get_mut(collection) {
+            Ok(engine) => apply_data_command(engine, other),
+            Err(e) => CommandResult::failed(e),
+        },
+    }
+}
+
+/// Apply a key command to an engine the caller already holds locked.
+/// Shared by single commands and EXEC so both behave identically.
+fn apply_data_command(engine: &mut AegMemoryEngine, cmd: AegisrCommand) -> CommandResult {
+    match cmd {
+        AegisrCommand::Put {
+            verbose,
+            key,
+            value,
+            nx,
+            xx,
+            get,
+        } => {
+            let condition = if nx {
+                SetCondition::IfAbsent
+            } else if xx {
+                SetCondition::IfPresent
+            } else {
+                SetCondition::Always
+            };
+            let (resp, previous) = AegCore::put_in(engine, &key, &value, condition);
+            if verbose {
+                info!("Verbose: PUT {} = {} ({:?})", key, value, condition);
+            }
+            if get {
+                CommandResult::Previous {
+                    message: resp,
+                    previous,
+                    success: true,
+                }
+            } else {
+                CommandResult::Text {
+                    message: resp,
+                    success: true,
+                }
+            }
+        }
+        AegisrCommand::Get { verbose, key } => match engine.get(&key) {
             Some(v) => {
                 if verbose {
                     info!("Verbose: GET {} = {}", key, v);
@@ -351,7 +1243,7 @@ async fn handle_command(cmd: AegisrCommand) -> CommandResult {
             }
         },
         AegisrCommand::Del { verbose, key } => {
-            let resp = AegCore::delete_value(&key);
+            let resp = AegCore::delete_in(engine, &key);
             if verbose {
                 info!("Verbose: DEL {}", key);
             }
@@ -361,7 +1253,7 @@ async fn handle_command(cmd: AegisrCommand) -> CommandResult {
             }
         }
         AegisrCommand::Clear { verbose } => {
-            let resp = AegCore::clear_values();
+            let resp = AegCore::clear_in(engine);
             if verbose {
                 info!("Verbose: CLEAR all values");
             }
@@ -370,6 +1262,197 @@ async fn handle_command(cmd: AegisrCommand) -> CommandResult {
                 success: true,
             }
         }
+        AegisrCommand::MGet { verbose, keys } => {
+            let values = engine.mget(&keys);
+            if verbose {
+                info!("Verbose: MGET {} key(s)", keys.len());
+            }
+            CommandResult::Values {
+                items: values,
+                success: true,
+            }
+        }
+        AegisrCommand::MSet { verbose, pairs } => {
+            let resp = AegCore::mset_in(engine, &pairs);
+            if verbose {
+                info!("Verbose: MSET {} pair(s)", pairs.len());
+            }
+            CommandResult::Text {
+                message: resp,
+                success: true,
+            }
+        }
+        AegisrCommand::MSetNx { verbose, pairs } => {
+            let resp = AegCore::msetnx_in(engine, &pairs);
+            if verbose {
+                info!("Verbose: MSETNX {} pair(s)", pairs.len());
+            }
+            CommandResult::Text {
+                message: resp,
+                success: true,
+            }
+        }
+        AegisrCommand::Exists { verbose, keys } => {
+            let count = engine.exists(&keys);
+            if verbose {
+                info!("Verbose: EXISTS {} of {} key(s)", count, keys.len());
+            }
+            CommandResult::Text {
+                message: count.to_string(),
+                success: true,
+            }
+        }
+        AegisrCommand::RenameKey {
+            verbose,
+            key,
+            new_key,
+            nx,
+        } => {
+            let resp = AegCore::rename_key_in(engine, &key, &new_key, nx);
+            if verbose {
+                info!("Verbose: RENAME {} -> {} (nx: {})", key, new_key, nx);
+            }
+            CommandResult::Text {
+                message: resp,
+                success: true,
+            }
+        }
+        AegisrCommand::Copy {
+            verbose,
+            source,
+            destination,
+            replace,
+            ..
+        } => {
+            let resp = AegCore::copy_key_in(engine, &source, &destination, replace);
+            if verbose {
+                info!(
+                    "Verbose: COPY {} -> {} (replace: {})",
+                    source, destination, replace
+                );
+            }
+            CommandResult::Text {
+                message: resp,
+                success: true,
+            }
+        }
+        AegisrCommand::Type { verbose, key } => {
+            let resp = engine.key_type(&key).to_string();
+            if verbose {
+                info!("Verbose: TYPE {} = {}", key, resp);
+            }
+            CommandResult::Text {
+                message: resp,
+                success: true,
+            }
+        }
+        AegisrCommand::SetNx {
+            verbose,
+            key,
+            value,
+        } => {
+            let (resp, _) = AegCore::put_in(engine, &key, &value, SetCondition::IfAbsent);
+            if verbose {
+                info!("Verbose: SETNX {} = {}", key, value);
+            }
+            CommandResult::Text {
+                message: resp,
+                success: true,
+            }
+        }
+        AegisrCommand::GetSet {
+            verbose,
+            key,
+            value,
+        } => {
+            let previous = engine.set_with(&key, &value, SetCondition::Always).1;
+            if verbose {
+                info!("Verbose: GETSET {} = {}", key, value);
+            }
+            CommandResult::Previous {
+                message: format!(
+                    "✓ Key '{}' saved in collection '{}' (in-memory)",
+                    key, engine.collection_name
+                ),
+                previous,
+                success: true,
+            }
+        }
+        AegisrCommand::Cas {
+            verbose,
+            key,
+            value,
+            expected,
+            version,
+        } => {
+            let expectation = match (expected, version) {
+                (_, Some(v)) => CasExpectation::Version(v),
+                (e, None) => CasExpectation::Value(e),
+            };
+            let resp = AegCore::cas_in(engine, &key, &value, &expectation);
+            if verbose {
+                info!("Verbose: CAS {} = {} ({:?})", key, value, expectation);
+            }
+            CommandResult::Text {
+                message: resp,
+                success: true,
+            }
+        }
+        AegisrCommand::KeyVersion { verbose, key } => {
+            let version = engine.key_version(&key);
+            if verbose {
+                info!("Verbose: KEY-VERSION {} = {}", key, version);
+            }
+            CommandResult::Text {
+                message: version.to_string(),
+                success: true,
+            }
+        }
+        AegisrCommand::Eval {
+            verbose,
+            script,
+            keys,
+            args,
+        } => {
+            let result = AegScripting::eval_in(engine, &script, &keys, &args);
+            if verbose {
+                info!(
+                    "Verbose: EVAL ({} key(s), {} arg(s))",
+                    keys.len(),
+                    args.len()
+                );
+            }
+            script_result(result)
+        }
+        AegisrCommand::EvalSha {
+            verbose,
+            sha,
+            keys,
+            args,
+        } => {
+            let result = AegScripting::eval_sha_in(engine, &sha, &keys, &args);
+            if verbose {
+                info!("Verbose: EVALSHA {}", sha);
+            }
+            script_result(result)
+        }
+        other => CommandResult::Text {
+            message: format!("{:?} is not a key command", other),
+            success: false,
+        },
+    }
+}
+
+fn script_result(result: Result<Value, String>) -> CommandResult {
+    match result {
+        Ok(data) => CommandResult::Data {
+            data,
+            success: true,
+        },
+        Err(e) => CommandResult::Text {
+            message: e,
+            success: false,
+        },
     }
 }
 
@@ -377,27 +1460,123 @@ async fn handle_command(cmd: AegisrCommand) -> CommandResult {
 async fn main() {
     let args = CliArgs::parse();
     let file_config = if let Some(cfg_path) = &args.config {
-        let cfg_str = fs::read_to_string(cfg_path).unwrap_or_default();
-        serde_json::from_str::<DaemonConfig>(&cfg_str).unwrap_or(DaemonConfig {
-            host: None,
-            port: None,
+        let cfg_str = fs::read_to_string(cfg_path).unwrap_or_else(|e| {
+            eprintln!("Failed to read config {}: {}", cfg_path, e);
+            process::exit(1);
+        });
+        serde_json::from_str::<DaemonConfig>(&cfg_str).unwrap_or_else(|e| {
+            eprintln!("Invalid config {}: {}", cfg_path, e);
+            process::exit(1);
         })
     } else {
-        DaemonConfig {
-            host: None,
-            port: None,
-        }
+        DaemonConfig::default()
     };
 
     let host = args.host.or(file_config.host).unwrap_or("127.0.0.1".into());
     let port = args.port.or(file_config.port).unwrap_or(1211);
     let address = format!("{}:{}", host, port);
 
+    if let Some(ms) = file_config.script_time_limit_ms {
+        AegScripting::set_time_limit(Duration::from_millis(ms));
+    }
+    let script_limits = ScriptLimits::default();
+    AegScripting::set_limits(ScriptLimits {
+        max_string_size: file_config
+            .script_max_string_size
+            .unwrap_or(script_limits.max_string_size),
+        max_array_size: file_config
+            .script_max_array_size
+            .unwrap_or(script_limits.max_array_size),
+        max_map_size: file_config
+            .script_max_map_size
+            .unwrap_or(script_limits.max_map_size),
+        max_call_levels: file_config
+            .script_max_call_levels
+            .unwrap_or(script_limits.max_call_levels),
+        max_operations: file_config
+            .script_max_operations
+            .unwrap_or(script_limits.max_operations),
+    });
+
     let logger_cfg = LoggerConfig {
         log_to_file: true,
         level: std::env::var("AEGISR_LOG_LEVEL").unwrap_or("info".into()),
     };
 
-    let daemon = AegDaemon::new(&address, logger_cfg);
+    AegBackup::configure(
+        file_config.backup_dir.map(PathBuf::from),
+        file_config
+            .snapshot_retention
+            .unwrap_or(DEFAULT_SNAPSHOT_RETENTION),
+    );
+    AegSnapshotFormat::set_compression(
+        file_config.compression.unwrap_or_default(),
+        file_config.collection_compression.unwrap_or_default(),
+    );
+    if let Some(rules) = file_config.save {
+        AegMemoryEngine::set_save_rules(rules);
+    }
+    AegWal::set_auto_rewrite(
+        file_config
+            .wal_rewrite_percentage
+            .unwrap_or(DEFAULT_WAL_REWRITE_PERCENTAGE),
+        file_config
+            .wal_rewrite_min_size
+            .unwrap_or(DEFAULT_WAL_REWRITE_MIN_SIZE),
+    );
+    let wal_policy = file_config
+        .appendonly
+        .unwrap_or(true)
+        .then(|| file_config.appendfsync.unwrap_or_default());
+
+    // Before the key is loaded, so it never sits in swappable memory.
+    if file_config.secure_memory.unwrap_or(false)
+        && let Err(e) = AegSecureMemory::enable()
+    {
+        eprintln!(
+            "WARNING: secure_memory could not lock memory: {}. Values are still zeroized when deleted.",
+            e
+        );
+    }
+    let key_provider = match &args.config {
+        Some(cfg_path) => AegKeyProvider::config_from_file(&PathBuf::from(cfg_path))
+            .unwrap_or_else(|e| {
+                eprintln!("Invalid key provider: {}", e);
+                process::exit(1);
+            }),
+        None => Default::default(),
+    };
+    AegKeyProvider::configure(key_provider);
+    // Like ssh with a private key: a key others can read is not a secret any more.
+    let problems = AegPermissions::key_file_problems();
+    let policy = file_config.key_file_permissions.unwrap_or_default();
+    for (path, problem) in &problems {
+        match policy {
+            PermissionPolicy::Strict => eprintln!("{}: {}", path.display(), problem),
+            PermissionPolicy::Warn => eprintln!("WARNING: {}: {}", path.display(), problem),
+        }
+    }
+    if !problems.is_empty() && policy == PermissionPolicy::Strict {
+        eprintln!(
+            "Refusing to start with an unprotected key file. Fix it (chmod 600, or aegisr-check --repair) or set \"key_file_permissions\": \"warn\"."
+        );
+        process::exit(1);
+    }
+    // The key must be available before anything reads the data directory.
+    if let Err(e) = AegKeyProvider::unlock(args.passphrase_fd) {
+        eprintln!("Failed to load authorization key: {}", e);
+        process::exit(1);
+    }
+
+    let audit = file_config.audit_log.unwrap_or(false).then(|| AuditConfig {
+        dir: file_config.audit_dir.map(PathBuf::from),
+        max_size: file_config.audit_max_size.unwrap_or(DEFAULT_AUDIT_MAX_SIZE),
+        retention: file_config
+            .audit_retention
+            .unwrap_or(DEFAULT_AUDIT_RETENTION),
+        read_collections: file_config.audit_read_collections.unwrap_or_default(),
+    });
+
+    let daemon = AegDaemon::new(&address, logger_cfg, wal_policy, audit);
     daemon.start().await;
 }
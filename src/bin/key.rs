@@ -0,0 +1,167 @@
+use aegisrlib::{
+    AegBackup, AegKeyProvider, AegKeyRotation, AegKeyStore, DEFAULT_SNAPSHOT_RETENTION,
+    ENGINE_DEVELOPER, ENGINE_VERSION, KeyProviderConfig,
+};
+use clap::{Parser, Subcommand};
+use colored::Colorize;
+use std::net::{SocketAddr, TcpStream};
+use std::path::PathBuf;
+use std::process;
+use std::time::Duration;
+
+const DAEMON_ADDRESS: &str = "127.0.0.1:1211";
+
+/// Manage the authorization key.
+#[derive(Parser)]
+#[command(name = "aegisr-key", author = ENGINE_DEVELOPER[0], version = ENGINE_VERSION)]
+pub struct AegKeyCli {
+    /// Daemon config file whose key_provider holds the key
+    #[arg(long, global = true, value_name = "FILE")]
+    config: Option<PathBuf>,
+    #[command(subcommand)]
+    command: KeyCommand,
+}
+
+#[derive(Subcommand)]
+enum KeyCommand {
+    /// Show where the key comes from and whether AUTHORIZATION_KEY is passphrase-protected
+    Status,
+    /// Protect AUTHORIZATION_KEY with a passphrase (Argon2id)
+    Protect {
+        /// Read the new passphrase from this file descriptor
+        #[arg(long, value_name = "FD")]
+        passphrase_fd: Option<u32>,
+    },
+    /// Store AUTHORIZATION_KEY in plaintext again
+    Unprotect {
+        /// Read the passphrase from this file descriptor
+        #[arg(long, value_name = "FD")]
+        passphrase_fd: Option<u32>,
+    },
+    /// Change the passphrase of a protected AUTHORIZATION_KEY
+    Passwd {
+        /// Read the current passphrase from this file descriptor
+        #[arg(long, value_name = "FD")]
+        passphrase_fd: Option<u32>,
+        /// Read the new passphrase from this file descriptor
+        #[arg(long, value_name = "FD")]
+        new_passphrase_fd: Option<u32>,
+    },
+    /// Re-encrypt all data under a new key (with the daemon stopped; use the
+    /// rotate-key command while it runs). Finishes an interrupted rotation.
+    Rotate {
+        /// Read the passphrase from this file descriptor
+        #[arg(long, value_name = "FD")]
+        passphrase_fd: Option<u32>,
+    },
+}
+
+impl AegKeyCli {
+    pub fn start() {
+        let cli = AegKeyCli::parse();
+        if let Some(path) = &cli.config {
+            match AegKeyProvider::config_from_file(path) {
+                Ok(config) => AegKeyProvider::configure(config),
+                Err(e) => Self::fail(&e),
+            }
+            // Rotation re-wraps the snapshots too, wherever they are kept.
+            match AegBackup::dir_from_file(path) {
+                Ok(dir) => AegBackup::configure(dir, DEFAULT_SNAPSHOT_RETENTION),
+                Err(e) => Self::fail(&e),
+            }
+        }
+        if !matches!(cli.command, KeyCommand::Status | KeyCommand::Rotate { .. }) {
+            if AegKeyProvider::config() != KeyProviderConfig::DataDir {
+                Self::fail(&format!(
+                    "the key comes from {}; passphrases only apply to the data directory key file",
+                    AegKeyProvider::describe()
+                ));
+            }
+            if AegKeyStore::is_kms_sealed() {
+                Self::fail("AUTHORIZATION_KEY is sealed by a KMS, not by a passphrase");
+            }
+        }
+        let result = match cli.command {
+            KeyCommand::Status => {
+                if AegKeyProvider::config() != KeyProviderConfig::DataDir {
+                    Ok(format!("The key comes from {}", AegKeyProvider::describe()))
+                } else if AegKeyStore::is_kms_sealed() {
+                    Ok("AUTHORIZATION_KEY is sealed by a KMS (use --config)".to_string())
+                } else if AegKeyStore::is_protected() {
+                    Ok("AUTHORIZATION_KEY is passphrase-protected".to_string())
+                } else {
+                    Ok("AUTHORIZATION_KEY is stored in plaintext".to_string())
+                }
+            }
+            KeyCommand::Protect { passphrase_fd } => {
+                AegKeyStore::read_new_passphrase(passphrase_fd)
+                    .and_then(|passphrase| AegKeyStore::protect(&passphrase))
+                    .map(|_| "AUTHORIZATION_KEY is now passphrase-protected".to_string())
+            }
+            KeyCommand::Unprotect { passphrase_fd } => {
+                AegKeyStore::read_passphrase(passphrase_fd, "Passphrase: ")
+                    .and_then(|passphrase| AegKeyStore::unprotect(&passphrase))
+                    .map(|_| "AUTHORIZATION_KEY is stored in plaintext again".to_string())
+            }
+            KeyCommand::Passwd {
+                passphrase_fd,
+                new_passphrase_fd,
+            } => AegKeyStore::read_passphrase(passphrase_fd, "Current passphrase: ").and_then(
+                |old| {
+                    let new = AegKeyStore::read_new_passphrase(new_passphrase_fd)?;
+                    AegKeyStore::change_passphrase(&old, &new)
+                        .map(|_| "Passphrase changed".to_string())
+                },
+            ),
+            KeyCommand::Rotate { passphrase_fd } => Self::rotate(passphrase_fd),
+        };
+        match result {
+            Ok(message) => println!("{}", format!("✓ {}", message).green()),
+            Err(e) => Self::fail(&e),
+        }
+    }
+
+    fn fail(message: &str) -> ! {
+        eprintln!("{}", format!("✗ {}", message).red());
+        process::exit(1);
+    }
+
+    fn rotate(passphrase_fd: Option<u32>) -> Result<String, String> {
+        if Self::daemon_running() {
+            return Err(
+                "the daemon is running; stop it first or use `aegisr rotate-key` instead".into(),
+            );
+        }
+        let resumed = AegKeyRotation::is_pending();
+        AegKeyProvider::unlock(passphrase_fd)?;
+        let report = AegKeyRotation::rotate_offline()?;
+        let mut message = format!(
+            "{} ({} collection data key(s) and {} snapshot(s) re-wrapped, {} WAL record(s) re-encrypted)",
+            if resumed {
+                "Finished interrupted key rotation"
+            } else {
+                "Authorization key rotated"
+            },
+            report.collections,
+            report.snapshots,
+            report.wal_records
+        );
+        if !report.skipped_snapshots.is_empty() {
+            message.push_str(&format!(
+                "; left {} snapshot(s) taken with an earlier key as they are: {}",
+                report.skipped_snapshots.len(),
+                report.skipped_snapshots.join(", ")
+            ));
+        }
+        Ok(message)
+    }
+
+    fn daemon_running() -> bool {
+        let address: SocketAddr = DAEMON_ADDRESS.parse().unwrap();
+        TcpStream::connect_timeout(&address, Duration::from_secs(1)).is_ok()
+    }
+}
+
+fn main() {
+    AegKeyCli::start();
+}
@@ -1,14 +1,37 @@
-use aegisrlib::{AegisrCommand, Commands, ENGINE_DEVELOPER, ENGINE_NAME, ENGINE_VERSION};
+use aegisrlib::{
+    AegFieldCrypto, AegFileSystem, AegMemoryEngine, AegPermissions, AegTransfer, AegisrCommand,
+    CLIENT_KEYFILE_ENV, ClientKeyArgs, Commands, ENGINE_DEVELOPER, ENGINE_NAME, ENGINE_VERSION,
+    EXPORT_BATCH_SIZE, ExportArgs, ExportPage, ExportWriter, ImportArgs, ImportSummary, MSetArgs,
+};
 use clap::Parser;
 use colored::Colorize;
-use serde_json::Value;
-use std::io::{Read, Write};
-use std::net::TcpStream;
+use serde_json::{Value, json};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::PathBuf;
 use std::time::Duration;
 
+const DAEMON_ADDRESS: &str = "127.0.0.1:1211";
+
 #[derive(Parser)]
 #[command(name = ENGINE_NAME, author = ENGINE_DEVELOPER[0], version = ENGINE_VERSION)]
 pub struct AegTerminal {
+    #[arg(
+        short = 'c',
+        long = "collection",
+        global = true,
+        help = "Run a key command against this collection instead of the active one"
+    )]
+    in_collection: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        env = CLIENT_KEYFILE_ENV,
+        value_name = "FILE",
+        help = "Encrypt written values with this client keyfile before sending them, and decrypt them on read"
+    )]
+    keyfile: Option<PathBuf>,
     #[command(subcommand)]
     command: Commands,
 }
@@ -18,12 +41,33 @@ impl AegTerminal {
     /// TODO: Accept optional host and port arguments to connect to a remote daemon.
     pub fn start() {
         let cli = AegTerminal::parse();
-        let mut stream = match TcpStream::connect_timeout(
-            &"127.0.0.1:1211".parse().unwrap(),
-            Duration::from_secs(1),
-        ) {
-            Ok(s) => s,
-            Err(_) => {
+        let transfer = matches!(
+            cli.command,
+            Commands::Export(_) | Commands::Import(_) | Commands::ClientKey(_)
+        );
+        if transfer && cli.in_collection.is_some() {
+            eprintln!(
+                "{}",
+                "Error: --collection only applies to key commands.".red()
+            );
+            std::process::exit(2);
+        }
+        match &cli.command {
+            Commands::Export(args) if args.offline => {
+                return Self::report(Self::export_offline(args), args.output.is_none());
+            }
+            Commands::Import(args) if args.offline => {
+                return Self::report(Self::import_offline(args), false);
+            }
+            Commands::ClientKey(args) => {
+                return Self::report(Self::client_key(cli.keyfile.as_ref(), args), false);
+            }
+            _ => {}
+        }
+
+        let mut stream = match Self::connect() {
+            Some(s) => s,
+            None => {
                 eprintln!("{}", "Error: daemon not running.".red());
                 std::process::exit(1);
             }
@@ -35,9 +79,11 @@ impl AegTerminal {
                 reset: args.reset,
             },
             Commands::List => AegisrCommand::List,
+            // Every terminal invocation is its own session, so `use` has to persist to matter.
             Commands::Use(args) => AegisrCommand::Use {
                 verbose: args.verbose,
                 name: args.name.clone(),
+                persist: true,
             },
             Commands::New(args) => AegisrCommand::New {
                 verbose: args.verbose,
@@ -57,6 +103,9 @@ impl AegTerminal {
                 verbose: args.verbose,
                 key: args.key.clone(),
                 value: args.value.clone(),
+                nx: args.nx,
+                xx: args.xx,
+                get: args.get,
             },
             Commands::Get(args) => AegisrCommand::Get {
                 verbose: args.verbose,
@@ -69,18 +118,365 @@ impl AegTerminal {
             Commands::Clear(args) => AegisrCommand::Clear {
                 verbose: args.verbose,
             },
+            Commands::MGet(args) => AegisrCommand::MGet {
+                verbose: args.verbose,
+                keys: args.keys.clone(),
+            },
+            Commands::MSet(args) => AegisrCommand::MSet {
+                verbose: args.verbose,
+                pairs: Self::pairs_or_exit(args),
+            },
+            Commands::MSetNx(args) => AegisrCommand::MSetNx {
+                verbose: args.verbose,
+                pairs: Self::pairs_or_exit(args),
+            },
+            Commands::Exists(args) => AegisrCommand::Exists {
+                verbose: args.verbose,
+                keys: args.keys.clone(),
+            },
+            Commands::RenameKey(args) => AegisrCommand::RenameKey {
+                verbose: args.verbose,
+                key: args.key.clone(),
+                new_key: args.new_key.clone(),
+                nx: args.nx,
+            },
+            Commands::Copy(args) => AegisrCommand::Copy {
+                verbose: args.verbose,
+                source: args.source.clone(),
+                destination: args.destination.clone(),
+                replace: args.replace,
+                to_collection: args.to_collection.clone(),
+            },
+            Commands::Move(args) => AegisrCommand::Move {
+                verbose: args.verbose,
+                key: args.key.clone(),
+                collection: args.collection.clone(),
+            },
+            Commands::Type(args) => AegisrCommand::Type {
+                verbose: args.verbose,
+                key: args.key.clone(),
+            },
+            Commands::SetNx(args) => AegisrCommand::SetNx {
+                verbose: args.verbose,
+                key: args.key.clone(),
+                value: args.value.clone(),
+            },
+            Commands::GetSet(args) => AegisrCommand::GetSet {
+                verbose: args.verbose,
+                key: args.key.clone(),
+                value: args.value.clone(),
+            },
+            Commands::Cas(args) => AegisrCommand::Cas {
+                verbose: args.verbose,
+                key: args.key.clone(),
+                value: args.value.clone(),
+                expected: args.expected.clone(),
+                version: args.version,
+            },
+            Commands::KeyVersion(args) => AegisrCommand::KeyVersion {
+                verbose: args.verbose,
+                key: args.key.clone(),
+            },
+            Commands::Eval(args) => AegisrCommand::Eval {
+                verbose: args.verbose,
+                script: args.script.clone(),
+                keys: args.keys.clone(),
+                args: args.args.clone(),
+            },
+            Commands::EvalSha(args) => AegisrCommand::EvalSha {
+                verbose: args.verbose,
+                sha: args.sha.clone(),
+                keys: args.keys.clone(),
+                args: args.args.clone(),
+            },
+            Commands::ScriptLoad(args) => AegisrCommand::ScriptLoad {
+                verbose: args.verbose,
+                script: args.script.clone(),
+            },
+            Commands::ScriptExists(args) => AegisrCommand::ScriptExists {
+                shas: args.shas.clone(),
+            },
+            Commands::ScriptFlush => AegisrCommand::ScriptFlush,
+            Commands::RewriteWal => AegisrCommand::RewriteWal,
+            Commands::Snapshot => AegisrCommand::Snapshot,
+            Commands::Snapshots => AegisrCommand::ListSnapshots,
+            Commands::Restore(args) => AegisrCommand::Restore {
+                verbose: args.verbose,
+                name: args.name.clone(),
+            },
+            Commands::RotateKey => AegisrCommand::RotateKey,
+            Commands::AuditVerify => AegisrCommand::AuditVerify,
+            Commands::AuditRotate => AegisrCommand::AuditRotate,
+            Commands::Export(args) => {
+                return Self::report(Self::export(&stream, args), args.output.is_none());
+            }
+            Commands::Import(args) => return Self::report(Self::import(&stream, args), false),
+            Commands::ClientKey(_) => unreachable!("handled without the daemon"),
         };
 
-        let cmd_bytes = serde_json::to_vec(&cmd).unwrap();
+        let cmd = match cli.in_collection {
+            Some(collection) if cmd.is_data_command() => AegisrCommand::WithCollection {
+                collection,
+                command: Box::new(cmd),
+            },
+            Some(_) => {
+                eprintln!(
+                    "{}",
+                    "Error: --collection only applies to key commands.".red()
+                );
+                std::process::exit(2);
+            }
+            None => cmd,
+        };
+
+        // Values are encrypted here, so the daemon only ever stores and returns envelopes.
+        let field_crypto = cli
+            .keyfile
+            .as_ref()
+            .map(|path| AegFieldCrypto::load(path).unwrap_or_else(|e| Self::fail(&e)));
+        let cmd = match &field_crypto {
+            Some(crypto) => crypto
+                .encrypt_command(cmd)
+                .unwrap_or_else(|e| Self::fail(&e)),
+            None => cmd,
+        };
+
+        let mut cmd_bytes = serde_json::to_vec(&cmd).unwrap();
+        cmd_bytes.push(b'\n');
         stream.write_all(&cmd_bytes).unwrap();
 
-        let mut response = vec![0; 4096];
-        let n = stream.read(&mut response).unwrap();
+        // The daemon answers each request with one JSON line and keeps the connection open.
+        let mut response = String::new();
+        BufReader::new(&stream).read_line(&mut response).unwrap();
 
-        if let Ok(value) = serde_json::from_slice::<Value>(&response[..n]) {
+        if let Ok(mut value) = serde_json::from_str::<Value>(&response) {
+            if let Some(crypto) = &field_crypto
+                && let Err(e) = crypto.decrypt_response(&mut value)
+            {
+                Self::fail(&e);
+            }
             println!("{}", serde_json::to_string_pretty(&value).unwrap().green());
         } else {
-            println!("{}", String::from_utf8_lossy(&response[..n]).red());
+            println!("{}", response.red());
+        }
+    }
+
+    fn fail(message: &str) -> ! {
+        eprintln!("{}", format!("Error: {}", message).red());
+        std::process::exit(1);
+    }
+
+    fn connect() -> Option<TcpStream> {
+        let address: SocketAddr = DAEMON_ADDRESS.parse().unwrap();
+        TcpStream::connect_timeout(&address, Duration::from_secs(1)).ok()
+    }
+
+    /// Print the outcome of an export or import. While an export goes to stdout, the
+    /// summary goes to stderr so it does not end up in the exported data.
+    fn report(result: Result<Value, String>, to_stderr: bool) {
+        match result {
+            Ok(value) => {
+                let text = serde_json::to_string_pretty(&value).unwrap().green();
+                if to_stderr {
+                    eprintln!("{}", text);
+                } else {
+                    println!("{}", text);
+                }
+            }
+            Err(e) => {
+                eprintln!("{}", format!("Error: {}", e).red());
+                std::process::exit(1);
+            }
+        }
+    }
+
+    /// Send one request and return the `data` of a successful response.
+    fn request(
+        mut stream: &TcpStream,
+        reader: &mut BufReader<&TcpStream>,
+        cmd: &AegisrCommand,
+    ) -> Result<Value, String> {
+        let mut cmd_bytes = serde_json::to_vec(cmd).unwrap();
+        cmd_bytes.push(b'\n');
+        stream
+            .write_all(&cmd_bytes)
+            .map_err(|e| format!("send failed: {}", e))?;
+        let mut line = String::new();
+        reader
+            .read_line(&mut line)
+            .map_err(|e| format!("receive failed: {}", e))?;
+        let mut response: Value =
+            serde_json::from_str(&line).map_err(|_| format!("unexpected response: {}", line))?;
+        if response["status"] != "ok" {
+            return Err(response["message"].as_str().unwrap_or(&line).to_string());
+        }
+        Ok(response["data"].take())
+    }
+
+    fn output(path: Option<&PathBuf>) -> Result<Box<dyn Write>, String> {
+        match path {
+            Some(path) => AegPermissions::create_private_file(path)
+                .map(|f| Box::new(io::BufWriter::new(f)) as Box<dyn Write>)
+                .map_err(|e| format!("create {}: {}", path.display(), e)),
+            None => Ok(Box::new(io::BufWriter::new(io::stdout().lock()))),
+        }
+    }
+
+    fn input(path: Option<&PathBuf>) -> Result<Box<dyn BufRead>, String> {
+        match path {
+            Some(path) if path.as_os_str() != "-" => fs::File::open(path)
+                .map(|f| Box::new(BufReader::new(f)) as Box<dyn BufRead>)
+                .map_err(|e| format!("open {}: {}", path.display(), e)),
+            _ => Ok(Box::new(io::stdin().lock())),
+        }
+    }
+
+    /// Page through a collection on the daemon and write it out.
+    fn export(stream: &TcpStream, args: &ExportArgs) -> Result<Value, String> {
+        let mut reader = BufReader::new(stream);
+        let mut writer = ExportWriter::new(Self::output(args.output.as_ref())?, args.format)?;
+        let mut after = None;
+        loop {
+            let data = Self::request(
+                stream,
+                &mut reader,
+                &AegisrCommand::Export {
+                    collection: args.collection.clone(),
+                    after: after.take(),
+                    count: EXPORT_BATCH_SIZE,
+                },
+            )?;
+            let page: ExportPage =
+                serde_json::from_value(data).map_err(|e| format!("invalid page: {}", e))?;
+            for (key, value) in &page.entries {
+                writer.write_entry(key, value)?;
+            }
+            if args.verbose {
+                eprintln!("exported {} key(s)", page.entries.len());
+            }
+            match page.next {
+                Some(next) => after = Some(next),
+                None => break,
+            }
+        }
+        let count = writer.finish()?;
+        Ok(Self::export_summary(&args.collection, count))
+    }
+
+    fn export_offline(args: &ExportArgs) -> Result<Value, String> {
+        let auth_key = AegFileSystem::resolve_authorization_key(args.auth_key.as_deref())?;
+        let engine = AegTransfer::offline_load(&args.collection, &auth_key)?;
+        let page = AegTransfer::export_page(&engine, None, usize::MAX);
+        let mut writer = ExportWriter::new(Self::output(args.output.as_ref())?, args.format)?;
+        for (key, value) in &page.entries {
+            writer.write_entry(key, value)?;
+        }
+        let count = writer.finish()?;
+        Ok(Self::export_summary(&args.collection, count))
+    }
+
+    fn export_summary(collection: &str, count: usize) -> Value {
+        json!({
+            "status": "ok",
+            "message": format!("✓ Exported {} key(s) from collection '{}'", count, collection)
+        })
+    }
+
+    /// Stream the input to the daemon in batches.
+    fn import(stream: &TcpStream, args: &ImportArgs) -> Result<Value, String> {
+        let mut reader = BufReader::new(stream);
+        let input = Self::input(args.input.as_ref())?;
+        let mut summary = ImportSummary::default();
+        let total = AegTransfer::read_entries(input, args.format, |pairs| {
+            let data = Self::request(
+                stream,
+                &mut reader,
+                &AegisrCommand::Import {
+                    verbose: args.verbose,
+                    collection: args.collection.clone(),
+                    pairs,
+                    mode: args.mode,
+                    dry_run: args.dry_run,
+                },
+            )?;
+            let batch: ImportSummary =
+                serde_json::from_value(data).map_err(|e| format!("invalid summary: {}", e))?;
+            summary.add(batch);
+            if args.verbose {
+                eprintln!("imported batch: {:?}", batch);
+            }
+            Ok(())
+        })?;
+        Ok(Self::import_summary(args, total, summary))
+    }
+
+    /// Apply the input to the files directly. Refuses to run next to a live daemon,
+    /// which would overwrite the result with its own state.
+    fn import_offline(args: &ImportArgs) -> Result<Value, String> {
+        if !args.dry_run && Self::connect().is_some() {
+            return Err("the daemon is running; stop it or import without --offline".into());
+        }
+        let auth_key = AegFileSystem::resolve_authorization_key(args.auth_key.as_deref())?;
+        let collections = AegTransfer::offline_collections(&auth_key)?;
+        let mut engine = if collections.contains(&args.collection) {
+            AegTransfer::offline_load(&args.collection, &auth_key)?
+        } else {
+            AegMemoryEngine::new(&args.collection)
+        };
+        let input = Self::input(args.input.as_ref())?;
+        let mut summary = ImportSummary::default();
+        let total = AegTransfer::read_entries(input, args.format, |pairs| {
+            let batch = AegTransfer::import_into(&mut engine, &pairs, args.mode, args.dry_run);
+            summary.add(batch);
+            Ok(())
+        })?;
+        if !args.dry_run {
+            AegTransfer::offline_save(&mut engine, &auth_key)?;
+        }
+        Ok(Self::import_summary(args, total, summary))
+    }
+
+    fn import_summary(args: &ImportArgs, total: usize, summary: ImportSummary) -> Value {
+        let message = if args.dry_run {
+            format!(
+                "Dry run: {} entr(ies) read, nothing written to collection '{}'",
+                total, args.collection
+            )
+        } else {
+            format!(
+                "✓ Imported {} entr(ies) into collection '{}'",
+                total, args.collection
+            )
+        };
+        json!({ "status": "ok", "message": message, "data": summary })
+    }
+
+    /// Add a key to the client keyfile, or list the keys in it.
+    fn client_key(keyfile: Option<&PathBuf>, args: &ClientKeyArgs) -> Result<Value, String> {
+        let path = keyfile.ok_or(format!("--keyfile (or {}) is required", CLIENT_KEYFILE_ENV))?;
+        if args.list {
+            let crypto = AegFieldCrypto::load(path)?;
+            let ids: Vec<&str> = crypto.key_ids().collect();
+            return Ok(json!({
+                "status": "ok",
+                "message": format!("Current key: {}", crypto.current_id()),
+                "data": ids
+            }));
+        }
+        let id = AegFieldCrypto::generate(path)?;
+        Ok(json!({
+            "status": "ok",
+            "message": format!("✓ Key {} added to {} and now encrypts new values", id, path.display())
+        }))
+    }
+
+    fn pairs_or_exit(args: &MSetArgs) -> Vec<(String, String)> {
+        match args.to_pairs() {
+            Ok(pairs) => pairs,
+            Err(e) => {
+                eprintln!("{}", format!("Error: {}", e).red());
+                std::process::exit(2);
+            }
         }
     }
 }